@@ -27,6 +27,10 @@ fn copy_opts() -> CopyOptions<'static> {
         dry_run: false,
         no_progress: true,
         no_preserve_times: true,
+        devices: false,
+        specials: false,
+        links: false,
+        atimes: false,
     }
 }
 
@@ -143,8 +147,8 @@ fn bench_sync_noop(c: &mut Criterion) {
                 &src_path,
                 local_backend(),
                 &dst_path,
-                parsync::sync::DEFAULT_CHUNK_SIZE,
                 true,
+                false,
             )
             .unwrap()
         })
@@ -0,0 +1,662 @@
+//! The `parsync --server` side of remote transfers and the client that
+//! drives it. Run at the far end of an SSH exec channel, the server walks
+//! trees, reports metadata, hashes files and applies deltas where the data
+//! lives, so only listings, checksums and changed bytes cross the network.
+//!
+//! The protocol is a sequence of frames over the command's stdin and
+//! stdout: a little-endian `u32` length, an op byte, and the payload.
+//! Requests are answered in order; a walk streams its entries before the
+//! final reply, a patch request is followed by its delta frames, and an
+//! unpack request by the small files it writes.
+
+use crate::backends::{
+    BatchFile, FileEntry, FileKind, FileMeta, LocalBackend, StorageBackend, SyncError,
+};
+use crate::delta::{self, DeltaOp, Patcher, Signature};
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bumped whenever a frame changes shape; both ends must agree.
+pub const PROTOCOL_VERSION: u32 = 2;
+const MAGIC: &[u8; 4] = b"PSYN";
+/// Largest frame either end accepts, which bounds a signature to files of
+/// several terabytes.
+const MAX_FRAME: usize = 1 << 30;
+
+const HELLO: u8 = 1;
+const STAT: u8 = 2;
+const WALK: u8 = 3;
+const HASH: u8 = 4;
+const SIGNATURE: u8 = 5;
+const PATCH: u8 = 6;
+const COPY: u8 = 7;
+const DATA: u8 = 8;
+const DONE: u8 = 9;
+const UNPACK: u8 = 10;
+const FILE: u8 = 11;
+const END: u8 = 12;
+
+const OK: u8 = 0x80;
+const ENTRY: u8 = 0x81;
+const NOT_FOUND: u8 = 0x82;
+const ERROR: u8 = 0x83;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn write_frame(writer: &mut impl Write, op: u8, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&(payload.len() as u32 + 1).to_le_bytes())?;
+    writer.write_all(&[op])?;
+    writer.write_all(payload)
+}
+
+/// Reads one frame; `None` when the stream ends cleanly between frames.
+fn read_frame(reader: &mut impl Read) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len == 0 || len > MAX_FRAME {
+        return Err(invalid(format!("Bad frame length {len}")));
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body)?;
+    let op = body.remove(0);
+    Ok(Some((op, body)))
+}
+
+/// Builds a frame payload.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, v: u8) -> &mut Self {
+        self.0.push(v);
+        self
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn bytes(&mut self, v: &[u8]) -> &mut Self {
+        self.u32(v.len() as u32);
+        self.0.extend_from_slice(v);
+        self
+    }
+
+    fn str(&mut self, v: &str) -> &mut Self {
+        self.bytes(v.as_bytes())
+    }
+
+    fn time(&mut self, t: Option<SystemTime>) -> &mut Self {
+        let Some(t) = t else {
+            return self.u8(0);
+        };
+        let (secs, nanos) = match t.duration_since(UNIX_EPOCH) {
+            Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+            Err(e) => {
+                let d = e.duration();
+                match d.subsec_nanos() {
+                    0 => (-(d.as_secs() as i64), 0),
+                    n => (-(d.as_secs() as i64) - 1, 1_000_000_000 - n),
+                }
+            }
+        };
+        self.u8(1).u64(secs as u64).u32(nanos)
+    }
+
+    fn meta(&mut self, meta: &FileMeta) -> &mut Self {
+        let kind = match meta.kind {
+            FileKind::File => 0,
+            FileKind::Dir => 1,
+            FileKind::Symlink => 2,
+            FileKind::Fifo => 3,
+            FileKind::Socket => 4,
+            FileKind::CharDevice => 5,
+            FileKind::BlockDevice => 6,
+        };
+        self.u8(kind)
+            .u64(meta.size)
+            .time(meta.modified)
+            .time(meta.accessed);
+        match meta.mode {
+            Some(mode) => self.u8(1).u32(mode),
+            None => self.u8(0),
+        };
+        match meta.rdev {
+            Some(rdev) => self.u8(1).u64(rdev),
+            None => self.u8(0),
+        }
+    }
+}
+
+/// Reads a frame payload, failing on truncation.
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid("Truncated frame"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("Path is not UTF-8"))
+    }
+
+    fn time(&mut self) -> io::Result<Option<SystemTime>> {
+        if self.u8()? == 0 {
+            return Ok(None);
+        }
+        let secs = self.u64()? as i64;
+        let nanos = Duration::from_nanos(self.u32()? as u64);
+        Ok(Some(if secs >= 0 {
+            UNIX_EPOCH + Duration::from_secs(secs as u64) + nanos
+        } else {
+            UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nanos
+        }))
+    }
+
+    fn meta(&mut self) -> io::Result<FileMeta> {
+        let kind = match self.u8()? {
+            0 => FileKind::File,
+            1 => FileKind::Dir,
+            2 => FileKind::Symlink,
+            3 => FileKind::Fifo,
+            4 => FileKind::Socket,
+            5 => FileKind::CharDevice,
+            6 => FileKind::BlockDevice,
+            other => return Err(invalid(format!("Unknown file kind {other}"))),
+        };
+        let size = self.u64()?;
+        let modified = self.time()?;
+        let accessed = self.time()?;
+        let mode = match self.u8()? {
+            0 => None,
+            _ => Some(self.u32()?),
+        };
+        let rdev = match self.u8()? {
+            0 => None,
+            _ => Some(self.u64()?),
+        };
+        Ok(FileMeta {
+            size,
+            kind,
+            modified,
+            accessed,
+            mode,
+            rdev,
+        })
+    }
+
+    fn hash(&mut self) -> io::Result<blake3::Hash> {
+        let bytes: [u8; 32] = self.take(32)?.try_into().unwrap();
+        Ok(blake3::Hash::from(bytes))
+    }
+}
+
+/// Answers requests from `reader` on the local filesystem until it ends.
+/// Failures of a request are sent back as replies; only a broken or
+/// garbled stream ends the loop early.
+pub fn serve(reader: impl Read, writer: impl Write) -> Result<(), SyncError> {
+    let mut server = Server {
+        reader: BufReader::new(reader),
+        writer: BufWriter::new(writer),
+        local: LocalBackend::new(),
+    };
+    while let Some((op, payload)) = read_frame(&mut server.reader)? {
+        server.handle(op, &payload)?;
+        server.writer.flush()?;
+    }
+    Ok(())
+}
+
+struct Server<R, W: Write> {
+    reader: BufReader<R>,
+    writer: BufWriter<W>,
+    local: LocalBackend,
+}
+
+impl<R: Read, W: Write> Server<R, W> {
+    fn handle(&mut self, op: u8, payload: &[u8]) -> io::Result<()> {
+        let mut args = Decoder(payload);
+        match op {
+            HELLO => {
+                let magic = args.take(4)?;
+                let version = args.u32()?;
+                if magic != MAGIC || version != PROTOCOL_VERSION {
+                    return self.reply(Err(SyncError::Other(format!(
+                        "Protocol version {version} not supported, this is {PROTOCOL_VERSION}"
+                    ))));
+                }
+                let mut hello = Encoder::default();
+                hello.0.extend_from_slice(MAGIC);
+                hello.u32(PROTOCOL_VERSION);
+                self.reply(Ok(hello))
+            }
+            STAT => {
+                let path = args.str()?;
+                let result = self.local.stat(&path).map(|meta| {
+                    let mut reply = Encoder::default();
+                    reply.meta(&meta);
+                    reply
+                });
+                self.reply(result)
+            }
+            WALK => {
+                let root = args.str()?;
+                let writer = &mut self.writer;
+                let mut sent = Ok(());
+                let walked = self.local.walk(&root, &mut |entry| {
+                    if sent.is_ok() {
+                        let mut frame = Encoder::default();
+                        frame.str(&entry.path).meta(&entry.metadata);
+                        sent = write_frame(writer, ENTRY, &frame.0);
+                    }
+                });
+                sent?;
+                self.reply(walked.map(|_| Encoder::default()))
+            }
+            HASH => {
+                let path = args.str()?;
+                let result = hash_file(Path::new(&path)).map(|hash| {
+                    let mut reply = Encoder::default();
+                    reply.0.extend_from_slice(hash.as_bytes());
+                    reply
+                });
+                self.reply(result)
+            }
+            SIGNATURE => {
+                let path = args.str()?;
+                let block_size = args.u32()?;
+                let result = signature(&path, block_size).map(|sig| {
+                    let mut reply = Encoder::default();
+                    reply.u32(sig.block_size).u64(sig.blocks.len() as u64);
+                    for block in &sig.blocks {
+                        reply.u32(block.weak);
+                        reply.0.extend_from_slice(&block.strong);
+                    }
+                    reply
+                });
+                self.reply(result)
+            }
+            PATCH => {
+                let path = args.str()?;
+                let block_size = args.u32()?;
+                let meta = args.meta()?;
+                let result = self.patch(&path, block_size, &meta)?;
+                self.reply(result.map(|_| Encoder::default()))
+            }
+            UNPACK => {
+                let results = self.unpack()?;
+                let mut reply = Encoder::default();
+                reply.u32(results.len() as u32);
+                for result in results {
+                    match result {
+                        Ok(()) => reply.u8(0),
+                        Err(e) => reply.u8(1).str(&message(e)),
+                    };
+                }
+                self.reply(Ok(reply))
+            }
+            other => Err(invalid(format!("Unknown request {other}"))),
+        }
+    }
+
+    /// Writes the files in the frames that follow, up to the end frame,
+    /// each with its metadata. The outer error is the stream failing;
+    /// otherwise there is one result per file.
+    fn unpack(&mut self) -> io::Result<Vec<Result<(), SyncError>>> {
+        let mut results = Vec::new();
+        loop {
+            let Some((op, payload)) = read_frame(&mut self.reader)? else {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            };
+            match op {
+                FILE => {
+                    let mut args = Decoder(&payload);
+                    let path = args.str()?;
+                    let meta = args.meta()?;
+                    let data = args.bytes()?;
+                    results.push(self.write_file(&path, &meta, data));
+                }
+                END => return Ok(results),
+                other => return Err(invalid(format!("Unexpected frame {other} in a batch"))),
+            }
+        }
+    }
+
+    fn write_file(&self, path: &str, meta: &FileMeta, data: &[u8]) -> Result<(), SyncError> {
+        if let Some(parent) = Path::new(path).parent() {
+            if !parent.as_os_str().is_empty() {
+                self.local.mkdir(&parent.to_string_lossy())?;
+            }
+        }
+        self.local.put(path, data)?;
+        self.local.set_metadata(path, meta)
+    }
+
+    fn reply(&mut self, result: Result<Encoder, SyncError>) -> io::Result<()> {
+        match result {
+            Ok(payload) => write_frame(&mut self.writer, OK, &payload.0),
+            Err(SyncError::NotFound(path)) => write_frame(
+                &mut self.writer,
+                NOT_FOUND,
+                &Encoder::default().str(&path).0,
+            ),
+            Err(e) => write_frame(
+                &mut self.writer,
+                ERROR,
+                &Encoder::default().str(&message(e)).0,
+            ),
+        }
+    }
+
+    /// Rebuilds `path` from its current contents and the delta frames that
+    /// follow, into a temporary file renamed over it once the result's hash
+    /// matches the sender's. The outer error is the stream failing; the
+    /// inner one is sent back. Frames are read to the end either way.
+    fn patch(
+        &mut self,
+        path: &str,
+        block_size: u32,
+        meta: &FileMeta,
+    ) -> io::Result<Result<(), SyncError>> {
+        let target = Path::new(path);
+        let name = target
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let temp = target.with_file_name(format!(".{name}.parsync-{}", std::process::id()));
+        let mut patcher = fs::File::open(target)
+            .and_then(|base| {
+                let out = fs::File::create(&temp)?;
+                Ok(Patcher::new(base, block_size, BufWriter::new(out)))
+            })
+            .map_err(SyncError::from);
+
+        let expected = loop {
+            let Some((op, payload)) = read_frame(&mut self.reader)? else {
+                let _ = fs::remove_file(&temp);
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            };
+            let mut args = Decoder(&payload);
+            let op = match op {
+                COPY => DeltaOp::Copy {
+                    block: args.u64()?,
+                    count: args.u64()?,
+                },
+                DATA => DeltaOp::Data(payload),
+                DONE => break args.hash()?,
+                other => {
+                    let _ = fs::remove_file(&temp);
+                    return Err(invalid(format!("Unexpected frame {other} in a patch")));
+                }
+            };
+            if let Ok(p) = &mut patcher {
+                if let Err(e) = p.apply(&op) {
+                    patcher = Err(e.into());
+                }
+            }
+        };
+
+        let result = patcher.and_then(|patcher| {
+            let (_, hash, _) = patcher.finish()?;
+            if hash != expected {
+                return Err(SyncError::Other(format!(
+                    "Patched {path} does not match the sender's checksum"
+                )));
+            }
+            fs::rename(&temp, target)?;
+            self.local.set_metadata(path, meta)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        Ok(result)
+    }
+}
+
+/// The text a failure is reported with.
+fn message(e: SyncError) -> String {
+    match e {
+        SyncError::Io(e) => e.to_string(),
+        SyncError::NotFound(path) => format!("{path}: not found"),
+        SyncError::Other(message) => message,
+    }
+}
+
+/// blake3 of the file at `path`.
+pub fn hash_file(path: &Path) -> Result<blake3::Hash, SyncError> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(SyncError::NotFound(path.to_string_lossy().to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+fn signature(path: &str, block_size: u32) -> Result<Signature, SyncError> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(SyncError::NotFound(path.to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Signature::compute(&mut file, block_size)?)
+}
+
+/// A running `parsync --server`, reached through `reader` (its stdout) and
+/// `writer` (its stdin). Any [`SyncError::Io`] leaves the stream in an
+/// unknown state: drop the agent rather than sending more requests.
+pub struct Agent<R, W: Write> {
+    reader: BufReader<R>,
+    writer: BufWriter<W>,
+}
+
+impl<R: Read, W: Write> Agent<R, W> {
+    /// Checks that the other end is a parsync server speaking this protocol
+    /// version before anything it sends is trusted.
+    pub fn handshake(reader: R, writer: W) -> Result<Self, SyncError> {
+        let mut agent = Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+        };
+        let mut hello = Encoder::default();
+        hello.0.extend_from_slice(MAGIC);
+        hello.u32(PROTOCOL_VERSION);
+        let reply = agent.call(HELLO, &hello, "")?;
+        let mut reply = Decoder(&reply);
+        if reply.take(4).ok() != Some(MAGIC.as_slice()) {
+            return Err(invalid("Not a parsync server").into());
+        }
+        match reply.u32() {
+            Ok(PROTOCOL_VERSION) => Ok(agent),
+            Ok(version) => Err(SyncError::Other(format!(
+                "Server speaks protocol version {version}, this is {PROTOCOL_VERSION}"
+            ))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn send(&mut self, op: u8, payload: &[u8]) -> io::Result<()> {
+        write_frame(&mut self.writer, op, payload)
+    }
+
+    /// Sends one request and returns the payload of its reply.
+    fn call(&mut self, op: u8, request: &Encoder, path: &str) -> Result<Vec<u8>, SyncError> {
+        self.send(op, &request.0)?;
+        self.writer.flush()?;
+        self.reply(path)
+    }
+
+    fn reply(&mut self, path: &str) -> Result<Vec<u8>, SyncError> {
+        let (op, payload) = self.next_frame()?;
+        outcome(op, payload, path)
+    }
+
+    fn next_frame(&mut self) -> Result<(u8, Vec<u8>), SyncError> {
+        read_frame(&mut self.reader)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+
+    pub fn stat(&mut self, path: &str) -> Result<FileMeta, SyncError> {
+        let reply = self.call(STAT, Encoder::default().str(path), path)?;
+        Ok(Decoder(&reply).meta()?)
+    }
+
+    /// Streams `root` and everything below it, parents before children and
+    /// symlinks not followed, as [`StorageBackend::walk`].
+    pub fn walk(&mut self, root: &str, visit: &mut dyn FnMut(FileEntry)) -> Result<(), SyncError> {
+        self.send(WALK, &Encoder::default().str(root).0)?;
+        self.writer.flush()?;
+        loop {
+            let (op, payload) = self.next_frame()?;
+            if op != ENTRY {
+                return outcome(op, payload, root).map(|_| ());
+            }
+            let mut entry = Decoder(&payload);
+            let path = entry.str()?;
+            let metadata = entry.meta()?;
+            visit(FileEntry { path, metadata });
+        }
+    }
+
+    pub fn hash(&mut self, path: &str) -> Result<blake3::Hash, SyncError> {
+        let reply = self.call(HASH, Encoder::default().str(path), path)?;
+        Ok(Decoder(&reply).hash()?)
+    }
+
+    /// The signature of `path` in blocks of `block_size`.
+    pub fn signature(&mut self, path: &str, block_size: u32) -> Result<Signature, SyncError> {
+        let reply = self.call(
+            SIGNATURE,
+            Encoder::default().str(path).u32(block_size),
+            path,
+        )?;
+        let mut reply = Decoder(&reply);
+        let block_size = reply.u32()?;
+        let count = reply.u64()? as usize;
+        let mut blocks = Vec::with_capacity(count.min(reply.0.len() / 20));
+        for _ in 0..count {
+            let weak = reply.u32()?;
+            let strong = reply.take(16)?.try_into().unwrap();
+            blocks.push(delta::BlockSig { weak, strong });
+        }
+        Ok(Signature { block_size, blocks })
+    }
+
+    /// Rewrites `path`, whose signature is `basis`, with the contents of
+    /// `reader`, sending only what the remote copy lacks; then applies the
+    /// permissions and timestamps in `meta`. Returns how many literal
+    /// bytes were sent.
+    pub fn patch(
+        &mut self,
+        path: &str,
+        basis: &Signature,
+        reader: &mut dyn Read,
+        meta: &FileMeta,
+    ) -> Result<u64, SyncError> {
+        let mut request = Encoder::default();
+        request.str(path).u32(basis.block_size).meta(meta);
+        self.send(PATCH, &request.0)?;
+        let writer = &mut self.writer;
+        let mut literal = 0u64;
+        let (hash, _) = delta::diff(basis, reader, &mut |op| match op {
+            DeltaOp::Copy { block, count } => {
+                write_frame(writer, COPY, &Encoder::default().u64(block).u64(count).0)
+            }
+            DeltaOp::Data(data) => {
+                literal += data.len() as u64;
+                write_frame(writer, DATA, &data)
+            }
+        })?;
+        self.send(DONE, hash.as_bytes())?;
+        self.writer.flush()?;
+        self.reply(path)?;
+        Ok(literal)
+    }
+
+    /// Writes `files` and their metadata in one request, streaming one
+    /// frame per file. Returns one result per file, in order.
+    pub fn unpack(&mut self, files: &[BatchFile]) -> Result<Vec<Result<(), SyncError>>, SyncError> {
+        self.send(UNPACK, &[])?;
+        for file in files {
+            let mut frame = Encoder::default();
+            frame.str(&file.path).meta(&file.meta).bytes(&file.data);
+            self.send(FILE, &frame.0)?;
+        }
+        self.send(END, &[])?;
+        self.writer.flush()?;
+        let reply = self.reply("")?;
+        let mut reply = Decoder(&reply);
+        let count = reply.u32()? as usize;
+        if count != files.len() {
+            return Err(invalid(format!("{count} results for {} files", files.len())).into());
+        }
+        files
+            .iter()
+            .map(|file| {
+                Ok(match reply.u8()? {
+                    0 => Ok(()),
+                    _ => Err(SyncError::Other(format!(
+                        "Remote parsync: {}: {}",
+                        file.path,
+                        reply.str()?
+                    ))),
+                })
+            })
+            .collect()
+    }
+}
+
+/// The payload of a final reply, or the failure it reports.
+fn outcome(op: u8, payload: Vec<u8>, path: &str) -> Result<Vec<u8>, SyncError> {
+    match op {
+        OK => Ok(payload),
+        NOT_FOUND => Err(SyncError::NotFound(path.to_string())),
+        ERROR => Err(SyncError::Other(format!(
+            "Remote parsync: {}",
+            Decoder(&payload).str()?
+        ))),
+        other => Err(invalid(format!("Unexpected reply {other}")).into()),
+    }
+}
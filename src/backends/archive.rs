@@ -0,0 +1,425 @@
+//! Pieces shared by the archive backends (tar, zip): entry paths, the
+//! read-or-write lifecycle, the entry index and readers over byte ranges.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path};
+use std::sync::Arc;
+
+use super::{FileEntry, FileKind, FileMeta, SyncError};
+
+/// Files up to this size are read into memory before the archive lock is
+/// taken, so parallel workers only serialize on the write itself.
+pub(super) const BUFFERED_ENTRY_LIMIT: u64 = 8 * 1024 * 1024;
+
+/// Maps an entry path to its key: `/`-prefixed, without `.` components.
+/// Returns `None` for paths that would escape the archive root.
+pub(super) fn key_of(path: &Path) -> Option<String> {
+    let mut key = String::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => {
+                key.push('/');
+                key.push_str(&name.to_string_lossy());
+            }
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(if key.is_empty() { "/".to_string() } else { key })
+}
+
+pub(super) fn normalize(path: &str) -> String {
+    key_of(Path::new(path)).unwrap_or_else(|| path.to_string())
+}
+
+/// Name stored in the archive for `path`: its key without the leading `/`.
+pub(super) fn entry_name(path: &str) -> Result<String, SyncError> {
+    let key = normalize(path);
+    match key.strip_prefix('/') {
+        Some(name) if !name.is_empty() => Ok(name.to_string()),
+        _ => Err(SyncError::Other(format!(
+            "Invalid archive entry path: {path}"
+        ))),
+    }
+}
+
+pub(super) fn dir_meta() -> FileMeta {
+    FileMeta {
+        size: 0,
+        kind: FileKind::Dir,
+        modified: None,
+        accessed: None,
+        mode: Some(0o755),
+        rdev: None,
+    }
+}
+
+pub(super) fn file_meta(size: u64) -> FileMeta {
+    FileMeta {
+        size,
+        kind: FileKind::File,
+        mode: None,
+        ..dir_meta()
+    }
+}
+
+/// An archive is either read or written, never both; the first operation
+/// decides.
+pub(super) enum State<R, W> {
+    Closed,
+    Reading(Arc<R>),
+    Writing(Box<W>),
+    Finished,
+}
+
+impl<R, W> State<R, W> {
+    /// Returns the index, building it with `open` on first use.
+    pub(super) fn reading(
+        &mut self,
+        open: impl FnOnce() -> Result<R, SyncError>,
+    ) -> Result<Arc<R>, SyncError> {
+        match self {
+            State::Reading(index) => Ok(index.clone()),
+            State::Closed => {
+                let index = Arc::new(open()?);
+                *self = State::Reading(index.clone());
+                Ok(index)
+            }
+            State::Writing(_) | State::Finished => {
+                Err(SyncError::Other("Archive is open for writing".to_string()))
+            }
+        }
+    }
+
+    /// Returns the writer, creating the archive with `create` on first use.
+    pub(super) fn writing(
+        &mut self,
+        create: impl FnOnce() -> Result<W, SyncError>,
+    ) -> Result<&mut W, SyncError> {
+        if let State::Closed = self {
+            *self = State::Writing(Box::new(create()?));
+        }
+        match self {
+            State::Writing(writer) => Ok(writer),
+            State::Reading(_) => Err(SyncError::Other(
+                "Archive is open for reading and cannot be modified".to_string(),
+            )),
+            State::Finished => Err(SyncError::Other("Archive is already finished".to_string())),
+            State::Closed => unreachable!("created above"),
+        }
+    }
+
+    /// Takes the writer to finish it, leaving the archive closed for good.
+    /// Returns `None` when nothing was written.
+    pub(super) fn take_writer(&mut self) -> Option<W> {
+        match std::mem::replace(self, State::Finished) {
+            State::Writing(writer) => Some(*writer),
+            other => {
+                *self = other;
+                None
+            }
+        }
+    }
+}
+
+/// Entries of an archive being read, by key, with directories that are
+/// only implied by deeper paths filled in.
+pub(super) struct Entries<T> {
+    map: BTreeMap<String, (FileMeta, T)>,
+}
+
+impl<T: Default> Entries<T> {
+    pub(super) fn new() -> Self {
+        Self {
+            map: BTreeMap::new(),
+        }
+    }
+
+    /// Adds an entry, replacing an implied or earlier one with the same key.
+    pub(super) fn insert(&mut self, key: String, meta: FileMeta, data: T) {
+        if key == "/" {
+            return;
+        }
+        for ancestor in Path::new(&key).ancestors().skip(1) {
+            let parent = ancestor.to_string_lossy().to_string();
+            if parent == "/" || self.map.contains_key(&parent) {
+                break;
+            }
+            let meta = FileMeta {
+                mode: None,
+                ..dir_meta()
+            };
+            self.map.insert(parent, (meta, T::default()));
+        }
+        self.map.insert(key, (meta, data));
+    }
+
+    pub(super) fn get(&self, key: &str) -> Option<&(FileMeta, T)> {
+        self.map.get(key)
+    }
+
+    pub(super) fn get_mut(&mut self, key: &str) -> Option<&mut (FileMeta, T)> {
+        self.map.get_mut(key)
+    }
+
+    /// Removes `key` and everything below it, returning them in path order.
+    pub(super) fn remove(&mut self, key: &str) -> Vec<(String, (FileMeta, T))> {
+        let below: Vec<String> = self.below(key).map(|(k, _)| k.clone()).collect();
+        std::iter::once(key.to_string())
+            .chain(below)
+            .filter_map(|k| self.map.remove(&k).map(|v| (k, v)))
+            .collect()
+    }
+
+    /// All entries in path order, which puts parents first.
+    pub(super) fn iter(&self) -> impl Iterator<Item = (&String, &(FileMeta, T))> {
+        self.map.iter()
+    }
+
+    pub(super) fn stat(&self, key: &str) -> Result<FileMeta, SyncError> {
+        if key == "/" {
+            return Ok(dir_meta());
+        }
+        self.map
+            .get(key)
+            .map(|(meta, _)| meta.clone())
+            .ok_or_else(|| SyncError::NotFound(key.to_string()))
+    }
+
+    /// Entries below `key` in path order, which puts parents first.
+    fn below<'a>(&'a self, key: &str) -> impl Iterator<Item = (&'a String, &'a FileMeta)> {
+        let prefix = if key == "/" {
+            key.to_string()
+        } else {
+            format!("{key}/")
+        };
+        self.map
+            .range(prefix.clone()..)
+            .take_while(move |(k, _)| k.starts_with(&prefix))
+            .map(|(k, (meta, _))| (k, meta))
+    }
+
+    pub(super) fn list(&self, key: &str) -> Result<Vec<FileEntry>, SyncError> {
+        if !self.stat(key)?.is_dir() {
+            return Err(SyncError::Other(format!("Not a directory: {key}")));
+        }
+        let depth = key.trim_end_matches('/').matches('/').count() + 1;
+        Ok(self
+            .below(key)
+            .filter(|(k, _)| k.matches('/').count() == depth)
+            .map(|(k, meta)| FileEntry {
+                path: k.clone(),
+                metadata: meta.clone(),
+            })
+            .collect())
+    }
+
+    /// Visits `root` as given, then everything below it.
+    pub(super) fn walk(
+        &self,
+        root: &str,
+        visit: &mut dyn FnMut(FileEntry),
+    ) -> Result<(), SyncError> {
+        let key = normalize(root);
+        let meta = self.stat(&key)?;
+        let is_dir = meta.is_dir();
+        visit(FileEntry {
+            path: root.to_string(),
+            metadata: meta,
+        });
+        if is_dir {
+            for (path, meta) in self.below(&key) {
+                visit(FileEntry {
+                    path: path.clone(),
+                    metadata: meta.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Directories created in an archive being written. Their entries are
+/// added when the archive is finished, once their final metadata is known,
+/// so extraction sets their mtimes after their contents.
+#[derive(Default)]
+pub(super) struct PendingDirs {
+    dirs: BTreeMap<String, FileMeta>,
+}
+
+impl PendingDirs {
+    /// Records `path` and any ancestors not seen yet.
+    pub(super) fn add(&mut self, path: &str) {
+        for ancestor in Path::new(&normalize(path)).ancestors() {
+            let dir = ancestor.to_string_lossy().to_string();
+            if dir == "/" || self.dirs.contains_key(&dir) {
+                break;
+            }
+            self.dirs.insert(dir, dir_meta());
+        }
+    }
+
+    /// Applies the times and mode in `meta` to a pending directory. Other
+    /// entries were written with their metadata already, so they are left
+    /// alone.
+    pub(super) fn set_metadata(&mut self, path: &str, meta: &FileMeta) {
+        if let Some(dir) = self.dirs.get_mut(&normalize(path)) {
+            if meta.mode.is_some() {
+                dir.mode = meta.mode;
+            }
+            if meta.modified.is_some() {
+                dir.modified = meta.modified;
+            }
+            if meta.accessed.is_some() {
+                dir.accessed = meta.accessed;
+            }
+        }
+    }
+
+    pub(super) fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        let key = normalize(path);
+        if key == "/" {
+            return Ok(dir_meta());
+        }
+        self.dirs.get(&key).cloned().ok_or(SyncError::NotFound(key))
+    }
+
+    /// Entry names (without the leading `/`) and metadata, parents first.
+    pub(super) fn into_entries(self) -> impl Iterator<Item = (String, FileMeta)> {
+        self.dirs
+            .into_iter()
+            .map(|(key, meta)| (key[1..].to_string(), meta))
+    }
+}
+
+/// A byte range of a shared file, read with positional reads so any number
+/// of workers can stream from the same archive at once.
+#[derive(Clone)]
+pub(super) struct FileSlice {
+    file: Arc<File>,
+    start: u64,
+    pos: u64,
+    end: u64,
+}
+
+impl FileSlice {
+    pub(super) fn new(file: Arc<File>, start: u64, len: u64) -> Self {
+        Self {
+            file,
+            start,
+            pos: start,
+            end: start + len,
+        }
+    }
+
+    pub(super) fn whole(file: Arc<File>) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self::new(file, 0, len))
+    }
+}
+
+impl Read for FileSlice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let want = buf.len().min(self.end.saturating_sub(self.pos) as usize);
+        if want == 0 {
+            return Ok(0);
+        }
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::read_at(&*self.file, &mut buf[..want], self.pos)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_read(&*self.file, &mut buf[..want], self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for FileSlice {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(self.start.saturating_add(n)),
+            SeekFrom::End(n) => self.end.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        match target {
+            Some(target) if target >= self.start => {
+                self.pos = target;
+                Ok(target - self.start)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before start of archive range",
+            )),
+        }
+    }
+}
+
+/// Yields exactly `remaining` bytes: input beyond that is ignored, and a
+/// short input is padded with zeros and flagged so the entry keeps the size
+/// already promised in its header.
+pub(super) struct Exact<'a> {
+    inner: &'a mut dyn Read,
+    remaining: u64,
+    short: bool,
+}
+
+impl<'a> Exact<'a> {
+    pub(super) fn new(inner: &'a mut dyn Read, size: u64) -> Self {
+        Self {
+            inner,
+            remaining: size,
+            short: false,
+        }
+    }
+}
+
+impl Read for Exact<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let want = buf.len().min(self.remaining as usize);
+        let n = if self.short {
+            0
+        } else {
+            self.inner.read(&mut buf[..want])?
+        };
+        let n = if n == 0 {
+            self.short = true;
+            buf[..want].fill(0);
+            want
+        } else {
+            n
+        };
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads `meta.size` bytes of `reader` into memory when small enough to be
+/// worth doing before taking the archive lock, and passes `write` a reader
+/// of exactly that many bytes. Errors if the source ended early.
+pub(super) fn write_exact(
+    path: &str,
+    reader: &mut dyn Read,
+    size: u64,
+    write: impl FnOnce(&mut Exact) -> Result<(), SyncError>,
+) -> Result<(), SyncError> {
+    let mut buffered;
+    let reader: &mut dyn Read = if size <= BUFFERED_ENTRY_LIMIT {
+        buffered = Vec::with_capacity(size as usize);
+        reader.take(size).read_to_end(&mut buffered)?;
+        &mut &buffered[..]
+    } else {
+        reader
+    };
+    let mut data = Exact::new(reader, size);
+    write(&mut data)?;
+    if data.short {
+        return Err(SyncError::Other(format!(
+            "Source ended before {size} bytes were archived for {path}; the entry is zero-padded"
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,389 @@
+//! Stores files in another backend as zstd streams. The original size is
+//! kept in the stored name, `name.<size in hex>.zst`, so listings report it
+//! without reading anything; mtimes and modes stay on the stored file.
+//! Entries without that suffix (directories, links, foreign files) pass
+//! through unchanged.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::{BackendUrl, FileEntry, FileMeta, StorageBackend, SyncError, WrapperFactory};
+
+/// Compressed files up to this size are kept in memory until they are
+/// stored; larger ones are spooled to a temporary file.
+const SPOOL_LIMIT: u64 = 8 * 1024 * 1024;
+
+/// Stored name for a file called `name` of `size` bytes.
+pub fn stored_name(name: &str, size: u64) -> String {
+    format!("{name}.{size:x}.zst")
+}
+
+/// The original name and size encoded in a stored name.
+pub fn split_stored_name(stored: &str) -> Option<(&str, u64)> {
+    let (name, size) = stored.strip_suffix(".zst")?.rsplit_once('.')?;
+    let hex = |c: char| c.is_ascii_digit() || ('a'..='f').contains(&c);
+    if name.is_empty() || size.is_empty() || !size.chars().all(hex) {
+        return None;
+    }
+    Some((name, u64::from_str_radix(size, 16).ok()?))
+}
+
+fn split_path(path: &str) -> Option<(String, String)> {
+    let p = Path::new(path);
+    let name = p.file_name()?.to_string_lossy().to_string();
+    let parent = p.parent()?.to_string_lossy().to_string();
+    Some((parent, name))
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.ends_with('/') {
+        format!("{dir}{name}")
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+/// Compresses exactly `size` bytes of `reader` into memory or a temporary
+/// file, returning a reader over the result and its length.
+fn compress(
+    reader: &mut dyn Read,
+    size: u64,
+    level: i32,
+) -> Result<(Box<dyn Read + Send>, u64), SyncError> {
+    fn encode<W: Write>(out: W, reader: &mut dyn Read, size: u64, level: i32) -> io::Result<W> {
+        let mut encoder = zstd::Encoder::new(out, level)?;
+        encoder.set_pledged_src_size(Some(size))?;
+        let copied = io::copy(&mut reader.take(size), &mut encoder)?;
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("source ended after {copied} of {size} bytes"),
+            ));
+        }
+        encoder.finish()
+    }
+    if size <= SPOOL_LIMIT {
+        let data = encode(Vec::new(), reader, size, level)?;
+        let len = data.len() as u64;
+        Ok((Box::new(io::Cursor::new(data)), len))
+    } else {
+        let mut file = encode(
+            io::BufWriter::new(tempfile::tempfile()?),
+            reader,
+            size,
+            level,
+        )?
+        .into_inner()
+        .map_err(|e| e.into_error())?;
+        let len = file.stream_position()?;
+        file.rewind()?;
+        Ok((Box::new(file), len))
+    }
+}
+
+/// Compresses what it stores in `inner`.
+pub struct CompressBackend {
+    inner: Arc<dyn StorageBackend + Send + Sync>,
+    level: i32,
+    /// Stored names by original name, per directory listed so far. Keeps
+    /// overwrites from leaving the old `name.<size>.zst` behind without a
+    /// listing per write.
+    names: Mutex<HashMap<String, HashMap<String, String>>>,
+}
+
+impl CompressBackend {
+    pub fn new(inner: Arc<dyn StorageBackend + Send + Sync>, level: i32) -> Self {
+        Self {
+            inner,
+            level,
+            names: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Maps a stored entry to the original name and size.
+    fn plain_entry(&self, mut entry: FileEntry) -> FileEntry {
+        if entry.metadata.is_file() {
+            if let Some((parent, stored)) = split_path(&entry.path) {
+                if let Some((name, size)) = split_stored_name(&stored) {
+                    entry.path = join(&parent, name);
+                    entry.metadata.size = size;
+                }
+            }
+        }
+        entry
+    }
+
+    fn remember(&self, dir: &str, name: &str, stored: &str) {
+        if let Some(names) = self.names.lock().unwrap().get_mut(dir) {
+            names.insert(name.to_string(), stored.to_string());
+        }
+    }
+
+    /// Merges a listing of `dir` into the cache. Cached names win: a write
+    /// may have been remembered after the listing was taken.
+    fn cache_listing(&self, dir: &str, listed: HashMap<String, String>) {
+        let mut names = self.names.lock().unwrap();
+        let cached = names.entry(dir.to_string()).or_default();
+        for (name, stored) in listed {
+            cached.entry(name).or_insert(stored);
+        }
+    }
+
+    fn forget(&self, path: &str) {
+        let mut names = self.names.lock().unwrap();
+        if let Some((dir, name)) = split_path(path) {
+            if let Some(entries) = names.get_mut(&dir) {
+                entries.remove(&name);
+            }
+        }
+        let prefix = join(path, "");
+        names.retain(|dir, _| dir != path && !dir.starts_with(&prefix));
+    }
+
+    /// Stored path of `path`, or `None` if nothing is stored under it.
+    fn resolve(&self, path: &str) -> Result<Option<String>, SyncError> {
+        let Some((dir, name)) = split_path(path) else {
+            return Ok(Some(path.to_string()));
+        };
+        if let Some(names) = self.names.lock().unwrap().get(&dir) {
+            return Ok(names.get(&name).map(|stored| join(&dir, stored)));
+        }
+        let listed = match self.inner.list(&dir) {
+            Ok(entries) => entries
+                .into_iter()
+                .filter_map(|entry| {
+                    let (_, stored) = split_path(&entry.path)?;
+                    let plain = split_path(&self.plain_entry(entry).path)?.1;
+                    Some((plain, stored))
+                })
+                .collect(),
+            Err(SyncError::NotFound(_)) => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        self.cache_listing(&dir, listed);
+        let names = self.names.lock().unwrap();
+        Ok(names[&dir].get(&name).map(|stored| join(&dir, stored)))
+    }
+
+    fn stored(&self, path: &str) -> Result<String, SyncError> {
+        self.resolve(path)?
+            .ok_or_else(|| SyncError::NotFound(path.to_string()))
+    }
+
+    /// Writes the file of `size` original bytes at `path` with `write`,
+    /// then removes what was stored there under a different size.
+    fn store(
+        &self,
+        path: &str,
+        size: u64,
+        write: impl FnOnce(&str) -> Result<(), SyncError>,
+    ) -> Result<(), SyncError> {
+        let (dir, name) = split_path(path)
+            .ok_or_else(|| SyncError::Other(format!("Invalid file path: {path}")))?;
+        let previous = self.resolve(path)?;
+        let stored = join(&dir, &stored_name(&name, size));
+        write(&stored)?;
+        if let Some(previous) = previous.filter(|p| *p != stored) {
+            self.inner.delete(&previous)?;
+        }
+        self.remember(&dir, &name, &stored_name(&name, size));
+        Ok(())
+    }
+}
+
+impl StorageBackend for CompressBackend {
+    fn list(&self, path: &str) -> Result<Vec<FileEntry>, SyncError> {
+        let stored = self.inner.list(path)?;
+        let mut names = HashMap::new();
+        let entries = stored
+            .into_iter()
+            .map(|entry| {
+                let stored = split_path(&entry.path).map(|(_, name)| name);
+                let entry = self.plain_entry(entry);
+                if let (Some(stored), Some((_, name))) = (stored, split_path(&entry.path)) {
+                    names.insert(name, stored);
+                }
+                entry
+            })
+            .collect();
+        self.cache_listing(path, names);
+        Ok(entries)
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>, SyncError> {
+        let mut data = Vec::new();
+        self.open_read(path, 0, None)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), SyncError> {
+        self.put_stream(path, &mut &data[..], data.len() as u64)
+    }
+
+    fn delete(&self, path: &str) -> Result<(), SyncError> {
+        let stored = self.stored(path)?;
+        self.inner.delete(&stored)?;
+        self.forget(path);
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, SyncError> {
+        Ok(self.resolve(path)?.is_some())
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn put_stream(&self, path: &str, reader: &mut dyn Read, size: u64) -> Result<(), SyncError> {
+        let (mut data, len) = compress(reader, size, self.level)?;
+        self.store(path, size, |stored| {
+            self.inner.put_stream(stored, &mut data, len)
+        })
+    }
+
+    fn put_with_meta(
+        &self,
+        path: &str,
+        reader: &mut dyn Read,
+        meta: &FileMeta,
+    ) -> Result<(), SyncError> {
+        let (mut data, len) = compress(reader, meta.size, self.level)?;
+        let stored_meta = FileMeta {
+            size: len,
+            ..meta.clone()
+        };
+        self.store(path, meta.size, |stored| {
+            self.inner.put_with_meta(stored, &mut data, &stored_meta)
+        })
+    }
+
+    fn open_read(
+        &self,
+        path: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read + Send>, SyncError> {
+        let stored = self.stored(path)?;
+        let is_compressed = split_path(&stored)
+            .and_then(|(_, name)| split_stored_name(&name).map(|_| ()))
+            .is_some();
+        if !is_compressed {
+            return self.inner.open_read(&stored, offset, len);
+        }
+        let mut reader = zstd::Decoder::new(self.inner.open_read(&stored, 0, None)?)?;
+        io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+        Ok(match len {
+            Some(len) => Box::new(reader.take(len)),
+            None => Box::new(reader),
+        })
+    }
+
+    fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        let stored = self.stored(path)?;
+        let meta = self.inner.stat(&stored)?;
+        Ok(self
+            .plain_entry(FileEntry {
+                path: stored,
+                metadata: meta,
+            })
+            .metadata)
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), SyncError> {
+        self.inner.mkdir(path)?;
+        if let Some((dir, name)) = split_path(path) {
+            self.remember(&dir, &name, &name);
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+        let stored = self.stored(from)?;
+        let (Some((_, stored_name)), Some((_, from_name)), Some((to_dir, to_name))) =
+            (split_path(&stored), split_path(from), split_path(to))
+        else {
+            return self.inner.rename(&stored, to);
+        };
+        let suffix = &stored_name[from_name.len()..];
+        let target = format!("{to_name}{suffix}");
+        let previous = self.resolve(to)?;
+        self.inner.rename(&stored, &join(&to_dir, &target))?;
+        self.forget(from);
+        if let Some(previous) = previous.filter(|p| *p != join(&to_dir, &target)) {
+            self.inner.delete(&previous)?;
+        }
+        self.remember(&to_dir, &to_name, &target);
+        Ok(())
+    }
+
+    fn set_metadata(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        self.inner.set_metadata(&self.stored(path)?, meta)
+    }
+
+    fn walk(&self, root: &str, visit: &mut dyn FnMut(FileEntry)) -> Result<(), SyncError> {
+        self.inner
+            .walk(root, &mut |entry| visit(self.plain_entry(entry)))
+    }
+
+    fn symlink(&self, target: &str, link: &str) -> Result<(), SyncError> {
+        self.inner.symlink(target, link)?;
+        if let Some((dir, name)) = split_path(link) {
+            self.remember(&dir, &name, &name);
+        }
+        Ok(())
+    }
+
+    fn read_link(&self, path: &str) -> Result<String, SyncError> {
+        self.inner.read_link(path)
+    }
+
+    fn mknod(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        self.inner.mknod(path, meta)?;
+        if let Some((dir, name)) = split_path(path) {
+            self.remember(&dir, &name, &name);
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<(), SyncError> {
+        self.inner.finish()
+    }
+
+    fn time_precision(&self) -> std::time::Duration {
+        self.inner.time_precision()
+    }
+}
+
+/// `zstd+scheme://...`, with `?zstd_level=N` (default 3).
+pub(super) struct CompressWrapper;
+
+impl WrapperFactory for CompressWrapper {
+    fn options(&self) -> &[&str] {
+        &["zstd_level"]
+    }
+
+    fn wrap(
+        &self,
+        inner: Arc<dyn StorageBackend + Send + Sync>,
+        url: &BackendUrl<'_>,
+        _root: &str,
+    ) -> Result<Arc<dyn StorageBackend + Send + Sync>, SyncError> {
+        let levels = zstd::compression_level_range();
+        let level = match url.option("zstd_level").map(str::parse::<i32>) {
+            None => zstd::DEFAULT_COMPRESSION_LEVEL,
+            Some(Ok(level)) if levels.contains(&level) => level,
+            Some(_) => {
+                return Err(SyncError::Other(format!(
+                    "zstd_level must be {}-{}: {}",
+                    levels.start(),
+                    levels.end(),
+                    url.url
+                )))
+            }
+        };
+        Ok(Arc::new(CompressBackend::new(inner, level)))
+    }
+}
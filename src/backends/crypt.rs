@@ -0,0 +1,708 @@
+//! Client-side encryption over any backend. File contents are split into
+//! 64 KiB chunks sealed with XChaCha20-Poly1305; names can be encrypted too,
+//! deterministically, so paths still map one-to-one and `sync` works.
+//!
+//! Encrypted file layout: `MAGIC`, a random 16-byte nonce prefix, then the
+//! chunks, each followed by its tag. A chunk's nonce is the prefix, its index
+//! and a final-chunk flag, so chunks can't be reordered, dropped or cut off.
+//! The plaintext size follows from the stored size; mtimes and modes are
+//! kept by the wrapped backend as usual.
+//!
+//! Keys are derived with a random salt stored in `.parsync-crypt` at the
+//! destination root (`SALT_MAGIC` and the salt), written with the first
+//! write. Until then the destination reads as empty, without being touched.
+
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use base64::Engine;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::XChaCha20;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use super::{BackendUrl, FileEntry, FileMeta, StorageBackend, SyncError, WrapperFactory};
+
+const MAGIC: &[u8; 8] = b"PSCRYPT\x01";
+const PREFIX_LEN: usize = 16;
+const HEADER_LEN: u64 = (MAGIC.len() + PREFIX_LEN) as u64;
+const CHUNK: u64 = 64 * 1024;
+const TAG: u64 = 16;
+/// Length of the synthetic IV that starts each encrypted name.
+const SIV_LEN: usize = 16;
+const SALT_MAGIC: &[u8; 8] = b"PSSALT\x00\x01";
+pub const SALT_LEN: usize = 16;
+/// Name of the salt file kept next to the encrypted files.
+pub const SALT_FILE: &str = ".parsync-crypt";
+
+fn read_key_file(path: &str) -> Result<Vec<u8>, SyncError> {
+    let data = std::fs::read(path)?;
+    if data.is_empty() {
+        return Err(SyncError::Other(format!("Crypt key file is empty: {path}")));
+    }
+    Ok(data)
+}
+
+/// What a key is derived from, together with the destination's salt.
+enum Secret {
+    Passphrase(String),
+    KeyFile(Vec<u8>),
+}
+
+impl Secret {
+    fn derive(&self, salt: &[u8; SALT_LEN]) -> Result<CryptKey, SyncError> {
+        match self {
+            Secret::Passphrase(passphrase) => CryptKey::from_passphrase(passphrase, salt),
+            Secret::KeyFile(data) => Ok(CryptKey::from_key_data(data, salt)),
+        }
+    }
+}
+
+/// Keys for file contents and names, derived from one master key.
+#[derive(Clone)]
+pub struct CryptKey {
+    content: [u8; 32],
+    name_mac: [u8; 32],
+    name_enc: [u8; 32],
+}
+
+impl CryptKey {
+    pub fn from_master(master: [u8; 32]) -> Self {
+        Self {
+            content: blake3::derive_key("parsync crypt v1 content", &master),
+            name_mac: blake3::derive_key("parsync crypt v1 name mac", &master),
+            name_enc: blake3::derive_key("parsync crypt v1 name encryption", &master),
+        }
+    }
+
+    /// Stretches `passphrase` with Argon2id.
+    pub fn from_passphrase(passphrase: &str, salt: &[u8; SALT_LEN]) -> Result<Self, SyncError> {
+        if passphrase.is_empty() {
+            return Err(SyncError::Other("Empty crypt passphrase".to_string()));
+        }
+        let mut master = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut master)
+            .map_err(|e| SyncError::Other(format!("Crypt key derivation failed: {e}")))?;
+        Ok(Self::from_master(master))
+    }
+
+    /// Hashes the contents of a key file, which can hold any bytes.
+    pub fn from_key_file(path: &str, salt: &[u8; SALT_LEN]) -> Result<Self, SyncError> {
+        Ok(Self::from_key_data(&read_key_file(path)?, salt))
+    }
+
+    fn from_key_data(data: &[u8], salt: &[u8; SALT_LEN]) -> Self {
+        let mut hasher = blake3::Hasher::new_derive_key("parsync crypt v1 key file");
+        hasher.update(salt).update(data);
+        Self::from_master(*hasher.finalize().as_bytes())
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new((&self.content).into())
+    }
+
+    /// Deterministic authenticated encryption: the IV is a keyed hash of the
+    /// name, checked again after decryption.
+    fn encrypt_name(&self, name: &str) -> String {
+        let siv = blake3::keyed_hash(&self.name_mac, name.as_bytes());
+        let mut out = siv.as_bytes()[..SIV_LEN].to_vec();
+        let mut data = name.as_bytes().to_vec();
+        self.name_stream(&out).apply_keystream(&mut data);
+        out.extend_from_slice(&data);
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(out)
+    }
+
+    fn decrypt_name(&self, encrypted: &str) -> Result<String, SyncError> {
+        let invalid = || SyncError::Other(format!("Not an encrypted name: {encrypted}"));
+        let data = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(encrypted)
+            .map_err(|_| invalid())?;
+        if data.len() < SIV_LEN {
+            return Err(invalid());
+        }
+        let (siv, data) = data.split_at(SIV_LEN);
+        let mut name = data.to_vec();
+        self.name_stream(siv).apply_keystream(&mut name);
+        let expected = blake3::keyed_hash(&self.name_mac, &name);
+        if &expected.as_bytes()[..SIV_LEN] != siv {
+            return Err(invalid());
+        }
+        String::from_utf8(name).map_err(|_| invalid())
+    }
+
+    fn name_stream(&self, siv: &[u8]) -> XChaCha20 {
+        let mut nonce = [0u8; 24];
+        nonce[..SIV_LEN].copy_from_slice(siv);
+        XChaCha20::new((&self.name_enc).into(), (&nonce).into())
+    }
+}
+
+fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK).max(1)
+}
+
+/// Stored size of a file of `size` plaintext bytes.
+pub fn encrypted_size(size: u64) -> u64 {
+    HEADER_LEN + size + TAG * chunk_count(size)
+}
+
+/// Plaintext size of a stored file, or `None` if no plaintext encrypts to
+/// that size.
+pub fn plaintext_size(stored: u64) -> Option<u64> {
+    let body = stored.checked_sub(HEADER_LEN)?;
+    let (full, rest) = (body / (CHUNK + TAG), body % (CHUNK + TAG));
+    match rest {
+        0 if full > 0 => Some(full * CHUNK),
+        0 => None,
+        rest => Some(full * CHUNK + rest.checked_sub(TAG)?),
+    }
+}
+
+fn nonce(prefix: &[u8; PREFIX_LEN], index: u64, last: bool) -> XNonce {
+    let mut nonce = [0u8; 24];
+    nonce[..PREFIX_LEN].copy_from_slice(prefix);
+    let counter = index | if last { 1 << 63 } else { 0 };
+    nonce[PREFIX_LEN..].copy_from_slice(&counter.to_be_bytes());
+    nonce.into()
+}
+
+/// Encrypts exactly `size` bytes of `src` as it is read.
+struct EncryptReader<'a> {
+    src: &'a mut dyn Read,
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; PREFIX_LEN],
+    remaining: u64,
+    index: u64,
+    chunks: u64,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> EncryptReader<'a> {
+    fn new(src: &'a mut dyn Read, key: &CryptKey, size: u64) -> Self {
+        let mut prefix = [0u8; PREFIX_LEN];
+        OsRng.fill_bytes(&mut prefix);
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&prefix);
+        Self {
+            src,
+            cipher: key.cipher(),
+            prefix,
+            remaining: size,
+            index: 0,
+            chunks: chunk_count(size),
+            out,
+            pos: 0,
+        }
+    }
+}
+
+impl Read for EncryptReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.out.len() {
+            if self.index == self.chunks {
+                return Ok(0);
+            }
+            let mut plain = vec![0u8; self.remaining.min(CHUNK) as usize];
+            self.src.read_exact(&mut plain)?;
+            self.remaining -= plain.len() as u64;
+            let last = self.index + 1 == self.chunks;
+            self.out = self
+                .cipher
+                .encrypt(&nonce(&self.prefix, self.index, last), &plain[..])
+                .map_err(|_| io::Error::other("chunk encryption failed"))?;
+            self.pos = 0;
+            self.index += 1;
+        }
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Decrypts chunks `index..chunks` of a file of `size` plaintext bytes,
+/// dropping the first `skip` bytes.
+struct DecryptReader {
+    inner: Box<dyn Read + Send>,
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; PREFIX_LEN],
+    size: u64,
+    index: u64,
+    chunks: u64,
+    skip: usize,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for DecryptReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.out.len() {
+            if self.index == self.chunks {
+                return Ok(0);
+            }
+            let last = self.index + 1 == self.chunks;
+            let plain_len = if last {
+                self.size - self.index * CHUNK
+            } else {
+                CHUNK
+            };
+            let mut sealed = vec![0u8; (plain_len + TAG) as usize];
+            self.inner.read_exact(&mut sealed).map_err(|e| {
+                io::Error::new(e.kind(), format!("encrypted file is truncated: {e}"))
+            })?;
+            self.out = self
+                .cipher
+                .decrypt(&nonce(&self.prefix, self.index, last), &sealed[..])
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("chunk {} failed authentication", self.index),
+                    )
+                })?;
+            self.pos = self.skip.min(self.out.len());
+            self.skip = 0;
+            self.index += 1;
+        }
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Encrypts what it stores in `inner`. With name encryption, path
+/// components below the root are encrypted; the root and its ancestors
+/// stay as they are.
+pub struct CryptBackend {
+    inner: Arc<dyn StorageBackend + Send + Sync>,
+    key: OnceLock<CryptKey>,
+    /// Set for a destination without a salt: the key is derived once the
+    /// first write has stored one.
+    pending: Option<Pending>,
+    names_root: Option<String>,
+    salt_file: OnceLock<String>,
+}
+
+/// A destination whose salt is written with its first write.
+struct Pending {
+    secret: Secret,
+    root: String,
+    creating: Mutex<()>,
+}
+
+/// Where the salt for `root` lives: in `root` itself, or beside it when
+/// `root` is a file.
+pub fn salt_path(inner: &dyn StorageBackend, root: &str) -> Result<String, SyncError> {
+    let root = root.trim_end_matches('/');
+    let dir = match inner.stat(if root.is_empty() { "/" } else { root }) {
+        Ok(meta) if !meta.is_dir() => parent(root),
+        Ok(_) | Err(SyncError::NotFound(_)) => root,
+        Err(e) => return Err(e),
+    };
+    Ok(format!("{dir}/{SALT_FILE}"))
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+/// The salt stored at `path`, if there is one.
+fn read_salt(inner: &dyn StorageBackend, path: &str) -> Result<Option<[u8; SALT_LEN]>, SyncError> {
+    match inner.get(path) {
+        Ok(data) => data
+            .strip_prefix(SALT_MAGIC.as_slice())
+            .and_then(|salt| <[u8; SALT_LEN]>::try_from(salt).ok())
+            .map(Some)
+            .ok_or_else(|| SyncError::Other(format!("Bad crypt salt file: {path}"))),
+        Err(SyncError::NotFound(_)) => Ok(None),
+        Err(SyncError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Refuses a `root` with files in it but no salt, whose key can't be
+/// derived.
+fn check_fresh(inner: &dyn StorageBackend, root: &str) -> Result<(), SyncError> {
+    let root = match root.trim_end_matches('/') {
+        "" => "/",
+        root => root,
+    };
+    let fresh = match inner.stat(root) {
+        Ok(meta) => meta.is_dir() && inner.list(root)?.is_empty(),
+        Err(SyncError::NotFound(_)) => true,
+        Err(e) => return Err(e),
+    };
+    if !fresh {
+        return Err(SyncError::Other(format!(
+            "{root} holds files but no {SALT_FILE}, so its key can't be derived"
+        )));
+    }
+    Ok(())
+}
+
+impl CryptBackend {
+    pub fn new(inner: Arc<dyn StorageBackend + Send + Sync>, key: CryptKey) -> Self {
+        Self {
+            inner,
+            key: OnceLock::from(key),
+            pending: None,
+            names_root: None,
+            salt_file: OnceLock::new(),
+        }
+    }
+
+    /// A backend for `root`, which has no salt yet.
+    fn pending(inner: Arc<dyn StorageBackend + Send + Sync>, secret: Secret, root: &str) -> Self {
+        Self {
+            inner,
+            key: OnceLock::new(),
+            pending: Some(Pending {
+                secret,
+                root: root.trim_end_matches('/').to_string(),
+                creating: Mutex::new(()),
+            }),
+            names_root: None,
+            salt_file: OnceLock::new(),
+        }
+    }
+
+    /// Leaves the salt file at `path` out of listings and walks.
+    fn with_salt_file(self, path: &str) -> Self {
+        let _ = self.salt_file.set(path.to_string());
+        self
+    }
+
+    /// The key, or `NotFound` while a new destination has no salt: nothing
+    /// can have been stored there yet.
+    fn key(&self, path: &str) -> Result<&CryptKey, SyncError> {
+        self.key
+            .get()
+            .ok_or_else(|| SyncError::NotFound(path.to_string()))
+    }
+
+    /// The key for writing `path`, first storing a new salt if there is
+    /// none. The salt goes next to `path` when a file is written as the
+    /// root itself.
+    fn write_key(&self, path: &str, file: bool) -> Result<&CryptKey, SyncError> {
+        if let Some(key) = self.key.get() {
+            return Ok(key);
+        }
+        let pending = self
+            .pending
+            .as_ref()
+            .ok_or_else(|| SyncError::NotFound(path.to_string()))?;
+        let _creating = pending.creating.lock().unwrap();
+        if let Some(key) = self.key.get() {
+            return Ok(key);
+        }
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let key = pending.secret.derive(&salt)?;
+        let dir = match file && path.trim_end_matches('/') == pending.root {
+            true => parent(&pending.root),
+            false => &pending.root,
+        };
+        if !dir.is_empty() {
+            self.inner.mkdir(dir)?;
+        }
+        let salt_file = format!("{dir}/{SALT_FILE}");
+        self.inner
+            .put(&salt_file, &[SALT_MAGIC.as_slice(), &salt].concat())?;
+        let _ = self.salt_file.set(salt_file);
+        Ok(self.key.get_or_init(|| key))
+    }
+
+    /// Also encrypts the names of everything below `root`.
+    pub fn with_name_encryption(mut self, root: &str) -> Self {
+        let root = root.trim_end_matches('/');
+        self.names_root = Some(if root.is_empty() { "/" } else { root }.to_string());
+        self
+    }
+
+    /// Maps the components of `path` below the name root with `map`.
+    fn map_path(
+        &self,
+        path: &str,
+        map: impl Fn(&str) -> Result<String, SyncError>,
+    ) -> Result<String, SyncError> {
+        let Some(root) = &self.names_root else {
+            return Ok(path.to_string());
+        };
+        let rest = if root == "/" {
+            path.strip_prefix('/')
+        } else {
+            path.strip_prefix(root.as_str())
+                .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        };
+        let Some(rest) = rest else {
+            return Ok(path.to_string());
+        };
+        let mut out = path[..path.len() - rest.len()].to_string();
+        for (i, component) in rest.split('/').enumerate() {
+            if i > 0 {
+                out.push('/');
+            }
+            match component {
+                "" | "." | ".." => out.push_str(component),
+                name => out.push_str(&map(name)?),
+            }
+        }
+        Ok(out)
+    }
+
+    fn outer(&self, path: &str) -> Result<String, SyncError> {
+        self.map_path(path, |name| Ok(self.key(path)?.encrypt_name(name)))
+    }
+
+    fn plain(&self, path: &str) -> Result<String, SyncError> {
+        self.map_path(path, |name| self.key(path)?.decrypt_name(name))
+    }
+
+    /// Reports plaintext sizes, and names unless they can't be decrypted.
+    fn plain_entry(&self, entry: FileEntry) -> Option<FileEntry> {
+        if self.salt_file.get() == Some(&entry.path) {
+            return None;
+        }
+        match self.plain(&entry.path) {
+            Ok(path) => Some(FileEntry {
+                path,
+                metadata: plain_meta(entry.metadata),
+            }),
+            Err(e) => {
+                log::warn!("Skipping {}: {e:?}", entry.path);
+                None
+            }
+        }
+    }
+}
+
+fn plain_meta(mut meta: FileMeta) -> FileMeta {
+    if meta.is_file() {
+        meta.size = plaintext_size(meta.size).unwrap_or(0);
+    }
+    meta
+}
+
+impl StorageBackend for CryptBackend {
+    fn list(&self, path: &str) -> Result<Vec<FileEntry>, SyncError> {
+        self.key(path)?;
+        Ok(self
+            .inner
+            .list(&self.outer(path)?)?
+            .into_iter()
+            .filter_map(|entry| self.plain_entry(entry))
+            .collect())
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>, SyncError> {
+        let mut data = Vec::new();
+        self.open_read(path, 0, None)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), SyncError> {
+        self.put_stream(path, &mut &data[..], data.len() as u64)
+    }
+
+    fn delete(&self, path: &str) -> Result<(), SyncError> {
+        if self.key.get().is_none() {
+            return Ok(());
+        }
+        self.inner.delete(&self.outer(path)?)
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, SyncError> {
+        if self.key.get().is_none() {
+            return Ok(false);
+        }
+        self.inner.exists(&self.outer(path)?)
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn put_stream(&self, path: &str, reader: &mut dyn Read, size: u64) -> Result<(), SyncError> {
+        let mut sealed = EncryptReader::new(reader, self.write_key(path, true)?, size);
+        self.inner
+            .put_stream(&self.outer(path)?, &mut sealed, encrypted_size(size))
+    }
+
+    fn put_with_meta(
+        &self,
+        path: &str,
+        reader: &mut dyn Read,
+        meta: &FileMeta,
+    ) -> Result<(), SyncError> {
+        let mut sealed = EncryptReader::new(reader, self.write_key(path, true)?, meta.size);
+        let meta = FileMeta {
+            size: encrypted_size(meta.size),
+            ..meta.clone()
+        };
+        self.inner
+            .put_with_meta(&self.outer(path)?, &mut sealed, &meta)
+    }
+
+    fn open_read(
+        &self,
+        path: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read + Send>, SyncError> {
+        let key = self.key(path)?;
+        let outer = self.outer(path)?;
+        let stored = self.inner.stat(&outer)?.size;
+        let size = plaintext_size(stored)
+            .ok_or_else(|| SyncError::Other(format!("Not an encrypted file: {path}")))?;
+        let chunks = chunk_count(size);
+        // Reads at or past the end of a non-empty file yield nothing.
+        let first = if offset >= size && size > 0 {
+            chunks
+        } else {
+            offset / CHUNK
+        };
+        let mut header = [0u8; HEADER_LEN as usize];
+        self.inner
+            .open_read(&outer, 0, Some(HEADER_LEN))?
+            .read_exact(&mut header)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(SyncError::Other(format!("Not an encrypted file: {path}")));
+        }
+        let reader = DecryptReader {
+            inner: self
+                .inner
+                .open_read(&outer, HEADER_LEN + first * (CHUNK + TAG), None)?,
+            cipher: key.cipher(),
+            prefix: header[MAGIC.len()..].try_into().expect("header length"),
+            size,
+            index: first,
+            chunks,
+            skip: (offset % CHUNK) as usize,
+            out: Vec::new(),
+            pos: 0,
+        };
+        Ok(match len {
+            Some(len) => Box::new(reader.take(len)),
+            None => Box::new(reader),
+        })
+    }
+
+    fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        self.key(path)?;
+        Ok(plain_meta(self.inner.stat(&self.outer(path)?)?))
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), SyncError> {
+        self.write_key(path, false)?;
+        self.inner.mkdir(&self.outer(path)?)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+        self.inner.rename(&self.outer(from)?, &self.outer(to)?)
+    }
+
+    fn set_metadata(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        self.inner.set_metadata(&self.outer(path)?, meta)
+    }
+
+    fn walk(&self, root: &str, visit: &mut dyn FnMut(FileEntry)) -> Result<(), SyncError> {
+        self.key(root)?;
+        self.inner.walk(&self.outer(root)?, &mut |entry| {
+            if let Some(entry) = self.plain_entry(entry) {
+                visit(entry);
+            }
+        })
+    }
+
+    /// With name encryption the target is encrypted as a whole, so the
+    /// stored link dangles.
+    fn symlink(&self, target: &str, link: &str) -> Result<(), SyncError> {
+        let key = self.write_key(link, true)?;
+        let target = match self.names_root {
+            Some(_) => key.encrypt_name(target),
+            None => target.to_string(),
+        };
+        self.inner.symlink(&target, &self.outer(link)?)
+    }
+
+    fn read_link(&self, path: &str) -> Result<String, SyncError> {
+        let key = self.key(path)?;
+        let target = self.inner.read_link(&self.outer(path)?)?;
+        match self.names_root {
+            Some(_) => key.decrypt_name(&target),
+            None => Ok(target),
+        }
+    }
+
+    fn mknod(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        self.write_key(path, true)?;
+        self.inner.mknod(&self.outer(path)?, meta)
+    }
+
+    fn finish(&self) -> Result<(), SyncError> {
+        self.inner.finish()
+    }
+
+    fn time_precision(&self) -> std::time::Duration {
+        self.inner.time_precision()
+    }
+}
+
+/// `crypt+scheme://...`: `?key_file=PATH`, or a passphrase from
+/// `PARSYNC_CRYPT_PASSPHRASE`; `?names=encrypt` encrypts names below the
+/// URL's path. A destination without a salt gets one with its first write.
+pub(super) struct CryptWrapper;
+
+impl WrapperFactory for CryptWrapper {
+    fn options(&self) -> &[&str] {
+        &["key_file", "names"]
+    }
+
+    fn wrap(
+        &self,
+        inner: Arc<dyn StorageBackend + Send + Sync>,
+        url: &BackendUrl<'_>,
+        root: &str,
+    ) -> Result<Arc<dyn StorageBackend + Send + Sync>, SyncError> {
+        let secret = match url.option("key_file") {
+            Some(path) => Secret::KeyFile(read_key_file(
+                &percent_encoding::percent_decode_str(path).decode_utf8_lossy(),
+            )?),
+            None => match std::env::var("PARSYNC_CRYPT_PASSPHRASE") {
+                Ok(passphrase) if passphrase.is_empty() => {
+                    return Err(SyncError::Other("Empty crypt passphrase".to_string()))
+                }
+                Ok(passphrase) => Secret::Passphrase(passphrase),
+                Err(_) => {
+                    return Err(SyncError::Other(format!(
+                        "crypt needs ?key_file=PATH or PARSYNC_CRYPT_PASSPHRASE: {}",
+                        url.url
+                    )))
+                }
+            },
+        };
+        let salt_file = salt_path(inner.as_ref(), root)?;
+        let backend = match read_salt(inner.as_ref(), &salt_file)? {
+            Some(salt) => {
+                CryptBackend::new(inner, secret.derive(&salt)?).with_salt_file(&salt_file)
+            }
+            None => {
+                check_fresh(inner.as_ref(), root)?;
+                CryptBackend::pending(inner, secret, root)
+            }
+        };
+        Ok(Arc::new(match url.option("names") {
+            None | Some("plain") => backend,
+            Some("encrypt") => backend.with_name_encryption(root),
+            Some(other) => {
+                return Err(SyncError::Other(format!(
+                    "crypt names must be plain or encrypt, not {other}: {}",
+                    url.url
+                )))
+            }
+        }))
+    }
+}
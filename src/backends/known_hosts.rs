@@ -0,0 +1,327 @@
+//! OpenSSH `known_hosts` files: plain, wildcard and hashed (`|1|salt|hash`)
+//! host patterns, `[host]:port` names for non-default ports, and `@revoked`
+//! keys. `@cert-authority` lines are skipped.
+
+use super::SyncError;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// What to do with a server key that isn't known yet, as OpenSSH's
+/// `StrictHostKeyChecking`. A changed or revoked key is refused unless
+/// checking is off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StrictHostKeyChecking {
+    /// Refuse hosts that aren't in the file.
+    Yes,
+    /// Append keys of new hosts to the file.
+    #[default]
+    AcceptNew,
+    /// Accept any key, logging a warning when it doesn't match.
+    No,
+}
+
+impl std::str::FromStr for StrictHostKeyChecking {
+    type Err = SyncError;
+
+    fn from_str(s: &str) -> Result<Self, SyncError> {
+        match s {
+            "yes" => Ok(Self::Yes),
+            "accept-new" => Ok(Self::AcceptNew),
+            "no" | "off" => Ok(Self::No),
+            _ => Err(SyncError::Other(format!(
+                "Invalid strict_host_key_checking value {s:?}: expected yes, accept-new or no"
+            ))),
+        }
+    }
+}
+
+/// The result of looking a server key up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostKeyStatus {
+    Match,
+    /// The host has a different key of the same type, on this 1-based line.
+    Mismatch {
+        line: usize,
+    },
+    /// The key is marked `@revoked` on this line.
+    Revoked {
+        line: usize,
+    },
+    Unknown,
+}
+
+enum Hosts {
+    Patterns(Vec<String>),
+    Hashed { salt: Vec<u8>, hash: Vec<u8> },
+}
+
+struct Entry {
+    line: usize,
+    revoked: bool,
+    hosts: Hosts,
+    key: Vec<u8>,
+}
+
+/// The entries of one `known_hosts` file.
+pub struct KnownHosts {
+    path: PathBuf,
+    entries: Vec<Entry>,
+    lines: usize,
+}
+
+/// The name a host is recorded under: `host`, or `[host]:port` off port 22.
+pub fn host_name(host: &str, port: u16) -> String {
+    let host = host.to_ascii_lowercase();
+    match port {
+        22 => host,
+        _ => format!("[{host}]:{port}"),
+    }
+}
+
+/// The algorithm name at the start of an SSH public key blob, such as
+/// `ssh-ed25519`.
+pub fn key_type(key: &[u8]) -> Option<&str> {
+    let len = u32::from_be_bytes(key.get(..4)?.try_into().ok()?) as usize;
+    std::str::from_utf8(key.get(4..4 + len)?).ok()
+}
+
+/// OpenSSH's `SHA256:...` fingerprint of a public key blob.
+pub fn fingerprint(key: &[u8]) -> String {
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(key)))
+}
+
+/// Matches `name` against a pattern with `*` and `?` wildcards.
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| wildcard_match(rest, &name[i..])),
+        Some((&p, rest)) => name
+            .split_first()
+            .is_some_and(|(&n, name)| (p == b'?' || p == n) && wildcard_match(rest, name)),
+    }
+}
+
+/// Matches `name` against OpenSSH host patterns, case-insensitively. A
+/// `!pattern` that matches excludes the host even if another pattern on the
+/// list matches it.
+pub(crate) fn match_pattern_list<'a>(
+    patterns: impl IntoIterator<Item = &'a str>,
+    name: &str,
+) -> bool {
+    let name = name.to_ascii_lowercase();
+    let mut found = false;
+    for pattern in patterns {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_prefix('!') {
+            Some(negated) if wildcard_match(negated.as_bytes(), name.as_bytes()) => return false,
+            Some(_) => {}
+            None => found |= wildcard_match(pattern.as_bytes(), name.as_bytes()),
+        }
+    }
+    found
+}
+
+impl Hosts {
+    fn parse(field: &str) -> Option<Self> {
+        let Some(hashed) = field.strip_prefix("|1|") else {
+            let patterns = field.split(',').map(String::from).collect();
+            return Some(Hosts::Patterns(patterns));
+        };
+        let (salt, hash) = hashed.split_once('|')?;
+        Some(Hosts::Hashed {
+            salt: STANDARD.decode(salt).ok()?,
+            hash: STANDARD.decode(hash).ok()?,
+        })
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Hosts::Patterns(patterns) => {
+                match_pattern_list(patterns.iter().map(String::as_str), name)
+            }
+            Hosts::Hashed { salt, hash } => {
+                let mut mac = Hmac::<sha1::Sha1>::new_from_slice(salt).expect("any key length");
+                mac.update(name.as_bytes());
+                mac.verify_slice(hash).is_ok()
+            }
+        }
+    }
+}
+
+impl KnownHosts {
+    /// Reads `path`; a missing file has no entries. Lines that don't parse
+    /// are skipped, as OpenSSH does.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, SyncError> {
+        let path = path.into();
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(SyncError::Other(format!("Reading {}: {e}", path.display()))),
+        };
+        let mut entries = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let mut fields = line.split_whitespace();
+            let mut first = fields.next();
+            let revoked = match first {
+                Some("@revoked") => true,
+                Some(marker) if marker.starts_with('@') => continue,
+                Some(f) if f.starts_with('#') => continue,
+                _ => false,
+            };
+            if revoked {
+                first = fields.next();
+            }
+            let (Some(hosts), Some(_), Some(key)) = (first, fields.next(), fields.next()) else {
+                continue;
+            };
+            if let (Some(hosts), Ok(key)) = (Hosts::parse(hosts), STANDARD.decode(key)) {
+                entries.push(Entry {
+                    line: i + 1,
+                    revoked,
+                    hosts,
+                    key,
+                });
+            }
+        }
+        Ok(Self {
+            path,
+            entries,
+            lines: text.lines().count(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Looks `key` up for `host:port`. A revoked key wins over everything;
+    /// otherwise any entry with the same key matches, even if others of the
+    /// same type differ.
+    pub fn check(&self, host: &str, port: u16, key: &[u8]) -> HostKeyStatus {
+        let name = host_name(host, port);
+        let mut status = HostKeyStatus::Unknown;
+        for entry in self.entries.iter().filter(|e| e.hosts.matches(&name)) {
+            if entry.key == key {
+                if entry.revoked {
+                    return HostKeyStatus::Revoked { line: entry.line };
+                }
+                status = HostKeyStatus::Match;
+            } else if !entry.revoked
+                && status == HostKeyStatus::Unknown
+                && key_type(&entry.key) == key_type(key)
+            {
+                status = HostKeyStatus::Mismatch { line: entry.line };
+            }
+        }
+        status
+    }
+
+    /// Key types recorded for `host:port`, in file order, so the handshake
+    /// can ask the server for one of them.
+    pub fn key_types(&self, host: &str, port: u16) -> Vec<String> {
+        let name = host_name(host, port);
+        let mut types: Vec<String> = Vec::new();
+        for entry in &self.entries {
+            if entry.revoked || !entry.hosts.matches(&name) {
+                continue;
+            }
+            if let Some(t) = key_type(&entry.key) {
+                if !types.iter().any(|known| known == t) {
+                    types.push(t.to_string());
+                }
+            }
+        }
+        types
+    }
+
+    /// Appends `key` for `host:port` to the file, creating it (and a missing
+    /// `~/.ssh`-style parent, mode 0700) if needed.
+    pub fn add(&mut self, host: &str, port: u16, key: &[u8]) -> Result<(), SyncError> {
+        let name = host_name(host, port);
+        let key_type = key_type(key)
+            .ok_or_else(|| SyncError::Other(format!("Malformed host key for {name}")))?;
+        let io_err =
+            |e: std::io::Error| SyncError::Other(format!("Writing {}: {e}", self.path.display()));
+        if let Some(parent) = self.path.parent().filter(|p| !p.exists()) {
+            let mut builder = std::fs::DirBuilder::new();
+            builder.recursive(true);
+            #[cfg(unix)]
+            std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+            builder.create(parent).map_err(io_err)?;
+        }
+        // Don't glue the entry onto a last line without a newline.
+        let unterminated = std::fs::read(&self.path)
+            .ok()
+            .and_then(|data| data.last().copied())
+            .is_some_and(|b| b != b'\n');
+        let line = format!(
+            "{}{name} {key_type} {}\n",
+            if unterminated { "\n" } else { "" },
+            STANDARD.encode(key)
+        );
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(io_err)?;
+        file.write_all(line.as_bytes()).map_err(io_err)?;
+        self.lines += 1;
+        self.entries.push(Entry {
+            line: self.lines,
+            revoked: false,
+            hosts: Hosts::Patterns(vec![name]),
+            key: key.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Checks the key a server presented and applies `policy`: a match
+    /// passes, a new host is refused or recorded, and a changed or revoked
+    /// key is refused with its fingerprint unless checking is off.
+    pub fn verify(
+        &mut self,
+        host: &str,
+        port: u16,
+        key: &[u8],
+        policy: StrictHostKeyChecking,
+    ) -> Result<(), SyncError> {
+        let name = host_name(host, port);
+        let key_type = key_type(key).unwrap_or("unknown");
+        let fingerprint = fingerprint(key);
+        let path = self.path.display().to_string();
+        let problem = match self.check(host, port, key) {
+            HostKeyStatus::Match => return Ok(()),
+            HostKeyStatus::Unknown => match policy {
+                StrictHostKeyChecking::Yes => format!(
+                    "{name} is not in {path}; its {key_type} key is {fingerprint}. \
+                     Connect once with ssh, or use strict_host_key_checking=accept-new"
+                ),
+                StrictHostKeyChecking::AcceptNew => {
+                    self.add(host, port, key)?;
+                    log::warn!("Added {key_type} key {fingerprint} for {name} to {path}");
+                    return Ok(());
+                }
+                StrictHostKeyChecking::No => return Ok(()),
+            },
+            HostKeyStatus::Mismatch { line } => format!(
+                "the {key_type} key of {name} is now {fingerprint}, which differs from \
+                 {path}:{line}. Someone may be intercepting the connection; if the key \
+                 changed on purpose, remove the old one with ssh-keygen -R '{name}'"
+            ),
+            HostKeyStatus::Revoked { line } => {
+                format!("the {key_type} key {fingerprint} of {name} is revoked at {path}:{line}")
+            }
+        };
+        if policy == StrictHostKeyChecking::No {
+            log::warn!("Ignoring host key problem: {problem}");
+            return Ok(());
+        }
+        Err(SyncError::Other(format!(
+            "Host key verification failed: {problem}"
+        )))
+    }
+}
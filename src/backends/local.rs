@@ -1,8 +1,12 @@
 use std::fs;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
-use super::{FileEntry, FileMeta, StorageBackend, SyncError};
+use walkdir::WalkDir;
+
+use super::{FileEntry, FileKind, FileMeta, StorageBackend, SyncError};
+
+const CHUNK: usize = 1 << 20;
 
 pub struct LocalBackend;
 
@@ -38,6 +42,47 @@ impl LocalBackend {
     }
 }
 
+impl From<&fs::Metadata> for FileMeta {
+    fn from(meta: &fs::Metadata) -> Self {
+        let file_type = meta.file_type();
+        #[cfg(unix)]
+        let (kind, mode, rdev) = {
+            use std::os::unix::fs::{FileTypeExt, MetadataExt};
+            let kind = if file_type.is_fifo() {
+                FileKind::Fifo
+            } else if file_type.is_socket() {
+                FileKind::Socket
+            } else if file_type.is_char_device() {
+                FileKind::CharDevice
+            } else if file_type.is_block_device() {
+                FileKind::BlockDevice
+            } else {
+                FileKind::File
+            };
+            (kind, Some(meta.mode() & 0o7777), Some(meta.rdev()))
+        };
+        #[cfg(not(unix))]
+        let (kind, mode, rdev) = (FileKind::File, None, None);
+
+        FileMeta {
+            size: meta.len(),
+            kind: if file_type.is_dir() {
+                FileKind::Dir
+            } else if file_type.is_symlink() {
+                FileKind::Symlink
+            } else if file_type.is_file() {
+                FileKind::File
+            } else {
+                kind
+            },
+            modified: meta.modified().ok(),
+            accessed: meta.accessed().ok(),
+            mode,
+            rdev,
+        }
+    }
+}
+
 impl StorageBackend for LocalBackend {
     fn list(&self, path: &str) -> Result<Vec<FileEntry>, SyncError> {
         let mut entries = Vec::new();
@@ -46,11 +91,7 @@ impl StorageBackend for LocalBackend {
             let meta = entry.metadata()?;
             entries.push(FileEntry {
                 path: entry.path().to_string_lossy().to_string(),
-                metadata: FileMeta {
-                    size: meta.len(),
-                    is_dir: meta.is_dir(),
-                    modified: meta.modified().ok(),
-                },
+                metadata: FileMeta::from(&meta),
             });
         }
         Ok(entries)
@@ -69,6 +110,35 @@ impl StorageBackend for LocalBackend {
         Ok(())
     }
 
+    fn put_stream(&self, path: &str, reader: &mut dyn Read, _size: u64) -> Result<(), SyncError> {
+        let mut file = fs::File::create(path)?;
+        let mut buf = vec![0u8; CHUNK];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            file.write_all(&buf[..n])?;
+        }
+        Ok(())
+    }
+
+    fn open_read(
+        &self,
+        path: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read + Send>, SyncError> {
+        let mut file = fs::File::open(path)?;
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        match len {
+            Some(len) => Ok(Box::new(file.take(len))),
+            None => Ok(Box::new(file)),
+        }
+    }
+
     fn delete(&self, path: &str) -> Result<(), SyncError> {
         if Path::new(path).is_dir() {
             fs::remove_dir_all(path)?;
@@ -86,6 +156,127 @@ impl StorageBackend for LocalBackend {
         Ok(Path::new(path).exists())
     }
 
+    fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        match fs::metadata(path) {
+            Ok(meta) => Ok(FileMeta::from(&meta)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(SyncError::NotFound(path.to_string()))
+            }
+            Err(e) => Err(SyncError::Io(e)),
+        }
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), SyncError> {
+        fs::create_dir_all(path)?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+        fs::rename(from, to)?;
+        Ok(())
+    }
+
+    fn set_metadata(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        #[cfg(unix)]
+        if let Some(mode) = meta.mode {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        crate::utils::set_local_times(Path::new(path), meta.modified, meta.accessed)?;
+        Ok(())
+    }
+
+    fn walk(&self, root: &str, visit: &mut dyn FnMut(FileEntry)) -> Result<(), SyncError> {
+        for entry in WalkDir::new(root) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if e.depth() == 0 => {
+                    return Err(match e.into_io_error() {
+                        Some(io) if io.kind() == std::io::ErrorKind::NotFound => {
+                            SyncError::NotFound(root.to_string())
+                        }
+                        Some(io) => SyncError::Io(io),
+                        None => SyncError::Other(format!("Cannot walk {root}")),
+                    });
+                }
+                Err(e) => {
+                    log::warn!("Skipping unreadable entry: {e}");
+                    continue;
+                }
+            };
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            visit(FileEntry {
+                path: entry.path().to_string_lossy().to_string(),
+                metadata: FileMeta::from(&meta),
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn symlink(&self, target: &str, link: &str) -> Result<(), SyncError> {
+        if fs::symlink_metadata(link).is_ok_and(|existing| !existing.is_dir()) {
+            fs::remove_file(link)?;
+        }
+        std::os::unix::fs::symlink(target, link)?;
+        Ok(())
+    }
+
+    fn read_link(&self, path: &str) -> Result<String, SyncError> {
+        Ok(fs::read_link(path)?.to_string_lossy().to_string())
+    }
+
+    #[cfg(unix)]
+    fn mknod(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let type_bits = match meta.kind {
+            FileKind::Fifo => libc::S_IFIFO,
+            FileKind::Socket => libc::S_IFSOCK,
+            FileKind::CharDevice => libc::S_IFCHR,
+            FileKind::BlockDevice => libc::S_IFBLK,
+            _ => {
+                return Err(SyncError::Other(format!(
+                    "Cannot create special file {path}: not a special file type"
+                )))
+            }
+        };
+        let c_path = std::ffi::CString::new(Path::new(path).as_os_str().as_bytes())
+            .map_err(|_| SyncError::Other(format!("Invalid path {path}")))?;
+
+        if let Ok(existing) = fs::symlink_metadata(path) {
+            if !existing.is_dir() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let mode = type_bits | meta.mode.unwrap_or(0o644) as libc::mode_t;
+        let rc =
+            unsafe { libc::mknod(c_path.as_ptr(), mode, meta.rdev.unwrap_or(0) as libc::dev_t) };
+        if rc != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EPERM)
+                && matches!(meta.kind, FileKind::CharDevice | FileKind::BlockDevice)
+            {
+                return Err(SyncError::Other(format!(
+                    "Cannot create device node {path}: requires root (CAP_MKNOD)"
+                )));
+            }
+            return Err(SyncError::Io(err));
+        }
+        Ok(())
+    }
+
+    fn local_path(&self, path: &str) -> Option<PathBuf> {
+        Some(PathBuf::from(path))
+    }
+
+    fn hash(&self, path: &str) -> Result<Option<blake3::Hash>, SyncError> {
+        crate::agent::hash_file(Path::new(path)).map(Some)
+    }
+
     fn as_any(&self) -> &dyn std::any::Any {
         self
     }
@@ -0,0 +1,348 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use super::{FileEntry, FileKind, FileMeta, StorageBackend, SyncError};
+
+struct Node {
+    meta: FileMeta,
+    data: Vec<u8>,
+    link_target: Option<String>,
+}
+
+#[derive(Default)]
+struct Faults {
+    puts: usize,
+    fail_put_at: Option<usize>,
+    read_delay: Duration,
+    truncate_writes_to: Option<usize>,
+}
+
+#[derive(Default)]
+struct Inner {
+    nodes: Mutex<BTreeMap<String, Node>>,
+    faults: Mutex<Faults>,
+}
+
+/// A thread-safe in-memory filesystem, mainly for tests. Clones share the
+/// same tree; `mem://name/path` URLs resolve to a process-wide store per
+/// `name`.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    inner: Arc<Inner>,
+}
+
+fn normalize(path: &str) -> String {
+    Path::new(path)
+        .components()
+        .collect::<PathBuf>()
+        .to_string_lossy()
+        .to_string()
+}
+
+fn is_root(key: &str) -> bool {
+    key.is_empty() || key == "/"
+}
+
+fn parent_key(key: &str) -> Option<String> {
+    Path::new(key)
+        .parent()
+        .map(|p| p.to_string_lossy().to_string())
+}
+
+fn dir_meta() -> FileMeta {
+    let now = SystemTime::now();
+    FileMeta {
+        size: 0,
+        kind: FileKind::Dir,
+        modified: Some(now),
+        accessed: Some(now),
+        mode: Some(0o755),
+        rdev: None,
+    }
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the process-wide store registered under `name`, creating it
+    /// on first use.
+    pub fn named(name: &str) -> Self {
+        static STORES: OnceLock<Mutex<HashMap<String, MemoryBackend>>> = OnceLock::new();
+        STORES
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Makes the `n`th `put`/`put_stream` from now on (1-based) fail.
+    pub fn fail_nth_put(&self, n: usize) {
+        let mut faults = self.inner.faults.lock().unwrap();
+        faults.fail_put_at = Some(faults.puts + n);
+    }
+
+    /// Sleeps for `delay` on every read from a stream returned by `open_read`.
+    pub fn set_read_delay(&self, delay: Duration) {
+        self.inner.faults.lock().unwrap().read_delay = delay;
+    }
+
+    /// Silently stores at most `len` bytes of every subsequent write.
+    pub fn truncate_writes_to(&self, len: Option<usize>) {
+        self.inner.faults.lock().unwrap().truncate_writes_to = len;
+    }
+
+    fn check_parent(nodes: &BTreeMap<String, Node>, key: &str) -> Result<(), SyncError> {
+        match parent_key(key) {
+            Some(parent) if !is_root(&parent) => match nodes.get(&parent) {
+                Some(node) if node.meta.is_dir() => Ok(()),
+                Some(_) => Err(SyncError::Other(format!("Not a directory: {parent}"))),
+                None => Err(SyncError::NotFound(parent)),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn insert(
+        &self,
+        path: &str,
+        meta: FileMeta,
+        data: Vec<u8>,
+        link_target: Option<String>,
+    ) -> Result<(), SyncError> {
+        let key = normalize(path);
+        let mut nodes = self.inner.nodes.lock().unwrap();
+        Self::check_parent(&nodes, &key)?;
+        if nodes.get(&key).is_some_and(|n| n.meta.is_dir()) {
+            return Err(SyncError::Other(format!("Is a directory: {key}")));
+        }
+        nodes.insert(
+            key,
+            Node {
+                meta,
+                data,
+                link_target,
+            },
+        );
+        Ok(())
+    }
+}
+
+struct SlowReader {
+    inner: std::io::Cursor<Vec<u8>>,
+    delay: Duration,
+}
+
+impl Read for SlowReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        std::thread::sleep(self.delay);
+        self.inner.read(buf)
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn list(&self, path: &str) -> Result<Vec<FileEntry>, SyncError> {
+        let key = normalize(path);
+        let nodes = self.inner.nodes.lock().unwrap();
+        if !is_root(&key) && !nodes.get(&key).is_some_and(|n| n.meta.is_dir()) {
+            return Err(SyncError::NotFound(key));
+        }
+        Ok(nodes
+            .iter()
+            .filter(|(k, _)| !is_root(k) && parent_key(k).as_deref() == Some(key.as_str()))
+            .map(|(k, n)| FileEntry {
+                path: k.clone(),
+                metadata: n.meta.clone(),
+            })
+            .collect())
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>, SyncError> {
+        let key = normalize(path);
+        match self.inner.nodes.lock().unwrap().get(&key) {
+            Some(node) if node.meta.is_file() => Ok(node.data.clone()),
+            Some(_) => Err(SyncError::Other(format!("Not a file: {key}"))),
+            None => Err(SyncError::NotFound(key)),
+        }
+    }
+
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), SyncError> {
+        let stored_len = {
+            let mut faults = self.inner.faults.lock().unwrap();
+            faults.puts += 1;
+            if faults.fail_put_at == Some(faults.puts) {
+                return Err(SyncError::Other(format!("Injected put failure: {path}")));
+            }
+            faults
+                .truncate_writes_to
+                .unwrap_or(data.len())
+                .min(data.len())
+        };
+        let now = SystemTime::now();
+        let meta = FileMeta {
+            size: stored_len as u64,
+            kind: FileKind::File,
+            modified: Some(now),
+            accessed: Some(now),
+            mode: Some(0o644),
+            rdev: None,
+        };
+        self.insert(path, meta, data[..stored_len].to_vec(), None)
+    }
+
+    fn open_read(
+        &self,
+        path: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read + Send>, SyncError> {
+        let data = self.get(path)?;
+        let start = (offset as usize).min(data.len());
+        let end = match len {
+            Some(len) => start.saturating_add(len as usize).min(data.len()),
+            None => data.len(),
+        };
+        let delay = self.inner.faults.lock().unwrap().read_delay;
+        Ok(Box::new(SlowReader {
+            inner: std::io::Cursor::new(data[start..end].to_vec()),
+            delay,
+        }))
+    }
+
+    fn delete(&self, path: &str) -> Result<(), SyncError> {
+        let key = normalize(path);
+        let prefix = if key.ends_with('/') {
+            key.clone()
+        } else {
+            format!("{key}/")
+        };
+        let mut nodes = self.inner.nodes.lock().unwrap();
+        nodes.retain(|k, _| *k != key && !k.starts_with(&prefix));
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, SyncError> {
+        let key = normalize(path);
+        Ok(is_root(&key) || self.inner.nodes.lock().unwrap().contains_key(&key))
+    }
+
+    fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        let key = normalize(path);
+        if is_root(&key) {
+            return Ok(dir_meta());
+        }
+        self.inner
+            .nodes
+            .lock()
+            .unwrap()
+            .get(&key)
+            .map(|n| n.meta.clone())
+            .ok_or(SyncError::NotFound(key))
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), SyncError> {
+        let key = normalize(path);
+        let mut nodes = self.inner.nodes.lock().unwrap();
+        for ancestor in Path::new(&key)
+            .ancestors()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+        {
+            let k = ancestor.to_string_lossy().to_string();
+            if is_root(&k) {
+                continue;
+            }
+            match nodes.get(&k) {
+                Some(node) if node.meta.is_dir() => {}
+                Some(_) => return Err(SyncError::Other(format!("Not a directory: {k}"))),
+                None => {
+                    nodes.insert(
+                        k,
+                        Node {
+                            meta: dir_meta(),
+                            data: Vec::new(),
+                            link_target: None,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+        let (from, to) = (normalize(from), normalize(to));
+        let mut nodes = self.inner.nodes.lock().unwrap();
+        if !nodes.contains_key(&from) {
+            return Err(SyncError::NotFound(from));
+        }
+        Self::check_parent(&nodes, &to)?;
+        let prefix = format!("{from}/");
+        let moved: Vec<String> = nodes
+            .keys()
+            .filter(|k| **k == from || k.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for k in moved {
+            let node = nodes.remove(&k).expect("key listed above");
+            nodes.insert(format!("{to}{}", &k[from.len()..]), node);
+        }
+        Ok(())
+    }
+
+    fn set_metadata(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        let key = normalize(path);
+        let mut nodes = self.inner.nodes.lock().unwrap();
+        let node = nodes.get_mut(&key).ok_or(SyncError::NotFound(key))?;
+        if meta.mode.is_some() {
+            node.meta.mode = meta.mode;
+        }
+        if meta.modified.is_some() {
+            node.meta.modified = meta.modified;
+        }
+        if meta.accessed.is_some() {
+            node.meta.accessed = meta.accessed;
+        }
+        Ok(())
+    }
+
+    fn symlink(&self, target: &str, link: &str) -> Result<(), SyncError> {
+        let meta = FileMeta {
+            size: target.len() as u64,
+            kind: FileKind::Symlink,
+            ..dir_meta()
+        };
+        self.insert(link, meta, Vec::new(), Some(target.to_string()))
+    }
+
+    fn read_link(&self, path: &str) -> Result<String, SyncError> {
+        let key = normalize(path);
+        match self.inner.nodes.lock().unwrap().get(&key) {
+            Some(Node {
+                link_target: Some(target),
+                ..
+            }) => Ok(target.clone()),
+            Some(_) => Err(SyncError::Other(format!("Not a symlink: {key}"))),
+            None => Err(SyncError::NotFound(key)),
+        }
+    }
+
+    fn mknod(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        let meta = FileMeta {
+            size: 0,
+            ..meta.clone()
+        };
+        self.insert(path, meta, Vec::new(), None)
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
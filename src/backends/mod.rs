@@ -1,7 +1,23 @@
+mod archive;
+pub mod compress;
+pub mod crypt;
+pub mod known_hosts;
 pub mod local;
+pub mod memory;
+pub mod pool;
+pub mod registry;
+pub mod repo;
+pub mod s3;
 pub mod ssh;
+pub mod ssh_auth;
+pub mod ssh_config;
+pub mod ssh_jump;
+pub mod tar;
+pub mod webdav;
+mod xml;
+pub mod zip;
 
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub enum SyncError {
@@ -16,11 +32,37 @@ impl From<std::io::Error> for SyncError {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
 #[derive(Debug, Clone)]
 pub struct FileMeta {
     pub size: u64,
-    pub is_dir: bool,
+    pub kind: FileKind,
     pub modified: Option<std::time::SystemTime>,
+    pub accessed: Option<std::time::SystemTime>,
+    /// Permission bits (`0o7777`), where the backend exposes them.
+    pub mode: Option<u32>,
+    /// Device number for character and block devices.
+    pub rdev: Option<u64>,
+}
+
+impl FileMeta {
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Dir
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.kind == FileKind::File
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -29,6 +71,15 @@ pub struct FileEntry {
     pub metadata: FileMeta,
 }
 
+/// A small file held in memory to be written with others by
+/// [`StorageBackend::put_batch`].
+#[derive(Debug, Clone)]
+pub struct BatchFile {
+    pub path: String,
+    pub meta: FileMeta,
+    pub data: Vec<u8>,
+}
+
 pub trait StorageBackend: Send + Sync + std::any::Any {
     fn list(&self, path: &str) -> Result<Vec<FileEntry>, SyncError>;
     fn get(&self, path: &str) -> Result<Vec<u8>, SyncError>;
@@ -47,49 +98,210 @@ pub trait StorageBackend: Send + Sync + std::any::Any {
         reader.read_to_end(&mut data)?;
         self.put(path, &data)
     }
-}
 
-pub use local::LocalBackend;
-pub use ssh::SshBackend;
-
-pub fn backend_and_path(
-    url: &str,
-    pool_size: usize,
-) -> Result<(Arc<dyn StorageBackend + Send + Sync>, &str), SyncError> {
-    if let Some(idx) = url.find("://") {
-        let (proto, rest) = url.split_at(idx);
-        let after_scheme = &rest[3..];
-        match proto {
-            "file" => Ok((Arc::new(LocalBackend::new()), after_scheme)),
-            "ssh" => {
-                let (user, host_port_path) = if let Some(at) = after_scheme.find('@') {
-                    (after_scheme[..at].to_string(), &after_scheme[at + 1..])
-                } else {
-                    (
-                        std::env::var("USER").unwrap_or_else(|_| "root".to_string()),
-                        after_scheme,
-                    )
-                };
-
-                let slash = host_port_path
-                    .find('/')
-                    .ok_or_else(|| SyncError::Other(format!("SSH URI missing path: {url}")))?;
-                let host_port = &host_port_path[..slash];
-                let remote_path = &host_port_path[slash..];
-
-                let (host, port) = if let Some(colon) = host_port.rfind(':') {
-                    let p = host_port[colon + 1..].parse::<u16>().unwrap_or(22);
-                    (&host_port[..colon], p)
-                } else {
-                    (host_port, 22u16)
-                };
-
-                let backend = SshBackend::connect(&user, host, port, pool_size)?;
-                Ok((Arc::new(backend), remote_path))
+    /// Writes a file and applies the permissions and timestamps in `meta`,
+    /// whose `size` is the expected length. The default is `put_stream`
+    /// followed by `set_metadata`. Backends that can record metadata in the
+    /// same write (S3 object headers, archive and repository entries)
+    /// override it, as do wrappers, to pass it on to the backend they wrap.
+    fn put_with_meta(
+        &self,
+        path: &str,
+        reader: &mut dyn std::io::Read,
+        meta: &FileMeta,
+    ) -> Result<(), SyncError> {
+        self.put_stream(path, reader, meta.size)?;
+        self.set_metadata(path, meta)
+    }
+
+    /// Opens `path` for streaming reads starting at byte `offset`, yielding at
+    /// most `len` bytes when given. The default buffers the whole file through
+    /// `get`; backends that can stream should override it.
+    fn open_read(
+        &self,
+        path: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn std::io::Read + Send>, SyncError> {
+        let data = self.get(path)?;
+        let start = (offset as usize).min(data.len());
+        let end = match len {
+            Some(len) => start.saturating_add(len as usize).min(data.len()),
+            None => data.len(),
+        };
+        Ok(Box::new(std::io::Cursor::new(data[start..end].to_vec())))
+    }
+
+    /// Returns metadata for `path`, or `SyncError::NotFound`. The default
+    /// looks the entry up in a listing of its parent directory.
+    fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        let p = Path::new(path);
+        let (parent, name) = match (p.parent(), p.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(SyncError::NotFound(path.to_string())),
+        };
+        self.list(&parent.to_string_lossy())?
+            .into_iter()
+            .find(|e| Path::new(&e.path).file_name() == Some(name))
+            .map(|e| e.metadata)
+            .ok_or_else(|| SyncError::NotFound(path.to_string()))
+    }
+
+    /// Creates `path` and any missing parents. Backends without real
+    /// directories (e.g. object stores) keep this default no-op.
+    fn mkdir(&self, _path: &str) -> Result<(), SyncError> {
+        Ok(())
+    }
+
+    /// Moves `from` to `to`, replacing `to` if it exists. The default copies
+    /// the data through `open_read`/`put_stream` and deletes the original.
+    fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+        let size = self.stat(from)?.size;
+        let mut reader = self.open_read(from, 0, None)?;
+        self.put_stream(to, &mut reader, size)?;
+        drop(reader);
+        self.delete(from)
+    }
+
+    /// Applies the permissions and timestamps set in `meta` to `path`;
+    /// `None` fields are left untouched. Backends that cannot store metadata
+    /// keep this default no-op.
+    fn set_metadata(&self, _path: &str, _meta: &FileMeta) -> Result<(), SyncError> {
+        Ok(())
+    }
+
+    /// Visits `root` and everything below it, parents before children.
+    /// Symlinks are reported, not followed. Unreadable subdirectories are
+    /// skipped with a warning; an unreadable root is an error.
+    fn walk(&self, root: &str, visit: &mut dyn FnMut(FileEntry)) -> Result<(), SyncError> {
+        let meta = self.stat(root)?;
+        let is_dir = meta.is_dir();
+        visit(FileEntry {
+            path: root.to_string(),
+            metadata: meta,
+        });
+        if !is_dir {
+            return Ok(());
+        }
+        let mut pending = vec![root.to_string()];
+        while let Some(dir) = pending.pop() {
+            let entries = match self.list(&dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    log::warn!("Skipping unreadable directory {dir}: {e:?}");
+                    continue;
+                }
+            };
+            for entry in entries {
+                if entry.metadata.is_dir() {
+                    pending.push(entry.path.clone());
+                }
+                visit(entry);
             }
-            _ => Err(SyncError::Other(format!("Unsupported protocol: {proto}"))),
         }
-    } else {
-        Ok((Arc::new(LocalBackend::new()), url))
+        Ok(())
+    }
+
+    /// Creates a symlink at `link` pointing to `target`.
+    fn symlink(&self, target: &str, link: &str) -> Result<(), SyncError> {
+        Err(SyncError::Other(format!(
+            "Cannot create symlink {link} -> {target}: backend does not support symlinks"
+        )))
+    }
+
+    /// Returns the target of the symlink at `path`.
+    fn read_link(&self, path: &str) -> Result<String, SyncError> {
+        Err(SyncError::Other(format!(
+            "Cannot read symlink {path}: backend does not support symlinks"
+        )))
+    }
+
+    /// Recreates a FIFO, socket or device node described by `meta` at `path`.
+    fn mknod(&self, path: &str, _meta: &FileMeta) -> Result<(), SyncError> {
+        Err(SyncError::Other(format!(
+            "Cannot create special file {path}: destination does not support special files"
+        )))
+    }
+
+    /// Completes pending writes after the last copy or sync into this
+    /// backend. Archive backends write their trailer here; others keep this
+    /// default no-op.
+    fn finish(&self) -> Result<(), SyncError> {
+        Ok(())
+    }
+
+    /// Files smaller than this are worth collecting for `put_batch` rather
+    /// than writing one at a time. `None`, the default, never batches.
+    fn batch_threshold(&self) -> Option<u64> {
+        None
+    }
+
+    /// Writes several small files and their metadata, returning one result
+    /// per file, in order. The default writes them one by one.
+    fn put_batch(&self, files: &[BatchFile]) -> Vec<Result<(), SyncError>> {
+        files
+            .iter()
+            .map(|file| self.put_with_meta(&file.path, &mut file.data.as_slice(), &file.meta))
+            .collect()
+    }
+
+    /// blake3 of the file at `path`, if the backend can compute it without
+    /// sending the data over the network. `sync` compares hashes before
+    /// copying a file whose size matches but whose mtime doesn't.
+    fn hash(&self, _path: &str) -> Result<Option<blake3::Hash>, SyncError> {
+        Ok(None)
+    }
+
+    /// Signature of the file at `path` in blocks of `block_size` (see
+    /// [`crate::delta`]), or `None` if the backend can't apply deltas.
+    fn signature(
+        &self,
+        _path: &str,
+        _block_size: u32,
+    ) -> Result<Option<crate::delta::Signature>, SyncError> {
+        Ok(None)
+    }
+
+    /// Rewrites `path`, whose signature is `basis`, with the contents of
+    /// `reader`, transferring only what differs, then applies `meta`. Only
+    /// called on backends whose `signature` returned one.
+    fn put_delta(
+        &self,
+        path: &str,
+        _basis: &crate::delta::Signature,
+        _reader: &mut dyn std::io::Read,
+        _meta: &FileMeta,
+    ) -> Result<(), SyncError> {
+        Err(SyncError::Other(format!(
+            "Cannot patch {path}: backend does not support delta transfers"
+        )))
+    }
+
+    /// The path on this machine's filesystem that `path` names, for backends
+    /// that are plain local directories. Lets `copy` and `sync` copy between
+    /// two of them inside the kernel (reflink, `copy_file_range`).
+    fn local_path(&self, _path: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// Finest timestamp resolution the backend stores. `sync` compares mtimes
+    /// truncated to the coarser precision of its two backends.
+    fn time_precision(&self) -> std::time::Duration {
+        std::time::Duration::from_nanos(1)
     }
 }
+
+pub use compress::CompressBackend;
+pub use crypt::{CryptBackend, CryptKey};
+pub use local::LocalBackend;
+pub use memory::MemoryBackend;
+pub use registry::{
+    backend_and_path, register_backend, register_wrapper, BackendFactory, BackendRegistry,
+    BackendUrl, WrapperFactory,
+};
+pub use repo::RepoBackend;
+pub use s3::{S3Backend, S3Config};
+pub use ssh::{SshBackend, SshConfig};
+pub use tar::TarBackend;
+pub use webdav::WebDavBackend;
+pub use zip::ZipBackend;
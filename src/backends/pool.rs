@@ -0,0 +1,236 @@
+//! Connections shared by worker threads. A pool is either a fixed set, or
+//! grows on demand through a [`Connector`] up to a maximum, checking idle
+//! connections before handing them out and replacing broken ones.
+
+use super::SyncError;
+use crossbeam_channel as channel;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a checkout waits for a returned connection before looking
+/// again whether it may open one.
+const WAIT: Duration = Duration::from_millis(50);
+/// After the server refuses an extra connection, how long the pool makes
+/// do with what it has before trying to grow again.
+const GROWTH_BACKOFF: Duration = Duration::from_secs(5);
+/// Tries for a first connection when none is open.
+const CONNECT_ATTEMPTS: u32 = 3;
+
+/// Opens and checks the connections of a growable pool.
+pub trait Connector<T>: Send + Sync {
+    fn connect(&self) -> Result<T, SyncError>;
+
+    /// Whether `conn`, idle for `idle`, still works. Called on checkout.
+    fn is_alive(&self, _conn: &mut T, _idle: Duration) -> bool {
+        true
+    }
+}
+
+struct Idle<T> {
+    conn: T,
+    since: Instant,
+}
+
+struct Size {
+    open: usize,
+    /// No growth before this, after the server refused a connection.
+    grow_after: Option<Instant>,
+}
+
+/// A set of connections shared by worker threads. `checkout` hands out an
+/// idle one, opens a new one if the pool may grow, or waits; the guard hands
+/// it back when dropped.
+pub struct Pool<T> {
+    idle: channel::Receiver<Idle<T>>,
+    ret: channel::Sender<Idle<T>>,
+    connector: Option<Box<dyn Connector<T>>>,
+    max: usize,
+    size: Mutex<Size>,
+}
+
+impl<T> Pool<T> {
+    /// A pool of exactly `conns`.
+    pub fn new(conns: Vec<T>) -> Self {
+        let max = conns.len();
+        let pool = Self::with_capacity(max, None);
+        pool.size.lock().unwrap().open = max;
+        for conn in conns {
+            let _ = pool.ret.send(Idle {
+                conn,
+                since: Instant::now(),
+            });
+        }
+        pool
+    }
+
+    /// A pool holding `first` that opens up to `max` connections in all
+    /// with `connector` as workers ask for them. Opening the first one up
+    /// front lets bad hosts or credentials fail before any work starts.
+    pub fn growable(max: usize, first: T, connector: impl Connector<T> + 'static) -> Self {
+        let pool = Self::with_capacity(max.max(1), Some(Box::new(connector)));
+        pool.size.lock().unwrap().open = 1;
+        let _ = pool.ret.send(Idle {
+            conn: first,
+            since: Instant::now(),
+        });
+        pool
+    }
+
+    fn with_capacity(max: usize, connector: Option<Box<dyn Connector<T>>>) -> Self {
+        let (ret, idle) = channel::bounded(max.max(1));
+        Self {
+            idle,
+            ret,
+            connector,
+            max: max.max(1),
+            size: Mutex::new(Size {
+                open: 0,
+                grow_after: None,
+            }),
+        }
+    }
+
+    /// Connections currently open, idle or checked out.
+    pub fn open(&self) -> usize {
+        self.size.lock().unwrap().open
+    }
+
+    /// Hands out a working connection. Fails only when none is open and new
+    /// ones can't be made.
+    pub fn checkout(self: &Arc<Self>) -> Result<PoolGuard<T>, SyncError> {
+        let mut failures = 0;
+        loop {
+            if let Ok(idle) = self.idle.try_recv() {
+                match self.check(idle) {
+                    Some(guard) => return Ok(guard),
+                    None => continue,
+                }
+            }
+            if let Some(connector) = self.connector.as_ref().filter(|_| self.reserve()) {
+                match connector.connect() {
+                    Ok(conn) => return Ok(self.guard(conn)),
+                    Err(e) => {
+                        if self.refused() > 0 {
+                            log::debug!("Server refused another connection, waiting: {e:?}");
+                        } else {
+                            failures += 1;
+                            if failures >= CONNECT_ATTEMPTS {
+                                return Err(e);
+                            }
+                            log::warn!("Connection failed, retrying: {e:?}");
+                            std::thread::sleep(WAIT * 4 * failures);
+                        }
+                        continue;
+                    }
+                }
+            }
+            if self.connector.is_none() && self.open() == 0 {
+                return Err(SyncError::Other(
+                    "Every pooled connection failed".to_string(),
+                ));
+            }
+            if let Ok(idle) = self.idle.recv_timeout(WAIT) {
+                if let Some(guard) = self.check(idle) {
+                    return Ok(guard);
+                }
+            }
+        }
+    }
+
+    fn guard(self: &Arc<Self>, conn: T) -> PoolGuard<T> {
+        PoolGuard {
+            conn: Some(conn),
+            broken: false,
+            pool: self.clone(),
+        }
+    }
+
+    /// Guards `idle` if it still works; otherwise drops it.
+    fn check(self: &Arc<Self>, mut idle: Idle<T>) -> Option<PoolGuard<T>> {
+        let alive = match &self.connector {
+            Some(connector) => connector.is_alive(&mut idle.conn, idle.since.elapsed()),
+            None => true,
+        };
+        if alive {
+            return Some(self.guard(idle.conn));
+        }
+        log::info!("Replacing a pooled connection that stopped responding");
+        drop(idle);
+        self.lost();
+        None
+    }
+
+    /// Counts a connection about to be opened, if the pool may grow. With
+    /// nothing open, growth is always allowed.
+    fn reserve(&self) -> bool {
+        let mut size = self.size.lock().unwrap();
+        let backing_off = size.grow_after.is_some_and(|t| Instant::now() < t);
+        if size.open >= self.max || (backing_off && size.open > 0) {
+            return false;
+        }
+        size.open += 1;
+        true
+    }
+
+    /// Undoes `reserve` after a failed connect and holds growth off for a
+    /// while, as servers limiting sessions refuse the extra ones. Returns
+    /// how many connections are still open.
+    fn refused(&self) -> usize {
+        let mut size = self.size.lock().unwrap();
+        size.open -= 1;
+        size.grow_after = Some(Instant::now() + GROWTH_BACKOFF);
+        size.open
+    }
+
+    fn lost(&self) {
+        self.size.lock().unwrap().open -= 1;
+    }
+}
+
+/// A checked-out connection, returned to the pool on drop unless it was
+/// marked broken.
+pub struct PoolGuard<T> {
+    conn: Option<T>,
+    broken: bool,
+    pool: Arc<Pool<T>>,
+}
+
+impl<T> PoolGuard<T> {
+    /// Closes the connection on drop instead of returning it; the pool may
+    /// open a replacement.
+    pub fn mark_broken(&mut self) {
+        self.broken = true;
+    }
+}
+
+impl<T> Deref for PoolGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for PoolGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for PoolGuard<T> {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else {
+            return;
+        };
+        if self.broken {
+            drop(conn);
+            self.pool.lost();
+            return;
+        }
+        let _ = self.pool.ret.send(Idle {
+            conn,
+            since: Instant::now(),
+        });
+    }
+}
@@ -0,0 +1,566 @@
+//! Maps URL schemes to the factories that open backends for them, so
+//! applications embedding parsync can add their own storage alongside the
+//! built-in `file`, `mem`, `repo`, `s3`, `ssh`, `tar`, `webdav(s)` and `zip`.
+//! Wrappers such as `crypt` and `zstd` are named before the scheme they wrap:
+//! `crypt+ssh://host/path`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+
+use super::compress::CompressWrapper;
+use super::crypt::CryptWrapper;
+use super::{
+    LocalBackend, MemoryBackend, RepoBackend, S3Backend, S3Config, SshBackend, SshConfig,
+    StorageBackend, SyncError, TarBackend, WebDavBackend, ZipBackend,
+};
+
+/// A `scheme://[user[:password]@]host[:port][/path][?key=value&...]` URL,
+/// split into borrowed parts. Parts are as written, not percent-decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendUrl<'a> {
+    pub url: &'a str,
+    pub scheme: &'a str,
+    /// Everything between `://` and `?`, for schemes whose locations are
+    /// not host-based, such as local paths and archive files.
+    pub location: &'a str,
+    /// `[user[:password]@]host[:port]`.
+    pub authority: &'a str,
+    pub user: Option<&'a str>,
+    pub password: Option<&'a str>,
+    /// The host, without brackets for IPv6 literals.
+    pub host: &'a str,
+    pub port: Option<u16>,
+    /// From the first `/` after the authority, or empty if there is none.
+    pub path: &'a str,
+    pub query: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> BackendUrl<'a> {
+    pub fn parse(url: &'a str) -> Result<Self, SyncError> {
+        Self::parse_at(url, |rest| rest.find('?'))
+    }
+
+    /// As [`BackendUrl::parse`], for schemes that take no options: a `?` is
+    /// part of the location, unless it starts a trailing query made up of
+    /// `options` alone (those of the wrappers around the scheme).
+    pub fn parse_opaque(url: &'a str, options: &[&str]) -> Result<Self, SyncError> {
+        Self::parse_at(url, |rest| {
+            let at = rest.rfind('?')?;
+            let mut keys = rest[at + 1..]
+                .split('&')
+                .map(|option| option.split_once('=').map_or(option, |(key, _)| key));
+            keys.all(|key| options.contains(&key)).then_some(at)
+        })
+    }
+
+    /// Parses `url`, with the query starting after the `?` that `query_at`
+    /// finds in the part after `://`, if any.
+    fn parse_at(url: &'a str, query_at: impl Fn(&str) -> Option<usize>) -> Result<Self, SyncError> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| SyncError::Other(format!("Not a URL: {url}")))?;
+        if scheme.is_empty() {
+            return Err(SyncError::Other(format!("URL missing scheme: {url}")));
+        }
+        let (location, query) = match query_at(rest) {
+            Some(at) => (&rest[..at], &rest[at + 1..]),
+            None => (rest, ""),
+        };
+        let (authority, path) = match location.find('/') {
+            Some(slash) => location.split_at(slash),
+            None => (location, ""),
+        };
+        let (userinfo, host_port) = match authority.rsplit_once('@') {
+            Some((userinfo, host_port)) => (Some(userinfo), host_port),
+            None => (None, authority),
+        };
+        let (user, password) = match userinfo.map(|info| info.split_once(':')) {
+            Some(Some((user, password))) => (Some(user), Some(password)),
+            Some(None) => (userinfo, None),
+            None => (None, None),
+        };
+        let (host, port) = split_host_port(host_port)
+            .ok_or_else(|| SyncError::Other(format!("Invalid port in URL: {url}")))?;
+        let query = query
+            .split('&')
+            .filter(|option| !option.is_empty())
+            .map(|option| option.split_once('=').unwrap_or((option, "")))
+            .collect();
+        Ok(Self {
+            url,
+            scheme,
+            location,
+            authority,
+            user,
+            password,
+            host,
+            port,
+            path,
+            query,
+        })
+    }
+
+    /// The value of query option `key`, if given.
+    pub fn option(&self, key: &str) -> Option<&'a str> {
+        self.query.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+    }
+
+    /// Errors on the first query option not in `known`.
+    pub fn check_options(&self, known: &[&str]) -> Result<(), SyncError> {
+        match self.query.iter().find(|(k, _)| !known.contains(k)) {
+            Some((key, _)) => Err(SyncError::Other(format!(
+                "Unknown {} option {key}: {}",
+                self.scheme, self.url
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// The path, or an error naming the scheme if the URL has none.
+    pub fn require_path(&self) -> Result<&'a str, SyncError> {
+        if self.path.is_empty() {
+            return Err(SyncError::Other(format!(
+                "{} URI missing path: {}",
+                self.scheme, self.url
+            )));
+        }
+        Ok(self.path)
+    }
+}
+
+/// Splits `host[:port]` or `[v6]:port`. Returns `None` for a bad port.
+fn split_host_port(host_port: &str) -> Option<(&str, Option<u16>)> {
+    let (host, port) = match host_port.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, after) = bracketed.split_once(']')?;
+            (host, after.strip_prefix(':'))
+        }
+        None => match host_port.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (host_port, None),
+        },
+    };
+    match port {
+        Some(port) => Some((host, Some(port.parse().ok()?))),
+        None => Some((host, None)),
+    }
+}
+
+/// Opens the backend a URL names, with the path inside it that copy, sync
+/// and delete should start from.
+pub trait BackendFactory: Send + Sync {
+    /// Whether URLs of this scheme carry `?key=value` options. If not, a `?`
+    /// belongs to the path (see [`BackendUrl::parse_opaque`]).
+    fn takes_query(&self) -> bool {
+        true
+    }
+
+    fn open<'a>(
+        &self,
+        url: &BackendUrl<'a>,
+        pool_size: usize,
+    ) -> Result<(Arc<dyn StorageBackend + Send + Sync>, &'a str), SyncError>;
+}
+
+/// Wraps the backend opened for the rest of a `name+scheme://` URL, e.g. to
+/// transform data on its way in and out.
+pub trait WrapperFactory: Send + Sync {
+    /// Query options this wrapper reads. They are not passed on to the
+    /// factories of the schemes it wraps.
+    fn options(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Wraps `inner`. `url` is the whole URL and `root` the path the inner
+    /// factory returned for it.
+    fn wrap(
+        &self,
+        inner: Arc<dyn StorageBackend + Send + Sync>,
+        url: &BackendUrl<'_>,
+        root: &str,
+    ) -> Result<Arc<dyn StorageBackend + Send + Sync>, SyncError>;
+}
+
+/// Factories by scheme. [`backend_and_path`] uses the process-wide registry
+/// that [`register_backend`] adds to.
+#[derive(Clone, Default)]
+pub struct BackendRegistry {
+    factories: HashMap<String, Arc<dyn BackendFactory>>,
+    wrappers: HashMap<String, Arc<dyn WrapperFactory>>,
+}
+
+/// The factories a scheme resolved to: wrappers outermost first, then the
+/// factory of the innermost scheme.
+struct Resolved {
+    wrappers: Vec<Arc<dyn WrapperFactory>>,
+    factory: Arc<dyn BackendFactory>,
+    /// Length of the `name+` prefixes before the innermost scheme.
+    prefix_len: usize,
+}
+
+impl Resolved {
+    fn parse<'a>(&self, url: &'a str) -> Result<BackendUrl<'a>, SyncError> {
+        if self.factory.takes_query() {
+            return BackendUrl::parse(url);
+        }
+        let options: Vec<&str> = self
+            .wrappers
+            .iter()
+            .flat_map(|w| w.options().iter().copied())
+            .collect();
+        BackendUrl::parse_opaque(url, &options)
+    }
+
+    fn open<'a>(
+        &self,
+        url: &'a str,
+        pool_size: usize,
+    ) -> Result<(Arc<dyn StorageBackend + Send + Sync>, &'a str), SyncError> {
+        let outer = self.parse(url)?;
+        if self.wrappers.is_empty() {
+            return self.factory.open(&outer, pool_size);
+        }
+        let mut inner = self.parse(&url[self.prefix_len..])?;
+        inner
+            .query
+            .retain(|(key, _)| !self.wrappers.iter().any(|w| w.options().contains(key)));
+        let (mut backend, root) = self.factory.open(&inner, pool_size)?;
+        for wrapper in self.wrappers.iter().rev() {
+            backend = wrapper.wrap(backend, &outer, root)?;
+        }
+        Ok((backend, root))
+    }
+}
+
+impl BackendRegistry {
+    /// A registry with no schemes; plain paths still open locally.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry with the built-in schemes.
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        registry.register("file", LocalFactory);
+        registry.register("mem", MemoryFactory);
+        registry.register("repo", RepoFactory);
+        registry.register("s3", S3Factory);
+        registry.register("ssh", SshFactory);
+        registry.register("tar", TarFactory);
+        registry.register("webdav", WebDavFactory { tls: false });
+        registry.register("webdavs", WebDavFactory { tls: true });
+        registry.register("zip", ZipFactory);
+        registry.register_wrapper("crypt", CryptWrapper);
+        registry.register_wrapper("zstd", CompressWrapper);
+        registry
+    }
+
+    /// Adds or replaces the factory for `scheme`, returning the previous one.
+    pub fn register(
+        &mut self,
+        scheme: &str,
+        factory: impl BackendFactory + 'static,
+    ) -> Option<Arc<dyn BackendFactory>> {
+        self.factories.insert(scheme.to_string(), Arc::new(factory))
+    }
+
+    /// Adds or replaces the wrapper `name`, used as `name+scheme://`, and
+    /// returns the previous one.
+    pub fn register_wrapper(
+        &mut self,
+        name: &str,
+        wrapper: impl WrapperFactory + 'static,
+    ) -> Option<Arc<dyn WrapperFactory>> {
+        self.wrappers.insert(name.to_string(), Arc::new(wrapper))
+    }
+
+    pub fn schemes(&self) -> Vec<&str> {
+        let mut schemes: Vec<_> = self.factories.keys().map(String::as_str).collect();
+        schemes.sort_unstable();
+        schemes
+    }
+
+    /// Opens `url` with the factory for its scheme. Strings without `://`
+    /// are local paths.
+    pub fn open<'a>(
+        &self,
+        url: &'a str,
+        pool_size: usize,
+    ) -> Result<(Arc<dyn StorageBackend + Send + Sync>, &'a str), SyncError> {
+        if !url.contains("://") {
+            return Ok((Arc::new(LocalBackend::new()), url));
+        }
+        let scheme = BackendUrl::parse(url)?.scheme;
+        self.resolve(scheme)?.open(url, pool_size)
+    }
+
+    /// Peels `name+` wrapper prefixes off `scheme` until the rest is a
+    /// registered scheme.
+    fn resolve(&self, scheme: &str) -> Result<Resolved, SyncError> {
+        let mut wrappers = Vec::new();
+        let mut rest = scheme;
+        while !self.factories.contains_key(rest) {
+            let Some((name, inner)) = rest.split_once('+') else {
+                break;
+            };
+            let wrapper = self.wrappers.get(name).ok_or_else(|| {
+                SyncError::Other(format!("Unsupported wrapper {name} in {scheme}"))
+            })?;
+            wrappers.push(wrapper.clone());
+            rest = inner;
+        }
+        let factory = self
+            .factories
+            .get(rest)
+            .cloned()
+            .ok_or_else(|| SyncError::Other(format!("Unsupported protocol: {rest}")))?;
+        Ok(Resolved {
+            wrappers,
+            factory,
+            prefix_len: scheme.len() - rest.len(),
+        })
+    }
+}
+
+fn global() -> &'static RwLock<BackendRegistry> {
+    static REGISTRY: OnceLock<RwLock<BackendRegistry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(BackendRegistry::with_builtins()))
+}
+
+/// Adds or replaces the factory for `scheme` in the process-wide registry,
+/// so the CLI and [`backend_and_path`] accept `scheme://` URLs.
+pub fn register_backend(
+    scheme: &str,
+    factory: impl BackendFactory + 'static,
+) -> Option<Arc<dyn BackendFactory>> {
+    global()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .register(scheme, factory)
+}
+
+/// Adds or replaces wrapper `name` in the process-wide registry.
+pub fn register_wrapper(
+    name: &str,
+    wrapper: impl WrapperFactory + 'static,
+) -> Option<Arc<dyn WrapperFactory>> {
+    global()
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .register_wrapper(name, wrapper)
+}
+
+/// Opens `url` with the process-wide registry.
+pub fn backend_and_path(
+    url: &str,
+    pool_size: usize,
+) -> Result<(Arc<dyn StorageBackend + Send + Sync>, &str), SyncError> {
+    // Factories may connect over the network, so the lock is not held
+    // while one runs.
+    let registry = global().read().unwrap_or_else(|e| e.into_inner()).clone();
+    registry.open(url, pool_size)
+}
+
+struct LocalFactory;
+
+impl BackendFactory for LocalFactory {
+    fn takes_query(&self) -> bool {
+        false
+    }
+
+    fn open<'a>(
+        &self,
+        url: &BackendUrl<'a>,
+        _pool_size: usize,
+    ) -> Result<(Arc<dyn StorageBackend + Send + Sync>, &'a str), SyncError> {
+        Ok((Arc::new(LocalBackend::new()), url.location))
+    }
+}
+
+struct MemoryFactory;
+
+impl BackendFactory for MemoryFactory {
+    fn open<'a>(
+        &self,
+        url: &BackendUrl<'a>,
+        _pool_size: usize,
+    ) -> Result<(Arc<dyn StorageBackend + Send + Sync>, &'a str), SyncError> {
+        let path = url.require_path()?;
+        Ok((Arc::new(MemoryBackend::named(url.authority)), path))
+    }
+}
+
+struct RepoFactory;
+
+impl BackendFactory for RepoFactory {
+    fn open<'a>(
+        &self,
+        url: &BackendUrl<'a>,
+        _pool_size: usize,
+    ) -> Result<(Arc<dyn StorageBackend + Send + Sync>, &'a str), SyncError> {
+        url.check_options(&["snapshot"])?;
+        let repo = RepoBackend::new(url.location, url.option("snapshot"));
+        Ok((Arc::new(repo), "/"))
+    }
+}
+
+struct S3Factory;
+
+impl BackendFactory for S3Factory {
+    fn open<'a>(
+        &self,
+        url: &BackendUrl<'a>,
+        pool_size: usize,
+    ) -> Result<(Arc<dyn StorageBackend + Send + Sync>, &'a str), SyncError> {
+        if url.authority.is_empty() {
+            return Err(SyncError::Other(format!(
+                "S3 URI missing bucket: {}",
+                url.url
+            )));
+        }
+        url.check_options(&["stored_mtimes"])?;
+        let key = if url.path.is_empty() { "/" } else { url.path };
+        let mut config = S3Config::from_env(url.authority)?;
+        match url.option("stored_mtimes") {
+            None => {}
+            Some("yes") => config.stored_mtimes = true,
+            Some("no") => config.stored_mtimes = false,
+            Some(other) => {
+                return Err(SyncError::Other(format!(
+                    "stored_mtimes must be yes or no, not {other}: {}",
+                    url.url
+                )))
+            }
+        }
+        Ok((Arc::new(S3Backend::new(config, pool_size)?), key))
+    }
+}
+
+struct SshFactory;
+
+impl BackendFactory for SshFactory {
+    fn open<'a>(
+        &self,
+        url: &BackendUrl<'a>,
+        pool_size: usize,
+    ) -> Result<(Arc<dyn StorageBackend + Send + Sync>, &'a str), SyncError> {
+        url.check_options(&[
+            "batch_mode",
+            "batch_threshold",
+            "known_hosts",
+            "parsync_path",
+            "passphrase_file",
+            "password_file",
+            "proxy_jump",
+            "ssh_config",
+            "strict_host_key_checking",
+        ])?;
+        let path = url.require_path()?;
+        let mut config = match url.option("ssh_config") {
+            Some(file) => SshConfig::resolve_with(url.host, url.user, url.port, &[file.into()])?,
+            None => SshConfig::resolve(url.host, url.user, url.port)?,
+        };
+        if let Some(known_hosts) = url.option("known_hosts") {
+            config.known_hosts = known_hosts.into();
+        }
+        if let Some(checking) = url.option("strict_host_key_checking") {
+            config.strict_host_key_checking = checking.parse()?;
+        }
+        if let Some(jump) = url.option("proxy_jump") {
+            config.proxy_jump = Some(jump.to_string()).filter(|jump| jump != "none");
+        }
+        if let Some(bytes) = url.option("batch_threshold") {
+            config.batch_threshold = bytes.parse().map_err(|_| {
+                SyncError::Other(format!(
+                    "batch_threshold must be a number of bytes, not {bytes}: {}",
+                    url.url
+                ))
+            })?;
+        }
+        if let Some(command) = url.option("parsync_path") {
+            config.parsync_path = Some(command.to_string()).filter(|command| command != "none");
+        }
+        if let Some(password) = url.password {
+            config.password = Some(
+                percent_encoding::percent_decode_str(password)
+                    .decode_utf8_lossy()
+                    .into_owned(),
+            );
+        }
+        if let Some(file) = url.option("password_file") {
+            config.password_file = Some(file.into());
+        }
+        if let Some(file) = url.option("passphrase_file") {
+            config.passphrase_file = Some(file.into());
+        }
+        match url.option("batch_mode") {
+            None => {}
+            Some("yes") => config.batch_mode = true,
+            Some("no") => config.batch_mode = false,
+            Some(other) => {
+                return Err(SyncError::Other(format!(
+                    "batch_mode must be yes or no, not {other}: {}",
+                    url.url
+                )))
+            }
+        }
+        let backend = SshBackend::connect_with(&config, pool_size)?;
+        Ok((Arc::new(backend), path))
+    }
+}
+
+struct TarFactory;
+
+impl BackendFactory for TarFactory {
+    fn takes_query(&self) -> bool {
+        false
+    }
+
+    fn open<'a>(
+        &self,
+        url: &BackendUrl<'a>,
+        _pool_size: usize,
+    ) -> Result<(Arc<dyn StorageBackend + Send + Sync>, &'a str), SyncError> {
+        Ok((Arc::new(TarBackend::new(url.location)), "/"))
+    }
+}
+
+struct WebDavFactory {
+    tls: bool,
+}
+
+impl BackendFactory for WebDavFactory {
+    fn open<'a>(
+        &self,
+        url: &BackendUrl<'a>,
+        pool_size: usize,
+    ) -> Result<(Arc<dyn StorageBackend + Send + Sync>, &'a str), SyncError> {
+        url.check_options(&[])?;
+        let path = url.require_path()?;
+        let backend = WebDavBackend::connect(url.authority, self.tls, pool_size)?;
+        Ok((Arc::new(backend), path))
+    }
+}
+
+struct ZipFactory;
+
+impl BackendFactory for ZipFactory {
+    fn open<'a>(
+        &self,
+        url: &BackendUrl<'a>,
+        _pool_size: usize,
+    ) -> Result<(Arc<dyn StorageBackend + Send + Sync>, &'a str), SyncError> {
+        url.check_options(&["level"])?;
+        let level = match url.option("level").map(str::parse::<i64>) {
+            None => None,
+            Some(Ok(n @ 0..=9)) => Some(n),
+            Some(_) => {
+                return Err(SyncError::Other(format!(
+                    "Zip level must be 0-9: {}",
+                    url.url
+                )))
+            }
+        };
+        Ok((Arc::new(ZipBackend::new(url.location, level)), "/"))
+    }
+}
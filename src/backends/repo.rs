@@ -0,0 +1,704 @@
+//! A deduplicating backup repository in a local directory. File contents
+//! are split into content-defined chunks stored once each under their
+//! blake3 hash; each copy or sync into the repository saves a snapshot
+//! manifest listing every entry and the chunks of every file.
+//!
+//! Layout: `config`, `chunks/<2 hex>/<hash>` (zstd-compressed chunk data)
+//! and `snapshots/<id>` (manifests, ids sort by creation time).
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+use super::archive::{dir_meta, file_meta, normalize, Entries};
+use super::{FileEntry, FileKind, FileMeta, StorageBackend, SyncError};
+use crate::utils::{format_mtime, parse_mtime};
+
+const CONFIG: &str = "parsync-repo 1\n";
+const MANIFEST_HEADER: &str = "parsync-snapshot 1";
+const MIN_CHUNK: usize = 512 * 1024;
+const MAX_CHUNK: usize = 4 * 1024 * 1024;
+/// Top 20 bits of the gear hash: cut points average 1 MiB past the minimum.
+const CUT_MASK: u64 = ((1 << 20) - 1) << 44;
+const CHUNK_LEVEL: i32 = 3;
+
+/// Characters escaped in manifest fields.
+const FIELD: &AsciiSet = &CONTROLS.add(b'%').add(b',');
+
+fn gear() -> &'static [u64; 256] {
+    static GEAR: OnceLock<[u64; 256]> = OnceLock::new();
+    GEAR.get_or_init(|| {
+        // splitmix64, so the table (and with it every cut point) is fixed.
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        std::array::from_fn(|_| {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        })
+    })
+}
+
+/// Length of the first chunk of `data`, which holds all remaining input
+/// when shorter than the maximum chunk.
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    let gear = gear();
+    let mut hash = 0u64;
+    let end = data.len().min(MAX_CHUNK);
+    for (i, &byte) in data[..end].iter().enumerate().skip(MIN_CHUNK) {
+        hash = (hash << 1).wrapping_add(gear[byte as usize]);
+        if hash & CUT_MASK == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// A chunk of a file: blake3 hash (hex) and length.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChunkRef {
+    hash: String,
+    len: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+enum Data {
+    #[default]
+    None,
+    File(Vec<ChunkRef>),
+    Link(String),
+}
+
+/// The snapshot being read or built.
+struct Tree {
+    entries: Entries<Data>,
+    /// Snapshot this one started from.
+    parent: Option<String>,
+    dirty: bool,
+}
+
+/// A saved snapshot.
+#[derive(Debug, Clone)]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub parent: Option<String>,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// What garbage collection removed and kept.
+#[derive(Debug, Clone, Default)]
+pub struct GcStats {
+    pub chunks_removed: u64,
+    pub bytes_freed: u64,
+    pub chunks_kept: u64,
+}
+
+pub struct RepoBackend {
+    dir: PathBuf,
+    /// `None` for the latest snapshot, `Some("none")` for an empty one.
+    snapshot: Option<String>,
+    tree: Mutex<Option<Tree>>,
+    tmp_counter: AtomicU64,
+}
+
+impl RepoBackend {
+    /// Opens the repository in `dir`, created on the first write. Reads see
+    /// `snapshot` (the latest if `None`, nothing if `"none"`); writes build
+    /// a new snapshot on top of it, saved by `finish`.
+    pub fn new(dir: impl AsRef<Path>, snapshot: Option<&str>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            snapshot: snapshot.map(str::to_string),
+            tree: Mutex::new(None),
+            tmp_counter: AtomicU64::new(0),
+        }
+    }
+
+    fn snapshots_dir(&self) -> PathBuf {
+        self.dir.join("snapshots")
+    }
+
+    fn check_repo(&self) -> Result<(), SyncError> {
+        match fs::read_to_string(self.dir.join("config")) {
+            Ok(config) if config == CONFIG => Ok(()),
+            _ => Err(SyncError::Other(format!(
+                "Not a parsync repository: {}",
+                self.dir.display()
+            ))),
+        }
+    }
+
+    fn init(&self) -> Result<(), SyncError> {
+        if self.dir.join("config").exists() {
+            return self.check_repo();
+        }
+        fs::create_dir_all(self.dir.join("chunks"))?;
+        fs::create_dir_all(self.snapshots_dir())?;
+        self.write_atomic(&self.dir.join("config"), CONFIG.as_bytes())
+    }
+
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> Result<(), SyncError> {
+        let n = self.tmp_counter.fetch_add(1, Ordering::Relaxed);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = path.with_file_name(format!(".{name}.{}.{n}.tmp", std::process::id()));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Snapshot ids, oldest first.
+    fn snapshot_ids(&self) -> Result<Vec<String>, SyncError> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(self.snapshots_dir())? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if !name.starts_with('.') {
+                ids.push(name);
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn load_tree(&self) -> Result<Tree, SyncError> {
+        let empty = Tree {
+            entries: Entries::new(),
+            parent: None,
+            dirty: false,
+        };
+        let id = match self.snapshot.as_deref() {
+            Some("none") => return Ok(empty),
+            Some(id) => id.to_string(),
+            None => {
+                if !self.dir.join("config").exists() {
+                    return Ok(empty);
+                }
+                self.check_repo()?;
+                match self.snapshot_ids()?.pop() {
+                    Some(id) => id,
+                    None => return Ok(empty),
+                }
+            }
+        };
+        self.check_repo()?;
+        let (_, entries) = self.read_manifest(&id)?;
+        Ok(Tree {
+            entries,
+            parent: Some(id),
+            dirty: false,
+        })
+    }
+
+    fn with_tree<T>(
+        &self,
+        f: impl FnOnce(&mut Tree) -> Result<T, SyncError>,
+    ) -> Result<T, SyncError> {
+        let mut tree = self.tree.lock().unwrap();
+        if tree.is_none() {
+            *tree = Some(self.load_tree()?);
+        }
+        f(tree.as_mut().expect("loaded above"))
+    }
+
+    fn modify<T>(
+        &self,
+        f: impl FnOnce(&mut Entries<Data>) -> Result<T, SyncError>,
+    ) -> Result<T, SyncError> {
+        self.with_tree(|tree| {
+            let result = f(&mut tree.entries)?;
+            tree.dirty = true;
+            Ok(result)
+        })
+    }
+
+    /// Stores `data` as a chunk unless a chunk with its hash exists.
+    fn store_chunk(&self, data: &[u8]) -> Result<ChunkRef, SyncError> {
+        let hash = blake3::hash(data).to_hex().to_string();
+        let path = chunk_path(&self.dir, &hash);
+        if !path.exists() {
+            fs::create_dir_all(path.parent().expect("chunk directory"))?;
+            self.write_atomic(&path, &zstd::encode_all(data, CHUNK_LEVEL)?)?;
+        }
+        Ok(ChunkRef {
+            hash,
+            len: data.len() as u64,
+        })
+    }
+
+    /// Splits exactly `size` bytes of `reader` into chunks and stores them.
+    fn store_file(
+        &self,
+        path: &str,
+        reader: &mut dyn Read,
+        size: u64,
+    ) -> Result<Vec<ChunkRef>, SyncError> {
+        self.init()?;
+        let mut reader = reader.take(size);
+        let mut chunks = Vec::new();
+        let mut buf = Vec::with_capacity(MAX_CHUNK.min(size as usize));
+        let mut total = 0u64;
+        loop {
+            let want = MAX_CHUNK - buf.len();
+            (&mut reader).take(want as u64).read_to_end(&mut buf)?;
+            if buf.is_empty() {
+                break;
+            }
+            let cut = cut_point(&buf);
+            chunks.push(self.store_chunk(&buf[..cut])?);
+            total += cut as u64;
+            buf.drain(..cut);
+        }
+        if total != size {
+            return Err(SyncError::Other(format!(
+                "Source ended after {total} of {size} bytes for {path}"
+            )));
+        }
+        Ok(chunks)
+    }
+
+    fn manifest(tree: &Tree) -> String {
+        let mut out = format!(
+            "{MANIFEST_HEADER}\nparent {}\n",
+            tree.parent.as_deref().unwrap_or("-")
+        );
+        let time = |t: Option<SystemTime>| t.map(format_mtime).unwrap_or_else(|| "-".to_string());
+        let opt = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+        for (key, (meta, data)) in tree.entries.iter() {
+            let kind = match meta.kind {
+                FileKind::File => 'f',
+                FileKind::Dir => 'd',
+                FileKind::Symlink => 'l',
+                FileKind::Fifo => 'p',
+                FileKind::Socket => 's',
+                FileKind::CharDevice => 'c',
+                FileKind::BlockDevice => 'b',
+            };
+            let data = match data {
+                Data::File(chunks) if !chunks.is_empty() => chunks
+                    .iter()
+                    .map(|c| format!("{}:{}", c.hash, c.len))
+                    .collect::<Vec<_>>()
+                    .join(","),
+                Data::Link(target) => utf8_percent_encode(target, FIELD).to_string(),
+                _ => "-".to_string(),
+            };
+            out.push_str(&format!(
+                "{kind}\t{}\t{}\t{}\t{}\t{}\t{}\t{data}\n",
+                opt(meta.mode.map(|m| format!("{m:o}"))),
+                time(meta.modified),
+                time(meta.accessed),
+                meta.size,
+                opt(meta.rdev.map(|r| r.to_string())),
+                utf8_percent_encode(key, FIELD),
+            ));
+        }
+        out
+    }
+
+    fn read_manifest(&self, id: &str) -> Result<(Option<String>, Entries<Data>), SyncError> {
+        if id.is_empty() || id.contains('/') || id.starts_with('.') {
+            return Err(SyncError::Other(format!("Invalid snapshot id: {id}")));
+        }
+        let text = match fs::read_to_string(self.snapshots_dir().join(id)) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(SyncError::NotFound(format!("snapshot {id}")))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let bad =
+            |line: &str| SyncError::Other(format!("Bad manifest line in snapshot {id}: {line}"));
+        let mut lines = text.lines();
+        if lines.next() != Some(MANIFEST_HEADER) {
+            return Err(SyncError::Other(format!(
+                "Unknown manifest format: snapshot {id}"
+            )));
+        }
+        let parent = match lines.next().and_then(|l| l.strip_prefix("parent ")) {
+            Some("-") => None,
+            Some(parent) => Some(parent.to_string()),
+            None => {
+                return Err(SyncError::Other(format!(
+                    "Manifest missing parent: snapshot {id}"
+                )))
+            }
+        };
+        let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().to_string();
+        let mut entries = Entries::new();
+        for line in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [kind, mode, mtime, atime, size, rdev, key, data] = fields[..] else {
+                return Err(bad(line));
+            };
+            let kind = match kind {
+                "f" => FileKind::File,
+                "d" => FileKind::Dir,
+                "l" => FileKind::Symlink,
+                "p" => FileKind::Fifo,
+                "s" => FileKind::Socket,
+                "c" => FileKind::CharDevice,
+                "b" => FileKind::BlockDevice,
+                _ => return Err(bad(line)),
+            };
+            let opt = |s: &str| (s != "-").then(|| s.to_string());
+            let meta = FileMeta {
+                size: size.parse().map_err(|_| bad(line))?,
+                kind,
+                modified: opt(mtime).and_then(|t| parse_mtime(&t)),
+                accessed: opt(atime).and_then(|t| parse_mtime(&t)),
+                mode: opt(mode).and_then(|m| u32::from_str_radix(&m, 8).ok()),
+                rdev: opt(rdev).and_then(|r| r.parse().ok()),
+            };
+            let data = match (kind, data) {
+                (FileKind::File, "-") => Data::File(Vec::new()),
+                (FileKind::File, chunks) => Data::File(
+                    chunks
+                        .split(',')
+                        .map(|c| {
+                            let (hash, len) = c.split_once(':')?;
+                            Some(ChunkRef {
+                                hash: hash.to_string(),
+                                len: len.parse().ok()?,
+                            })
+                        })
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| bad(line))?,
+                ),
+                (FileKind::Symlink, target) => Data::Link(decode(target)),
+                _ => Data::None,
+            };
+            entries.insert(decode(key), meta, data);
+        }
+        Ok((parent, entries))
+    }
+
+    /// Saved snapshots, oldest first.
+    pub fn snapshots(&self) -> Result<Vec<SnapshotInfo>, SyncError> {
+        self.check_repo()?;
+        self.snapshot_ids()?
+            .into_iter()
+            .map(|id| {
+                let (parent, entries) = self.read_manifest(&id)?;
+                let (mut files, mut bytes) = (0, 0);
+                for (_, (meta, _)) in entries.iter() {
+                    if meta.is_file() {
+                        files += 1;
+                        bytes += meta.size;
+                    }
+                }
+                Ok(SnapshotInfo {
+                    id,
+                    parent,
+                    files,
+                    bytes,
+                })
+            })
+            .collect()
+    }
+
+    /// Deletes the manifest of snapshot `id`. Its chunks stay until `gc`.
+    pub fn forget(&self, id: &str) -> Result<(), SyncError> {
+        self.check_repo()?;
+        self.read_manifest(id)?;
+        fs::remove_file(self.snapshots_dir().join(id))?;
+        Ok(())
+    }
+
+    /// Removes chunks no snapshot refers to, and temporary files left by
+    /// interrupted writes. Must not run while a copy into the repository is
+    /// in progress: its chunks are not referenced until it finishes.
+    pub fn gc(&self) -> Result<GcStats, SyncError> {
+        self.check_repo()?;
+        let mut live = HashSet::new();
+        for id in self.snapshot_ids()? {
+            let (_, entries) = self.read_manifest(&id)?;
+            for (_, (_, data)) in entries.iter() {
+                if let Data::File(chunks) = data {
+                    live.extend(chunks.iter().map(|c| c.hash.clone()));
+                }
+            }
+        }
+        let mut stats = GcStats::default();
+        for shard in fs::read_dir(self.dir.join("chunks"))? {
+            let shard = shard?.path();
+            for chunk in fs::read_dir(&shard)? {
+                let chunk = chunk?;
+                let name = chunk.file_name().to_string_lossy().to_string();
+                if live.contains(&name) {
+                    stats.chunks_kept += 1;
+                    continue;
+                }
+                stats.bytes_freed += chunk.metadata()?.len();
+                fs::remove_file(chunk.path())?;
+                if !name.starts_with('.') {
+                    stats.chunks_removed += 1;
+                }
+            }
+            let _ = fs::remove_dir(&shard);
+        }
+        Ok(stats)
+    }
+}
+
+fn chunk_path(dir: &Path, hash: &str) -> PathBuf {
+    dir.join("chunks").join(&hash[..2]).join(hash)
+}
+
+/// Reads a chunk back, checking it against its hash.
+fn read_chunk(dir: &Path, chunk: &ChunkRef) -> Result<Vec<u8>, SyncError> {
+    let data = zstd::decode_all(fs::File::open(chunk_path(dir, &chunk.hash))?)?;
+    if blake3::hash(&data).to_hex().as_str() != chunk.hash || data.len() as u64 != chunk.len {
+        return Err(SyncError::Other(format!("Corrupt chunk {}", chunk.hash)));
+    }
+    Ok(data)
+}
+
+/// Reads the chunks of a file from `skip` bytes into the first one.
+struct ChunkReader {
+    dir: PathBuf,
+    chunks: std::vec::IntoIter<ChunkRef>,
+    skip: usize,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            let Some(chunk) = self.chunks.next() else {
+                return Ok(0);
+            };
+            self.buf = read_chunk(&self.dir, &chunk).map_err(|e| match e {
+                SyncError::Io(e) => e,
+                e => io::Error::new(io::ErrorKind::InvalidData, format!("{e:?}")),
+            })?;
+            self.pos = self.skip.min(self.buf.len());
+            self.skip = 0;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl StorageBackend for RepoBackend {
+    fn list(&self, path: &str) -> Result<Vec<FileEntry>, SyncError> {
+        self.with_tree(|tree| tree.entries.list(&normalize(path)))
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>, SyncError> {
+        let mut data = Vec::new();
+        self.open_read(path, 0, None)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), SyncError> {
+        self.put_stream(path, &mut &data[..], data.len() as u64)
+    }
+
+    fn delete(&self, path: &str) -> Result<(), SyncError> {
+        let key = normalize(path);
+        self.modify(|entries| {
+            if entries.remove(&key).is_empty() {
+                return Err(SyncError::NotFound(path.to_string()));
+            }
+            Ok(())
+        })
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, SyncError> {
+        match self.stat(path) {
+            Ok(_) => Ok(true),
+            Err(SyncError::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn put_stream(&self, path: &str, reader: &mut dyn Read, size: u64) -> Result<(), SyncError> {
+        let meta = FileMeta {
+            modified: Some(SystemTime::now()),
+            mode: Some(0o644),
+            ..file_meta(size)
+        };
+        self.put_with_meta(path, reader, &meta)
+    }
+
+    /// Chunking, hashing and compression run on the calling worker; only
+    /// the index update is serialized.
+    fn put_with_meta(
+        &self,
+        path: &str,
+        reader: &mut dyn Read,
+        meta: &FileMeta,
+    ) -> Result<(), SyncError> {
+        let chunks = self.store_file(path, reader, meta.size)?;
+        let key = normalize(path);
+        let meta = FileMeta {
+            kind: FileKind::File,
+            ..meta.clone()
+        };
+        self.modify(|entries| {
+            entries.insert(key, meta, Data::File(chunks));
+            Ok(())
+        })
+    }
+
+    fn open_read(
+        &self,
+        path: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read + Send>, SyncError> {
+        let key = normalize(path);
+        let mut chunks = self.with_tree(|tree| match tree.entries.get(&key) {
+            Some((_, Data::File(chunks))) => Ok(chunks.clone()),
+            Some(_) => Err(SyncError::Other(format!("Not a file: {path}"))),
+            None => Err(SyncError::NotFound(path.to_string())),
+        })?;
+        let mut start = 0;
+        let mut skip = offset;
+        while start < chunks.len() && skip >= chunks[start].len {
+            skip -= chunks[start].len;
+            start += 1;
+        }
+        let reader = ChunkReader {
+            dir: self.dir.clone(),
+            chunks: chunks.split_off(start).into_iter(),
+            skip: skip as usize,
+            buf: Vec::new(),
+            pos: 0,
+        };
+        Ok(match len {
+            Some(len) => Box::new(reader.take(len)),
+            None => Box::new(reader),
+        })
+    }
+
+    fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        self.with_tree(|tree| tree.entries.stat(&normalize(path)))
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), SyncError> {
+        let key = normalize(path);
+        if key == "/" || self.stat(path).map(|m| m.is_dir()).unwrap_or(false) {
+            return Ok(());
+        }
+        self.modify(|entries| {
+            entries.insert(key, dir_meta(), Data::None);
+            Ok(())
+        })
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+        let (from_key, to_key) = (normalize(from), normalize(to));
+        self.modify(|entries| {
+            let moved = entries.remove(&from_key);
+            if moved.is_empty() {
+                return Err(SyncError::NotFound(from.to_string()));
+            }
+            entries.remove(&to_key);
+            for (key, (meta, data)) in moved {
+                entries.insert(format!("{to_key}{}", &key[from_key.len()..]), meta, data);
+            }
+            Ok(())
+        })
+    }
+
+    fn set_metadata(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        let key = normalize(path);
+        if key == "/" {
+            return Ok(());
+        }
+        self.modify(|entries| {
+            let (entry, _) = entries
+                .get_mut(&key)
+                .ok_or_else(|| SyncError::NotFound(path.to_string()))?;
+            if meta.mode.is_some() {
+                entry.mode = meta.mode;
+            }
+            if meta.modified.is_some() {
+                entry.modified = meta.modified;
+            }
+            if meta.accessed.is_some() {
+                entry.accessed = meta.accessed;
+            }
+            Ok(())
+        })
+    }
+
+    fn walk(&self, root: &str, visit: &mut dyn FnMut(FileEntry)) -> Result<(), SyncError> {
+        self.with_tree(|tree| tree.entries.walk(root, visit))
+    }
+
+    fn symlink(&self, target: &str, link: &str) -> Result<(), SyncError> {
+        let meta = FileMeta {
+            kind: FileKind::Symlink,
+            size: target.len() as u64,
+            mode: Some(0o777),
+            ..dir_meta()
+        };
+        let key = normalize(link);
+        self.modify(|entries| {
+            entries.insert(key, meta, Data::Link(target.to_string()));
+            Ok(())
+        })
+    }
+
+    fn read_link(&self, path: &str) -> Result<String, SyncError> {
+        let key = normalize(path);
+        self.with_tree(|tree| match tree.entries.get(&key) {
+            Some((_, Data::Link(target))) => Ok(target.clone()),
+            Some(_) => Err(SyncError::Other(format!("Not a symlink: {path}"))),
+            None => Err(SyncError::NotFound(path.to_string())),
+        })
+    }
+
+    fn mknod(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        let key = normalize(path);
+        let meta = FileMeta {
+            size: 0,
+            ..meta.clone()
+        };
+        self.modify(|entries| {
+            entries.insert(key, meta, Data::None);
+            Ok(())
+        })
+    }
+
+    /// Saves the snapshot built since the last `finish`, if anything was
+    /// written.
+    fn finish(&self) -> Result<(), SyncError> {
+        let mut guard = self.tree.lock().unwrap();
+        let Some(tree) = guard.as_mut().filter(|tree| tree.dirty) else {
+            return Ok(());
+        };
+        self.init()?;
+        let mut id = format_mtime(SystemTime::now());
+        while self.snapshots_dir().join(&id).exists() {
+            id.push('0');
+        }
+        let manifest = Self::manifest(tree);
+        let path = self.snapshots_dir().join(&id);
+        self.write_atomic(&path, manifest.as_bytes())?;
+        fs::File::open(&path)?.sync_all()?;
+        log::info!("Saved snapshot {id} in {}", self.dir.display());
+        tree.parent = Some(id);
+        tree.dirty = false;
+        Ok(())
+    }
+}
@@ -0,0 +1,938 @@
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::xml::{parse_xml, XmlNode};
+use super::{FileEntry, FileKind, FileMeta, StorageBackend, SyncError};
+use crate::utils::{format_mtime, parse_mtime, parse_nanos};
+
+/// User metadata key holding the source mtime as `seconds.nanoseconds`.
+const MTIME_HEADER: &str = "x-amz-meta-mtime";
+/// Smallest part of a multipart upload; files below this go up in one PUT.
+const MIN_PART_SIZE: u64 = 8 << 20;
+const MAX_PARTS: u64 = 10_000;
+/// Largest object a single CopyObject request accepts.
+const MAX_COPY_SIZE: u64 = 5 << 30;
+
+/// Characters left unescaped by SigV4 URI encoding.
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+const PATH_UNRESERVED: &AsciiSet = &UNRESERVED.remove(b'/');
+
+/// Connection settings for an S3-compatible bucket.
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    /// Custom endpoint such as `http://localhost:9000` for MinIO. Requests
+    /// to a custom endpoint use path-style addressing.
+    pub endpoint: Option<String>,
+    pub access_key: String,
+    pub secret_key: String,
+    pub session_token: Option<String>,
+    /// Whether listings and walks read each object's stored mtime with a
+    /// HEAD request. Off, they report S3's `LastModified` and cost no extra
+    /// requests; `stat` reads the stored mtime either way.
+    pub stored_mtimes: bool,
+}
+
+/// Reads `[section]` of an INI-style AWS config file.
+fn ini_section(path: &Path, section: &str) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    let Ok(text) = std::fs::read_to_string(path) else {
+        return values;
+    };
+    let mut in_section = false;
+    for line in text.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_section = name.trim() == section;
+        } else if in_section {
+            if let Some((key, value)) = line.split_once('=') {
+                values.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+    }
+    values
+}
+
+impl S3Config {
+    /// Reads credentials, region and endpoint from the standard AWS
+    /// environment variables, falling back to the shared credentials and
+    /// config files for `AWS_PROFILE` (default `default`).
+    pub fn from_env(bucket: &str) -> Result<Self, SyncError> {
+        let env = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let home = PathBuf::from(env("HOME").unwrap_or_default());
+        let profile = env("AWS_PROFILE").unwrap_or_else(|| "default".to_string());
+
+        let credentials = ini_section(
+            &env("AWS_SHARED_CREDENTIALS_FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| home.join(".aws/credentials")),
+            &profile,
+        );
+        let config_section = if profile == "default" {
+            profile.clone()
+        } else {
+            format!("profile {profile}")
+        };
+        let config = ini_section(
+            &env("AWS_CONFIG_FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| home.join(".aws/config")),
+            &config_section,
+        );
+        let lookup = |var: &str, key: &str| {
+            env(var)
+                .or_else(|| credentials.get(key).cloned())
+                .or_else(|| config.get(key).cloned())
+        };
+
+        let missing = || {
+            SyncError::Other(format!(
+                "No S3 credentials: set AWS_ACCESS_KEY_ID/AWS_SECRET_ACCESS_KEY or configure profile '{profile}'"
+            ))
+        };
+        Ok(Self {
+            bucket: bucket.to_string(),
+            region: env("AWS_REGION")
+                .or_else(|| env("AWS_DEFAULT_REGION"))
+                .or_else(|| config.get("region").cloned())
+                .unwrap_or_else(|| "us-east-1".to_string()),
+            endpoint: env("AWS_ENDPOINT_URL_S3")
+                .or_else(|| env("AWS_ENDPOINT_URL"))
+                .or_else(|| config.get("endpoint_url").cloned()),
+            access_key: lookup("AWS_ACCESS_KEY_ID", "aws_access_key_id").ok_or_else(missing)?,
+            secret_key: lookup("AWS_SECRET_ACCESS_KEY", "aws_secret_access_key")
+                .ok_or_else(missing)?,
+            session_token: lookup("AWS_SESSION_TOKEN", "aws_session_token"),
+            stored_mtimes: true,
+        })
+    }
+}
+
+/// Converts days since the Unix epoch to a (year, month, day) date.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Formats `t` as the SigV4 timestamp `YYYYMMDDTHHMMSSZ`.
+fn amz_date(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let (y, m, d) = civil_from_days(secs.div_euclid(86_400));
+    let rem = secs.rem_euclid(86_400);
+    format!(
+        "{y:04}{m:02}{d:02}T{:02}{:02}{:02}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// Parses the ISO 8601 `LastModified` of a listing, e.g.
+/// `2024-01-02T03:04:05.000Z`.
+fn parse_iso8601(s: &str) -> Option<SystemTime> {
+    let (date, time) = s.trim_end_matches('Z').split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (y, mo, d) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let (hms, frac) = time.split_once('.').unwrap_or((time, ""));
+    let mut hms = hms.splitn(3, ':').map(str::parse::<i64>);
+    let (h, mi, sec) = (hms.next()?.ok()?, hms.next()?.ok()?, hms.next()?.ok()?);
+    let secs = days_from_civil(y, mo, d) * 86_400 + h * 3600 + mi * 60 + sec;
+    Some(UNIX_EPOCH + Duration::new(u64::try_from(secs).ok()?, parse_nanos(frac)?))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Maps a backend path to an object key: `/a/b` and `a/b` become `a/b`.
+fn object_key(path: &str) -> String {
+    Path::new(path)
+        .components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(s) => Some(s.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The listing prefix for everything below `key`.
+fn dir_prefix(key: &str) -> String {
+    if key.is_empty() {
+        String::new()
+    } else {
+        format!("{key}/")
+    }
+}
+
+fn dir_meta() -> FileMeta {
+    FileMeta {
+        size: 0,
+        kind: FileKind::Dir,
+        modified: None,
+        accessed: None,
+        mode: None,
+        rdev: None,
+    }
+}
+
+fn file_meta(size: u64, modified: Option<SystemTime>) -> FileMeta {
+    FileMeta {
+        size,
+        kind: FileKind::File,
+        modified,
+        accessed: None,
+        mode: None,
+        rdev: None,
+    }
+}
+
+/// One page of ListObjectsV2 results, or all pages merged.
+#[derive(Default)]
+struct Listing {
+    objects: Vec<(String, FileMeta)>,
+    prefixes: Vec<String>,
+    next: Option<String>,
+}
+
+/// Objects in an S3-compatible bucket. Directories are implied by `/` in
+/// keys, so empty directories are not kept; mtimes are stored as object
+/// metadata when an object is written and read back with HEAD requests.
+pub struct S3Backend {
+    config: S3Config,
+    agent: ureq::Agent,
+    /// `scheme://authority` that requests are sent to.
+    base_url: String,
+    /// Value of the signed `Host` header.
+    host: String,
+    /// `/bucket` for path-style addressing, empty for virtual-hosted.
+    path_prefix: String,
+    threads: usize,
+}
+
+impl S3Backend {
+    /// Opens `bucket` with settings from [`S3Config::from_env`].
+    pub fn connect(bucket: &str, threads: usize) -> Result<Self, SyncError> {
+        Self::new(S3Config::from_env(bucket)?, threads)
+    }
+
+    /// Uses up to `threads` concurrent requests for listings and deletes.
+    pub fn new(config: S3Config, threads: usize) -> Result<Self, SyncError> {
+        let (base_url, host, path_prefix) = match &config.endpoint {
+            Some(endpoint) => {
+                let endpoint = endpoint.trim_end_matches('/');
+                let (_, authority) = endpoint.split_once("://").ok_or_else(|| {
+                    SyncError::Other(format!("S3 endpoint needs a scheme: {endpoint}"))
+                })?;
+                (
+                    endpoint.to_string(),
+                    authority.to_string(),
+                    format!("/{}", config.bucket),
+                )
+            }
+            // Dotted bucket names do not match the wildcard TLS certificate.
+            None if config.bucket.contains('.') => {
+                let host = format!("s3.{}.amazonaws.com", config.region);
+                (
+                    format!("https://{host}"),
+                    host,
+                    format!("/{}", config.bucket),
+                )
+            }
+            None => {
+                let host = format!("{}.s3.{}.amazonaws.com", config.bucket, config.region);
+                (format!("https://{host}"), host, String::new())
+            }
+        };
+        let threads = threads.max(1);
+        let agent = ureq::AgentBuilder::new()
+            .max_idle_connections_per_host(threads)
+            .timeout_connect(Duration::from_secs(30))
+            .build();
+        Ok(Self {
+            config,
+            agent,
+            base_url,
+            host,
+            path_prefix,
+            threads,
+        })
+    }
+
+    /// Computes the SigV4 `Authorization` header for a request whose
+    /// `headers` (lowercase names, including `host`, `x-amz-date` and
+    /// `x-amz-content-sha256`) are all signed.
+    fn authorization(
+        &self,
+        method: &str,
+        path: &str,
+        query: &str,
+        headers: &BTreeMap<String, String>,
+        timestamp: &str,
+    ) -> String {
+        let date = &timestamp[..8];
+        let canonical_headers: String = headers.iter().map(|(k, v)| format!("{k}:{v}\n")).collect();
+        let signed_headers = headers.keys().cloned().collect::<Vec<_>>().join(";");
+        let payload_hash = &headers["x-amz-content-sha256"];
+
+        let canonical_request = format!(
+            "{method}\n{path}\n{query}\n{canonical_headers}\n{signed_headers}\n{payload_hash}"
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let mut signing_key = format!("AWS4{}", self.config.secret_key).into_bytes();
+        for part in [date, self.config.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac(&signing_key, part);
+        }
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={}",
+            self.config.access_key,
+            hex::encode(hmac(&signing_key, &string_to_sign))
+        )
+    }
+
+    /// Signs and sends one request with AWS Signature Version 4.
+    fn send(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<ureq::Response, Box<ureq::Error>> {
+        let timestamp = amz_date(SystemTime::now());
+        let payload_hash = sha256_hex(body);
+        let path = utf8_percent_encode(&format!("{}/{key}", self.path_prefix), PATH_UNRESERVED)
+            .to_string();
+
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| {
+                (
+                    utf8_percent_encode(k, UNRESERVED).to_string(),
+                    utf8_percent_encode(v, UNRESERVED).to_string(),
+                )
+            })
+            .collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let mut signed: BTreeMap<String, String> = headers
+            .iter()
+            .map(|(k, v)| (k.to_ascii_lowercase(), v.trim().to_string()))
+            .collect();
+        signed.insert("host".into(), self.host.clone());
+        signed.insert("x-amz-date".into(), timestamp.clone());
+        signed.insert("x-amz-content-sha256".into(), payload_hash);
+        if let Some(token) = &self.config.session_token {
+            signed.insert("x-amz-security-token".into(), token.clone());
+        }
+        let authorization = self.authorization(method, &path, &query, &signed, &timestamp);
+
+        let url = if query.is_empty() {
+            format!("{}{path}", self.base_url)
+        } else {
+            format!("{}{path}?{query}", self.base_url)
+        };
+        let mut request = self
+            .agent
+            .request(method, &url)
+            .set("Authorization", &authorization);
+        for (name, value) in &signed {
+            request = request.set(name, value);
+        }
+        match method {
+            "PUT" | "POST" => request.send_bytes(body),
+            _ => request.call(),
+        }
+        .map_err(Box::new)
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<ureq::Response, SyncError> {
+        self.send(method, key, query, headers, body)
+            .map_err(|e| self.map_error(method, key, e))
+    }
+
+    fn map_error(&self, method: &str, key: &str, err: Box<ureq::Error>) -> SyncError {
+        match *err {
+            ureq::Error::Status(status, response) => {
+                let body = response.into_string().unwrap_or_default();
+                let error = parse_xml(&body).ok();
+                let code = error.as_ref().and_then(|e| e.text_of("Code"));
+                if status == 404 && code != Some("NoSuchBucket") {
+                    return SyncError::NotFound(key.to_string());
+                }
+                let message = error
+                    .as_ref()
+                    .and_then(|e| e.text_of("Message"))
+                    .unwrap_or("");
+                SyncError::Other(format!(
+                    "S3 {method} s3://{}/{key} failed with {status} {}: {message}",
+                    self.config.bucket,
+                    code.unwrap_or("")
+                ))
+            }
+            ureq::Error::Transport(t) => SyncError::Other(format!(
+                "S3 {method} s3://{}/{key}: {t}",
+                self.config.bucket
+            )),
+        }
+    }
+
+    /// Parses a 200 response body, which CopyObject and
+    /// CompleteMultipartUpload also use to report failures.
+    fn xml_response(
+        &self,
+        what: &str,
+        key: &str,
+        response: ureq::Response,
+    ) -> Result<XmlNode, SyncError> {
+        let node = parse_xml(&response.into_string()?)?;
+        if node.name == "Error" {
+            return Err(SyncError::Other(format!(
+                "S3 {what} s3://{}/{key} failed: {}: {}",
+                self.config.bucket,
+                node.text_of("Code").unwrap_or(""),
+                node.text_of("Message").unwrap_or("")
+            )));
+        }
+        Ok(node)
+    }
+
+    fn head(&self, key: &str) -> Result<FileMeta, SyncError> {
+        let response = self.request("HEAD", key, &[], &[], &[])?;
+        let size = response
+            .header("Content-Length")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        let modified = response
+            .header(MTIME_HEADER)
+            .and_then(parse_mtime)
+            .or_else(|| {
+                response
+                    .header("Last-Modified")
+                    .and_then(|v| httpdate::parse_http_date(v).ok())
+            });
+        Ok(file_meta(size, modified))
+    }
+
+    fn list_page(
+        &self,
+        prefix: &str,
+        delimiter: bool,
+        token: Option<&str>,
+    ) -> Result<Listing, SyncError> {
+        let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+        if delimiter {
+            query.push(("delimiter", "/"));
+        }
+        if let Some(token) = token {
+            query.push(("continuation-token", token));
+        }
+        let response = self.request("GET", "", &query, &[], &[])?;
+        let result = self.xml_response("LIST", prefix, response)?;
+        let mut page = Listing::default();
+        for object in result.children("Contents") {
+            let key = object.text_of("Key").unwrap_or("").to_string();
+            let size = object
+                .text_of("Size")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(0);
+            let modified = object.text_of("LastModified").and_then(parse_iso8601);
+            page.objects.push((key, file_meta(size, modified)));
+        }
+        for common in result.children("CommonPrefixes") {
+            page.prefixes
+                .push(common.text_of("Prefix").unwrap_or("").to_string());
+        }
+        if result.text_of("IsTruncated") == Some("true") {
+            page.next = result.text_of("NextContinuationToken").map(str::to_string);
+        }
+        Ok(page)
+    }
+
+    /// Lists every key under `prefix`, following continuation tokens. With
+    /// `delimiter`, keys below the next `/` are rolled up into prefixes.
+    fn list_all(&self, prefix: &str, delimiter: bool) -> Result<Listing, SyncError> {
+        let mut all = Listing::default();
+        let mut token = None;
+        loop {
+            let page = self.list_page(prefix, delimiter, token.as_deref())?;
+            all.objects.extend(page.objects);
+            all.prefixes.extend(page.prefixes);
+            match page.next {
+                Some(next) => token = Some(next),
+                None => return Ok(all),
+            }
+        }
+    }
+
+    /// Replaces listing timestamps with the stored mtimes, issuing HEAD
+    /// requests from up to `threads` workers, unless
+    /// [`S3Config::stored_mtimes`] is off.
+    fn fill_mtimes(&self, objects: &mut [(String, FileMeta)]) {
+        if objects.is_empty() || !self.config.stored_mtimes {
+            return;
+        }
+        let per_worker = objects.len().div_ceil(self.threads);
+        std::thread::scope(|s| {
+            for chunk in objects.chunks_mut(per_worker) {
+                s.spawn(move || {
+                    for (key, meta) in chunk {
+                        if let Ok(head) = self.head(key) {
+                            meta.modified = head.modified;
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    /// Runs `op` on every key from up to `threads` workers, returning the
+    /// first failure after all keys have been attempted.
+    fn for_each_key(
+        &self,
+        keys: &[String],
+        op: impl Fn(&str) -> Result<(), SyncError> + Sync,
+    ) -> Result<(), SyncError> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let per_worker = keys.len().div_ceil(self.threads);
+        let op = &op;
+        std::thread::scope(|s| {
+            let workers: Vec<_> = keys
+                .chunks(per_worker)
+                .map(|chunk| {
+                    s.spawn(move || {
+                        let mut first_err = None;
+                        for key in chunk {
+                            if let Err(e) = op(key) {
+                                first_err.get_or_insert(e);
+                            }
+                        }
+                        first_err
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .filter_map(|w| w.join().expect("S3 worker panicked"))
+                .next()
+                .map_or(Ok(()), Err)
+        })
+    }
+
+    fn mtime_headers(mtime: Option<SystemTime>) -> Vec<(&'static str, String)> {
+        mtime
+            .map(|t| (MTIME_HEADER, format_mtime(t)))
+            .into_iter()
+            .collect()
+    }
+
+    fn put_object(
+        &self,
+        key: &str,
+        data: &[u8],
+        mtime: Option<SystemTime>,
+    ) -> Result<(), SyncError> {
+        self.request("PUT", key, &[], &Self::mtime_headers(mtime), data)?;
+        Ok(())
+    }
+
+    fn create_multipart(&self, key: &str, mtime: Option<SystemTime>) -> Result<String, SyncError> {
+        let response = self.request(
+            "POST",
+            key,
+            &[("uploads", "")],
+            &Self::mtime_headers(mtime),
+            &[],
+        )?;
+        self.xml_response("CreateMultipartUpload", key, response)?
+            .text_of("UploadId")
+            .map(str::to_string)
+            .ok_or_else(|| SyncError::Other(format!("S3 gave no upload id for {key}")))
+    }
+
+    fn complete_multipart(
+        &self,
+        key: &str,
+        upload_id: &str,
+        etags: &[String],
+    ) -> Result<(), SyncError> {
+        let parts: String = etags
+            .iter()
+            .enumerate()
+            .map(|(i, etag)| {
+                format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{etag}</ETag></Part>",
+                    i + 1
+                )
+            })
+            .collect();
+        let body = format!("<CompleteMultipartUpload>{parts}</CompleteMultipartUpload>");
+        let response = self.request(
+            "POST",
+            key,
+            &[("uploadId", upload_id)],
+            &[],
+            body.as_bytes(),
+        )?;
+        self.xml_response("CompleteMultipartUpload", key, response)?;
+        Ok(())
+    }
+
+    /// Runs `upload` against a new multipart upload, completing it with the
+    /// returned part ETags or aborting it on failure.
+    fn multipart(
+        &self,
+        key: &str,
+        mtime: Option<SystemTime>,
+        upload: impl FnOnce(&str) -> Result<Vec<String>, SyncError>,
+    ) -> Result<(), SyncError> {
+        let upload_id = self.create_multipart(key, mtime)?;
+        let result =
+            upload(&upload_id).and_then(|etags| self.complete_multipart(key, &upload_id, &etags));
+        if result.is_err() {
+            let _ = self.request("DELETE", key, &[("uploadId", &upload_id)], &[], &[]);
+        }
+        result
+    }
+
+    fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        number: usize,
+        headers: &[(&str, String)],
+        data: &[u8],
+    ) -> Result<ureq::Response, SyncError> {
+        let number = number.to_string();
+        self.request(
+            "PUT",
+            key,
+            &[("partNumber", &number), ("uploadId", upload_id)],
+            headers,
+            data,
+        )
+    }
+
+    /// Uploads in one PUT when the data fits in a single part, otherwise as
+    /// a multipart upload holding one part in memory at a time.
+    fn upload(
+        &self,
+        key: &str,
+        reader: &mut dyn Read,
+        size: u64,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), SyncError> {
+        let part_size = MIN_PART_SIZE.max(size.div_ceil(MAX_PARTS));
+        let mut part = Vec::new();
+        reader.take(part_size).read_to_end(&mut part)?;
+        if (part.len() as u64) < part_size {
+            return self.put_object(key, &part, mtime);
+        }
+        self.multipart(key, mtime, |upload_id| {
+            let mut etags = Vec::new();
+            while !part.is_empty() {
+                let response = self.upload_part(key, upload_id, etags.len() + 1, &[], &part)?;
+                etags.push(response.header("ETag").unwrap_or("").to_string());
+                part.clear();
+                reader.take(part_size).read_to_end(&mut part)?;
+            }
+            Ok(etags)
+        })
+    }
+
+    /// Server-side copy of `from` to `to`, storing `mtime` as the new
+    /// object's metadata. Objects over 5 GiB are copied in parts.
+    fn copy_object(
+        &self,
+        from: &str,
+        to: &str,
+        size: u64,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), SyncError> {
+        let source =
+            utf8_percent_encode(&format!("/{}/{from}", self.config.bucket), PATH_UNRESERVED)
+                .to_string();
+        if size <= MAX_COPY_SIZE {
+            let mut headers = Self::mtime_headers(mtime);
+            headers.push(("x-amz-copy-source", source));
+            headers.push(("x-amz-metadata-directive", "REPLACE".into()));
+            let response = self.request("PUT", to, &[], &headers, &[])?;
+            self.xml_response("CopyObject", to, response)?;
+            return Ok(());
+        }
+        self.multipart(to, mtime, |upload_id| {
+            let mut etags = Vec::new();
+            for (i, start) in (0..size).step_by(MAX_COPY_SIZE as usize).enumerate() {
+                let end = (start + MAX_COPY_SIZE).min(size) - 1;
+                let headers = [
+                    ("x-amz-copy-source", source.clone()),
+                    ("x-amz-copy-source-range", format!("bytes={start}-{end}")),
+                ];
+                let response = self.upload_part(to, upload_id, i + 1, &headers, &[])?;
+                let result = self.xml_response("UploadPartCopy", to, response)?;
+                etags.push(result.text_of("ETag").unwrap_or("").to_string());
+            }
+            Ok(etags)
+        })
+    }
+}
+
+impl StorageBackend for S3Backend {
+    /// Lists the keys and prefixes directly below `path`.
+    fn list(&self, path: &str) -> Result<Vec<FileEntry>, SyncError> {
+        let prefix = dir_prefix(&object_key(path));
+        let mut listing = self.list_all(&prefix, true)?;
+        listing.objects.retain(|(key, _)| *key != prefix);
+        self.fill_mtimes(&mut listing.objects);
+
+        let name_of = |key: &str| key[prefix.len()..].trim_end_matches('/').to_string();
+        let dirs = listing.prefixes.iter().map(|p| (p.as_str(), dir_meta()));
+        let files = listing
+            .objects
+            .iter()
+            .map(|(key, meta)| (key.as_str(), meta.clone()));
+        Ok(dirs
+            .chain(files)
+            .map(|(key, metadata)| FileEntry {
+                path: Path::new(path)
+                    .join(name_of(key))
+                    .to_string_lossy()
+                    .to_string(),
+                metadata,
+            })
+            .collect())
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>, SyncError> {
+        let mut data = Vec::new();
+        self.open_read(path, 0, None)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), SyncError> {
+        self.put_object(&object_key(path), data, None)
+    }
+
+    fn put_stream(&self, path: &str, reader: &mut dyn Read, size: u64) -> Result<(), SyncError> {
+        self.upload(&object_key(path), reader, size, None)
+    }
+
+    /// Sends the mtime with the upload itself, so a new object needs no
+    /// second request to store it.
+    fn put_with_meta(
+        &self,
+        path: &str,
+        reader: &mut dyn Read,
+        meta: &FileMeta,
+    ) -> Result<(), SyncError> {
+        self.upload(&object_key(path), reader, meta.size, meta.modified)
+    }
+
+    /// Streams the object body, using a ranged GET for partial reads.
+    fn open_read(
+        &self,
+        path: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read + Send>, SyncError> {
+        let key = object_key(path);
+        let range = match len {
+            Some(0) => return Ok(Box::new(std::io::empty())),
+            Some(len) => Some(format!("bytes={offset}-{}", offset + len - 1)),
+            None if offset > 0 => Some(format!("bytes={offset}-")),
+            None => None,
+        };
+        let headers: Vec<_> = range.map(|r| ("range", r)).into_iter().collect();
+        match self.send("GET", &key, &[], &headers, &[]) {
+            Ok(response) => Ok(Box::new(response.into_reader())),
+            // Range starts past the end of the object.
+            Err(e) if matches!(*e, ureq::Error::Status(416, _)) => Ok(Box::new(std::io::empty())),
+            Err(e) => Err(self.map_error("GET", &key, e)),
+        }
+    }
+
+    /// Deletes the object at `path` and every object below it.
+    fn delete(&self, path: &str) -> Result<(), SyncError> {
+        let key = object_key(path);
+        let mut keys: Vec<String> = self
+            .list_all(&dir_prefix(&key), false)?
+            .objects
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        if !key.is_empty() {
+            keys.push(key);
+        }
+        self.for_each_key(&keys, |k| {
+            self.request("DELETE", k, &[], &[], &[]).map(|_| ())
+        })
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, SyncError> {
+        match self.stat(path) {
+            Ok(_) => Ok(true),
+            Err(SyncError::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// An object, or a directory if any key lives below `path`.
+    fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        let key = object_key(path);
+        if key.is_empty() {
+            return Ok(dir_meta());
+        }
+        match self.head(&key) {
+            Err(SyncError::NotFound(_)) => {}
+            other => return other,
+        }
+        let page = self.list_page(&dir_prefix(&key), true, None)?;
+        if page.objects.is_empty() && page.prefixes.is_empty() {
+            return Err(SyncError::NotFound(path.to_string()));
+        }
+        Ok(dir_meta())
+    }
+
+    /// Copies server-side and deletes the originals; directories move key
+    /// by key.
+    fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+        let (from_key, to_key) = (object_key(from), object_key(to));
+        let meta = self.stat(from)?;
+        if meta.is_dir() {
+            let prefix = dir_prefix(&from_key);
+            let mut listing = self.list_all(&prefix, false)?;
+            self.fill_mtimes(&mut listing.objects);
+            for (key, meta) in &listing.objects {
+                let dest = format!("{}{}", dir_prefix(&to_key), &key[prefix.len()..]);
+                self.copy_object(key, &dest, meta.size, meta.modified)?;
+            }
+        } else {
+            self.copy_object(&from_key, &to_key, meta.size, meta.modified)?;
+        }
+        self.delete(from)
+    }
+
+    /// Stores the mtime of an existing file by copying the object onto
+    /// itself with new metadata; new files get theirs from
+    /// `put_with_meta`. S3 has no permissions or directory times to set.
+    fn set_metadata(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        if !meta.is_file() || meta.modified.is_none() {
+            return Ok(());
+        }
+        let key = object_key(path);
+        self.copy_object(&key, &key, meta.size, meta.modified)
+    }
+
+    /// Lists the whole prefix in one paginated pass, then visits implied
+    /// directories before the objects inside them.
+    fn walk(&self, root: &str, visit: &mut dyn FnMut(FileEntry)) -> Result<(), SyncError> {
+        let key = object_key(root);
+        if !key.is_empty() {
+            match self.head(&key) {
+                Ok(metadata) => {
+                    visit(FileEntry {
+                        path: root.to_string(),
+                        metadata,
+                    });
+                    return Ok(());
+                }
+                Err(SyncError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let prefix = dir_prefix(&key);
+        let listing = self.list_all(&prefix, false)?;
+        if listing.objects.is_empty() && !key.is_empty() {
+            return Err(SyncError::NotFound(root.to_string()));
+        }
+        // Zero-byte `dir/` markers stand for (possibly empty) directories.
+        let (markers, mut files): (Vec<_>, Vec<_>) = listing
+            .objects
+            .into_iter()
+            .partition(|(k, _)| k.ends_with('/'));
+        self.fill_mtimes(&mut files);
+
+        let mut entries: BTreeMap<String, FileMeta> = BTreeMap::new();
+        let add_parents = |entries: &mut BTreeMap<String, FileMeta>, rel: &str| {
+            for (i, _) in rel.match_indices('/') {
+                entries.entry(rel[..i].to_string()).or_insert_with(dir_meta);
+            }
+        };
+        for (k, _) in markers {
+            add_parents(&mut entries, &k[prefix.len()..]);
+        }
+        for (k, meta) in files {
+            let rel = k[prefix.len()..].to_string();
+            add_parents(&mut entries, &rel);
+            entries.insert(rel, meta);
+        }
+
+        visit(FileEntry {
+            path: root.to_string(),
+            metadata: dir_meta(),
+        });
+        // A directory sorts before everything below it.
+        for (rel, metadata) in entries {
+            visit(FileEntry {
+                path: Path::new(root).join(rel).to_string_lossy().to_string(),
+                metadata,
+            });
+        }
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
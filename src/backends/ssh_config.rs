@@ -0,0 +1,255 @@
+//! OpenSSH client config files (`~/.ssh/config`, `/etc/ssh/ssh_config`):
+//! the directives parsync uses, from the `Host` blocks that match an alias.
+//! As in OpenSSH, the first value found for a directive wins, `Include`
+//! pulls in other files (globs allowed; relative paths are taken from the
+//! directory of the top-level file) and `Host` takes wildcard and negated
+//! patterns. `Match` blocks other than `Match all` are skipped.
+
+use super::known_hosts::{match_pattern_list, StrictHostKeyChecking};
+use super::SyncError;
+use std::path::{Path, PathBuf};
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// The directives that apply to one host alias.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostConfig {
+    pub host_name: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    /// Every `IdentityFile` that applies, in order, with `~` and `%` tokens
+    /// still unexpanded.
+    pub identity_files: Vec<String>,
+    pub identities_only: Option<bool>,
+    pub proxy_jump: Option<String>,
+    pub strict_host_key_checking: Option<StrictHostKeyChecking>,
+    pub user_known_hosts_file: Option<String>,
+    pub batch_mode: Option<bool>,
+}
+
+/// The user's and the system config file.
+pub fn default_files() -> Vec<PathBuf> {
+    let home = std::env::var("HOME").unwrap_or_default();
+    vec![
+        Path::new(&home).join(".ssh/config"),
+        PathBuf::from("/etc/ssh/ssh_config"),
+    ]
+}
+
+/// Splits a config line into words, honouring double quotes. The keyword
+/// may be separated from its first argument by `=`.
+fn words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut in_word = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            '=' if words.is_empty() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+fn parse_bool(keyword: &str, value: &str) -> Result<bool, SyncError> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" | "true" => Ok(true),
+        "no" | "false" => Ok(false),
+        _ => Err(SyncError::Other(format!(
+            "Invalid {keyword} value {value:?} in SSH config"
+        ))),
+    }
+}
+
+/// Expands a leading `~` to the home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => Path::new(&std::env::var("HOME").unwrap_or_default()).join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+impl HostConfig {
+    /// Reads the user's and the system config for `alias`. Missing files
+    /// are skipped.
+    pub fn for_host(alias: &str) -> Result<Self, SyncError> {
+        Self::load(alias, &default_files())
+    }
+
+    /// Reads `files` in order for `alias`.
+    pub fn load(alias: &str, files: &[PathBuf]) -> Result<Self, SyncError> {
+        let mut config = Self::default();
+        for file in files {
+            let base = file.parent().unwrap_or(Path::new("/"));
+            config.read_file(alias, file, base, 0)?;
+        }
+        Ok(config)
+    }
+
+    fn read_file(
+        &mut self,
+        alias: &str,
+        file: &Path,
+        base: &Path,
+        depth: usize,
+    ) -> Result<(), SyncError> {
+        let text = match std::fs::read_to_string(file) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(SyncError::Other(format!("Reading {}: {e}", file.display()))),
+        };
+        // Lines before the first Host or Match apply to every host.
+        let mut active = true;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words = words(line);
+            let Some((keyword, args)) = words.split_first() else {
+                continue;
+            };
+            let keyword = keyword.to_ascii_lowercase();
+            let at = || format!("{}:{}", file.display(), i + 1);
+            match keyword.as_str() {
+                "host" => {
+                    active = match_pattern_list(args.iter().map(String::as_str), alias);
+                    continue;
+                }
+                "match" => {
+                    active = args.len() == 1 && args[0].eq_ignore_ascii_case("all");
+                    continue;
+                }
+                _ if !active => continue,
+                "include" => {
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(SyncError::Other(format!(
+                            "SSH config includes nest too deeply at {}",
+                            at()
+                        )));
+                    }
+                    for pattern in args {
+                        let mut path = expand_home(pattern);
+                        if path.is_relative() {
+                            path = base.join(path);
+                        }
+                        let matches = glob::glob(&path.to_string_lossy())
+                            .map_err(|e| SyncError::Other(format!("Include at {}: {e}", at())))?;
+                        for included in matches.filter_map(Result::ok) {
+                            self.read_file(alias, &included, base, depth + 1)?;
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let Some(value) = args.first() else {
+                return Err(SyncError::Other(format!(
+                    "Missing value for {keyword} at {}",
+                    at()
+                )));
+            };
+            let value = value.clone();
+            match keyword.as_str() {
+                "hostname" => {
+                    self.host_name.get_or_insert(value);
+                }
+                "user" => {
+                    self.user.get_or_insert(value);
+                }
+                "port" if self.port.is_none() => {
+                    let port = value.parse().map_err(|_| {
+                        SyncError::Other(format!("Invalid Port {value:?} at {}", at()))
+                    })?;
+                    self.port = Some(port);
+                }
+                "identityfile" if !self.identity_files.contains(&value) => {
+                    self.identity_files.push(value);
+                }
+                "identitiesonly" if self.identities_only.is_none() => {
+                    self.identities_only = Some(parse_bool("IdentitiesOnly", &value)?);
+                }
+                "proxyjump" => {
+                    self.proxy_jump.get_or_insert(value);
+                }
+                "stricthostkeychecking" if self.strict_host_key_checking.is_none() => {
+                    let checking = match value.to_ascii_lowercase().as_str() {
+                        // parsync never prompts, so "ask" refuses unknown hosts.
+                        "ask" | "true" => StrictHostKeyChecking::Yes,
+                        "false" => StrictHostKeyChecking::No,
+                        other => other.parse()?,
+                    };
+                    self.strict_host_key_checking = Some(checking);
+                }
+                "batchmode" if self.batch_mode.is_none() => {
+                    self.batch_mode = Some(parse_bool("BatchMode", &value)?);
+                }
+                "userknownhostsfile" => {
+                    self.user_known_hosts_file.get_or_insert(value);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Expands OpenSSH `%` tokens: `%h` host name, `%n` the alias as given,
+/// `%p` port, `%r` remote user, `%u` local user, `%d` home directory and
+/// `%%`. Then expands a leading `~`.
+pub fn expand_tokens(
+    value: &str,
+    alias: &str,
+    host: &str,
+    port: u16,
+    user: &str,
+) -> Result<PathBuf, SyncError> {
+    let home = std::env::var("HOME").unwrap_or_default();
+    let local_user = std::env::var("USER").unwrap_or_default();
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => out.push_str(host),
+            Some('n') => out.push_str(alias),
+            Some('p') => out.push_str(&port.to_string()),
+            Some('r') => out.push_str(user),
+            Some('u') => out.push_str(&local_user),
+            Some('d') => out.push_str(&home),
+            Some('%') => out.push('%'),
+            other => {
+                return Err(SyncError::Other(format!(
+                    "Unsupported token %{} in SSH config value {value:?}",
+                    other.map(String::from).unwrap_or_default()
+                )))
+            }
+        }
+    }
+    Ok(expand_home(&out))
+}
@@ -0,0 +1,209 @@
+//! Jump hosts: `[user@]host[:port]` specs as in OpenSSH's `ProxyJump`, and
+//! the relay that carries a session through a `direct-tcpip` channel on the
+//! previous hop. The relay polls a Unix socket pair, so jump hosts are only
+//! supported on Unix.
+
+use super::SyncError;
+#[cfg(unix)]
+use ssh2::BlockDirections;
+use ssh2::{Channel, Session};
+#[cfg(unix)]
+use std::io::{ErrorKind, Read, Write};
+#[cfg(unix)]
+use std::os::fd::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::thread;
+
+/// Bytes buffered in each direction before the relay stops reading.
+#[cfg(unix)]
+const RELAY_BUFFER: usize = 256 << 10;
+/// Upper bound on one wait, in case libssh2 buffered data the socket no
+/// longer shows.
+#[cfg(unix)]
+const POLL_MS: i32 = 100;
+
+/// One hop of a `ProxyJump` list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JumpHost {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+/// Parses `[user@]host[:port],...`, also accepting `ssh://` prefixes and
+/// bracketed IPv6 hosts.
+pub fn parse_jumps(spec: &str) -> Result<Vec<JumpHost>, SyncError> {
+    let invalid = |hop: &str| SyncError::Other(format!("Invalid jump host {hop:?} in {spec:?}"));
+    spec.split(',')
+        .map(|hop| {
+            let rest = hop.trim();
+            let rest = rest.strip_prefix("ssh://").unwrap_or(rest);
+            let (user, rest) = match rest.rsplit_once('@') {
+                Some((user, rest)) => (Some(user.to_string()), rest),
+                None => (None, rest),
+            };
+            let (host, port) = match rest.strip_prefix('[') {
+                Some(bracketed) => {
+                    let (host, after) = bracketed.split_once(']').ok_or_else(|| invalid(hop))?;
+                    (host, after.strip_prefix(':'))
+                }
+                None => match rest.split_once(':') {
+                    Some((host, port)) => (host, Some(port)),
+                    None => (rest, None),
+                },
+            };
+            let port = match port {
+                Some(port) => Some(port.parse().map_err(|_| invalid(hop))?),
+                None => None,
+            };
+            if host.is_empty() || user.as_deref() == Some("") {
+                return Err(invalid(hop));
+            }
+            Ok(JumpHost {
+                user,
+                host: host.to_string(),
+                port,
+            })
+        })
+        .collect()
+}
+
+/// Relays `channel` to one end of a socket pair on its own thread and
+/// returns the other end, ready for [`Session::set_tcp_stream`]. The thread
+/// owns the jump session and ends when either side closes.
+#[cfg(unix)]
+pub(crate) fn tunnel(session: Session, channel: Channel) -> Result<UnixStream, SyncError> {
+    let (ours, theirs) =
+        UnixStream::pair().map_err(|e| SyncError::Other(format!("Jump socket pair: {e}")))?;
+    ours.set_nonblocking(true)
+        .map_err(|e| SyncError::Other(format!("Jump socket pair: {e}")))?;
+    thread::spawn(move || {
+        let mut channel = channel;
+        session.set_blocking(false);
+        if let Err(e) = relay(&session, &mut channel, &ours) {
+            log::debug!("Jump host relay ended: {e}");
+        }
+        // Best effort: let the bastion know, without waiting long on it.
+        session.set_blocking(true);
+        session.set_timeout(1000);
+        let _ = channel.close();
+    });
+    Ok(theirs)
+}
+
+/// Moves bytes both ways without blocking on either side: a blocking read
+/// on the channel would hold the session lock and stall writes.
+#[cfg(unix)]
+fn relay(session: &Session, channel: &mut Channel, local: &UnixStream) -> std::io::Result<()> {
+    let mut up = Vec::new();
+    let mut down = Vec::new();
+    let mut buf = vec![0u8; 32 << 10];
+    let mut local_open = true;
+    loop {
+        let mut progress = false;
+        if local_open && up.len() < RELAY_BUFFER {
+            match (&*local).read(&mut buf) {
+                Ok(0) => local_open = false,
+                Ok(n) => {
+                    up.extend_from_slice(&buf[..n]);
+                    progress = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !up.is_empty() {
+            match channel.write(&up) {
+                Ok(n) => {
+                    up.drain(..n);
+                    progress |= n > 0;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        // The session on top is gone; nothing it sent is left to deliver.
+        if !local_open && up.is_empty() {
+            return Ok(());
+        }
+        if down.len() < RELAY_BUFFER {
+            match channel.read(&mut buf) {
+                Ok(0) if channel.eof() && down.is_empty() => return Ok(()),
+                Ok(n) => {
+                    down.extend_from_slice(&buf[..n]);
+                    progress |= n > 0;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !down.is_empty() {
+            match (&*local).write(&down) {
+                Ok(n) => {
+                    down.drain(..n);
+                    progress |= n > 0;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !progress {
+            wait(
+                session,
+                local,
+                down.len() < RELAY_BUFFER,
+                local_open && up.len() < RELAY_BUFFER,
+                !down.is_empty(),
+            );
+        }
+    }
+}
+
+/// Sleeps until the jump session's socket or the local end may make
+/// progress, or `POLL_MS` passes. Sides whose buffer is full aren't
+/// waited on for reading, so a slow reader doesn't make this spin.
+#[cfg(unix)]
+fn wait(
+    session: &Session,
+    local: &UnixStream,
+    remote_read: bool,
+    local_read: bool,
+    local_write: bool,
+) {
+    let events = |read: bool, write: bool| {
+        (if read { libc::POLLIN } else { 0 }) | (if write { libc::POLLOUT } else { 0 })
+    };
+    let remote_events = match session.block_directions() {
+        BlockDirections::Inbound => events(true, false),
+        BlockDirections::Outbound => events(remote_read, true),
+        BlockDirections::Both => events(true, true),
+        BlockDirections::None => events(remote_read, false),
+    };
+    let local_events = events(local_read, local_write);
+    let mut fds = [
+        libc::pollfd {
+            fd: session.as_raw_fd(),
+            events: remote_events,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: local.as_raw_fd(),
+            events: local_events,
+            revents: 0,
+        },
+    ];
+    unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, POLL_MS) };
+}
+
+/// Jump hosts need the Unix socket pair relay.
+#[cfg(not(unix))]
+pub(crate) fn tunnel(
+    _session: Session,
+    _channel: Channel,
+) -> Result<std::net::TcpStream, SyncError> {
+    Err(SyncError::Other(
+        "ProxyJump is not supported on this platform".to_string(),
+    ))
+}
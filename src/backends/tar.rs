@@ -0,0 +1,524 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use tar::{Archive, Builder, EntryType, Header};
+
+use super::archive::{
+    dir_meta, entry_name, file_meta, key_of, normalize, write_exact, Entries, FileSlice,
+    PendingDirs, State,
+};
+use super::{FileEntry, FileKind, FileMeta, StorageBackend, SyncError};
+use crate::utils::{format_mtime, parse_mtime};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+enum Location {
+    File(PathBuf),
+    /// `-`: read from stdin, write to stdout.
+    Stdio,
+}
+
+/// Where an entry's data starts in the uncompressed archive, and a
+/// symlink's target.
+#[derive(Default)]
+struct Data {
+    offset: u64,
+    link: Option<String>,
+}
+
+struct Index {
+    file: Arc<File>,
+    entries: Entries<Data>,
+}
+
+enum Sink {
+    Plain(Box<dyn Write + Send>),
+    Gzip(GzEncoder<Box<dyn Write + Send>>),
+    Zstd(zstd::Encoder<'static, Box<dyn Write + Send>>),
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(w) => w.write(buf),
+            Sink::Gzip(w) => w.write(buf),
+            Sink::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(w) => w.flush(),
+            Sink::Gzip(w) => w.flush(),
+            Sink::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+impl Sink {
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Sink::Plain(mut w) => w.flush(),
+            Sink::Gzip(w) => w.finish()?.flush(),
+            Sink::Zstd(w) => w.finish()?.flush(),
+        }
+    }
+}
+
+struct Writer {
+    builder: Builder<Sink>,
+    dirs: PendingDirs,
+}
+
+/// A tar archive, optionally gzip or zstd compressed, used either as a copy
+/// source or as a destination, never both. Reads index the whole archive
+/// first; writes truncate it and append entries in the order workers finish
+/// them. Call [`StorageBackend::finish`] after the last write to add the
+/// end-of-archive marker.
+pub struct TarBackend {
+    location: Location,
+    compression: Compression,
+    state: Mutex<State<Index, Writer>>,
+}
+
+fn makedev(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (major as u64, minor as u64);
+    ((major & 0xfffff000) << 32)
+        | ((major & 0xfff) << 8)
+        | ((minor & 0xffffff00) << 12)
+        | (minor & 0xff)
+}
+
+fn dev_major(rdev: u64) -> u32 {
+    (((rdev >> 32) & 0xfffff000) | ((rdev >> 8) & 0xfff)) as u32
+}
+
+fn dev_minor(rdev: u64) -> u32 {
+    (((rdev >> 12) & 0xffffff00) | (rdev & 0xff)) as u32
+}
+
+impl TarBackend {
+    /// Opens the archive at `path`, or stdin/stdout for `-`. A `.gz`/`.tgz`
+    /// or `.zst`/`.tzst` extension compresses what is written; compression
+    /// of archives being read is detected from their contents.
+    pub fn new(path: &str) -> Self {
+        let compression = if path.ends_with(".gz") || path.ends_with(".tgz") {
+            Compression::Gzip
+        } else if path.ends_with(".zst") || path.ends_with(".tzst") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        };
+        let location = if path == "-" {
+            Location::Stdio
+        } else {
+            Location::File(PathBuf::from(path))
+        };
+        Self {
+            location,
+            compression,
+            state: Mutex::new(State::Closed),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.location {
+            Location::File(path) => path.display().to_string(),
+            Location::Stdio => "-".to_string(),
+        }
+    }
+
+    /// Returns the archive as an uncompressed, seekable file, spooling
+    /// stdin and compressed input to a temporary file first.
+    fn open_uncompressed(&self) -> Result<File, SyncError> {
+        let mut raw = match &self.location {
+            Location::File(path) => File::open(path).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => SyncError::NotFound(path.display().to_string()),
+                _ => SyncError::Io(e),
+            })?,
+            Location::Stdio => {
+                let mut spool = tempfile::tempfile()?;
+                io::copy(&mut io::stdin().lock(), &mut spool)?;
+                spool.rewind()?;
+                spool
+            }
+        };
+        let mut magic = [0u8; 4];
+        let n = raw.read(&mut magic)?;
+        raw.rewind()?;
+        let mut decoder: Box<dyn Read> = if magic[..n].starts_with(GZIP_MAGIC) {
+            Box::new(GzDecoder::new(raw))
+        } else if magic[..n].starts_with(ZSTD_MAGIC) {
+            Box::new(zstd::Decoder::new(raw)?)
+        } else {
+            return Ok(raw);
+        };
+        let mut spool = tempfile::tempfile()?;
+        io::copy(&mut decoder, &mut spool)?;
+        spool.rewind()?;
+        Ok(spool)
+    }
+
+    fn build_index(&self) -> Result<Index, SyncError> {
+        let file = self.open_uncompressed()?;
+        let mut entries = Entries::<Data>::new();
+        let mut archive = Archive::new(&file);
+        for entry in archive.entries_with_seek()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let Some(key) = key_of(&path) else {
+                log::warn!(
+                    "Skipping tar entry outside the archive root: {}",
+                    path.display()
+                );
+                continue;
+            };
+            let header = entry.header();
+            let entry_type = header.entry_type();
+            let kind = match entry_type {
+                EntryType::Regular | EntryType::Continuous | EntryType::Link => FileKind::File,
+                EntryType::Directory => FileKind::Dir,
+                EntryType::Symlink => FileKind::Symlink,
+                EntryType::Char => FileKind::CharDevice,
+                EntryType::Block => FileKind::BlockDevice,
+                EntryType::Fifo => FileKind::Fifo,
+                other => {
+                    log::warn!(
+                        "Skipping unsupported tar entry {} ({other:?})",
+                        path.display()
+                    );
+                    continue;
+                }
+            };
+            let mode = header.mode().ok().map(|m| m & 0o7777);
+            let rdev = match (header.device_major(), header.device_minor()) {
+                (Ok(Some(major)), Ok(Some(minor))) if major != 0 || minor != 0 => {
+                    Some(makedev(major, minor))
+                }
+                _ => None,
+            };
+            let mut modified = header
+                .mtime()
+                .ok()
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+            let link = entry
+                .link_name()?
+                .map(|target| target.to_string_lossy().to_string());
+            let size = entry.size();
+            let offset = entry.raw_file_position();
+            let mut accessed = None;
+            if let Some(extensions) = entry.pax_extensions()? {
+                for extension in extensions.flatten() {
+                    match (extension.key(), extension.value()) {
+                        (Ok("mtime"), Ok(value)) => modified = parse_mtime(value).or(modified),
+                        (Ok("atime"), Ok(value)) => accessed = parse_mtime(value),
+                        _ => {}
+                    }
+                }
+            }
+
+            if entry_type == EntryType::Link {
+                // Hard links share the data of an earlier entry.
+                let target = link.as_deref().and_then(|t| key_of(Path::new(t)));
+                match target.and_then(|t| entries.get(&t)) {
+                    Some((meta, data)) if meta.is_file() => {
+                        let (meta, offset) = (meta.clone(), data.offset);
+                        entries.insert(key, meta, Data { offset, link: None });
+                    }
+                    _ => log::warn!(
+                        "Skipping hard link with no earlier target: {}",
+                        path.display()
+                    ),
+                }
+                continue;
+            }
+            let meta = FileMeta {
+                size: if kind == FileKind::File { size } else { 0 },
+                kind,
+                modified,
+                accessed,
+                mode,
+                rdev,
+            };
+            entries.insert(key, meta, Data { offset, link });
+        }
+        Ok(Index {
+            file: Arc::new(file),
+            entries,
+        })
+    }
+
+    /// Returns the index, reading the archive on first use.
+    fn index(&self) -> Result<Arc<Index>, SyncError> {
+        self.state.lock().unwrap().reading(|| self.build_index())
+    }
+
+    fn lookup(&self, path: &str) -> Result<FileMeta, SyncError> {
+        {
+            let state = self.state.lock().unwrap();
+            match &*state {
+                State::Writing(writer) => return writer.dirs.stat(path),
+                // Never consume stdin just to answer whether a destination
+                // file already exists.
+                State::Closed if matches!(self.location, Location::Stdio) => {
+                    return PendingDirs::default().stat(path);
+                }
+                _ => {}
+            }
+        }
+        self.index()?.entries.stat(&normalize(path))
+    }
+
+    fn create_sink(&self) -> Result<Sink, SyncError> {
+        let out: Box<dyn Write + Send> = match &self.location {
+            Location::File(path) => Box::new(io::BufWriter::new(File::create(path)?)),
+            Location::Stdio => Box::new(io::BufWriter::new(io::stdout())),
+        };
+        Ok(match self.compression {
+            Compression::None => Sink::Plain(out),
+            Compression::Gzip => Sink::Gzip(GzEncoder::new(out, flate2::Compression::default())),
+            Compression::Zstd => Sink::Zstd(zstd::Encoder::new(out, 0)?),
+        })
+    }
+
+    /// Runs `write` against the archive, creating it on first use.
+    fn with_writer<T>(
+        &self,
+        write: impl FnOnce(&mut Writer) -> Result<T, SyncError>,
+    ) -> Result<T, SyncError> {
+        let mut state = self.state.lock().unwrap();
+        let writer = state.writing(|| {
+            let mut builder = Builder::new(self.create_sink()?);
+            builder.follow_symlinks(false);
+            Ok(Writer {
+                builder,
+                dirs: PendingDirs::default(),
+            })
+        })?;
+        write(writer)
+    }
+}
+
+/// Builds a header for `meta` and, when the mtime has sub-second precision
+/// or an atime is kept, writes the PAX record that carries it.
+fn header_for(
+    builder: &mut Builder<Sink>,
+    entry_type: EntryType,
+    meta: &FileMeta,
+) -> Result<Header, SyncError> {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_size(if entry_type == EntryType::Regular {
+        meta.size
+    } else {
+        0
+    });
+    let default_mode = if entry_type == EntryType::Directory {
+        0o755
+    } else {
+        0o644
+    };
+    header.set_mode(meta.mode.unwrap_or(default_mode));
+    let modified = meta.modified.unwrap_or_else(SystemTime::now);
+    let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    header.set_mtime(since_epoch.as_secs());
+
+    let mut pax = Vec::new();
+    if since_epoch.subsec_nanos() != 0 {
+        pax.push(("mtime", format_mtime(modified)));
+    }
+    if let Some(accessed) = meta.accessed {
+        pax.push(("atime", format_mtime(accessed)));
+    }
+    if !pax.is_empty() {
+        builder.append_pax_extensions(pax.iter().map(|(k, v)| (*k, v.as_bytes())))?;
+    }
+    Ok(header)
+}
+
+impl StorageBackend for TarBackend {
+    fn list(&self, path: &str) -> Result<Vec<FileEntry>, SyncError> {
+        self.index()?.entries.list(&normalize(path))
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>, SyncError> {
+        let mut data = Vec::new();
+        self.open_read(path, 0, None)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), SyncError> {
+        self.put_stream(path, &mut &data[..], data.len() as u64)
+    }
+
+    fn put_stream(&self, path: &str, reader: &mut dyn Read, size: u64) -> Result<(), SyncError> {
+        self.put_with_meta(path, reader, &file_meta(size))
+    }
+
+    fn put_with_meta(
+        &self,
+        path: &str,
+        reader: &mut dyn Read,
+        meta: &FileMeta,
+    ) -> Result<(), SyncError> {
+        let name = entry_name(path)?;
+        write_exact(path, reader, meta.size, |data| {
+            self.with_writer(|writer| {
+                let mut header = header_for(&mut writer.builder, EntryType::Regular, meta)?;
+                writer.builder.append_data(&mut header, &name, data)?;
+                Ok(())
+            })
+        })
+    }
+
+    fn open_read(
+        &self,
+        path: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read + Send>, SyncError> {
+        let key = normalize(path);
+        let index = self.index()?;
+        let (start, size) = match index.entries.get(&key) {
+            Some((meta, data)) if meta.is_file() => (data.offset, meta.size),
+            Some(_) => return Err(SyncError::Other(format!("Not a file: {key}"))),
+            None => return Err(SyncError::NotFound(key)),
+        };
+        let from = offset.min(size);
+        let to = len.map_or(size, |len| from.saturating_add(len).min(size));
+        Ok(Box::new(FileSlice::new(
+            index.file.clone(),
+            start + from,
+            to - from,
+        )))
+    }
+
+    fn delete(&self, path: &str) -> Result<(), SyncError> {
+        Err(SyncError::Other(format!(
+            "Cannot delete {path}: tar archives do not support deletion"
+        )))
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, SyncError> {
+        match self.lookup(path) {
+            Ok(_) => Ok(true),
+            Err(SyncError::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        self.lookup(path)
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), SyncError> {
+        self.with_writer(|writer| {
+            writer.dirs.add(path);
+            Ok(())
+        })
+    }
+
+    fn set_metadata(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        self.with_writer(|writer| {
+            writer.dirs.set_metadata(path, meta);
+            Ok(())
+        })
+    }
+
+    fn walk(&self, root: &str, visit: &mut dyn FnMut(FileEntry)) -> Result<(), SyncError> {
+        self.index()?.entries.walk(root, visit)
+    }
+
+    fn symlink(&self, target: &str, link: &str) -> Result<(), SyncError> {
+        let name = entry_name(link)?;
+        let meta = FileMeta {
+            kind: FileKind::Symlink,
+            mode: Some(0o777),
+            ..dir_meta()
+        };
+        self.with_writer(|writer| {
+            let mut header = header_for(&mut writer.builder, EntryType::Symlink, &meta)?;
+            writer.builder.append_link(&mut header, &name, target)?;
+            Ok(())
+        })
+    }
+
+    fn read_link(&self, path: &str) -> Result<String, SyncError> {
+        let key = normalize(path);
+        match self.index()?.entries.get(&key) {
+            Some((
+                meta,
+                Data {
+                    link: Some(target), ..
+                },
+            )) if meta.kind == FileKind::Symlink => Ok(target.clone()),
+            Some(_) => Err(SyncError::Other(format!("Not a symlink: {key}"))),
+            None => Err(SyncError::NotFound(key)),
+        }
+    }
+
+    fn mknod(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        let name = entry_name(path)?;
+        let entry_type = match meta.kind {
+            FileKind::Fifo => EntryType::Fifo,
+            FileKind::CharDevice => EntryType::Char,
+            FileKind::BlockDevice => EntryType::Block,
+            kind => {
+                return Err(SyncError::Other(format!(
+                    "Cannot archive {path}: tar has no entry type for {kind:?}"
+                )))
+            }
+        };
+        self.with_writer(|writer| {
+            let mut header = header_for(&mut writer.builder, entry_type, meta)?;
+            let rdev = meta.rdev.unwrap_or(0);
+            header.set_device_major(dev_major(rdev))?;
+            header.set_device_minor(dev_minor(rdev))?;
+            writer
+                .builder
+                .append_data(&mut header, &name, io::empty())?;
+            Ok(())
+        })
+    }
+
+    fn finish(&self) -> Result<(), SyncError> {
+        let Some(Writer { mut builder, dirs }) = self.state.lock().unwrap().take_writer() else {
+            return Ok(());
+        };
+        for (name, meta) in dirs.into_entries() {
+            let mut header = header_for(&mut builder, EntryType::Directory, &meta)?;
+            builder.append_data(&mut header, format!("{name}/"), io::empty())?;
+        }
+        builder.into_inner()?.finish()?;
+        Ok(())
+    }
+
+    fn time_precision(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+impl Drop for TarBackend {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::warn!("Failed to finish tar archive {}: {e:?}", self.describe());
+        }
+    }
+}
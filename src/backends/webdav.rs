@@ -0,0 +1,489 @@
+use base64::Engine;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::pool::{Pool, PoolGuard};
+use super::xml::{parse_xml, XmlNode};
+use super::{FileEntry, FileKind, FileMeta, StorageBackend, SyncError};
+use crate::utils::{format_mtime, parse_mtime};
+
+/// Namespace of the dead property that holds the source mtime.
+const PARSYNC_NS: &str = "https://github.com/Saphereye/parsync";
+
+const PROPFIND_BODY: &str = concat!(
+    r#"<?xml version="1.0" encoding="utf-8"?>"#,
+    r#"<d:propfind xmlns:d="DAV:" xmlns:p="https://github.com/Saphereye/parsync"><d:prop>"#,
+    "<d:resourcetype/><d:getcontentlength/><d:getlastmodified/><p:mtime/>",
+    "</d:prop></d:propfind>"
+);
+
+/// Characters escaped in request paths.
+const PATH_ESCAPE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// A GET response body being streamed. Holds its pooled agent until dropped.
+struct DavReader {
+    inner: Box<dyn Read + Send>,
+    _guard: PoolGuard<ureq::Agent>,
+}
+
+impl Read for DavReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// Yields exactly `remaining` bytes, failing if `inner` ends early, so a
+/// PUT body always matches its `Content-Length`.
+struct ExactReader<'a> {
+    inner: &'a mut dyn Read,
+    remaining: u64,
+}
+
+impl Read for ExactReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let max = buf.len().min(self.remaining as usize);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "source ended before its reported size",
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Files on a WebDAV server (Nextcloud, Apache mod_dav, ...). Requests go
+/// through a pool of HTTP agents, one keep-alive connection each, so every
+/// worker thread gets its own connection.
+pub struct WebDavBackend {
+    pool: Arc<Pool<ureq::Agent>>,
+    /// `http(s)://host[:port]`; backend paths are appended to it.
+    base_url: String,
+    /// `Authorization` header value for Basic auth.
+    auth: Option<String>,
+    /// Collections known to exist, so `mkdir` skips repeated MKCOLs.
+    made_dirs: Mutex<HashSet<String>>,
+}
+
+/// Makes `path` absolute and drops `.`, empty and trailing components.
+fn normalize(path: &str) -> String {
+    Path::new("/")
+        .join(path)
+        .components()
+        .collect::<PathBuf>()
+        .to_string_lossy()
+        .to_string()
+}
+
+fn decode(s: &str) -> String {
+    percent_decode_str(s).decode_utf8_lossy().to_string()
+}
+
+/// Reduces an `href`, which may be a full URL, to a decoded path without a
+/// trailing slash.
+fn href_path(href: &str) -> String {
+    let path = match href.find("://") {
+        Some(i) => href[i + 3..].find('/').map_or("/", |j| &href[i + 3 + j..]),
+        None => href,
+    };
+    normalize(&decode(path))
+}
+
+/// Reads the `200 OK` properties of one multistatus `response`.
+fn response_meta(response: &XmlNode) -> FileMeta {
+    let mut meta = FileMeta {
+        size: 0,
+        kind: FileKind::File,
+        modified: None,
+        accessed: None,
+        mode: None,
+        rdev: None,
+    };
+    let mut stored_mtime = None;
+    for propstat in response.children("propstat") {
+        if !propstat
+            .text_of("status")
+            .is_some_and(|s| s.contains(" 200"))
+        {
+            continue;
+        }
+        let Some(prop) = propstat.child("prop") else {
+            continue;
+        };
+        if prop
+            .child("resourcetype")
+            .is_some_and(|r| r.child("collection").is_some())
+        {
+            meta.kind = FileKind::Dir;
+        }
+        if let Some(len) = prop.text_of("getcontentlength") {
+            meta.size = len.trim().parse().unwrap_or(0);
+        }
+        if let Some(date) = prop.text_of("getlastmodified") {
+            meta.modified = httpdate::parse_http_date(date.trim()).ok();
+        }
+        stored_mtime = stored_mtime.or(prop.text_of("mtime").and_then(|m| parse_mtime(m.trim())));
+    }
+    meta.modified = stored_mtime.or(meta.modified);
+    if meta.is_dir() {
+        meta.size = 0;
+    }
+    meta
+}
+
+impl WebDavBackend {
+    /// Connects to `[user[:password]@]host[:port]`, over https when `tls`
+    /// is set. A user without a password in the authority takes it from
+    /// `PARSYNC_WEBDAV_PASSWORD`.
+    pub fn connect(authority: &str, tls: bool, pool_size: usize) -> Result<Self, SyncError> {
+        let (userinfo, host) = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => (Some(userinfo), host),
+            None => (None, authority),
+        };
+        if host.is_empty() {
+            return Err(SyncError::Other(format!(
+                "WebDAV URI missing host: {authority}"
+            )));
+        }
+        let auth = userinfo.map(|info| {
+            let (user, password) = match info.split_once(':') {
+                Some((user, password)) => (decode(user), decode(password)),
+                None => (
+                    decode(info),
+                    std::env::var("PARSYNC_WEBDAV_PASSWORD").unwrap_or_default(),
+                ),
+            };
+            let token =
+                base64::engine::general_purpose::STANDARD.encode(format!("{user}:{password}"));
+            format!("Basic {token}")
+        });
+        let agents = (0..pool_size.max(1))
+            .map(|_| {
+                ureq::AgentBuilder::new()
+                    .max_idle_connections(1)
+                    .redirects(0)
+                    .timeout_connect(Duration::from_secs(30))
+                    .build()
+            })
+            .collect();
+        Ok(Self {
+            pool: Arc::new(Pool::new(agents)),
+            base_url: format!("{}://{host}", if tls { "https" } else { "http" }),
+            auth,
+            made_dirs: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// The URL of `path`; collections get a trailing slash.
+    fn url(&self, path: &str, collection: bool) -> String {
+        let mut path = normalize(path);
+        if collection && !path.ends_with('/') {
+            path.push('/');
+        }
+        format!(
+            "{}{}",
+            self.base_url,
+            utf8_percent_encode(&path, PATH_ESCAPE)
+        )
+    }
+
+    fn request(&self, agent: &ureq::Agent, method: &str, url: &str) -> ureq::Request {
+        let request = agent.request(method, url);
+        match &self.auth {
+            Some(auth) => request.set("Authorization", auth),
+            None => request,
+        }
+    }
+
+    fn send(
+        &self,
+        agent: &ureq::Agent,
+        method: &str,
+        url: &str,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<ureq::Response, Box<ureq::Error>> {
+        let mut request = self.request(agent, method, url);
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        if method == "PUT" || !body.is_empty() {
+            request.send_bytes(body)
+        } else {
+            request.call()
+        }
+        .map_err(Box::new)
+    }
+
+    /// Sends `method` to `path`, retrying at the collection URL when the
+    /// server redirects there (Apache does for directories without a
+    /// trailing slash).
+    fn call_path(
+        &self,
+        agent: &ureq::Agent,
+        method: &str,
+        path: &str,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<ureq::Response, SyncError> {
+        // With redirects disabled, ureq hands 3xx back as a response.
+        let redirected = |r: &Result<ureq::Response, Box<ureq::Error>>| matches!(r, Ok(response) if (300..400).contains(&response.status()));
+        let mut result = self.send(agent, method, &self.url(path, false), headers, body);
+        if redirected(&result) {
+            result = self.send(agent, method, &self.url(path, true), headers, body);
+        }
+        match result {
+            Ok(response) if (300..400).contains(&response.status()) => {
+                Err(SyncError::Other(format!(
+                    "WebDAV {method} {path}: unexpected redirect to {}",
+                    response.header("Location").unwrap_or("?")
+                )))
+            }
+            Ok(response) => Ok(response),
+            Err(e) => Err(self.map_error(method, path, e)),
+        }
+    }
+
+    fn map_error(&self, method: &str, path: &str, err: Box<ureq::Error>) -> SyncError {
+        match *err {
+            ureq::Error::Status(404, _) => SyncError::NotFound(path.to_string()),
+            ureq::Error::Status(status, response) => SyncError::Other(format!(
+                "WebDAV {method} {path} failed with {status} {}",
+                response.status_text()
+            )),
+            ureq::Error::Transport(t) => SyncError::Other(format!("WebDAV {method} {path}: {t}")),
+        }
+    }
+
+    /// Runs PROPFIND on `path` and returns `(path, metadata)` per response,
+    /// addressing it as a collection when `collection` is set.
+    fn propfind(
+        &self,
+        path: &str,
+        depth: &str,
+        collection: bool,
+    ) -> Result<Vec<(String, FileMeta)>, SyncError> {
+        let agent = self.pool.checkout()?;
+        let headers = [
+            ("Depth", depth.to_string()),
+            ("Content-Type", "application/xml".to_string()),
+        ];
+        let body = PROPFIND_BODY.as_bytes();
+        let response = if collection {
+            self.send(&agent, "PROPFIND", &self.url(path, true), &headers, body)
+                .map_err(|e| self.map_error("PROPFIND", path, e))?
+        } else {
+            self.call_path(&agent, "PROPFIND", path, &headers, body)?
+        };
+        let multistatus = parse_xml(&response.into_string()?)?;
+        Ok(multistatus
+            .children("response")
+            .map(|r| {
+                (
+                    href_path(r.text_of("href").unwrap_or("/")),
+                    response_meta(r),
+                )
+            })
+            .collect())
+    }
+
+    fn forget_dirs(&self) {
+        self.made_dirs.lock().unwrap().clear();
+    }
+}
+
+impl StorageBackend for WebDavBackend {
+    fn list(&self, path: &str) -> Result<Vec<FileEntry>, SyncError> {
+        let own = normalize(path);
+        Ok(self
+            .propfind(path, "1", true)?
+            .into_iter()
+            .filter(|(href, _)| *href != own)
+            .filter_map(|(href, metadata)| {
+                let name = Path::new(&href).file_name()?.to_owned();
+                Some(FileEntry {
+                    path: Path::new(path).join(name).to_string_lossy().to_string(),
+                    metadata,
+                })
+            })
+            .collect())
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>, SyncError> {
+        let mut data = Vec::new();
+        self.open_read(path, 0, None)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), SyncError> {
+        let agent = self.pool.checkout()?;
+        self.send(&agent, "PUT", &self.url(path, false), &[], data)
+            .map_err(|e| self.map_error("PUT", path, e))?;
+        Ok(())
+    }
+
+    /// Streams the body with a fixed `Content-Length` of `size`.
+    fn put_stream(&self, path: &str, reader: &mut dyn Read, size: u64) -> Result<(), SyncError> {
+        let agent = self.pool.checkout()?;
+        self.request(&agent, "PUT", &self.url(path, false))
+            .set("Content-Length", &size.to_string())
+            .send(ExactReader {
+                inner: reader,
+                remaining: size,
+            })
+            .map_err(|e| self.map_error("PUT", path, Box::new(e)))?;
+        Ok(())
+    }
+
+    /// Uses a ranged GET; servers that ignore the range are skipped forward
+    /// on the client.
+    fn open_read(
+        &self,
+        path: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read + Send>, SyncError> {
+        let range = match len {
+            Some(0) => return Ok(Box::new(std::io::empty())),
+            Some(len) => Some(format!("bytes={offset}-{}", offset + len - 1)),
+            None if offset > 0 => Some(format!("bytes={offset}-")),
+            None => None,
+        };
+        let headers: Vec<_> = range.map(|r| ("Range", r)).into_iter().collect();
+        let agent = self.pool.checkout()?;
+        let response = match self.send(&agent, "GET", &self.url(path, false), &headers, &[]) {
+            Ok(response) => response,
+            Err(e) if matches!(*e, ureq::Error::Status(416, _)) => {
+                return Ok(Box::new(std::io::empty()))
+            }
+            Err(e) => return Err(self.map_error("GET", path, e)),
+        };
+        let partial = response.status() == 206;
+        let mut inner: Box<dyn Read + Send> = Box::new(response.into_reader());
+        if !partial && offset > 0 {
+            std::io::copy(&mut (&mut inner).take(offset), &mut std::io::sink())?;
+        }
+        if let Some(len) = len {
+            inner = Box::new(inner.take(len));
+        }
+        Ok(Box::new(DavReader {
+            inner,
+            _guard: agent,
+        }))
+    }
+
+    /// Deletes a file or a whole collection; a missing path is not an error.
+    fn delete(&self, path: &str) -> Result<(), SyncError> {
+        let agent = self.pool.checkout()?;
+        let result = self.call_path(&agent, "DELETE", path, &[], &[]);
+        self.forget_dirs();
+        match result {
+            Ok(_) | Err(SyncError::NotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, SyncError> {
+        match self.stat(path) {
+            Ok(_) => Ok(true),
+            Err(SyncError::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        self.propfind(path, "0", false)?
+            .into_iter()
+            .next()
+            .map(|(_, meta)| meta)
+            .ok_or_else(|| SyncError::NotFound(path.to_string()))
+    }
+
+    /// Creates `path` with MKCOL, creating missing parents first.
+    fn mkdir(&self, path: &str) -> Result<(), SyncError> {
+        let path = normalize(path);
+        if path == "/" || self.made_dirs.lock().unwrap().contains(&path) {
+            return Ok(());
+        }
+        let mkcol = || {
+            let agent = self.pool.checkout()?;
+            Ok::<_, SyncError>(self.send(&agent, "MKCOL", &self.url(&path, true), &[], &[]))
+        };
+        match mkcol()? {
+            // 405: something already exists there.
+            Ok(_) => {}
+            Err(e) if matches!(*e, ureq::Error::Status(405, _)) => {}
+            // 409: a parent is missing.
+            Err(e) if matches!(*e, ureq::Error::Status(409, _)) => {
+                if let Some(parent) = Path::new(&path).parent() {
+                    self.mkdir(&parent.to_string_lossy())?;
+                }
+                match mkcol()? {
+                    Ok(_) => {}
+                    Err(e) if matches!(*e, ureq::Error::Status(405, _)) => {}
+                    Err(e) => return Err(self.map_error("MKCOL", &path, e)),
+                }
+            }
+            Err(e) => return Err(self.map_error("MKCOL", &path, e)),
+        }
+        self.made_dirs.lock().unwrap().insert(path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), SyncError> {
+        let agent = self.pool.checkout()?;
+        let headers = [
+            ("Destination", self.url(to, false)),
+            ("Overwrite", "T".to_string()),
+        ];
+        self.call_path(&agent, "MOVE", from, &headers, &[])?;
+        self.forget_dirs();
+        Ok(())
+    }
+
+    /// Stores the mtime as a dead property, since `getlastmodified` is
+    /// read-only on most servers. There are no permissions to set.
+    fn set_metadata(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        let Some(modified) = meta.modified else {
+            return Ok(());
+        };
+        let body = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?><d:propertyupdate xmlns:d="DAV:" xmlns:p="{PARSYNC_NS}"><d:set><d:prop><p:mtime>{}</p:mtime></d:prop></d:set></d:propertyupdate>"#,
+            format_mtime(modified)
+        );
+        let agent = self.pool.checkout()?;
+        let headers = [("Content-Type", "application/xml".to_string())];
+        let response = self.call_path(&agent, "PROPPATCH", path, &headers, body.as_bytes())?;
+        let multistatus = parse_xml(&response.into_string()?)?;
+        let rejected = multistatus
+            .children("response")
+            .flat_map(|r| r.children("propstat"))
+            .filter_map(|p| p.text_of("status"))
+            .find(|status| !status.contains(" 200"));
+        match rejected {
+            Some(status) => Err(SyncError::Other(format!(
+                "WebDAV server rejected mtime property on {path}: {}",
+                status.trim()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
@@ -0,0 +1,60 @@
+use quick_xml::events::Event;
+
+use super::SyncError;
+
+/// Minimal XML element tree for S3 and WebDAV responses. Names are stored
+/// without their namespace prefix.
+#[derive(Default)]
+pub(crate) struct XmlNode {
+    pub(crate) name: String,
+    pub(crate) text: String,
+    pub(crate) children: Vec<XmlNode>,
+}
+
+impl XmlNode {
+    pub(crate) fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a XmlNode> {
+        self.children.iter().filter(move |c| c.name == name)
+    }
+
+    pub(crate) fn child(&self, name: &str) -> Option<&XmlNode> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    pub(crate) fn text_of(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|c| c.text.as_str())
+    }
+}
+
+/// Parses `body` and returns its root element.
+pub(crate) fn parse_xml(body: &str) -> Result<XmlNode, SyncError> {
+    let malformed =
+        |e: &dyn std::fmt::Display| SyncError::Other(format!("Malformed XML response: {e}"));
+    let mut reader = quick_xml::Reader::from_str(body);
+    let mut stack = vec![XmlNode::default()];
+    loop {
+        match reader.read_event().map_err(|e| malformed(&e))? {
+            Event::Start(e) => stack.push(XmlNode {
+                name: String::from_utf8_lossy(e.local_name().as_ref()).into_owned(),
+                ..Default::default()
+            }),
+            Event::Empty(e) => stack.last_mut().unwrap().children.push(XmlNode {
+                name: String::from_utf8_lossy(e.local_name().as_ref()).into_owned(),
+                ..Default::default()
+            }),
+            Event::Text(t) => {
+                let text = t.unescape().map_err(|e| malformed(&e))?;
+                stack.last_mut().unwrap().text.push_str(&text);
+            }
+            Event::End(_) if stack.len() > 1 => {
+                let node = stack.pop().unwrap();
+                stack.last_mut().unwrap().children.push(node);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    stack
+        .pop()
+        .and_then(|doc| doc.children.into_iter().next())
+        .ok_or_else(|| malformed(&"empty document"))
+}
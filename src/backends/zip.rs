@@ -0,0 +1,446 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::read::DeflateDecoder;
+use zip::write::{ExtendedFileOptions, FileOptions};
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+use super::archive::{
+    entry_name, file_meta, key_of, normalize, write_exact, Entries, FileSlice, PendingDirs, State,
+};
+use super::{FileEntry, FileKind, FileMeta, StorageBackend, SyncError};
+
+/// Extra field holding the mtime as Unix seconds (Info-ZIP "UT").
+const EXTENDED_TIMESTAMP: u16 = 0x5455;
+
+/// The entry's position in the central directory; `None` for directories
+/// only implied by deeper paths.
+#[derive(Default)]
+struct Data {
+    index: Option<usize>,
+}
+
+struct Index {
+    file: Arc<File>,
+    /// Parsed once; clones share the central directory, so each reader
+    /// gets its own cheaply.
+    archive: ZipArchive<FileSlice>,
+    entries: Entries<Data>,
+}
+
+struct Writer {
+    zip: ZipWriter<BufWriter<File>>,
+    dirs: PendingDirs,
+}
+
+/// A zip archive used either as a copy source or as a destination, never
+/// both. Reads index the central directory once, after which workers
+/// decompress different entries concurrently. Writes are serialized, with
+/// files deflated at the configured level (0 stores them uncompressed). Call
+/// [`StorageBackend::finish`] after the last write to add the central
+/// directory.
+pub struct ZipBackend {
+    path: PathBuf,
+    level: Option<i64>,
+    state: Mutex<State<Index, Writer>>,
+}
+
+fn zip_error(e: zip::result::ZipError) -> SyncError {
+    match e {
+        zip::result::ZipError::Io(e) => SyncError::Io(e),
+        e => SyncError::Other(format!("Zip error: {e}")),
+    }
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Inverse of [`days_from_civil`]: (year, month, day).
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Interprets an MS-DOS timestamp as UTC; zip records no time zone.
+fn from_dos(t: DateTime) -> Option<SystemTime> {
+    let days = days_from_civil(t.year().into(), t.month().into(), t.day().into());
+    let secs = days * 86400 + i64::from(t.hour()) * 3600 + i64::from(t.minute()) * 60;
+    let secs = u64::try_from(secs + i64::from(t.second())).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn to_dos(t: SystemTime) -> DateTime {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let time = secs.rem_euclid(86400);
+    u16::try_from(year)
+        .ok()
+        .and_then(|year| {
+            DateTime::from_date_and_time(
+                year,
+                month as u8,
+                day as u8,
+                (time / 3600) as u8,
+                (time / 60 % 60) as u8,
+                (time % 60) as u8,
+            )
+            .ok()
+        })
+        .unwrap_or_else(DateTime::default_for_write)
+}
+
+/// Checks the CRC-32 of an entry read from its first to its last byte.
+struct CrcReader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+    expected: u32,
+    checked: bool,
+    name: String,
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        if n == 0 && !buf.is_empty() && !self.checked {
+            self.checked = true;
+            if self.hasher.clone().finalize() != self.expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("CRC mismatch in zip entry {}", self.name),
+                ));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl ZipBackend {
+    /// Opens the archive at `path`. `level` sets the deflate level (0-9)
+    /// for entries written; 0 stores them, `None` uses the default.
+    pub fn new(path: &str, level: Option<i64>) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            level,
+            state: Mutex::new(State::Closed),
+        }
+    }
+
+    fn build_index(&self) -> Result<Index, SyncError> {
+        let file = Arc::new(File::open(&self.path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => SyncError::NotFound(self.path.display().to_string()),
+            _ => SyncError::Io(e),
+        })?);
+        let mut archive = ZipArchive::new(FileSlice::whole(file.clone())?).map_err(zip_error)?;
+        let mut entries = Entries::<Data>::new();
+        for index in 0..archive.len() {
+            let entry = archive.by_index_raw(index).map_err(zip_error)?;
+            let Some(key) = key_of(Path::new(entry.name())) else {
+                log::warn!(
+                    "Skipping zip entry outside the archive root: {}",
+                    entry.name()
+                );
+                continue;
+            };
+            let kind = if entry.is_dir() {
+                FileKind::Dir
+            } else if entry.is_symlink() {
+                FileKind::Symlink
+            } else {
+                FileKind::File
+            };
+            let modified = entry
+                .extra_data_fields()
+                .find_map(|field| match field {
+                    zip::extra_fields::ExtraField::ExtendedTimestamp(ts) => ts.mod_time(),
+                    _ => None,
+                })
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs.into()))
+                .or_else(|| entry.last_modified().and_then(from_dos));
+            let meta = FileMeta {
+                size: if kind == FileKind::Dir {
+                    0
+                } else {
+                    entry.size()
+                },
+                kind,
+                modified,
+                accessed: None,
+                mode: entry.unix_mode().map(|m| m & 0o7777),
+                rdev: None,
+            };
+            entries.insert(key, meta, Data { index: Some(index) });
+        }
+        Ok(Index {
+            file,
+            archive,
+            entries,
+        })
+    }
+
+    /// Returns the index, reading the central directory on first use.
+    fn index(&self) -> Result<Arc<Index>, SyncError> {
+        self.state.lock().unwrap().reading(|| self.build_index())
+    }
+
+    fn lookup(&self, path: &str) -> Result<FileMeta, SyncError> {
+        if let State::Writing(writer) = &*self.state.lock().unwrap() {
+            return writer.dirs.stat(path);
+        }
+        self.index()?.entries.stat(&normalize(path))
+    }
+
+    /// Opens the decompressed data of the entry at `path`.
+    fn open_entry(&self, path: &str) -> Result<Box<dyn Read + Send>, SyncError> {
+        let key = normalize(path);
+        let index = self.index()?;
+        let position = match index.entries.get(&key) {
+            Some((meta, Data { index: Some(i) })) if !meta.is_dir() => *i,
+            Some(_) => return Err(SyncError::Other(format!("Not a file: {key}"))),
+            None => return Err(SyncError::NotFound(key)),
+        };
+        let mut archive = index.archive.clone();
+        let entry = archive.by_index_raw(position).map_err(zip_error)?;
+        if entry.encrypted() {
+            return Err(SyncError::Other(format!(
+                "Cannot read encrypted zip entry {key}"
+            )));
+        }
+        let raw = FileSlice::new(
+            index.file.clone(),
+            entry.data_start(),
+            entry.compressed_size(),
+        );
+        let data: Box<dyn Read + Send> = match entry.compression() {
+            CompressionMethod::Stored => Box::new(raw),
+            CompressionMethod::Deflated => Box::new(DeflateDecoder::new(raw)),
+            method => {
+                return Err(SyncError::Other(format!(
+                    "Unsupported compression {method} in zip entry {key}"
+                )))
+            }
+        };
+        Ok(Box::new(CrcReader {
+            inner: data.take(entry.size()),
+            hasher: crc32fast::Hasher::new(),
+            expected: entry.crc32(),
+            checked: false,
+            name: key,
+        }))
+    }
+
+    /// Runs `write` against the archive, creating it on first use.
+    fn with_writer<T>(
+        &self,
+        write: impl FnOnce(&mut Writer) -> Result<T, SyncError>,
+    ) -> Result<T, SyncError> {
+        let mut state = self.state.lock().unwrap();
+        let writer = state.writing(|| {
+            Ok(Writer {
+                zip: ZipWriter::new(BufWriter::new(File::create(&self.path)?)),
+                dirs: PendingDirs::default(),
+            })
+        })?;
+        write(writer)
+    }
+
+    /// Entry options carrying `meta`'s permissions and mtime. Only files
+    /// are compressed.
+    fn options(
+        &self,
+        meta: &FileMeta,
+        default_mode: u32,
+    ) -> Result<FileOptions<'static, ExtendedFileOptions>, SyncError> {
+        let (method, level) = match self.level {
+            Some(0) => (CompressionMethod::Stored, None),
+            level => (CompressionMethod::Deflated, level),
+        };
+        let modified = meta.modified.unwrap_or_else(SystemTime::now);
+        let mut options = FileOptions::<ExtendedFileOptions>::default()
+            .compression_method(if meta.is_file() {
+                method
+            } else {
+                CompressionMethod::Stored
+            })
+            .compression_level(level)
+            .unix_permissions(meta.mode.unwrap_or(default_mode))
+            .last_modified_time(to_dos(modified))
+            .large_file(meta.size >= u32::MAX as u64);
+        let secs = modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if let Ok(secs) = u32::try_from(secs) {
+            let mut field = vec![1u8];
+            field.extend_from_slice(&secs.to_le_bytes());
+            options
+                .add_extra_data(EXTENDED_TIMESTAMP, field.into(), false)
+                .map_err(zip_error)?;
+        }
+        Ok(options)
+    }
+}
+
+impl StorageBackend for ZipBackend {
+    fn list(&self, path: &str) -> Result<Vec<FileEntry>, SyncError> {
+        self.index()?.entries.list(&normalize(path))
+    }
+
+    fn get(&self, path: &str) -> Result<Vec<u8>, SyncError> {
+        let mut data = Vec::new();
+        self.open_entry(path)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), SyncError> {
+        self.put_stream(path, &mut &data[..], data.len() as u64)
+    }
+
+    fn put_stream(&self, path: &str, reader: &mut dyn Read, size: u64) -> Result<(), SyncError> {
+        self.put_with_meta(path, reader, &file_meta(size))
+    }
+
+    fn put_with_meta(
+        &self,
+        path: &str,
+        reader: &mut dyn Read,
+        meta: &FileMeta,
+    ) -> Result<(), SyncError> {
+        let name = entry_name(path)?;
+        let options = self.options(meta, 0o644)?;
+        write_exact(path, reader, meta.size, |data| {
+            self.with_writer(|writer| {
+                writer.zip.start_file(name, options).map_err(zip_error)?;
+                io::copy(data, &mut writer.zip)?;
+                Ok(())
+            })
+        })
+    }
+
+    fn open_read(
+        &self,
+        path: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read + Send>, SyncError> {
+        let mut reader = self.open_entry(path)?;
+        if offset > 0 {
+            // Compressed data cannot be entered mid-stream.
+            io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+        }
+        Ok(match len {
+            Some(len) => Box::new(reader.take(len)),
+            None => reader,
+        })
+    }
+
+    fn delete(&self, path: &str) -> Result<(), SyncError> {
+        Err(SyncError::Other(format!(
+            "Cannot delete {path}: zip archives do not support deletion"
+        )))
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, SyncError> {
+        match self.lookup(path) {
+            Ok(_) => Ok(true),
+            Err(SyncError::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        self.lookup(path)
+    }
+
+    fn mkdir(&self, path: &str) -> Result<(), SyncError> {
+        self.with_writer(|writer| {
+            writer.dirs.add(path);
+            Ok(())
+        })
+    }
+
+    fn set_metadata(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        self.with_writer(|writer| {
+            writer.dirs.set_metadata(path, meta);
+            Ok(())
+        })
+    }
+
+    fn walk(&self, root: &str, visit: &mut dyn FnMut(FileEntry)) -> Result<(), SyncError> {
+        self.index()?.entries.walk(root, visit)
+    }
+
+    fn symlink(&self, target: &str, link: &str) -> Result<(), SyncError> {
+        let name = entry_name(link)?;
+        let meta = FileMeta {
+            kind: FileKind::Symlink,
+            ..file_meta(target.len() as u64)
+        };
+        let options = self.options(&meta, 0o777)?;
+        self.with_writer(|writer| {
+            writer
+                .zip
+                .add_symlink(name, target, options)
+                .map_err(zip_error)
+        })
+    }
+
+    fn read_link(&self, path: &str) -> Result<String, SyncError> {
+        if self.lookup(path)?.kind != FileKind::Symlink {
+            return Err(SyncError::Other(format!("Not a symlink: {path}")));
+        }
+        let mut target = String::new();
+        self.open_entry(path)?.read_to_string(&mut target)?;
+        Ok(target)
+    }
+
+    fn finish(&self) -> Result<(), SyncError> {
+        let Some(Writer { mut zip, dirs }) = self.state.lock().unwrap().take_writer() else {
+            return Ok(());
+        };
+        for (name, meta) in dirs.into_entries() {
+            let options = self.options(&meta, 0o755)?;
+            zip.add_directory(name, options).map_err(zip_error)?;
+        }
+        zip.finish().map_err(zip_error)?.flush()?;
+        Ok(())
+    }
+
+    fn time_precision(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+impl Drop for ZipBackend {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            log::warn!(
+                "Failed to finish zip archive {}: {e:?}",
+                self.path.display()
+            );
+        }
+    }
+}
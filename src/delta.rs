@@ -0,0 +1,332 @@
+//! Delta transfers in the manner of rsync: the receiver describes the file
+//! it already has as a [`Signature`] of per-block checksums, the sender
+//! finds those blocks in the new contents at any offset with a rolling
+//! checksum, and only the bytes in between travel. [`Patcher`] rebuilds the
+//! new file from the old one and the resulting [`DeltaOp`]s.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Smallest and largest block size chosen by [`block_size`].
+const MIN_BLOCK: u32 = 2 << 10;
+const MAX_BLOCK: u32 = 128 << 10;
+/// Unmatched bytes sent as one `Data` op at most.
+const MAX_LITERAL: usize = 256 << 10;
+/// Bytes read from the new contents at a time.
+const READ_CHUNK: usize = 64 << 10;
+
+/// Checksums of one block of the old file: a weak rolling one to find
+/// candidates cheaply and a strong one to confirm them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSig {
+    pub weak: u32,
+    pub strong: [u8; 16],
+}
+
+/// The old file as the sender sees it: `blocks[i]` covers bytes
+/// `i * block_size ..` up to `block_size` long; the last may be shorter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub block_size: u32,
+    pub blocks: Vec<BlockSig>,
+}
+
+/// One step in rebuilding the new file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeltaOp {
+    /// `count` blocks of the old file starting at block `block`.
+    Copy { block: u64, count: u64 },
+    /// Bytes found nowhere in the old file.
+    Data(Vec<u8>),
+}
+
+/// A block size for a file of `size` bytes: about its square root, so the
+/// signature and the expected literal data stay in proportion.
+pub fn block_size(size: u64) -> u32 {
+    let root = (size as f64).sqrt() as u64;
+    (root.next_multiple_of(1024) as u32).clamp(MIN_BLOCK, MAX_BLOCK)
+}
+
+/// rsync's rolling checksum: `a` sums the bytes, `b` weighs each by its
+/// distance from the end of the window, both modulo 2^16.
+#[derive(Default)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let mut sum = Self {
+            len,
+            ..Self::default()
+        };
+        for (i, &byte) in block.iter().enumerate() {
+            sum.a = sum.a.wrapping_add(byte as u32);
+            sum.b = sum
+                .b
+                .wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        sum
+    }
+
+    /// Slides the window one byte: `out` leaves at the front, `new` joins
+    /// at the back.
+    fn roll(&mut self, out: u8, new: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(new as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(block: &[u8]) -> [u8; 16] {
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&blake3::hash(block).as_bytes()[..16]);
+    out
+}
+
+impl Signature {
+    /// Checksums `reader` in blocks of `block_size` bytes.
+    pub fn compute(reader: &mut dyn Read, block_size: u32) -> io::Result<Self> {
+        let mut blocks = Vec::new();
+        let mut buf = vec![0u8; block_size.max(1) as usize];
+        loop {
+            let n = read_full(reader, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            blocks.push(BlockSig {
+                weak: Rolling::new(&buf[..n]).value(),
+                strong: strong(&buf[..n]),
+            });
+            if n < buf.len() {
+                break;
+            }
+        }
+        Ok(Self {
+            block_size: block_size.max(1),
+            blocks,
+        })
+    }
+}
+
+/// Fills `buf` unless the reader ends first; returns how much was read.
+fn read_full(reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Passes `emit` the ops that turn the file described by `basis` into the
+/// contents of `reader`, merging runs of consecutive blocks. Memory stays
+/// bounded by the block and literal sizes however long the input is.
+/// Returns the blake3 hash and length of everything read.
+pub fn diff(
+    basis: &Signature,
+    reader: &mut dyn Read,
+    emit: &mut dyn FnMut(DeltaOp) -> io::Result<()>,
+) -> io::Result<(blake3::Hash, u64)> {
+    let bs = basis.block_size as usize;
+    // A short last block never matches a full window's strong checksum.
+    let mut table: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in basis.blocks.iter().enumerate() {
+        table.entry(block.weak).or_default().push(i);
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    let mut total = 0u64;
+    let mut buf: Vec<u8> = Vec::new();
+    let mut chunk = vec![0u8; READ_CHUNK];
+    let mut eof = false;
+    // `buf[lit..pos]` is unmatched and not yet emitted; the window being
+    // checked is `buf[pos..pos + bs]`.
+    let (mut lit, mut pos) = (0usize, 0usize);
+    let mut rolling: Option<Rolling> = None;
+    let mut pending: Option<(u64, u64)> = None;
+
+    let mut fill = |buf: &mut Vec<u8>, want: usize, eof: &mut bool| -> io::Result<()> {
+        while buf.len() < want && !*eof {
+            let n = reader.read(&mut chunk)?;
+            if n == 0 {
+                *eof = true;
+            } else {
+                hasher.update(&chunk[..n]);
+                total += n as u64;
+                buf.extend_from_slice(&chunk[..n]);
+            }
+        }
+        Ok(())
+    };
+    let flush_copy = |pending: &mut Option<(u64, u64)>,
+                      emit: &mut dyn FnMut(DeltaOp) -> io::Result<()>|
+     -> io::Result<()> {
+        match pending.take() {
+            Some((block, count)) => emit(DeltaOp::Copy { block, count }),
+            None => Ok(()),
+        }
+    };
+
+    if !table.is_empty() {
+        loop {
+            fill(&mut buf, pos + bs + 1, &mut eof)?;
+            if buf.len() < pos + bs {
+                break;
+            }
+            let window = &buf[pos..pos + bs];
+            let weak = rolling.get_or_insert_with(|| Rolling::new(window)).value();
+            let matched = table.get(&weak).and_then(|candidates| {
+                let sum = strong(window);
+                candidates
+                    .iter()
+                    .copied()
+                    .find(|&i| basis.blocks[i].strong == sum)
+            });
+            if let Some(i) = matched {
+                if lit < pos {
+                    flush_copy(&mut pending, emit)?;
+                    emit(DeltaOp::Data(buf[lit..pos].to_vec()))?;
+                }
+                push_copy(&mut pending, i as u64, emit)?;
+                pos += bs;
+                lit = pos;
+                rolling = None;
+            } else {
+                if buf.len() <= pos + bs {
+                    break;
+                }
+                if let Some(sum) = rolling.as_mut() {
+                    sum.roll(buf[pos], buf[pos + bs]);
+                }
+                pos += 1;
+                if pos - lit >= MAX_LITERAL {
+                    flush_copy(&mut pending, emit)?;
+                    emit(DeltaOp::Data(buf[lit..pos].to_vec()))?;
+                    lit = pos;
+                }
+            }
+            if lit >= READ_CHUNK {
+                buf.drain(..lit);
+                pos -= lit;
+                lit = 0;
+            }
+        }
+        // The old file's last block may be short; it can only match the
+        // very end of the new contents.
+        let tail = &buf[pos..];
+        if !tail.is_empty()
+            && tail.len() < bs
+            && basis.blocks.last().unwrap().strong == strong(tail)
+        {
+            if lit < pos {
+                flush_copy(&mut pending, emit)?;
+                emit(DeltaOp::Data(buf[lit..pos].to_vec()))?;
+            }
+            push_copy(&mut pending, basis.blocks.len() as u64 - 1, emit)?;
+            lit = buf.len();
+        }
+    }
+
+    flush_copy(&mut pending, emit)?;
+    loop {
+        fill(&mut buf, lit + MAX_LITERAL, &mut eof)?;
+        if lit == buf.len() {
+            break;
+        }
+        let end = buf.len().min(lit + MAX_LITERAL);
+        emit(DeltaOp::Data(buf[lit..end].to_vec()))?;
+        buf.drain(..end);
+        lit = 0;
+    }
+    Ok((hasher.finalize(), total))
+}
+
+/// Adds `block` to the pending run of copied blocks if it follows on,
+/// otherwise emits the run and starts another.
+fn push_copy(
+    pending: &mut Option<(u64, u64)>,
+    block: u64,
+    emit: &mut dyn FnMut(DeltaOp) -> io::Result<()>,
+) -> io::Result<()> {
+    if let Some((start, count)) = pending {
+        if *start + *count == block {
+            *count += 1;
+            return Ok(());
+        }
+    }
+    match pending.replace((block, 1)) {
+        Some((block, count)) => emit(DeltaOp::Copy { block, count }),
+        None => Ok(()),
+    }
+}
+
+/// Rebuilds a file from the old one (`base`) and a stream of [`DeltaOp`]s,
+/// hashing what it writes so the result can be checked against the
+/// sender's hash.
+pub struct Patcher<B, W> {
+    base: B,
+    block_size: u64,
+    out: W,
+    hasher: blake3::Hasher,
+    written: u64,
+}
+
+impl<B: Read + Seek, W: Write> Patcher<B, W> {
+    pub fn new(base: B, block_size: u32, out: W) -> Self {
+        Self {
+            base,
+            block_size: block_size as u64,
+            out,
+            hasher: blake3::Hasher::new(),
+            written: 0,
+        }
+    }
+
+    pub fn apply(&mut self, op: &DeltaOp) -> io::Result<()> {
+        match op {
+            DeltaOp::Copy { block, count } => {
+                self.base.seek(SeekFrom::Start(block * self.block_size))?;
+                let want = count * self.block_size;
+                let mut buf = vec![0u8; READ_CHUNK];
+                let mut copied = 0u64;
+                while copied < want {
+                    let len = (want - copied).min(buf.len() as u64) as usize;
+                    let n = self.base.read(&mut buf[..len])?;
+                    if n == 0 {
+                        break;
+                    }
+                    self.write(&buf[..n])?;
+                    copied += n as u64;
+                }
+                Ok(())
+            }
+            DeltaOp::Data(data) => self.write(data),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.hasher.update(data);
+        self.written += data.len() as u64;
+        self.out.write_all(data)
+    }
+
+    /// The output, with the hash and length of everything written to it.
+    pub fn finish(mut self) -> io::Result<(W, blake3::Hash, u64)> {
+        self.out.flush()?;
+        Ok((self.out, self.hasher.finalize(), self.written))
+    }
+}
@@ -1,18 +1,20 @@
+pub mod agent;
 pub mod backends;
+pub mod delta;
 pub mod sync;
 pub mod utils;
 
 pub use backends::{
-    backend_and_path, FileEntry, LocalBackend, SshBackend, StorageBackend, SyncError,
+    backend_and_path, FileEntry, FileKind, FileMeta, LocalBackend, MemoryBackend, SshBackend,
+    StorageBackend, SyncError,
 };
-pub use sync::sync;
+pub use sync::{sync, sync_to_all};
 
 use crossbeam_channel::unbounded;
 use indicatif::{ProgressBar, ProgressStyle};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use walkdir::WalkDir;
 
 pub struct CopyOptions<'a> {
     pub threads: usize,
@@ -21,6 +23,10 @@ pub struct CopyOptions<'a> {
     pub dry_run: bool,
     pub no_progress: bool,
     pub no_preserve_times: bool,
+    pub devices: bool,
+    pub specials: bool,
+    pub links: bool,
+    pub atimes: bool,
 }
 
 pub fn copy(
@@ -30,6 +36,21 @@ pub fn copy(
     dest_path: &str,
     options: &CopyOptions,
 ) -> Result<(), SyncError> {
+    copy_to_all(source, source_path, &[(dest, dest_path)], options)?
+        .pop()
+        .unwrap()
+}
+
+/// Copies `source_path` to every destination in one pass: each file is read
+/// once and streamed to all destinations concurrently. The outer error is a
+/// failed walk of the source; otherwise there is one result per destination,
+/// in order, so a broken destination doesn't stop the others.
+pub fn copy_to_all(
+    source: Arc<dyn crate::backends::StorageBackend + Sync + Send>,
+    source_path: &str,
+    dests: &[(Arc<dyn crate::backends::StorageBackend + Sync + Send>, &str)],
+    options: &CopyOptions,
+) -> Result<Vec<Result<(), SyncError>>, SyncError> {
     let (tx, rx) = unbounded();
 
     let pb = if options.no_progress {
@@ -44,39 +65,56 @@ pub fn copy(
     let source_path_buf = source_path.to_string();
     let include = options.include.cloned();
     let exclude = options.exclude.cloned();
+    let wanted = utils::Specials {
+        devices: options.devices,
+        specials: options.specials,
+        links: options.links,
+    };
+    let source_producer = Arc::clone(&source);
     let tx_producer = tx.clone();
     let pb_producer = pb.clone();
     let producer = thread::spawn(move || {
         let mut total_bytes = 0u64;
         let mut file_count = 0u64;
         let mut switched = false;
-        for entry in WalkDir::new(&source_path_buf)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            if !entry.file_type().is_file() {
-                continue;
+        let mut dirs = Vec::new();
+        let mut special_files = Vec::new();
+        let walked = source_producer.walk(&source_path_buf, &mut |entry| {
+            let kind = entry.metadata.kind;
+            if kind != FileKind::Dir && kind != FileKind::File && !wanted.wants(kind) {
+                return;
             }
-            let file_str = entry.path().to_string_lossy().to_string();
             if let Some(ref re) = include {
-                if !re.is_match(&file_str) {
-                    continue;
+                if !re.is_match(&entry.path) {
+                    return;
                 }
             }
             if let Some(ref re) = exclude {
-                if re.is_match(&file_str) {
-                    continue;
+                if re.is_match(&entry.path) {
+                    return;
+                }
+            }
+            let rel_path = match std::path::Path::new(&entry.path).strip_prefix(&source_path_buf)
+            {
+                Ok(p) => p.to_path_buf(),
+                Err(_) => return,
+            };
+            if kind != FileKind::File {
+                let source_entry = utils::SourceEntry {
+                    rel_path,
+                    metadata: entry.metadata,
+                };
+                if kind == FileKind::Dir {
+                    dirs.push(source_entry);
+                } else {
+                    special_files.push(source_entry);
                 }
+                return;
             }
-            let rel_path = entry
-                .path()
-                .strip_prefix(&source_path_buf)
-                .unwrap()
-                .to_path_buf();
-            let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+            let size = entry.metadata.size;
             tx_producer
-                .send((rel_path, size))
-                .expect("Failed to send file path and size");
+                .send((rel_path, entry.metadata))
+                .expect("Failed to send file path and metadata");
             total_bytes += size;
             file_count += 1;
             if let Some(pb) = pb_producer.as_ref() {
@@ -94,48 +132,43 @@ pub fn copy(
                 }
                 pb.set_length(total_bytes);
             }
-        }
+        });
         drop(tx_producer);
+        walked.map(|_| (dirs, special_files))
     });
     drop(tx);
 
-    let is_local_src = source
-        .as_ref()
-        .as_any()
-        .is::<crate::backends::LocalBackend>();
-    let is_local_dst = dest.as_ref().as_any().is::<crate::backends::LocalBackend>();
-    let both_local = is_local_src && is_local_dst;
+    let dests: Arc<Vec<_>> = Arc::new(
+        dests
+            .iter()
+            .map(|(dest, path)| (Arc::clone(dest), path.to_string()))
+            .collect(),
+    );
 
     let mut handles = Vec::new();
     let rx = Arc::new(rx);
-    let errors: Arc<Mutex<Vec<SyncError>>> = Arc::new(Mutex::new(Vec::new()));
+    let errors: Arc<Vec<Mutex<Vec<SyncError>>>> =
+        Arc::new(dests.iter().map(|_| Mutex::new(Vec::new())).collect());
 
     for _ in 0..options.threads {
         let rx = Arc::clone(&rx);
         let source = Arc::clone(&source);
-        let dest = Arc::clone(&dest);
+        let dests = Arc::clone(&dests);
         let pb_worker = pb.clone();
-        let dest_path = dest_path.to_string();
         let source_path = source_path.to_string();
         let dry_run = options.dry_run;
-        let no_preserve_times = options.no_preserve_times;
+        let preserve_times = !options.no_preserve_times;
+        let atimes = options.atimes;
         let errors = Arc::clone(&errors);
 
         let handle = thread::spawn(move || {
-            let mut src_file = PathBuf::with_capacity(256);
-            let mut dst_file = PathBuf::with_capacity(256);
-            let mut _buf = vec![0u8; 1024 * 1024];
-            let mut created_dirs: std::collections::HashSet<PathBuf> =
-                std::collections::HashSet::new();
+            let mut created_dirs: Vec<std::collections::HashSet<PathBuf>> =
+                dests.iter().map(|_| Default::default()).collect();
+            let mut batches = utils::Batches::new(dests.iter().map(|(d, _)| d.as_ref()).collect());
 
-            while let Ok((rel_path, size)) = rx.recv() {
-                src_file.clear();
-                src_file.push(&source_path);
-                src_file.push(&rel_path);
-
-                dst_file.clear();
-                dst_file.push(&dest_path);
-                dst_file.push(&rel_path);
+            while let Ok((rel_path, meta)) = rx.recv() {
+                let size = meta.size;
+                let src_file = utils::join_rel(&source_path, &rel_path);
 
                 if dry_run {
                     if let Some(pb) = pb_worker.as_ref() {
@@ -144,258 +177,114 @@ pub fn copy(
                     continue;
                 }
 
-                if both_local {
-                    if let Some(parent) = dst_file.parent() {
-                        if !created_dirs.contains(parent) {
-                            if let Err(e) = std::fs::create_dir_all(parent) {
-                                errors.lock().unwrap().push(SyncError::Io(e));
-                                if let Some(pb) = pb_worker.as_ref() {
-                                    pb.inc(size);
-                                }
+                let mut targets = Vec::new();
+                for (i, (dest, dest_path)) in dests.iter().enumerate() {
+                    let dst_file = utils::join_rel(dest_path, &rel_path);
+                    if let Some(parent) = std::path::Path::new(&dst_file).parent() {
+                        if !parent.as_os_str().is_empty() && !created_dirs[i].contains(parent) {
+                            if let Err(e) = dest.mkdir(&parent.to_string_lossy()) {
+                                errors[i].lock().unwrap().push(e);
                                 continue;
                             }
-                            created_dirs.insert(parent.to_path_buf());
+                            created_dirs[i].insert(parent.to_path_buf());
                         }
                     }
+                    targets.push((i, dst_file));
+                }
 
-                    let mut copied = fast_copy(
+                let dst_meta = utils::preserved_meta(&meta, preserve_times, atimes);
+                let (batched, targets): (Vec<_>, Vec<_>) = targets
+                    .into_iter()
+                    .partition(|(i, _)| batches.takes(*i, size));
+                for (i, e) in batches.add(source.as_ref(), &src_file, &dst_meta, &batched) {
+                    errors[i].lock().unwrap().push(e);
+                }
+                let fast = match targets.as_slice() {
+                    [(i, dst_file)] => utils::local_copy(
+                        source.as_ref(),
                         &src_file,
-                        &dst_file,
+                        dests[*i].0.as_ref(),
+                        dst_file,
                         size,
-                        std::fs::metadata(src_file.to_str().unwrap())
-                            .ok()
-                            .and_then(|m| m.modified().ok()),
-                        !no_preserve_times,
-                    );
-                    if copied == 0 {
-                        match std::fs::copy(src_file.to_str().unwrap(), dst_file.to_str().unwrap())
-                        {
-                            Ok(n) => {
-                                copied = n;
-                                if !no_preserve_times {
-                                    if let Ok(src_meta) =
-                                        std::fs::metadata(src_file.to_str().unwrap())
-                                    {
-                                        if let Ok(st) = src_meta.modified() {
-                                            let _ = filetime::set_file_mtime(
-                                                dst_file.to_str().unwrap(),
-                                                filetime::FileTime::from_system_time(st),
-                                            );
-                                        }
-                                    }
-                                }
-                            }
-                            Err(fs_err) => {
-                                if let Some(src_local) = source
-                                    .as_ref()
-                                    .as_any()
-                                    .downcast_ref::<crate::backends::LocalBackend>(
-                                ) {
-                                    match src_local.copy_file(
-                                        src_file.to_str().unwrap(),
-                                        dst_file.to_str().unwrap(),
-                                        &mut _buf,
-                                    ) {
-                                        Ok(_) => {
-                                            copied = size;
-                                        }
-                                        Err(be_err) => {
-                                            errors
-                                                .lock()
-                                                .unwrap()
-                                                .push(SyncError::Other(format!(
-                                                    "Failed to copy {:?}: std fs error: {:?}, backend error: {:?}",
-                                                    src_file, fs_err, be_err
-                                                )));
-                                            if let Some(pb) = pb_worker.as_ref() {
-                                                pb.inc(size);
-                                            }
-                                            continue;
-                                        }
-                                    }
-                                } else {
-                                    errors
-                                        .lock()
-                                        .unwrap()
-                                        .push(SyncError::Other(format!(
-                                            "Failed to copy {:?}: std fs error: {:?}, no LocalBackend fallback",
-                                            src_file, fs_err
-                                        )));
-                                    if let Some(pb) = pb_worker.as_ref() {
-                                        pb.inc(size);
-                                    }
-                                    continue;
-                                }
-                            }
-                        }
-                    }
-                    if let Some(pb) = pb_worker.as_ref() {
-                        pb.inc(copied.max(size));
-                    }
-                    continue;
-                } else if is_local_src {
-                    if is_local_dst {
-                        if let Some(parent) = dst_file.parent() {
-                            if let Err(e) = std::fs::create_dir_all(parent) {
-                                errors.lock().unwrap().push(SyncError::Io(e));
-                                if let Some(pb) = pb_worker.as_ref() {
-                                    pb.inc(size);
-                                }
-                                continue;
-                            }
-                        }
-                    }
-                    match std::fs::File::open(&src_file) {
-                        Ok(mut f) => {
-                            if let Err(e) =
-                                dest.put_stream(dst_file.to_str().unwrap(), &mut f, size)
-                            {
-                                errors.lock().unwrap().push(e);
-                            }
-                        }
-                        Err(e) => errors.lock().unwrap().push(SyncError::Io(e)),
-                    }
-                    if let Some(pb) = pb_worker.as_ref() {
-                        pb.inc(size);
-                    }
+                    ),
+                    _ => false,
+                };
+                let results = if fast {
+                    let (i, dst_file) = &targets[0];
+                    vec![dests[*i].0.set_metadata(dst_file, &dst_meta)]
+                } else if targets.is_empty() {
+                    Vec::new()
                 } else {
-                    if is_local_dst {
-                        if let Some(parent) = dst_file.parent() {
-                            if let Err(e) = std::fs::create_dir_all(parent) {
-                                errors.lock().unwrap().push(SyncError::Io(e));
-                                if let Some(pb) = pb_worker.as_ref() {
-                                    pb.inc(size);
-                                }
-                                continue;
-                            }
-                        }
-                    }
-                    match source.get(src_file.to_str().unwrap()) {
-                        Ok(data) => {
-                            if let Err(e) = dest.put(dst_file.to_str().unwrap(), &data) {
-                                errors.lock().unwrap().push(e);
-                            }
-                        }
-                        Err(e) => errors.lock().unwrap().push(e),
-                    }
-                    if let Some(pb) = pb_worker.as_ref() {
-                        pb.inc(size);
+                    let writes: Vec<_> = targets
+                        .iter()
+                        .map(|(i, dst_file)| (dests[*i].0.as_ref(), dst_file.clone()))
+                        .collect();
+                    utils::copy_to_targets(source.as_ref(), &src_file, &writes, &dst_meta)
+                };
+                for ((i, _), result) in targets.iter().zip(results) {
+                    if let Err(e) = result {
+                        errors[*i].lock().unwrap().push(e);
                     }
                 }
+                if let Some(pb) = pb_worker.as_ref() {
+                    pb.inc(size);
+                }
+            }
+            for (i, e) in batches.finish() {
+                errors[i].lock().unwrap().push(e);
             }
         });
         handles.push(handle);
     }
 
-    producer.join().expect("Producer thread panicked");
+    let walked = producer.join().expect("Producer thread panicked");
     for handle in handles {
         handle.join().expect("Worker thread panicked");
     }
+    let (dirs, special_files) = walked?;
+
+    if !options.dry_run {
+        let preserve_times = !options.no_preserve_times;
+        for (i, (dest, dest_path)) in dests.iter().enumerate() {
+            let mut errors = errors[i].lock().unwrap();
+            errors.extend(utils::recreate_specials(
+                source.as_ref(),
+                source_path,
+                dest.as_ref(),
+                dest_path,
+                &special_files,
+                preserve_times,
+                options.atimes,
+            ));
+
+            let mut dirs = dirs.clone();
+            errors.extend(utils::finalize_dirs(
+                dest.as_ref(),
+                &mut dirs,
+                dest_path,
+                preserve_times,
+                options.atimes,
+            ));
+        }
+    }
     if let Some(pb) = pb.as_ref() {
         pb.finish_with_message("Copy complete");
     }
 
-    let errors = Arc::try_unwrap(errors).unwrap().into_inner().unwrap();
-    if !errors.is_empty() {
-        return Err(SyncError::Other(format!(
-            "{} errors occurred during copy",
-            errors.len()
-        )));
-    }
-    Ok(())
-}
-
-#[cfg(target_os = "linux")]
-fn fast_copy(
-    src: &std::path::Path,
-    dst: &std::path::Path,
-    size: u64,
-    src_modified: Option<std::time::SystemTime>,
-    preserve_times: bool,
-) -> u64 {
-    use std::fs::OpenOptions;
-    use std::os::unix::io::AsRawFd;
-
-    let mut copied_bytes: u64 = 0;
-
-    let src_f = match OpenOptions::new().read(true).open(src) {
-        Ok(f) => f,
-        Err(_) => return 0,
-    };
-    let dst_f = match OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(dst)
-    {
-        Ok(f) => f,
-        Err(_) => return 0,
-    };
-
-    unsafe {
-        const FICLONE: libc::c_ulong = 0x4004_9409;
-        if libc::ioctl(dst_f.as_raw_fd(), FICLONE, src_f.as_raw_fd()) == 0 {
-            copied_bytes = size;
-        } else {
-            let in_fd = src_f.as_raw_fd();
-            let out_fd = dst_f.as_raw_fd();
-            let mut off_in: libc::loff_t = 0;
-            let mut off_out: libc::loff_t = 0;
-            loop {
-                let n = libc::copy_file_range(in_fd, &mut off_in, out_fd, &mut off_out, 1 << 30, 0);
-                if n <= 0 {
-                    break;
-                }
-                copied_bytes = copied_bytes.saturating_add(n as u64);
-                if copied_bytes >= size {
-                    break;
-                }
-            }
-
-            if copied_bytes == 0 {
-                let mut offset: libc::off_t = 0;
-                loop {
-                    let n = libc::sendfile(out_fd, in_fd, &mut offset, 1 << 30);
-                    if n <= 0 {
-                        break;
-                    }
-                    copied_bytes = copied_bytes.saturating_add(n as u64);
-                    if copied_bytes >= size {
-                        break;
-                    }
-                }
+    let errors = Arc::try_unwrap(errors).ok().unwrap();
+    Ok(errors
+        .into_iter()
+        .map(|errors| {
+            let count = errors.into_inner().unwrap().len();
+            if count == 0 {
+                Ok(())
+            } else {
+                Err(SyncError::Other(format!(
+                    "{count} errors occurred during copy"
+                )))
             }
-        }
-    }
-
-    if copied_bytes == 0 {
-        copied_bytes = std::fs::copy(src, dst).unwrap_or(0);
-    }
-
-    if preserve_times {
-        if let Some(st) = src_modified {
-            let _ = filetime::set_file_mtime(dst, filetime::FileTime::from_system_time(st));
-        }
-    }
-
-    copied_bytes
-}
-
-#[cfg(not(target_os = "linux"))]
-fn fast_copy(
-    src: &std::path::Path,
-    dst: &std::path::Path,
-    _size: u64,
-    src_modified: Option<std::time::SystemTime>,
-    preserve_times: bool,
-) -> u64 {
-    let copied = std::fs::copy(src, dst).unwrap_or(0);
-    if preserve_times {
-        if let Some(st) = src_modified {
-            let _ = filetime::set_file_mtime(dst, filetime::FileTime::from_system_time(st));
-        }
-    }
-    copied
+        })
+        .collect())
 }
 
 pub fn delete(
@@ -409,10 +298,6 @@ pub fn delete(
 ) -> Result<(), SyncError> {
     use indicatif::{ProgressBar, ProgressStyle};
 
-    let (tx, rx) = crossbeam_channel::unbounded::<PathBuf>();
-    let include_producer = include.cloned();
-    let exclude_producer = exclude.cloned();
-
     let pb = if no_progress {
         None
     } else {
@@ -427,97 +312,69 @@ pub fn delete(
         Some(pb)
     };
 
-    let mut files: Vec<PathBuf> = Vec::new();
-    let mut dirs: Vec<PathBuf> = Vec::new();
+    let mut files: Vec<String> = Vec::new();
+    let mut dirs: Vec<String> = Vec::new();
+    let error_acc: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
     for root in roots {
-        for entry in walkdir::WalkDir::new(root)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            let file_str = entry.path().to_string_lossy();
-            if let Some(ref re) = include_producer {
-                if !re.is_match(&file_str) {
-                    continue;
+        let walked = backend.walk(root, &mut |entry| {
+            if let Some(re) = include {
+                if !re.is_match(&entry.path) {
+                    return;
                 }
             }
-            if let Some(ref re) = exclude_producer {
-                if re.is_match(&file_str) {
-                    continue;
+            if let Some(re) = exclude {
+                if re.is_match(&entry.path) {
+                    return;
                 }
             }
-            if let Ok(meta) = entry.metadata() {
-                if meta.is_file() {
-                    files.push(entry.path().to_path_buf());
-                } else if meta.is_dir() {
-                    dirs.push(entry.path().to_path_buf());
-                }
+            if entry.metadata.is_dir() {
+                dirs.push(entry.path);
+            } else {
+                files.push(entry.path);
             }
+        });
+        if let Err(e) = walked {
+            error_acc.lock().unwrap().push(format!("{e:?}"));
         }
     }
 
-    dirs.sort_by_key(|b| std::cmp::Reverse(b.components().count()));
-
     if let Some(ref pb) = pb {
         pb.set_length((files.len() + dirs.len()) as u64);
     }
 
-    let tx_producer = tx.clone();
-    for f in files {
-        tx_producer.send(f).expect("send file");
-    }
-    drop(tx_producer);
-    drop(tx);
-
-    let error_acc: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
-
-    thread::scope(|s| {
-        let rx = Arc::new(rx);
-        for _ in 0..threads {
-            let rx = Arc::clone(&rx);
-            let backend = Arc::clone(&backend);
-            let errors = Arc::clone(&error_acc);
-            let pb = pb.clone();
-
-            s.spawn(move || {
-                while let Ok(path) = rx.recv() {
-                    if dry_run {
-                        println!("Would delete: {}", path.display());
-                        continue;
-                    }
-                    match backend.delete(&path.to_string_lossy()) {
-                        Ok(_) => {
-                            if let Some(ref pb) = pb {
-                                pb.inc(1);
-                            }
-                        }
-                        Err(e) => errors.lock().unwrap().push(format!("{e:?}")),
-                    }
-                }
-            });
-        }
-    });
-
-    for dir in &dirs {
+    let remove = |path: &str| {
         if dry_run {
-            println!("Would delete: {}", dir.display());
-            continue;
+            match &pb {
+                Some(pb) => pb.println(format!("Would delete: {path}")),
+                None => eprintln!("Would delete: {path}"),
+            }
+            return Ok(());
         }
-        match backend.delete(&dir.to_string_lossy()) {
+        backend.delete(path)
+    };
+    utils::remove_all(
+        files,
+        dirs,
+        threads,
+        remove,
+        remove,
+        |_, result| match result {
+            Ok(_) if dry_run => {}
             Ok(_) => {
                 if let Some(ref pb) = pb {
                     pb.inc(1);
                 }
             }
             Err(e) => error_acc.lock().unwrap().push(format!("{e:?}")),
-        }
-    }
+        },
+    );
 
     if let Some(pb) = pb {
         pb.finish_with_message("Delete complete");
     }
 
-    let errors = Arc::try_unwrap(error_acc).unwrap().into_inner().unwrap();
+    let errors = error_acc.into_inner().unwrap();
     if !errors.is_empty() {
         return Err(SyncError::Other(format!(
             "{} errors occurred during delete",
@@ -1,6 +1,8 @@
-use clap::{Parser, Subcommand};
-use parsync::backends::backend_and_path;
+use clap::{CommandFactory, Parser, Subcommand};
+use parsync::backends::{backend_and_path, StorageBackend, SyncError};
+use std::collections::BTreeSet;
 use std::num::NonZeroUsize;
+use std::sync::Arc;
 
 #[derive(Parser)]
 #[command(name = "parsync", version, about = "A parallel file synchronizer")]
@@ -19,6 +21,22 @@ struct Cli {
     #[arg(long, global = true)]
     no_preserve_times: bool,
 
+    /// Preserve access times in addition to modification times
+    #[arg(long, global = true)]
+    atimes: bool,
+
+    /// Recreate character and block device nodes (requires root)
+    #[arg(long, global = true)]
+    devices: bool,
+
+    /// Recreate named pipes (FIFOs) and sockets
+    #[arg(long, global = true)]
+    specials: bool,
+
+    /// Recreate symlinks instead of skipping them
+    #[arg(long, global = true)]
+    links: bool,
+
     /// Regex pattern to exclude matching files and directories
     #[arg(short, long, value_name = "EXCLUDE", global = true)]
     exclude: Option<String>,
@@ -35,8 +53,13 @@ struct Cli {
     #[arg(long, global = true)]
     diff: bool,
 
+    /// Serve requests from a remote parsync on stdin and stdout, as started
+    /// over SSH
+    #[arg(long, hide = true)]
+    server: bool,
+
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -44,9 +67,13 @@ enum Commands {
     /// Copy files from source(s) to destination
     Copy {
         /// Source path(s). Globs are expanded for local files.
+        #[arg(required = true)]
         sources: Vec<String>,
         /// Destination path (supports local paths and URIs, e.g., file:///path/to/dest)
         destination: String,
+        /// Another destination; repeat to copy to several in one pass
+        #[arg(long, value_name = "DESTINATION")]
+        to: Vec<String>,
     },
     /// Delete files or directories recursively
     Delete {
@@ -56,25 +83,60 @@ enum Commands {
     /// Sync only those files which differ
     Sync {
         /// Source path(s). Globs are expanded for local files.
+        #[arg(required = true)]
         sources: Vec<String>,
         /// Destination path (e.g., file:///path/to/dest)
         destination: String,
+        /// Another destination; repeat to sync to several in one pass
+        #[arg(long, value_name = "DESTINATION")]
+        to: Vec<String>,
+    },
+    /// Manage a backup repository (the directory behind a repo:// URL)
+    Repo {
+        /// Repository directory
+        repository: String,
+        #[command(subcommand)]
+        action: RepoAction,
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum RepoAction {
+    /// List snapshots, oldest first
+    Snapshots,
+    /// Delete snapshots; their chunks stay until gc
+    Forget {
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Remove chunks that no snapshot refers to
+    Gc,
+}
+
 fn main() {
     env_logger::Builder::from_env(env_logger::Env::new().filter("PARSYNC_LOG")).init();
 
     let cli = Cli::parse();
 
-    match cli.command {
+    if cli.server {
+        if let Err(e) = parsync::agent::serve(std::io::stdin().lock(), std::io::stdout().lock()) {
+            eprintln!("parsync server: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let Some(command) = cli.command else {
+        let _ = Cli::command().print_help();
+        std::process::exit(2);
+    };
+
+    match command {
         Commands::Copy {
             sources,
             destination,
+            to,
         } => {
             use glob::glob;
-            use std::collections::BTreeSet;
-            use std::fs;
 
             let mut all_sources = BTreeSet::new();
             let mut backend_opt = None;
@@ -122,19 +184,16 @@ fn main() {
                 std::process::exit(1);
             }
 
-            let (dst_backend, dst_path) = match backend_and_path(&destination, cli.threads) {
-                Ok((b, p)) => (b, p),
-                Err(e) => {
-                    eprintln!("Invalid destination: {:?}", e);
-                    return;
-                }
-            };
+            let urls: Vec<String> = std::iter::once(destination).chain(to).collect();
+            let dests = open_destinations(&urls, cli.threads);
 
             if all_sources.len() > 1 {
-                let meta = fs::metadata(dst_path);
-                if meta.as_ref().map(|m| !m.is_dir()).unwrap_or(false) {
-                    eprintln!("Destination must be a directory when copying multiple sources.");
-                    std::process::exit(1);
+                for (_, dst_backend, dst_path) in &dests {
+                    let meta = dst_backend.stat(dst_path);
+                    if meta.as_ref().map(|m| !m.is_dir()).unwrap_or(false) {
+                        eprintln!("Destination must be a directory when copying multiple sources.");
+                        std::process::exit(1);
+                    }
                 }
             }
 
@@ -166,59 +225,23 @@ fn main() {
                 dry_run: cli.dry_run,
                 no_progress: cli.no_progress,
                 no_preserve_times: cli.no_preserve_times,
+                devices: cli.devices,
+                specials: cli.specials,
+                links: cli.links,
+                atimes: cli.atimes,
             };
 
             let src_backend = backend_opt.unwrap();
-
-            if all_sources.len() == 1 {
-                let src_path = all_sources.iter().next().unwrap();
-                match parsync::copy(
-                    src_backend.clone(),
-                    src_path,
-                    dst_backend,
-                    dst_path,
-                    &options,
-                ) {
-                    Ok(_) => println!("Copy completed successfully."),
-                    Err(e) => eprintln!("Copy failed: {:?}", e),
-                }
-            } else {
-                let mut any_failed = false;
-                for src_path in all_sources {
-                    let file_name = std::path::Path::new(&src_path)
-                        .file_name()
-                        .and_then(|n| n.to_str())
-                        .unwrap_or("");
-                    let mut dst_file_path = std::path::PathBuf::from(dst_path);
-                    dst_file_path.push(file_name);
-                    match parsync::copy(
-                        src_backend.clone(),
-                        &src_path,
-                        dst_backend.clone(),
-                        dst_file_path.to_str().unwrap(),
-                        &options,
-                    ) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            eprintln!("Copy failed for '{}': {:?}", src_path, e);
-                            any_failed = true;
-                        }
-                    }
-                }
-                if any_failed {
-                    std::process::exit(1);
-                } else {
-                    println!("Copy completed successfully.");
-                }
-            }
+            transfer_all("Copy", &all_sources, &dests, |src_path, targets| {
+                parsync::copy_to_all(src_backend.clone(), src_path, targets, &options)
+            });
         }
         Commands::Sync {
             sources,
             destination,
+            to,
         } => {
             use glob::glob;
-            use std::collections::BTreeSet;
-            use std::fs;
 
             let mut all_sources = BTreeSet::new();
             let mut backend_opt = None;
@@ -266,69 +289,34 @@ fn main() {
                 std::process::exit(1);
             }
 
-            let (dst_backend, dst_path) = match backend_and_path(&destination, cli.threads) {
-                Ok((b, p)) => (b, p),
-                Err(e) => {
-                    eprintln!("Invalid destination: {:?}", e);
-                    std::process::exit(1);
-                }
-            };
+            let urls: Vec<String> = std::iter::once(destination).chain(to).collect();
+            let dests = open_destinations(&urls, cli.threads);
 
             if all_sources.len() > 1 {
-                let meta = fs::metadata(dst_path);
-                if meta.as_ref().map(|m| !m.is_dir()).unwrap_or(false) {
-                    eprintln!("Destination must be a directory when syncing multiple sources.");
-                    std::process::exit(1);
+                for (_, dst_backend, dst_path) in &dests {
+                    let meta = dst_backend.stat(dst_path);
+                    if meta.as_ref().map(|m| !m.is_dir()).unwrap_or(false) {
+                        eprintln!("Destination must be a directory when syncing multiple sources.");
+                        std::process::exit(1);
+                    }
                 }
             }
 
             let src_backend = backend_opt.unwrap();
-
-            if all_sources.len() == 1 {
-                let src_path = all_sources.iter().next().unwrap();
-                let result = parsync::sync(
+            transfer_all("Sync", &all_sources, &dests, |src_path, targets| {
+                parsync::sync::sync_to_all(
                     src_backend.clone(),
                     src_path,
-                    dst_backend,
-                    dst_path,
-                    parsync::sync::DEFAULT_CHUNK_SIZE,
+                    targets,
                     cli.no_progress,
-                );
-                match result {
-                    Ok(_) => println!("Sync completed successfully."),
-                    Err(e) => eprintln!("Sync failed: {:?}", e),
-                }
-            } else {
-                let mut any_failed = false;
-                for src_path in all_sources {
-                    let file_name = std::path::Path::new(&src_path)
-                        .file_name()
-                        .and_then(|n| n.to_str())
-                        .unwrap_or("");
-                    let mut dst_file_path = std::path::PathBuf::from(dst_path);
-                    dst_file_path.push(file_name);
-                    let result = parsync::sync(
-                        src_backend.clone(),
-                        &src_path,
-                        dst_backend.clone(),
-                        dst_file_path.to_str().unwrap(),
-                        parsync::sync::DEFAULT_CHUNK_SIZE,
-                        cli.no_progress,
-                    );
-                    match result {
-                        Ok(_) => {}
-                        Err(e) => {
-                            eprintln!("Sync failed for '{}': {:?}", src_path, e);
-                            any_failed = true;
-                        }
-                    }
-                }
-                if any_failed {
-                    std::process::exit(1);
-                } else {
-                    println!("Sync completed successfully.");
-                }
-            }
+                    cli.atimes,
+                    parsync::utils::Specials {
+                        devices: cli.devices,
+                        specials: cli.specials,
+                        links: cli.links,
+                    },
+                )
+            });
         }
         Commands::Delete { paths } => {
             use glob::glob;
@@ -408,20 +396,133 @@ fn main() {
             let root_vec: Vec<String> = all_paths.into_iter().collect();
 
             match parsync::delete(
-                backend,
+                backend.clone(),
                 &root_vec,
                 threads,
                 dry_run,
                 no_progress,
                 include_re.as_ref(),
                 exclude_re.as_ref(),
-            ) {
-                Ok(_) => println!("Delete completed successfully."),
+            )
+            .and_then(|_| backend.finish())
+            {
+                Ok(_) => eprintln!("Delete completed successfully."),
                 Err(e) => {
                     eprintln!("Delete failed: {:?}", e);
                     std::process::exit(1);
                 }
             }
         }
+        Commands::Repo { repository, action } => {
+            let dir = repository.strip_prefix("repo://").unwrap_or(&repository);
+            let repo = parsync::backends::RepoBackend::new(dir, None);
+            let result = match action {
+                RepoAction::Snapshots => repo.snapshots().map(|snapshots| {
+                    for s in snapshots {
+                        println!("{}  {} files  {} bytes", s.id, s.files, s.bytes);
+                    }
+                }),
+                RepoAction::Forget { ids } => ids.iter().try_for_each(|id| repo.forget(id)),
+                RepoAction::Gc => repo.gc().map(|stats| {
+                    println!(
+                        "Removed {} chunks ({} bytes), kept {}.",
+                        stats.chunks_removed, stats.bytes_freed, stats.chunks_kept
+                    );
+                }),
+            };
+            if let Err(e) = result {
+                eprintln!("Repository operation failed: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+type Backend = Arc<dyn StorageBackend + Send + Sync>;
+
+/// Opens every destination URL, exiting on the first invalid one.
+fn open_destinations(urls: &[String], threads: usize) -> Vec<(&str, Backend, &str)> {
+    urls.iter()
+        .map(|url| match backend_and_path(url, threads) {
+            Ok((b, p)) => (url.as_str(), b, p),
+            Err(e) => {
+                eprintln!("Invalid destination '{}': {:?}", url, e);
+                std::process::exit(1);
+            }
+        })
+        .collect()
+}
+
+/// Runs `transfer` once per source, into a same-named subdirectory of each
+/// destination when there are several sources, then finishes every
+/// destination. Failures are reported per destination; any failure exits 1.
+fn transfer_all(
+    verb: &str,
+    sources: &BTreeSet<String>,
+    dests: &[(&str, Backend, &str)],
+    transfer: impl Fn(&str, &[(Backend, &str)]) -> Result<Vec<Result<(), SyncError>>, SyncError>,
+) {
+    let mut failed = vec![false; dests.len()];
+    let mut report = |i: usize, src_path: Option<&str>, e: &SyncError| {
+        let src = src_path.map(|p| format!(" for '{p}'")).unwrap_or_default();
+        let dst = match dests.len() {
+            1 => String::new(),
+            _ => format!(" to '{}'", dests[i].0),
+        };
+        eprintln!("{verb} failed{src}{dst}: {e:?}");
+        failed[i] = true;
+    };
+
+    for src_path in sources {
+        let dst_paths: Vec<String> = dests
+            .iter()
+            .map(|(_, _, dst_path)| {
+                if sources.len() == 1 {
+                    return dst_path.to_string();
+                }
+                let file_name = std::path::Path::new(src_path)
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or("");
+                let mut dst_file_path = std::path::PathBuf::from(dst_path);
+                dst_file_path.push(file_name);
+                dst_file_path.to_string_lossy().into_owned()
+            })
+            .collect();
+        let targets: Vec<(Backend, &str)> = dests
+            .iter()
+            .zip(&dst_paths)
+            .map(|((_, b, _), p)| (b.clone(), p.as_str()))
+            .collect();
+        let src = (sources.len() > 1).then_some(src_path.as_str());
+        match transfer(src_path, &targets) {
+            Ok(results) => {
+                for (i, result) in results.iter().enumerate() {
+                    if let Err(e) = result {
+                        report(i, src, e);
+                    }
+                }
+            }
+            Err(e) => (0..dests.len()).for_each(|i| report(i, src, &e)),
+        }
+    }
+    for (i, (_, backend, _)) in dests.iter().enumerate() {
+        if let Err(e) = backend.finish() {
+            report(i, None, &e);
+        }
+    }
+
+    let failures = failed.iter().filter(|f| **f).count();
+    if failures == 0 {
+        eprintln!("{verb} completed successfully.");
+        return;
+    }
+    if failures < dests.len() {
+        eprintln!(
+            "{verb} completed for {} of {} destinations.",
+            dests.len() - failures,
+            dests.len()
+        );
     }
+    std::process::exit(1);
 }
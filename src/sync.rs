@@ -1,65 +1,90 @@
-use crate::backends::{StorageBackend, SyncError};
+use crate::backends::{FileKind, FileMeta, StorageBackend, SyncError};
+use crate::delta::{self, Signature};
+use crate::utils::{
+    copy_to_targets, finalize_dirs, join_rel, local_copy, pipelined, preserved_meta,
+    recreate_specials, same_mtime, Batches, SourceEntry, Specials,
+};
 use indicatif::{ProgressBar, ProgressStyle};
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use walkdir::WalkDir;
 
-pub const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
 pub const LARGE_FILE_THRESHOLD: u64 = 32 * 1024 * 1024;
+/// Changed files at least this large are sent as deltas to destinations
+/// that can apply them.
+pub const DELTA_THRESHOLD: u64 = 64 * 1024;
 
 struct FileJob {
-    src_path: PathBuf,
-    dst_path: PathBuf,
-    size: u64,
-    src_modified: Option<std::time::SystemTime>,
+    src_path: String,
+    rel_path: PathBuf,
+    meta: FileMeta,
 }
 
 pub fn sync(
-    _src_backend: Arc<dyn StorageBackend + Send + Sync>,
+    src_backend: Arc<dyn StorageBackend + Send + Sync>,
     src_root: &str,
-    _dst_backend: Arc<dyn StorageBackend + Send + Sync>,
+    dst_backend: Arc<dyn StorageBackend + Send + Sync>,
     dst_root: &str,
-    _chunk_size: usize,
     no_progress: bool,
+    atimes: bool,
 ) -> Result<(), SyncError> {
+    sync_to_all(
+        src_backend,
+        src_root,
+        &[(dst_backend, dst_root)],
+        no_progress,
+        atimes,
+        Specials::default(),
+    )?
+    .pop()
+    .unwrap()
+}
+
+/// Syncs `src_root` to every destination in one pass. Each destination is
+/// compared on its own; a file that differs on several is read once and
+/// streamed to all of them concurrently. The outer error is a failed walk of
+/// the source; otherwise there is one result per destination, in order.
+pub fn sync_to_all(
+    src_backend: Arc<dyn StorageBackend + Send + Sync>,
+    src_root: &str,
+    dsts: &[(Arc<dyn StorageBackend + Send + Sync>, &str)],
+    no_progress: bool,
+    atimes: bool,
+    specials: Specials,
+) -> Result<Vec<Result<(), SyncError>>, SyncError> {
     let src_root_path = Path::new(src_root);
-    let dst_root_path = Path::new(dst_root);
 
     let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    let mut special_files = Vec::new();
     let mut total_bytes = 0u64;
-    for entry in WalkDir::new(src_root).min_depth(0) {
-        let entry = entry.map_err(|e| SyncError::Other(format!("WalkDir error: {e}")))?;
-        let src_path = entry.path();
-        let rel_path = match src_path.strip_prefix(src_root_path) {
-            Ok(p) => p,
-            Err(_) => continue,
+    src_backend.walk(src_root, &mut |entry| {
+        let rel_path = match Path::new(&entry.path).strip_prefix(src_root_path) {
+            Ok(p) => p.to_path_buf(),
+            Err(_) => return,
         };
-        let dst_path: PathBuf = dst_root_path.join(rel_path);
-        let file_type = entry.file_type();
-        if file_type.is_dir() {
-            if !dst_path.exists() {
-                std::fs::create_dir_all(&dst_path).map_err(|e| {
-                    SyncError::Other(format!("Failed to create dir {:?}: {e}", dst_path))
-                })?;
+        match entry.metadata.kind {
+            FileKind::Dir => dirs.push(SourceEntry {
+                rel_path,
+                metadata: entry.metadata,
+            }),
+            FileKind::File => {
+                total_bytes += entry.metadata.size;
+                files.push(FileJob {
+                    rel_path,
+                    src_path: entry.path,
+                    meta: entry.metadata,
+                });
             }
-        } else if file_type.is_file() {
-            let meta = entry.metadata();
-            let (size, src_modified) = match meta {
-                Ok(m) => (m.len(), m.modified().ok()),
-                Err(_) => (0, None),
-            };
-            total_bytes += size;
-            files.push(FileJob {
-                src_path: src_path.to_path_buf(),
-                dst_path,
-                size,
-                src_modified,
-            });
+            kind if specials.wants(kind) => special_files.push(SourceEntry {
+                rel_path,
+                metadata: entry.metadata,
+            }),
+            _ => {}
         }
-    }
+    })?;
 
     let pb = if no_progress {
         None
@@ -80,15 +105,35 @@ pub fn sync(
     let index = Arc::new(AtomicUsize::new(0));
     let total_files = files.len();
     let num_threads = num_cpus::get().max(2);
+    let src_local = src_backend.local_path(src_root).is_some();
+    let dsts: Arc<Vec<_>> = Arc::new(
+        dsts.iter()
+            .map(|(dst_backend, dst_root)| Destination {
+                precision: src_backend
+                    .time_precision()
+                    .max(dst_backend.time_precision()),
+                both_local: src_local && dst_backend.local_path(dst_root).is_some(),
+                backend: Arc::clone(dst_backend),
+                root: dst_root.to_string(),
+            })
+            .collect(),
+    );
+    let errors: Arc<Vec<Mutex<Vec<SyncError>>>> =
+        Arc::new(dsts.iter().map(|_| Mutex::new(Vec::new())).collect());
     let mut workers = Vec::new();
     let pb_shared = pb.clone();
 
     for _ in 0..num_threads {
         let files = Arc::clone(&files);
         let index = Arc::clone(&index);
+        let src_backend = Arc::clone(&src_backend);
+        let dsts = Arc::clone(&dsts);
+        let errors = Arc::clone(&errors);
         let pb_worker = pb_shared.clone();
         workers.push(thread::spawn(move || {
-            let mut created_dirs = HashSet::new();
+            let mut created_dirs: Vec<HashSet<PathBuf>> =
+                dsts.iter().map(|_| HashSet::new()).collect();
+            let mut batches = Batches::new(dsts.iter().map(|d| d.backend.as_ref()).collect());
             loop {
                 let i = index.fetch_add(1, Ordering::Relaxed);
                 if i >= total_files {
@@ -96,36 +141,114 @@ pub fn sync(
                 }
                 let file = &files[i];
 
-                let dst_meta = std::fs::metadata(&file.dst_path).ok();
-                let mut skipped = false;
-                if let Some(ref dm) = dst_meta {
-                    if file.size == dm.len() {
-                        if let (Some(st), Ok(dt)) = (file.src_modified, dm.modified()) {
-                            if st == dt {
-                                if let Some(ref pb) = pb_worker {
-                                    pb.inc(file.size);
+                let dst_meta = preserved_meta(&file.meta, true, atimes);
+                let mut src_hash = None;
+                let mut targets = Vec::new();
+                let mut deltas = Vec::new();
+                for (d, dst) in dsts.iter().enumerate() {
+                    let dst_path = join_rel(&dst.root, &file.rel_path);
+                    let existing = dst.backend.stat(&dst_path).ok();
+                    let skipped = existing.as_ref().is_some_and(|dm| {
+                        file.meta.size == dm.size
+                            && matches!(
+                                (file.meta.modified, dm.modified),
+                                (Some(st), Some(dt)) if same_mtime(st, dt, dst.precision)
+                            )
+                    });
+                    if skipped {
+                        continue;
+                    }
+                    if let Some(dm) = existing.filter(|dm| dm.is_file() && !dst.both_local) {
+                        match reusable(
+                            src_backend.as_ref(),
+                            file,
+                            &mut src_hash,
+                            dst,
+                            &dst_path,
+                            &dm,
+                        ) {
+                            Reuse::Unchanged => {
+                                if let Err(e) = dst.backend.set_metadata(&dst_path, &dst_meta) {
+                                    errors[d].lock().unwrap().push(e);
                                 }
-                                skipped = true;
+                                continue;
+                            }
+                            Reuse::Delta(basis) => {
+                                deltas.push((d, dst_path, basis));
+                                continue;
+                            }
+                            Reuse::Nothing => {}
+                        }
+                    }
+                    if let Some(parent) = Path::new(&dst_path).parent() {
+                        if !created_dirs[d].contains(parent) {
+                            if let Err(e) = dst.backend.mkdir(&parent.to_string_lossy()) {
+                                errors[d].lock().unwrap().push(e);
+                                continue;
                             }
+                            created_dirs[d].insert(parent.to_path_buf());
                         }
                     }
+                    targets.push((d, dst_path));
                 }
-                if skipped {
-                    continue;
+
+                for (d, dst_path, basis) in deltas {
+                    let dst = &dsts[d];
+                    let patched =
+                        src_backend
+                            .open_read(&file.src_path, 0, None)
+                            .and_then(|mut reader| {
+                                pipelined(&mut reader, |reader| {
+                                    dst.backend.put_delta(&dst_path, &basis, reader, &dst_meta)
+                                })
+                            });
+                    if let Err(e) = patched {
+                        log::warn!("Delta transfer to {dst_path} failed, copying it whole: {e:?}");
+                        targets.push((d, dst_path));
+                    }
                 }
 
-                if let Some(parent) = file.dst_path.parent() {
-                    if !created_dirs.contains(parent) {
-                        let _ = std::fs::create_dir_all(parent);
-                        created_dirs.insert(parent.to_path_buf());
+                let (batched, targets): (Vec<_>, Vec<_>) = targets
+                    .into_iter()
+                    .partition(|(d, _)| batches.takes(*d, file.meta.size));
+                for (d, e) in batches.add(src_backend.as_ref(), &file.src_path, &dst_meta, &batched)
+                {
+                    errors[d].lock().unwrap().push(e);
+                }
+                let fast = match targets.as_slice() {
+                    [(d, dst_path)] => local_copy(
+                        src_backend.as_ref(),
+                        &file.src_path,
+                        dsts[*d].backend.as_ref(),
+                        dst_path,
+                        file.meta.size,
+                    ),
+                    _ => false,
+                };
+                let results = if fast {
+                    let (d, dst_path) = &targets[0];
+                    vec![dsts[*d].backend.set_metadata(dst_path, &dst_meta)]
+                } else if targets.is_empty() {
+                    Vec::new()
+                } else {
+                    let writes: Vec<_> = targets
+                        .iter()
+                        .map(|(d, dst_path)| (dsts[*d].backend.as_ref(), dst_path.clone()))
+                        .collect();
+                    copy_to_targets(src_backend.as_ref(), &file.src_path, &writes, &dst_meta)
+                };
+                for ((d, _), result) in targets.iter().zip(results) {
+                    if let Err(e) = result {
+                        errors[*d].lock().unwrap().push(e);
                     }
                 }
-                let copied =
-                    fast_copy(&file.src_path, &file.dst_path, file.size, file.src_modified);
                 if let Some(ref pb) = pb_worker {
-                    pb.inc(copied);
+                    pb.inc(file.meta.size);
                 }
             }
+            for (d, e) in batches.finish() {
+                errors[d].lock().unwrap().push(e);
+            }
         }));
     }
 
@@ -136,92 +259,91 @@ pub fn sync(
         pb.finish_with_message("Sync complete");
     }
 
-    Ok(())
+    let errors = Arc::try_unwrap(errors).ok().unwrap();
+    Ok(dsts
+        .iter()
+        .zip(errors)
+        .map(|(dst, errors)| {
+            let mut errors = errors.into_inner().unwrap();
+            errors.extend(recreate_specials(
+                src_backend.as_ref(),
+                src_root,
+                dst.backend.as_ref(),
+                &dst.root,
+                &special_files,
+                true,
+                atimes,
+            ));
+            errors.extend(finalize_dirs(
+                dst.backend.as_ref(),
+                &mut dirs.clone(),
+                &dst.root,
+                true,
+                atimes,
+            ));
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(SyncError::Other(format!(
+                    "{} errors occurred during sync",
+                    errors.len()
+                )))
+            }
+        })
+        .collect())
 }
 
-#[cfg(target_os = "linux")]
-fn fast_copy(
-    src: &Path,
-    dst: &Path,
-    size: u64,
-    src_modified: Option<std::time::SystemTime>,
-) -> u64 {
-    use std::fs::OpenOptions;
-    use std::os::unix::io::AsRawFd;
-
-    let mut copied_bytes: u64 = 0;
-
-    let src_f = match OpenOptions::new().read(true).open(src) {
-        Ok(f) => f,
-        Err(_) => return 0,
-    };
-    let dst_f = match OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(dst)
-    {
-        Ok(f) => f,
-        Err(_) => return 0,
-    };
-
-    unsafe {
-        const FICLONE: libc::c_ulong = 0x4004_9409;
-        if libc::ioctl(dst_f.as_raw_fd(), FICLONE, src_f.as_raw_fd()) == 0 {
-            copied_bytes = size;
-        } else {
-            let in_fd = src_f.as_raw_fd();
-            let out_fd = dst_f.as_raw_fd();
-            let mut off_in: libc::loff_t = 0;
-            let mut off_out: libc::loff_t = 0;
-            loop {
-                let n = libc::copy_file_range(in_fd, &mut off_in, out_fd, &mut off_out, 1 << 30, 0);
-                if n <= 0 {
-                    break;
-                }
-                copied_bytes = copied_bytes.saturating_add(n as u64);
-                if copied_bytes >= size {
-                    break;
-                }
-            }
+/// What a changed file can make of its existing copy on a destination.
+enum Reuse {
+    /// Same contents; only the metadata needs updating.
+    Unchanged,
+    /// Send a delta against this signature of the copy.
+    Delta(Signature),
+    Nothing,
+}
 
-            if copied_bytes == 0 {
-                let mut offset: libc::off_t = 0;
-                loop {
-                    let n = libc::sendfile(out_fd, in_fd, &mut offset, 1 << 30);
-                    if n <= 0 {
-                        break;
-                    }
-                    copied_bytes = copied_bytes.saturating_add(n as u64);
-                    if copied_bytes >= size {
-                        break;
-                    }
-                }
+/// Compares hashes when the sizes match, then asks for a signature if the
+/// file is large enough for a delta to pay off. Only backends that compute
+/// these where the data lives take part; failures fall back to a full copy.
+fn reusable(
+    src_backend: &(dyn StorageBackend + Send + Sync),
+    file: &FileJob,
+    src_hash: &mut Option<Option<blake3::Hash>>,
+    dst: &Destination,
+    dst_path: &str,
+    dm: &FileMeta,
+) -> Reuse {
+    if file.meta.size == dm.size {
+        if let Ok(Some(theirs)) = dst.backend.hash(dst_path) {
+            let ours = src_hash.get_or_insert_with(|| {
+                src_backend.hash(&file.src_path).unwrap_or_else(|e| {
+                    log::debug!("Cannot hash {}: {e:?}", file.src_path);
+                    None
+                })
+            });
+            if *ours == Some(theirs) {
+                return Reuse::Unchanged;
             }
         }
     }
-
-    if copied_bytes == 0 {
-        copied_bytes = std::fs::copy(src, dst).unwrap_or(0);
-    }
-
-    if let Some(st) = src_modified {
-        let _ = filetime::set_file_mtime(dst, filetime::FileTime::from_system_time(st));
+    if file.meta.size >= DELTA_THRESHOLD && dm.size >= DELTA_THRESHOLD {
+        match dst
+            .backend
+            .signature(dst_path, delta::block_size(file.meta.size))
+        {
+            Ok(Some(basis)) => return Reuse::Delta(basis),
+            Ok(None) => {}
+            Err(e) => log::debug!("No signature for {dst_path}: {e:?}"),
+        }
     }
-
-    copied_bytes
+    Reuse::Nothing
 }
 
-#[cfg(not(target_os = "linux"))]
-fn fast_copy(
-    src: &Path,
-    dst: &Path,
-    _size: u64,
-    src_modified: Option<std::time::SystemTime>,
-) -> u64 {
-    let copied = std::fs::copy(src, dst).unwrap_or(0);
-    if let Some(st) = src_modified {
-        let _ = filetime::set_file_mtime(dst, filetime::FileTime::from_system_time(st));
-    }
-    copied
+/// One destination of [`sync_to_all`].
+struct Destination {
+    backend: Arc<dyn StorageBackend + Send + Sync>,
+    root: String,
+    /// Coarser timestamp precision of the source and this destination.
+    precision: std::time::Duration,
+    both_local: bool,
 }
@@ -1 +1,522 @@
+use crate::backends::{BatchFile, FileKind, FileMeta, StorageBackend, SyncError};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+/// A source directory or special file recorded during a walk, relative to the
+/// source root.
+#[derive(Clone)]
+pub struct SourceEntry {
+    pub rel_path: PathBuf,
+    pub metadata: FileMeta,
+}
+
+/// Joins `rel_path` onto `root`, returning `root` itself for the empty path
+/// so a walk rooted at a single file maps onto the destination path.
+pub fn join_rel(root: &str, rel_path: &Path) -> String {
+    if rel_path.as_os_str().is_empty() {
+        root.to_string()
+    } else {
+        Path::new(root).join(rel_path).to_string_lossy().to_string()
+    }
+}
+
+/// The subset of source metadata to apply at the destination: permissions
+/// always, the mtime unless `preserve_times` is off, the atime only with
+/// `atimes`.
+pub fn preserved_meta(meta: &FileMeta, preserve_times: bool, atimes: bool) -> FileMeta {
+    FileMeta {
+        modified: meta.modified.filter(|_| preserve_times),
+        accessed: meta.accessed.filter(|_| preserve_times && atimes),
+        ..meta.clone()
+    }
+}
+
+/// Recreates every directory in `dirs` under `dest_root`, then applies the
+/// source permissions and (optionally) modification times deepest-first.
+///
+/// Must run after all file writes have finished: creating an entry inside a
+/// directory bumps its mtime, and a read-only directory would reject writes.
+pub fn finalize_dirs(
+    dest: &dyn StorageBackend,
+    dirs: &mut [SourceEntry],
+    dest_root: &str,
+    preserve_times: bool,
+    atimes: bool,
+) -> Vec<SyncError> {
+    let mut errors = Vec::new();
+
+    for dir in dirs.iter() {
+        if let Err(e) = dest.mkdir(&join_rel(dest_root, &dir.rel_path)) {
+            errors.push(e);
+        }
+    }
+
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.rel_path.components().count()));
+
+    for dir in dirs.iter() {
+        let meta = preserved_meta(&dir.metadata, preserve_times, atimes);
+        if let Err(e) = dest.set_metadata(&join_rel(dest_root, &dir.rel_path), &meta) {
+            errors.push(e);
+        }
+    }
+
+    errors
+}
+
+/// Which entries other than files and directories `copy` and `sync`
+/// recreate: device nodes (`--devices`), FIFOs and sockets (`--specials`)
+/// and symlinks (`--links`). The rest are skipped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Specials {
+    pub devices: bool,
+    pub specials: bool,
+    pub links: bool,
+}
+
+impl Specials {
+    pub fn wants(&self, kind: FileKind) -> bool {
+        match kind {
+            FileKind::Fifo | FileKind::Socket => self.specials,
+            FileKind::CharDevice | FileKind::BlockDevice => self.devices,
+            FileKind::Symlink => self.links,
+            FileKind::File | FileKind::Dir => false,
+        }
+    }
+}
+
+/// Recreates the symlinks and special files in `entries` under `dest_root`,
+/// leaving alone those the destination already has with the same target or
+/// kind and device number. Permissions and times are applied to special
+/// files, not to links. Returns the failures.
+pub fn recreate_specials(
+    source: &dyn StorageBackend,
+    source_root: &str,
+    dest: &dyn StorageBackend,
+    dest_root: &str,
+    entries: &[SourceEntry],
+    preserve_times: bool,
+    atimes: bool,
+) -> Vec<SyncError> {
+    let mut errors = Vec::new();
+    for entry in entries {
+        let dst = join_rel(dest_root, &entry.rel_path);
+        if let Some(parent) = Path::new(&dst).parent() {
+            if let Err(e) = dest.mkdir(&parent.to_string_lossy()) {
+                errors.push(e);
+                continue;
+            }
+        }
+        let result = if entry.metadata.kind == FileKind::Symlink {
+            source
+                .read_link(&join_rel(source_root, &entry.rel_path))
+                .and_then(|target| match dest.read_link(&dst) {
+                    Ok(existing) if existing == target => Ok(()),
+                    _ => dest.symlink(&target, &dst),
+                })
+        } else {
+            let same = dest.stat(&dst).is_ok_and(|existing| {
+                existing.kind == entry.metadata.kind && existing.rdev == entry.metadata.rdev
+            });
+            let created = if same {
+                Ok(())
+            } else {
+                dest.mknod(&dst, &entry.metadata)
+            };
+            created.and_then(|_| {
+                dest.set_metadata(
+                    &dst,
+                    &preserved_meta(&entry.metadata, preserve_times, atimes),
+                )
+            })
+        };
+        if let Err(e) = result {
+            errors.push(e);
+        }
+    }
+    errors
+}
+
+/// Sets the mtime (and the atime, when given) of a local path with full
+/// nanosecond precision. Does nothing when `modified` is `None`.
+pub fn set_local_times(
+    path: &Path,
+    modified: Option<SystemTime>,
+    accessed: Option<SystemTime>,
+) -> std::io::Result<()> {
+    let Some(modified) = modified else {
+        return Ok(());
+    };
+    let mtime = filetime::FileTime::from_system_time(modified);
+    match accessed {
+        Some(at) => filetime::set_file_times(path, filetime::FileTime::from_system_time(at), mtime),
+        None => filetime::set_file_mtime(path, mtime),
+    }
+}
+
+/// Compares two mtimes after truncating both to `precision`, so a timestamp
+/// that went through a whole-second backend still matches its source.
+pub fn same_mtime(a: SystemTime, b: SystemTime, precision: Duration) -> bool {
+    let step = precision.as_nanos().max(1);
+    let truncate = |t: SystemTime| {
+        t.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() / step)
+            .ok()
+    };
+    truncate(a) == truncate(b)
+}
+
+/// Parses a fractional-seconds suffix (`"5"`, `"000000005"`) into
+/// nanoseconds.
+pub fn parse_nanos(frac: &str) -> Option<u32> {
+    if frac.is_empty() {
+        return Some(0);
+    }
+    let digits: String = frac.chars().chain(std::iter::repeat('0')).take(9).collect();
+    digits.parse().ok()
+}
+
+/// Formats an mtime as `seconds.nanoseconds` for backends that keep it in
+/// object metadata or a custom property.
+pub fn format_mtime(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:09}", d.as_secs(), d.subsec_nanos())
+}
+
+/// Parses an mtime written by [`format_mtime`].
+pub fn parse_mtime(s: &str) -> Option<SystemTime> {
+    let (secs, frac) = s.split_once('.').unwrap_or((s, ""));
+    Some(UNIX_EPOCH + Duration::new(secs.parse().ok()?, parse_nanos(frac)?))
+}
+
+const TEE_BLOCK: usize = 256 * 1024;
+const TEE_DEPTH: usize = 8;
+
+/// Copies `src_file` to `dst_file` inside the kernel when both backends
+/// are local directories, returning whether it did. The caller still
+/// applies metadata; on `false` it copies through the backends instead.
+pub fn local_copy(
+    source: &dyn StorageBackend,
+    src_file: &str,
+    dest: &dyn StorageBackend,
+    dst_file: &str,
+    size: u64,
+) -> bool {
+    match (source.local_path(src_file), dest.local_path(dst_file)) {
+        (Some(src), Some(dst)) => fast_copy(&src, &dst, size).is_ok(),
+        _ => false,
+    }
+}
+
+/// Reflinks `src` to `dst` where the filesystem allows, else copies with
+/// `copy_file_range` or `sendfile`, falling back to `std::fs::copy`.
+#[cfg(target_os = "linux")]
+fn fast_copy(src: &Path, dst: &Path, size: u64) -> std::io::Result<u64> {
+    use std::fs::OpenOptions;
+    use std::os::unix::io::AsRawFd;
+
+    let mut copied_bytes: u64 = 0;
+
+    let src_f = OpenOptions::new().read(true).open(src)?;
+    let dst_f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dst)?;
+
+    unsafe {
+        const FICLONE: libc::c_ulong = 0x4004_9409;
+        if libc::ioctl(dst_f.as_raw_fd(), FICLONE, src_f.as_raw_fd()) == 0 {
+            copied_bytes = size;
+        } else {
+            let in_fd = src_f.as_raw_fd();
+            let out_fd = dst_f.as_raw_fd();
+            let mut off_in: libc::loff_t = 0;
+            let mut off_out: libc::loff_t = 0;
+            loop {
+                let n = libc::copy_file_range(in_fd, &mut off_in, out_fd, &mut off_out, 1 << 30, 0);
+                if n <= 0 {
+                    break;
+                }
+                copied_bytes = copied_bytes.saturating_add(n as u64);
+                if copied_bytes >= size {
+                    break;
+                }
+            }
+
+            if copied_bytes == 0 {
+                let mut offset: libc::off_t = 0;
+                loop {
+                    let n = libc::sendfile(out_fd, in_fd, &mut offset, 1 << 30);
+                    if n <= 0 {
+                        break;
+                    }
+                    copied_bytes = copied_bytes.saturating_add(n as u64);
+                    if copied_bytes >= size {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    if copied_bytes == 0 {
+        copied_bytes = std::fs::copy(src, dst)?;
+    }
+
+    Ok(copied_bytes)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fast_copy(src: &Path, dst: &Path, _size: u64) -> std::io::Result<u64> {
+    std::fs::copy(src, dst)
+}
+
+type Block = std::io::Result<Arc<[u8]>>;
+
+/// Copies `src_file` to every target, reading the source once and streaming
+/// each block to all targets concurrently. Returns one result per target, in
+/// order. A target that fails stops receiving blocks while the others carry
+/// on; each waits for the slowest only as far as its bounded queue allows.
+/// A lone target is written straight from a local source and through
+/// [`pipelined`] from any other.
+pub fn copy_to_targets(
+    source: &(dyn StorageBackend + Send + Sync),
+    src_file: &str,
+    targets: &[(&(dyn StorageBackend + Send + Sync), String)],
+    meta: &FileMeta,
+) -> Vec<Result<(), SyncError>> {
+    let mut reader = match source.open_read(src_file, 0, None) {
+        Ok(reader) => reader,
+        Err(e) => {
+            let message = format!("{e:?}");
+            let mut results = vec![Err(e)];
+            results.extend((1..targets.len()).map(|_| Err(SyncError::Other(message.clone()))));
+            return results;
+        }
+    };
+    if let [(dest, dst_file)] = targets {
+        if source.local_path(src_file).is_some() {
+            return vec![dest.put_with_meta(dst_file, &mut reader, meta)];
+        }
+        return vec![pipelined(&mut reader, |reader| {
+            dest.put_with_meta(dst_file, reader, meta)
+        })];
+    }
+
+    std::thread::scope(|s| {
+        let mut senders = Vec::new();
+        let writers: Vec<_> = targets
+            .iter()
+            .map(|(dest, dst_file)| {
+                let (tx, rx) = crossbeam_channel::bounded(TEE_DEPTH);
+                senders.push(Some(tx));
+                s.spawn(move || {
+                    let mut reader = TeeReader {
+                        rx,
+                        block: Arc::from(&[][..]),
+                        pos: 0,
+                    };
+                    dest.put_with_meta(dst_file, &mut reader, meta)
+                })
+            })
+            .collect();
+
+        feed(&mut reader, &mut senders);
+        drop(senders);
+
+        writers
+            .into_iter()
+            .map(|w| w.join().expect("Tee writer panicked"))
+            .collect()
+    })
+}
+
+/// Runs `write` on the contents of `reader` while another thread reads
+/// ahead, so a transfer between two remote backends keeps both connections
+/// busy instead of taking turns. At most `TEE_DEPTH` blocks are in flight.
+pub fn pipelined<T>(reader: &mut (dyn Read + Send), write: impl FnOnce(&mut dyn Read) -> T) -> T {
+    let (tx, rx) = crossbeam_channel::bounded(TEE_DEPTH);
+    std::thread::scope(|s| {
+        s.spawn(move || feed(reader, &mut [Some(tx)]));
+        let mut reader = TeeReader {
+            rx,
+            block: Arc::from(&[][..]),
+            pos: 0,
+        };
+        write(&mut reader)
+    })
+}
+
+/// Reads `reader` to the end in `TEE_BLOCK`s, sending each to every queue
+/// still open. Stops early on a read error, which is passed on, or once
+/// every reader has gone.
+fn feed(reader: &mut dyn Read, senders: &mut [Option<crossbeam_channel::Sender<Block>>]) {
+    let mut buf = vec![0; TEE_BLOCK];
+    loop {
+        let block = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => Ok(Arc::from(&buf[..n])),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => Err(e),
+        };
+        let failed = block.is_err();
+        for sender in senders.iter_mut() {
+            let block = match &block {
+                Ok(data) => Ok(Arc::clone(data)),
+                Err(e) => Err(std::io::Error::new(e.kind(), e.to_string())),
+            };
+            if sender.as_ref().is_some_and(|tx| tx.send(block).is_err()) {
+                *sender = None;
+            }
+        }
+        if failed || senders.iter().all(Option::is_none) {
+            break;
+        }
+    }
+}
+
+/// The reading end of one [`copy_to_targets`] or [`pipelined`] queue.
+struct TeeReader {
+    rx: crossbeam_channel::Receiver<Block>,
+    block: Arc<[u8]>,
+    pos: usize,
+}
+
+impl Read for TeeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos == self.block.len() {
+            match self.rx.recv() {
+                Ok(block) => {
+                    self.block = block?;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Bytes of file data that make a batch full.
+const BATCH_BYTES: usize = 8 << 20;
+/// Files that make a batch full.
+const BATCH_FILES: usize = 1000;
+
+/// Small files one worker has collected for the destinations that take
+/// them in batches (see [`StorageBackend::batch_threshold`]).
+pub struct Batches<'a> {
+    dests: Vec<&'a (dyn StorageBackend + Send + Sync)>,
+    thresholds: Vec<Option<u64>>,
+    pending: Vec<(Vec<BatchFile>, usize)>,
+}
+
+impl<'a> Batches<'a> {
+    pub fn new(dests: Vec<&'a (dyn StorageBackend + Send + Sync)>) -> Self {
+        Self {
+            thresholds: dests.iter().map(|d| d.batch_threshold()).collect(),
+            pending: dests.iter().map(|_| (Vec::new(), 0)).collect(),
+            dests,
+        }
+    }
+
+    /// Whether destination `d` takes a file of `size` bytes in a batch.
+    pub fn takes(&self, d: usize, size: u64) -> bool {
+        self.thresholds[d].is_some_and(|threshold| size < threshold)
+    }
+
+    /// Reads `src_file` once and queues it for every target, writing any
+    /// batch that fills up. Returns the failures by destination index.
+    pub fn add(
+        &mut self,
+        source: &(dyn StorageBackend + Send + Sync),
+        src_file: &str,
+        meta: &FileMeta,
+        targets: &[(usize, String)],
+    ) -> Vec<(usize, SyncError)> {
+        if targets.is_empty() {
+            return Vec::new();
+        }
+        let data = match source.open_read(src_file, 0, None).and_then(|mut reader| {
+            let mut data = Vec::with_capacity(meta.size as usize);
+            reader.read_to_end(&mut data)?;
+            Ok(data)
+        }) {
+            Ok(data) => data,
+            Err(e) => {
+                let message = format!("{e:?}");
+                return targets
+                    .iter()
+                    .map(|(d, _)| (*d, SyncError::Other(message.clone())))
+                    .collect();
+            }
+        };
+        let mut errors = Vec::new();
+        for (d, path) in targets {
+            let (files, bytes) = &mut self.pending[*d];
+            *bytes += data.len();
+            files.push(BatchFile {
+                path: path.clone(),
+                meta: meta.clone(),
+                data: data.clone(),
+            });
+            if *bytes >= BATCH_BYTES || files.len() >= BATCH_FILES {
+                errors.extend(self.flush(*d));
+            }
+        }
+        errors
+    }
+
+    fn flush(&mut self, d: usize) -> Vec<(usize, SyncError)> {
+        let (files, _) = std::mem::take(&mut self.pending[d]);
+        if files.is_empty() {
+            return Vec::new();
+        }
+        self.dests[d]
+            .put_batch(&files)
+            .into_iter()
+            .filter_map(|result| result.err().map(|e| (d, e)))
+            .collect()
+    }
+
+    /// Writes every batch still pending.
+    pub fn finish(mut self) -> Vec<(usize, SyncError)> {
+        (0..self.dests.len()).flat_map(|d| self.flush(d)).collect()
+    }
+}
+
+/// Removes a walked tree: `files` on `threads` workers, then `dirs`
+/// deepest-first, so each directory is empty by the time it is removed.
+/// `done` sees the outcome of every removal, from any worker.
+pub fn remove_all(
+    files: Vec<String>,
+    mut dirs: Vec<String>,
+    threads: usize,
+    remove_file: impl Fn(&str) -> Result<(), SyncError> + Sync,
+    remove_dir: impl Fn(&str) -> Result<(), SyncError>,
+    done: impl Fn(&str, Result<(), SyncError>) + Sync,
+) {
+    let (tx, rx) = crossbeam_channel::unbounded::<String>();
+    for file in files {
+        let _ = tx.send(file);
+    }
+    drop(tx);
+    std::thread::scope(|s| {
+        for _ in 0..threads.max(1) {
+            let (rx, remove_file, done) = (rx.clone(), &remove_file, &done);
+            s.spawn(move || {
+                while let Ok(file) = rx.recv() {
+                    done(&file, remove_file(&file));
+                }
+            });
+        }
+    });
+    dirs.sort_by_key(|dir| std::cmp::Reverse(Path::new(dir).components().count()));
+    for dir in dirs {
+        done(&dir, remove_dir(&dir));
+    }
+}
@@ -0,0 +1,464 @@
+mod common;
+
+use parsync::agent::Agent;
+use parsync::backends::{
+    backend_and_path, BatchFile, FileEntry, FileMeta, LocalBackend, StorageBackend, SyncError,
+};
+use parsync::delta::{self, DeltaOp, Patcher, Signature};
+use std::fs;
+use std::io::{Cursor, Read};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::tempdir;
+
+/// Deterministic bytes that don't repeat at block scale.
+/// Diffs `new` against `old` and patches `old` with the result; returns the
+/// ops sent.
+fn round_trip(old: &[u8], new: &[u8], block_size: u32) -> Vec<DeltaOp> {
+    let basis = Signature::compute(&mut Cursor::new(old), block_size).unwrap();
+    let mut ops = Vec::new();
+    let (hash, len) = delta::diff(&basis, &mut Cursor::new(new), &mut |op| {
+        ops.push(op);
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(hash, blake3::hash(new));
+    assert_eq!(len, new.len() as u64);
+
+    let mut patcher = Patcher::new(Cursor::new(old), block_size, Vec::new());
+    for op in &ops {
+        patcher.apply(op).unwrap();
+    }
+    let (out, patched_hash, _) = patcher.finish().unwrap();
+    assert!(out == new, "patched output differs");
+    assert_eq!(patched_hash, hash);
+    ops
+}
+
+fn literal(ops: &[DeltaOp]) -> usize {
+    ops.iter()
+        .map(|op| match op {
+            DeltaOp::Data(data) => data.len(),
+            DeltaOp::Copy { .. } => 0,
+        })
+        .sum()
+}
+
+#[test]
+/// Blocks are found again after insertions shift them, runs of blocks
+/// merge, and only the changed bytes are sent
+fn test_delta_round_trip() {
+    let old = common::noise(1 << 20, 1);
+    let bs = delta::block_size(old.len() as u64);
+    assert_eq!(bs, 1024 * 2);
+
+    let ops = round_trip(&old, &old, bs);
+    assert_eq!(
+        ops,
+        [DeltaOp::Copy {
+            block: 0,
+            count: (old.len() / bs as usize) as u64
+        }]
+    );
+
+    let mut new = b"inserted at the front".to_vec();
+    new.extend_from_slice(&old[..300_000]);
+    new.extend_from_slice(&common::noise(5000, 2));
+    new.extend_from_slice(&old[310_000..]);
+    let ops = round_trip(&old, &new, bs);
+    assert!(
+        literal(&ops) < 5000 + 21 + 2 * bs as usize,
+        "{}",
+        literal(&ops)
+    );
+
+    // Nothing to reuse, or nothing to send.
+    assert_eq!(literal(&round_trip(&[], &new, bs)), new.len());
+    assert_eq!(
+        literal(&round_trip(&common::noise(10_000, 3), &new, bs)),
+        new.len()
+    );
+    assert!(round_trip(&old, &[], bs).is_empty());
+    round_trip(&old[..1000], &old[..999], bs);
+}
+
+struct Server {
+    child: Child,
+    agent: Option<Agent<ChildStdout, ChildStdin>>,
+}
+
+impl Server {
+    fn start() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_parsync"))
+            .arg("--server")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let agent =
+            Agent::handshake(child.stdout.take().unwrap(), child.stdin.take().unwrap()).unwrap();
+        Self {
+            child,
+            agent: Some(agent),
+        }
+    }
+
+    fn agent(&mut self) -> &mut Agent<ChildStdout, ChildStdin> {
+        self.agent.as_mut().unwrap()
+    }
+
+    /// Closes the server's stdin and waits for it to exit.
+    fn stop(mut self) -> bool {
+        self.agent = None;
+        self.child.wait().unwrap().success()
+    }
+}
+
+fn meta_with_mtime(path: &str, secs: u64) -> FileMeta {
+    FileMeta {
+        modified: Some(UNIX_EPOCH + Duration::new(secs, 123_456_789)),
+        accessed: None,
+        mode: Some(0o600),
+        ..LocalBackend::new().stat(path).unwrap()
+    }
+}
+
+#[test]
+/// Walks, stats, hashes and patches against `parsync --server`; failed
+/// requests are answered without ending the session
+fn test_server_requests() {
+    let dir = tempdir().unwrap();
+    let root = dir.path().to_str().unwrap();
+    fs::create_dir(dir.path().join("sub")).unwrap();
+    let old = common::noise(200_000, 4);
+    fs::write(dir.path().join("sub/big"), &old).unwrap();
+    fs::write(dir.path().join("small"), b"hello").unwrap();
+
+    let mut server = Server::start();
+    let agent = server.agent();
+    let mut walked = Vec::new();
+    agent
+        .walk(root, &mut |entry| {
+            walked.push((entry.path, entry.metadata.size))
+        })
+        .unwrap();
+    walked.sort();
+    let big = format!("{root}/sub/big");
+    assert_eq!(walked.len(), 4);
+    assert!(walked.contains(&(big.clone(), 200_000)));
+    let mut visited = Vec::new();
+    assert!(matches!(
+        agent.walk(&format!("{root}/missing"), &mut |entry| visited.push(entry)),
+        Err(SyncError::NotFound(_))
+    ));
+    assert!(visited.is_empty());
+
+    assert_eq!(agent.stat(&format!("{root}/small")).unwrap().size, 5);
+    assert!(matches!(
+        agent.stat(&format!("{root}/missing")),
+        Err(SyncError::NotFound(_))
+    ));
+    assert_eq!(agent.hash(&big).unwrap(), blake3::hash(&old));
+
+    let bs = delta::block_size(old.len() as u64);
+    let basis = agent.signature(&big, bs).unwrap();
+    assert_eq!(
+        basis,
+        Signature::compute(&mut Cursor::new(&old), bs).unwrap()
+    );
+    let mut new = old.clone();
+    new[100_000..100_010].copy_from_slice(b"0123456789");
+    let meta = meta_with_mtime(&big, 1_700_000_000);
+    let sent = agent
+        .patch(&big, &basis, &mut Cursor::new(&new), &meta)
+        .unwrap();
+    assert!(sent <= bs as u64, "{sent}");
+    assert!(fs::read(&big).unwrap() == new);
+    let patched = LocalBackend::new().stat(&big).unwrap();
+    assert_eq!(patched.modified, meta.modified);
+    assert_eq!(patched.mode, Some(0o600));
+
+    // A stale signature makes the result fail its checksum; the file stays.
+    let err = agent
+        .patch(&big, &basis, &mut Cursor::new(&old[..150_000]), &meta)
+        .map(|_| ())
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("checksum"), "{err:?}");
+    let err = agent
+        .patch(
+            &format!("{root}/missing"),
+            &basis,
+            &mut Cursor::new(&new),
+            &meta,
+        )
+        .unwrap_err();
+    assert!(matches!(err, SyncError::Other(_)), "{err:?}");
+    assert!(fs::read(&big).unwrap() == new);
+    assert_eq!(fs::read_dir(dir.path().join("sub")).unwrap().count(), 1);
+    assert_eq!(agent.hash(&big).unwrap(), blake3::hash(&new));
+    assert!(server.stop());
+
+    // The remote command is configurable, and can be turned off.
+    for url in [
+        "ssh://127.0.0.1:1/x?parsync_path=none",
+        "ssh://127.0.0.1:1/x?parsync_path=/opt/bin/parsync",
+    ] {
+        let err = backend_and_path(url, 1).err().unwrap();
+        assert!(format!("{err:?}").contains("TCP 127.0.0.1:1"), "{err:?}");
+    }
+}
+
+/// A local directory reached through `parsync --server` for hashes,
+/// deltas and, given a threshold, batches; counts whole-file writes.
+struct AgentBackend {
+    local: LocalBackend,
+    server: Mutex<Server>,
+    writes: AtomicUsize,
+    batch_threshold: Option<u64>,
+    batched: AtomicUsize,
+}
+
+impl AgentBackend {
+    fn new(batch_threshold: Option<u64>) -> Arc<Self> {
+        Arc::new(Self {
+            local: LocalBackend::new(),
+            server: Mutex::new(Server::start()),
+            writes: AtomicUsize::new(0),
+            batch_threshold,
+            batched: AtomicUsize::new(0),
+        })
+    }
+
+    fn stop(self: Arc<Self>) -> bool {
+        let server = Arc::try_unwrap(self).ok().unwrap().server;
+        server.into_inner().unwrap().stop()
+    }
+}
+
+impl StorageBackend for AgentBackend {
+    fn list(&self, path: &str) -> Result<Vec<FileEntry>, SyncError> {
+        self.local.list(path)
+    }
+    fn get(&self, path: &str) -> Result<Vec<u8>, SyncError> {
+        self.local.get(path)
+    }
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), SyncError> {
+        self.local.put(path, data)
+    }
+    fn put_stream(&self, path: &str, reader: &mut dyn Read, size: u64) -> Result<(), SyncError> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.local.put_stream(path, reader, size)
+    }
+    fn delete(&self, path: &str) -> Result<(), SyncError> {
+        self.local.delete(path)
+    }
+    fn exists(&self, path: &str) -> Result<bool, SyncError> {
+        self.local.exists(path)
+    }
+    fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        self.local.stat(path)
+    }
+    fn mkdir(&self, path: &str) -> Result<(), SyncError> {
+        self.local.mkdir(path)
+    }
+    fn set_metadata(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        self.local.set_metadata(path, meta)
+    }
+    fn hash(&self, path: &str) -> Result<Option<blake3::Hash>, SyncError> {
+        self.server.lock().unwrap().agent().hash(path).map(Some)
+    }
+    fn signature(&self, path: &str, block_size: u32) -> Result<Option<Signature>, SyncError> {
+        let mut server = self.server.lock().unwrap();
+        server.agent().signature(path, block_size).map(Some)
+    }
+    fn put_delta(
+        &self,
+        path: &str,
+        basis: &Signature,
+        reader: &mut dyn Read,
+        meta: &FileMeta,
+    ) -> Result<(), SyncError> {
+        let mut server = self.server.lock().unwrap();
+        server.agent().patch(path, basis, reader, meta).map(|_| ())
+    }
+    fn batch_threshold(&self) -> Option<u64> {
+        self.batch_threshold
+    }
+    fn put_batch(&self, files: &[BatchFile]) -> Vec<Result<(), SyncError>> {
+        self.batched.fetch_add(files.len(), Ordering::SeqCst);
+        let mut server = self.server.lock().unwrap();
+        server.agent().unpack(files).unwrap()
+    }
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+#[test]
+/// The client only trusts a reply to its hello that carries the magic and
+/// its own protocol version
+fn test_handshake_checks_magic_and_version() {
+    let reply = |payload: &[u8]| {
+        let mut frame = (payload.len() as u32 + 1).to_le_bytes().to_vec();
+        frame.push(0x80);
+        frame.extend_from_slice(payload);
+        Agent::handshake(Cursor::new(frame), Vec::new())
+    };
+    let hello = |version: u32| [b"PSYN".as_slice(), &version.to_le_bytes()].concat();
+    assert!(reply(&hello(parsync::agent::PROTOCOL_VERSION)).is_ok());
+    let err = reply(&hello(99)).err().unwrap();
+    assert!(format!("{err:?}").contains("version 99"), "{err:?}");
+    assert!(reply(b"Last login: yesterday").is_err());
+    assert!(reply(b"PSY").is_err());
+}
+
+#[test]
+/// A second sync patches the changed large file, only touches up the one
+/// whose mtime alone changed, and copies small changed files whole
+fn test_sync_uses_hashes_and_deltas() {
+    let src = tempdir().unwrap();
+    let dst = tempdir().unwrap();
+    let (src_root, dst_root) = (src.path().to_str().unwrap(), dst.path().to_str().unwrap());
+    fs::create_dir(src.path().join("data")).unwrap();
+    fs::write(src.path().join("data/big"), common::noise(500_000, 5)).unwrap();
+    fs::write(src.path().join("same"), common::noise(100_000, 6)).unwrap();
+    fs::write(src.path().join("small"), b"v1").unwrap();
+
+    let backend = AgentBackend::new(None);
+    let sync = || {
+        parsync::sync(
+            Arc::new(LocalBackend::new()),
+            src_root,
+            backend.clone(),
+            dst_root,
+            true,
+            false,
+        )
+        .unwrap()
+    };
+    sync();
+    assert_eq!(backend.writes.load(Ordering::SeqCst), 3);
+
+    let mut big = fs::read(src.path().join("data/big")).unwrap();
+    big.splice(250_000..250_000, *b"grown");
+    fs::write(src.path().join("data/big"), &big).unwrap();
+    fs::write(src.path().join("small"), b"v2").unwrap();
+    let later = SystemTime::now() + Duration::from_secs(60);
+    filetime::set_file_mtime(
+        src.path().join("same"),
+        filetime::FileTime::from_system_time(later),
+    )
+    .unwrap();
+    sync();
+
+    assert_eq!(backend.writes.load(Ordering::SeqCst), 4);
+    assert!(fs::read(dst.path().join("data/big")).unwrap() == big);
+    assert_eq!(fs::read(dst.path().join("small")).unwrap(), b"v2");
+    let same = LocalBackend::new()
+        .stat(&format!("{dst_root}/same"))
+        .unwrap();
+    assert_eq!(same.modified, Some(later));
+    assert!(backend.stop());
+}
+
+#[test]
+/// Files under the threshold reach the server in batches with their
+/// metadata, larger ones are written on their own, and a file the server
+/// can't write fails alone
+fn test_small_files_are_batched() {
+    let src = tempdir().unwrap();
+    let dst = tempdir().unwrap();
+    let (src_root, dst_root) = (src.path().to_str().unwrap(), dst.path().to_str().unwrap());
+    for i in 0..60 {
+        let dir = src.path().join(format!("d{}", i % 4));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join(format!("f{i}")),
+            common::noise(100 + i * 10, i as u64),
+        )
+        .unwrap();
+    }
+    fs::write(src.path().join("large"), common::noise(10_000, 99)).unwrap();
+    let old = SystemTime::now() - Duration::from_secs(86_400);
+    filetime::set_file_mtime(
+        src.path().join("d1/f1"),
+        filetime::FileTime::from_system_time(old),
+    )
+    .unwrap();
+
+    let backend = AgentBackend::new(Some(4096));
+    let options = parsync::CopyOptions {
+        threads: 4,
+        include: None,
+        exclude: None,
+        dry_run: false,
+        no_progress: true,
+        no_preserve_times: false,
+        devices: false,
+        specials: false,
+        links: false,
+        atimes: false,
+    };
+    parsync::copy(
+        Arc::new(LocalBackend::new()),
+        src_root,
+        backend.clone(),
+        dst_root,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(backend.writes.load(Ordering::SeqCst), 1);
+    assert_eq!(backend.batched.load(Ordering::SeqCst), 60);
+    for i in 0..60 {
+        let rel = format!("d{}/f{i}", i % 4);
+        assert_eq!(
+            fs::read(dst.path().join(&rel)).unwrap(),
+            common::noise(100 + i * 10, i as u64),
+            "{rel}"
+        );
+    }
+    let copied = LocalBackend::new()
+        .stat(&format!("{dst_root}/d1/f1"))
+        .unwrap();
+    assert_eq!(copied.modified, Some(old));
+
+    fs::write(src.path().join("d2/f2"), b"changed").unwrap();
+    parsync::sync(
+        Arc::new(LocalBackend::new()),
+        src_root,
+        backend.clone(),
+        dst_root,
+        true,
+        false,
+    )
+    .unwrap();
+    assert_eq!(backend.batched.load(Ordering::SeqCst), 61);
+    assert_eq!(fs::read(dst.path().join("d2/f2")).unwrap(), b"changed");
+
+    let file = |path: String| BatchFile {
+        path,
+        meta: LocalBackend::new()
+            .stat(&format!("{src_root}/large"))
+            .unwrap(),
+        data: b"data".to_vec(),
+    };
+    let results = backend.put_batch(&[
+        file(format!("{dst_root}/large/inside")),
+        file(format!("{dst_root}/new/file")),
+    ]);
+    assert!(results[0].is_err());
+    assert!(results[1].is_ok());
+    assert_eq!(fs::read(dst.path().join("new/file")).unwrap(), b"data");
+    assert!(backend.stop());
+
+    for url in [
+        "ssh://127.0.0.1:1/x?batch_threshold=0",
+        "ssh://127.0.0.1:1/x?batch_threshold=1048576",
+    ] {
+        let err = backend_and_path(url, 1).err().unwrap();
+        assert!(format!("{err:?}").contains("TCP 127.0.0.1:1"), "{err:?}");
+    }
+    assert!(backend_and_path("ssh://host/x?batch_threshold=64k", 1).is_err());
+}
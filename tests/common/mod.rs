@@ -0,0 +1,129 @@
+//! Helpers shared by the integration tests: default copy options, seeded
+//! noise and a loopback HTTP server for the S3 and WebDAV backends.
+#![allow(dead_code)]
+
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+
+pub struct Request {
+    pub method: String,
+    /// Percent-decoded request path.
+    pub path: String,
+    pub query: HashMap<String, String>,
+    /// Header names are lowercase.
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// Status, extra headers and body. HEAD replies send the body's length but
+/// not the body.
+pub type Reply = (u16, Vec<(String, String)>, Vec<u8>);
+
+pub fn decode(s: &str) -> String {
+    percent_decode_str(s).decode_utf8_lossy().to_string()
+}
+
+/// Serves HTTP/1.1 with keep-alive on a loopback port until the process
+/// exits, returning `http://addr`.
+pub fn serve(handler: impl Fn(Request) -> Reply + Send + Sync + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let handler = Arc::new(handler);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let handler = handler.clone();
+            std::thread::spawn(move || serve_connection(stream.unwrap(), &*handler));
+        }
+    });
+    endpoint
+}
+
+fn serve_connection(stream: TcpStream, handler: &dyn Fn(Request) -> Reply) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut parts = line.split_whitespace();
+        let method = parts.next().unwrap().to_string();
+        let target = parts.next().unwrap().to_string();
+        let mut headers = HashMap::new();
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            match header.trim_end().split_once(':') {
+                Some((k, v)) => headers.insert(k.to_ascii_lowercase(), v.trim().to_string()),
+                None => break,
+            };
+        }
+        let len = headers
+            .get("content-length")
+            .map_or(0, |v| v.parse().unwrap());
+        let mut body = vec![0; len];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        let query = query
+            .split('&')
+            .filter(|q| !q.is_empty())
+            .map(|q| {
+                let (k, v) = q.split_once('=').unwrap_or((q, ""));
+                (decode(k), decode(v))
+            })
+            .collect();
+        let request = Request {
+            method: method.clone(),
+            path: decode(path),
+            query,
+            headers,
+            body,
+        };
+
+        let (status, extra, body) = handler(request);
+        let mut head = format!("HTTP/1.1 {status} X\r\nContent-Length: {}\r\n", body.len());
+        for (k, v) in extra {
+            head.push_str(&format!("{k}: {v}\r\n"));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).unwrap();
+        if method != "HEAD" {
+            stream.write_all(&body).unwrap();
+        }
+    }
+}
+
+/// Four threads, no progress bar, times preserved, nothing special copied.
+pub fn copy_options() -> parsync::CopyOptions<'static> {
+    parsync::CopyOptions {
+        threads: 4,
+        include: None,
+        exclude: None,
+        dry_run: false,
+        no_progress: true,
+        no_preserve_times: false,
+        devices: false,
+        specials: false,
+        links: false,
+        atimes: false,
+    }
+}
+
+/// `len` pseudo-random bytes, the same for the same `seed`.
+pub fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut x = seed ^ 0x2545_f491_4f6c_dd1d;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect()
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use parsync::backends::compress::{split_stored_name, stored_name};
+use parsync::backends::{
+    backend_and_path, CompressBackend, LocalBackend, MemoryBackend, StorageBackend,
+};
+use std::fs;
+use std::io::Read;
+use std::sync::Arc;
+use tempfile::tempdir;
+
+#[test]
+/// local → zstd+file → local: files are stored compressed under
+/// size-suffixed names, listed with their original sizes, and read back
+fn test_compress_round_trip() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("logs");
+    fs::create_dir_all(src.join("app/empty")).unwrap();
+    let line = "2024-01-01T00:00:00Z INFO request served in 12ms path=/index.html\n";
+    for i in 0..20 {
+        fs::write(src.join(format!("app/{i}.log")), line.repeat(100 * i)).unwrap();
+    }
+    let large = line.repeat((9 << 20) / line.len() + 1);
+    fs::write(src.join("large.log"), &large).unwrap();
+    let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_mtime(src.join("app/3.log"), mtime).unwrap();
+
+    let stored = dir.path().join("stored");
+    let url = format!("zstd+file://{}?zstd_level=19", stored.display());
+    let (zstd, root) = backend_and_path(&url, 4).unwrap();
+    parsync::copy(
+        Arc::new(LocalBackend::new()),
+        src.to_str().unwrap(),
+        zstd.clone(),
+        root,
+        &common::copy_options(),
+    )
+    .unwrap();
+
+    let raw = stored.join(stored_name("large.log", large.len() as u64));
+    assert!(fs::metadata(&raw).unwrap().len() < large.len() as u64 / 20);
+    assert!(!stored.join("large.log").exists());
+    assert!(stored.join("app/empty").is_dir());
+    let meta = zstd.stat(&format!("{root}/large.log")).unwrap();
+    assert_eq!(meta.size, large.len() as u64);
+    let mut names: Vec<_> = zstd
+        .list(&format!("{root}/app"))
+        .unwrap()
+        .into_iter()
+        .filter(|e| e.metadata.is_file())
+        .map(|e| (e.path, e.metadata.size))
+        .collect();
+    names.sort();
+    assert_eq!(names[0], (format!("{root}/app/0.log"), 0));
+
+    let out = dir.path().join("out");
+    parsync::copy(
+        zstd,
+        root,
+        Arc::new(LocalBackend::new()),
+        out.to_str().unwrap(),
+        &common::copy_options(),
+    )
+    .unwrap();
+    for i in 0..20 {
+        assert_eq!(
+            fs::read_to_string(out.join(format!("app/{i}.log"))).unwrap(),
+            line.repeat(100 * i)
+        );
+    }
+    assert_eq!(fs::read_to_string(out.join("large.log")).unwrap(), large);
+    let meta = fs::metadata(out.join("app/3.log")).unwrap();
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&meta),
+        mtime
+    );
+}
+
+#[test]
+/// A second sync into a compressed store writes nothing
+fn test_compress_sync_converges() {
+    let dir = tempdir().unwrap();
+    for i in 0..10 {
+        fs::write(dir.path().join(format!("{i}.txt")), "text ".repeat(i)).unwrap();
+    }
+    let (zstd, root) = backend_and_path("zstd+mem://zstd-sync/dst", 4).unwrap();
+    let sync = || {
+        parsync::sync(
+            Arc::new(LocalBackend::new()),
+            dir.path().to_str().unwrap(),
+            zstd.clone(),
+            root,
+            true,
+            false,
+        )
+        .unwrap()
+    };
+    sync();
+    MemoryBackend::named("zstd-sync").fail_nth_put(1);
+    sync();
+}
+
+#[test]
+/// Overwrites and renames replace the old stored name; uncompressed files
+/// already in the store read as they are
+fn test_compress_overwrite_rename_and_foreign_files() {
+    let store = MemoryBackend::new();
+    store.mkdir("/d").unwrap();
+    store.put("/d/plain.txt", b"not compressed").unwrap();
+    let zstd = CompressBackend::new(Arc::new(store.clone()), 3);
+
+    zstd.put("/d/a.txt", b"first version").unwrap();
+    zstd.put("/d/a.txt", b"second").unwrap();
+    assert_eq!(zstd.get("/d/a.txt").unwrap(), b"second");
+    assert!(!store
+        .exists(&format!("/d/{}", stored_name("a.txt", 13)))
+        .unwrap());
+    assert!(store
+        .exists(&format!("/d/{}", stored_name("a.txt", 6)))
+        .unwrap());
+
+    let mut range = Vec::new();
+    zstd.open_read("/d/a.txt", 2, Some(3))
+        .unwrap()
+        .read_to_end(&mut range)
+        .unwrap();
+    assert_eq!(range, b"con");
+
+    zstd.put("/d/b.txt", b"bbbb").unwrap();
+    zstd.rename("/d/a.txt", "/d/b.txt").unwrap();
+    assert_eq!(zstd.get("/d/b.txt").unwrap(), b"second");
+    assert!(!zstd.exists("/d/a.txt").unwrap());
+    let mut stored: Vec<_> = store
+        .list("/d")
+        .unwrap()
+        .into_iter()
+        .map(|e| e.path)
+        .collect();
+    stored.sort();
+    assert_eq!(stored, ["/d/b.txt.6.zst", "/d/plain.txt"]);
+
+    assert_eq!(zstd.get("/d/plain.txt").unwrap(), b"not compressed");
+    assert_eq!(zstd.stat("/d/plain.txt").unwrap().size, 14);
+    zstd.delete("/d/b.txt").unwrap();
+    assert!(!store.exists("/d/b.txt.6.zst").unwrap());
+
+    assert_eq!(split_stored_name("x.log.1f.zst"), Some(("x.log", 31)));
+    assert_eq!(split_stored_name("x.zst"), None);
+    assert_eq!(split_stored_name("x.ZZ.zst"), None);
+    assert!(backend_and_path("zstd+mem://z/p?zstd_level=99", 1).is_err());
+}
@@ -0,0 +1,235 @@
+mod common;
+
+use parsync::backends::crypt::{encrypted_size, plaintext_size};
+use parsync::backends::{
+    backend_and_path, CryptBackend, CryptKey, LocalBackend, MemoryBackend, StorageBackend,
+    SyncError,
+};
+use std::fs;
+use std::io::Read;
+use std::sync::Arc;
+use tempfile::tempdir;
+
+const CHUNK: usize = 64 * 1024;
+const SALT: [u8; 16] = *b"0123456789abcdef";
+
+#[test]
+/// local → crypt+mem → local with encrypted names: the store holds neither
+/// names nor contents in the clear, and a second sync writes nothing
+fn test_crypt_round_trip_with_encrypted_names() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("secret-dir/empty")).unwrap();
+    let sizes = [0, 1, CHUNK - 1, CHUNK, CHUNK + 1, 3 * CHUNK + 17];
+    for size in sizes {
+        let data: Vec<u8> = (0..size).map(|i| b"secret"[i % 6]).collect();
+        fs::write(src.join(format!("secret-dir/secret{size}.txt")), data).unwrap();
+    }
+    let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+    filetime::set_file_mtime(src.join("secret-dir/secret1.txt"), mtime).unwrap();
+    let key_file = dir.path().join("key");
+    fs::write(&key_file, b"0123456789abcdef").unwrap();
+
+    let url = format!(
+        "crypt+mem://crypt-vault/enc?key_file={}&names=encrypt",
+        key_file.display()
+    );
+    let (vault, root) = backend_and_path(&url, 4).unwrap();
+    assert_eq!(root, "/enc");
+    let sync = || {
+        parsync::sync(
+            Arc::new(LocalBackend::new()),
+            src.to_str().unwrap(),
+            vault.clone(),
+            root,
+            true,
+            false,
+        )
+        .unwrap()
+    };
+    sync();
+
+    let raw = MemoryBackend::named("crypt-vault");
+    let mut stored = Vec::new();
+    raw.walk("/enc", &mut |e| stored.push(e)).unwrap();
+    assert_eq!(stored.len(), 1 + 1 + 2 + sizes.len());
+    for entry in &stored {
+        assert!(!entry.path.contains("secret"), "{}", entry.path);
+        if entry.metadata.is_file() && entry.path != "/enc/.parsync-crypt" {
+            let data = raw.get(&entry.path).unwrap();
+            assert!(!data.windows(6).any(|w| w == b"secret"));
+            let size = plaintext_size(data.len() as u64).unwrap();
+            assert!(sizes.contains(&(size as usize)));
+            assert_eq!(encrypted_size(size), data.len() as u64);
+        }
+    }
+    assert_eq!(
+        vault.stat("/enc/secret-dir/secret65537.txt").unwrap().size,
+        65537
+    );
+
+    let out = dir.path().join("out");
+    parsync::copy(
+        vault.clone(),
+        root,
+        Arc::new(LocalBackend::new()),
+        out.to_str().unwrap(),
+        &common::copy_options(),
+    )
+    .unwrap();
+    for size in sizes {
+        let name = format!("secret-dir/secret{size}.txt");
+        assert_eq!(
+            fs::read(out.join(&name)).unwrap(),
+            fs::read(src.join(&name)).unwrap()
+        );
+    }
+    assert!(out.join("secret-dir/empty").is_dir());
+    let meta = fs::metadata(out.join("secret-dir/secret1.txt")).unwrap();
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&meta),
+        mtime
+    );
+
+    // Sizes and mtimes compare equal, so nothing is written again.
+    raw.fail_nth_put(1);
+    sync();
+}
+
+#[test]
+/// Ranged reads cross chunk boundaries; wrong keys, flipped bytes and
+/// dropped chunks are all errors
+fn test_crypt_authenticates_contents() {
+    let store = MemoryBackend::new();
+    let key = CryptKey::from_passphrase("correct horse", &SALT).unwrap();
+    let crypt = CryptBackend::new(Arc::new(store.clone()), key);
+    let data: Vec<u8> = (0..3 * CHUNK + 5).map(|i| (i % 251) as u8).collect();
+    crypt.put("/file.bin", &data).unwrap();
+    assert_eq!(crypt.get("/file.bin").unwrap(), data);
+
+    let mut range = Vec::new();
+    crypt
+        .open_read("/file.bin", CHUNK as u64 - 3, Some(CHUNK as u64 + 6))
+        .unwrap()
+        .read_to_end(&mut range)
+        .unwrap();
+    assert_eq!(range, &data[CHUNK - 3..2 * CHUNK + 3]);
+    let mut tail = Vec::new();
+    crypt
+        .open_read("/file.bin", data.len() as u64 + 10, None)
+        .unwrap()
+        .read_to_end(&mut tail)
+        .unwrap();
+    assert!(tail.is_empty());
+
+    // The same passphrase derives the same key.
+    let again = CryptBackend::new(
+        Arc::new(store.clone()),
+        CryptKey::from_passphrase("correct horse", &SALT).unwrap(),
+    );
+    assert_eq!(again.get("/file.bin").unwrap(), data);
+    let wrong = CryptBackend::new(
+        Arc::new(store.clone()),
+        CryptKey::from_passphrase("battery staple", &SALT).unwrap(),
+    );
+    assert!(wrong.get("/file.bin").is_err());
+
+    let sealed = store.get("/file.bin").unwrap();
+    let mut flipped = sealed.clone();
+    flipped[100] ^= 1;
+    store.put("/file.bin", &flipped).unwrap();
+    assert!(crypt.get("/file.bin").is_err());
+
+    // Cut at a chunk boundary: still a valid size, but the last chunk left
+    // was not sealed as the last one.
+    let cut = encrypted_size(2 * CHUNK as u64) as usize;
+    store.put("/file.bin", &sealed[..cut]).unwrap();
+    assert_eq!(crypt.stat("/file.bin").unwrap().size, 2 * CHUNK as u64);
+    assert!(crypt.get("/file.bin").is_err());
+}
+
+#[test]
+/// Stored sizes map back to plaintext sizes around chunk boundaries
+fn test_crypt_sizes() {
+    for size in [
+        0,
+        1,
+        100,
+        CHUNK - 1,
+        CHUNK,
+        CHUNK + 1,
+        2 * CHUNK,
+        5 * CHUNK + 9,
+    ] {
+        let size = size as u64;
+        assert_eq!(plaintext_size(encrypted_size(size)), Some(size));
+    }
+    assert_eq!(plaintext_size(0), None);
+    assert_eq!(plaintext_size(encrypted_size(0) - 1), None);
+    assert_eq!(plaintext_size(encrypted_size(CHUNK as u64) + 5), None);
+}
+
+#[test]
+/// Each destination gets its own random salt, written with its first write,
+/// so the same key file yields different keys; the salt file stays out of
+/// listings, and a populated root without one is refused
+fn test_crypt_salt_per_destination() {
+    let dir = tempdir().unwrap();
+    let key_file = dir.path().join("key");
+    fs::write(&key_file, b"0123456789abcdef").unwrap();
+    let open = |root: &str| {
+        let url = format!(
+            "crypt+mem://crypt-salts{root}?key_file={}&names=encrypt",
+            key_file.display()
+        );
+        backend_and_path(&url, 1).map(|(backend, _)| backend)
+    };
+
+    let raw = MemoryBackend::named("crypt-salts");
+    let mut names = Vec::new();
+    for root in ["/one", "/two"] {
+        let vault = open(root).unwrap();
+        assert!(matches!(vault.list(root), Err(SyncError::NotFound(_))));
+        assert!(matches!(vault.stat(root), Err(SyncError::NotFound(_))));
+        assert!(!vault.exists(&format!("{root}/file.txt")).unwrap());
+        assert!(!raw.exists(&format!("{root}/.parsync-crypt")).unwrap());
+        vault.put(&format!("{root}/file.txt"), b"same").unwrap();
+        let listed = vault.list(root).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].path, format!("{root}/file.txt"));
+        let stored: Vec<_> = raw
+            .list(root)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.path.rsplit('/').next().unwrap().to_string())
+            .filter(|name| name != ".parsync-crypt")
+            .collect();
+        names.extend(stored);
+    }
+    assert_eq!(names.len(), 2);
+    assert_ne!(names[0], names[1]);
+    assert_ne!(
+        raw.get("/one/.parsync-crypt").unwrap(),
+        raw.get("/two/.parsync-crypt").unwrap()
+    );
+
+    // Reopening reads the stored salt back.
+    assert_eq!(open("/one").unwrap().get("/one/file.txt").unwrap(), b"same");
+
+    // A file written as the root keeps its salt beside it.
+    let single = open("/files/single.txt").unwrap();
+    single.put("/files/single.txt", b"alone").unwrap();
+    assert!(raw.exists("/files/.parsync-crypt").unwrap());
+    assert_eq!(
+        open("/files/single.txt")
+            .unwrap()
+            .get("/files/single.txt")
+            .unwrap(),
+        b"alone"
+    );
+
+    raw.delete("/two/.parsync-crypt").unwrap();
+    assert!(open("/two").is_err());
+    raw.put("/one/.parsync-crypt", b"garbage").unwrap();
+    assert!(open("/one").is_err());
+}
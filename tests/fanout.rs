@@ -0,0 +1,271 @@
+mod common;
+
+use parsync::backends::{
+    FileEntry, FileMeta, LocalBackend, MemoryBackend, StorageBackend, SyncError,
+};
+use std::fs;
+use std::io::Read;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tempfile::tempdir;
+
+/// An in-memory source that counts the streams opened on it.
+#[derive(Default)]
+struct CountingSource {
+    inner: MemoryBackend,
+    opened: AtomicUsize,
+}
+
+impl StorageBackend for CountingSource {
+    fn list(&self, path: &str) -> Result<Vec<FileEntry>, SyncError> {
+        self.inner.list(path)
+    }
+    fn get(&self, path: &str) -> Result<Vec<u8>, SyncError> {
+        self.inner.get(path)
+    }
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), SyncError> {
+        self.inner.put(path, data)
+    }
+    fn delete(&self, path: &str) -> Result<(), SyncError> {
+        self.inner.delete(path)
+    }
+    fn exists(&self, path: &str) -> Result<bool, SyncError> {
+        self.inner.exists(path)
+    }
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        self.inner.stat(path)
+    }
+    fn mkdir(&self, path: &str) -> Result<(), SyncError> {
+        self.inner.mkdir(path)
+    }
+    fn walk(&self, root: &str, visit: &mut dyn FnMut(FileEntry)) -> Result<(), SyncError> {
+        self.inner.walk(root, visit)
+    }
+    fn open_read(
+        &self,
+        path: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read + Send>, SyncError> {
+        self.opened.fetch_add(1, Ordering::SeqCst);
+        self.inner.open_read(path, offset, len)
+    }
+}
+
+fn source_tree() -> Arc<CountingSource> {
+    let src = CountingSource::default();
+    src.mkdir("/src/sub/empty").unwrap();
+    for i in 0..10 {
+        src.put(&format!("/src/sub/{i}.txt"), format!("file {i}").as_bytes())
+            .unwrap();
+    }
+    let big: Vec<u8> = (0..3 << 20).map(|i| (i % 253) as u8).collect();
+    src.put("/src/big.bin", &big).unwrap();
+    Arc::new(src)
+}
+
+#[test]
+/// One pass reads each file once and writes it everywhere; a destination
+/// that rejects every write, or one file, fails alone
+fn test_copy_to_all_isolates_failures() {
+    let dir = tempdir().unwrap();
+    let src = source_tree();
+    let good = MemoryBackend::new();
+    let flaky = MemoryBackend::new();
+    flaky.mkdir("/out").unwrap();
+    flaky.fail_nth_put(3);
+    let local = dir.path().join("local");
+    let blocked = dir.path().join("file");
+    fs::write(&blocked, b"not a directory").unwrap();
+    let blocked = blocked.join("out");
+
+    let results = parsync::copy_to_all(
+        src.clone(),
+        "/src",
+        &[
+            (Arc::new(good.clone()), "/out"),
+            (Arc::new(LocalBackend::new()), blocked.to_str().unwrap()),
+            (Arc::new(flaky.clone()), "/out"),
+            (Arc::new(LocalBackend::new()), local.to_str().unwrap()),
+        ],
+        &common::copy_options(),
+    )
+    .unwrap();
+    assert!(results[0].is_ok());
+    assert!(results[1].is_err());
+    assert!(results[2].is_err());
+    assert!(results[3].is_ok());
+    assert_eq!(src.opened.load(Ordering::SeqCst), 11);
+
+    let big = src.get("/src/big.bin").unwrap();
+    assert_eq!(good.get("/out/big.bin").unwrap(), big);
+    assert_eq!(fs::read(local.join("big.bin")).unwrap(), big);
+    for i in 0..10 {
+        let name = format!("sub/{i}.txt");
+        assert_eq!(
+            good.get(&format!("/out/{name}")).unwrap(),
+            format!("file {i}").as_bytes()
+        );
+        assert_eq!(
+            fs::read(local.join(&name)).unwrap(),
+            format!("file {i}").as_bytes()
+        );
+    }
+    assert!(local.join("sub/empty").is_dir());
+    let written = (0..10)
+        .filter(|i| flaky.exists(&format!("/out/sub/{i}.txt")).unwrap())
+        .count();
+    assert_eq!(written + flaky.exists("/out/big.bin").unwrap() as usize, 10);
+
+    // A failed source walk fails the whole pass.
+    assert!(parsync::copy_to_all(
+        src,
+        "/missing",
+        &[(Arc::new(good), "/out")],
+        &common::copy_options(),
+    )
+    .is_err());
+}
+
+#[test]
+/// Each destination is compared on its own: a file missing from one is read
+/// once and written only there
+fn test_sync_to_all_compares_per_destination() {
+    let src = source_tree();
+    let full = MemoryBackend::new();
+    parsync::copy(
+        src.clone(),
+        "/src",
+        Arc::new(full.clone()),
+        "/dst",
+        &common::copy_options(),
+    )
+    .unwrap();
+    full.delete("/dst/sub/3.txt").unwrap();
+    let empty = MemoryBackend::new();
+    src.opened.store(0, Ordering::SeqCst);
+
+    let sync = |src: Arc<CountingSource>| {
+        parsync::sync_to_all(
+            src,
+            "/src",
+            &[
+                (Arc::new(full.clone()), "/dst"),
+                (Arc::new(empty.clone()), "/dst"),
+            ],
+            true,
+            false,
+            Default::default(),
+        )
+        .unwrap()
+    };
+    // Only 3.txt goes to both; the rest are read for `empty` alone.
+    full.fail_nth_put(2);
+    assert!(sync(src.clone()).iter().all(Result::is_ok));
+    assert_eq!(src.opened.load(Ordering::SeqCst), 11);
+    for store in [&full, &empty] {
+        assert_eq!(store.get("/dst/sub/3.txt").unwrap(), b"file 3");
+        assert!(store.stat("/dst/sub/empty").unwrap().is_dir());
+    }
+
+    src.opened.store(0, Ordering::SeqCst);
+    full.fail_nth_put(1);
+    empty.fail_nth_put(1);
+    assert!(sync(src.clone()).iter().all(Result::is_ok));
+    assert_eq!(src.opened.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+/// `--to` adds destinations; the run reports the broken one, finishes the
+/// others and exits non-zero
+fn test_cli_copy_to_several_destinations() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("nested")).unwrap();
+    fs::write(src.join("nested/a.txt"), b"alpha").unwrap();
+    let blocked = dir.path().join("file");
+    fs::write(&blocked, b"not a directory").unwrap();
+    let (one, two) = (dir.path().join("one"), dir.path().join("two.tar"));
+
+    let bin = env!("CARGO_BIN_EXE_parsync");
+    let out = Command::new(bin)
+        .args(["--no-progress", "copy", src.to_str().unwrap()])
+        .arg(&one)
+        .arg("--to")
+        .arg(blocked.join("out"))
+        .arg("--to")
+        .arg(format!("tar://{}", two.display()))
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Copy failed to '"), "{stderr}");
+    assert!(
+        stderr.contains("completed for 2 of 3 destinations"),
+        "{stderr}"
+    );
+    assert_eq!(fs::read(one.join("nested/a.txt")).unwrap(), b"alpha");
+    assert!(fs::metadata(&two).unwrap().len() > 0);
+
+    let out = Command::new(bin)
+        .args(["--no-progress", "sync", src.to_str().unwrap()])
+        .arg(&one)
+        .arg("--to")
+        .arg(dir.path().join("three"))
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(
+        fs::read(dir.path().join("three/nested/a.txt")).unwrap(),
+        b"alpha"
+    );
+}
+
+#[test]
+/// Status messages go to stderr, leaving stdout empty; a failed
+/// single-source run and an invalid destination both exit non-zero
+fn test_cli_status_and_exit_codes() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("a.txt"), b"alpha").unwrap();
+    let blocked = dir.path().join("file");
+    fs::write(&blocked, b"not a directory").unwrap();
+    let bin = env!("CARGO_BIN_EXE_parsync");
+    let run = |args: &[&str]| {
+        Command::new(bin)
+            .arg("--no-progress")
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let (src, out) = (src.to_str().unwrap(), dir.path().join("out"));
+    let out = out.to_str().unwrap();
+
+    for verb in ["copy", "sync"] {
+        let result = run(&[verb, src, out]);
+        assert!(result.status.success());
+        assert!(result.stdout.is_empty());
+        assert!(String::from_utf8_lossy(&result.stderr).contains("completed successfully"));
+    }
+    let result = run(&["delete", "--dry-run", out]);
+    assert!(result.status.success());
+    assert!(result.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&result.stderr).contains("Would delete: "));
+    let result = run(&["delete", out]);
+    assert!(result.status.success());
+    assert!(result.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&result.stderr).contains("Delete completed successfully"));
+
+    let into_file = blocked.join("out");
+    for verb in ["copy", "sync"] {
+        assert!(!run(&[verb, src, into_file.to_str().unwrap()])
+            .status
+            .success());
+        assert!(!run(&[verb, src, "nosuch://host/path"]).status.success());
+    }
+}
@@ -1,3 +1,5 @@
+mod common;
+
 use parsync::backends::{LocalBackend, StorageBackend};
 use std::fs::{self, File};
 use std::io::Write;
@@ -395,3 +397,405 @@ fn test_exists_returns_correct_values() {
         .unwrap());
     assert!(backend.exists(dir.path().to_str().unwrap()).unwrap());
 }
+
+#[test]
+/// Empty source directories are recreated at the destination
+fn test_copy_creates_empty_directories() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("empty").join("nested_empty")).unwrap();
+    File::create(src.join("file.txt")).unwrap();
+    let dst = dir.path().join("dst");
+
+    let backend = Arc::new(LocalBackend::new());
+    parsync::copy(
+        backend.clone(),
+        src.to_str().unwrap(),
+        backend,
+        dst.to_str().unwrap(),
+        &common::copy_options(),
+    )
+    .unwrap();
+
+    assert!(dst.join("file.txt").is_file());
+    assert!(dst.join("empty").join("nested_empty").is_dir());
+}
+
+#[test]
+/// Directory mtimes survive the file writes performed inside them
+fn test_copy_and_sync_preserve_directory_mtimes() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("sub")).unwrap();
+    File::create(src.join("sub").join("file.txt")).unwrap();
+    let old = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_mtime(src.join("sub"), old).unwrap();
+    filetime::set_file_mtime(&src, old).unwrap();
+
+    let backend = Arc::new(LocalBackend::new());
+    for (i, use_sync) in [false, true].into_iter().enumerate() {
+        let dst = dir.path().join(format!("dst{i}"));
+        if use_sync {
+            parsync::sync(
+                backend.clone(),
+                src.to_str().unwrap(),
+                backend.clone(),
+                dst.to_str().unwrap(),
+                true,
+                false,
+            )
+            .unwrap();
+        } else {
+            parsync::copy(
+                backend.clone(),
+                src.to_str().unwrap(),
+                backend.clone(),
+                dst.to_str().unwrap(),
+                &common::copy_options(),
+            )
+            .unwrap();
+        }
+
+        for d in [dst.clone(), dst.join("sub")] {
+            let mtime = filetime::FileTime::from_last_modification_time(&fs::metadata(&d).unwrap());
+            assert_eq!(mtime, old, "{d:?}");
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+/// Named pipes are recreated with `--specials` and skipped without it
+fn test_copy_recreates_fifo_with_specials() {
+    use std::os::unix::fs::FileTypeExt;
+
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir(&src).unwrap();
+    let fifo = std::ffi::CString::new(src.join("pipe").to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+    let backend = Arc::new(LocalBackend::new());
+    let plain = dir.path().join("plain");
+    parsync::copy(
+        backend.clone(),
+        src.to_str().unwrap(),
+        backend.clone(),
+        plain.to_str().unwrap(),
+        &common::copy_options(),
+    )
+    .unwrap();
+    assert!(!plain.join("pipe").exists());
+
+    let special = dir.path().join("special");
+    let options = parsync::CopyOptions {
+        specials: true,
+        ..common::copy_options()
+    };
+    parsync::copy(
+        backend.clone(),
+        src.to_str().unwrap(),
+        backend,
+        special.to_str().unwrap(),
+        &options,
+    )
+    .unwrap();
+    let meta = fs::symlink_metadata(special.join("pipe")).unwrap();
+    assert!(meta.file_type().is_fifo());
+}
+
+#[cfg(unix)]
+#[test]
+/// `sync` recreates FIFOs and symlinks when asked, leaves matching ones
+/// alone on the next run and repoints a link whose target changed
+fn test_sync_recreates_specials_and_links() {
+    use std::os::unix::fs::FileTypeExt;
+
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("file.txt"), b"data").unwrap();
+    let fifo = std::ffi::CString::new(src.join("pipe").to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+    std::os::unix::fs::symlink("file.txt", src.join("link")).unwrap();
+    let dst = dir.path().join("dst");
+
+    let backend = Arc::new(LocalBackend::new());
+    let sync = |specials| {
+        parsync::sync_to_all(
+            backend.clone(),
+            src.to_str().unwrap(),
+            &[(backend.clone(), dst.to_str().unwrap())],
+            true,
+            false,
+            specials,
+        )
+        .unwrap()
+        .pop()
+        .unwrap()
+    };
+    sync(Default::default()).unwrap();
+    assert!(fs::symlink_metadata(dst.join("pipe")).is_err());
+    assert!(fs::symlink_metadata(dst.join("link")).is_err());
+
+    let all = parsync::utils::Specials {
+        devices: false,
+        specials: true,
+        links: true,
+    };
+    sync(all).unwrap();
+    let pipe = fs::symlink_metadata(dst.join("pipe")).unwrap();
+    assert!(pipe.file_type().is_fifo());
+    assert_eq!(
+        fs::read_link(dst.join("link")).unwrap(),
+        Path::new("file.txt")
+    );
+
+    sync(all).unwrap();
+    fs::remove_file(src.join("link")).unwrap();
+    std::os::unix::fs::symlink("elsewhere", src.join("link")).unwrap();
+    sync(all).unwrap();
+    assert_eq!(
+        fs::read_link(dst.join("link")).unwrap(),
+        Path::new("elsewhere")
+    );
+}
+
+#[test]
+/// mtimes keep nanosecond precision and atimes are carried over with `atimes`
+fn test_copy_preserves_nanosecond_times_and_atimes() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("file.txt"), b"data").unwrap();
+    let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 123_456_789);
+    let atime = filetime::FileTime::from_unix_time(1_500_000_000, 987_654_321);
+    filetime::set_file_times(src.join("file.txt"), atime, mtime).unwrap();
+
+    let dst = dir.path().join("dst");
+    let backend = Arc::new(LocalBackend::new());
+    let options = parsync::CopyOptions {
+        atimes: true,
+        ..common::copy_options()
+    };
+    parsync::copy(
+        backend.clone(),
+        src.to_str().unwrap(),
+        backend,
+        dst.to_str().unwrap(),
+        &options,
+    )
+    .unwrap();
+
+    let meta = fs::metadata(dst.join("file.txt")).unwrap();
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&meta),
+        mtime
+    );
+    assert_eq!(filetime::FileTime::from_last_access_time(&meta), atime);
+}
+
+#[test]
+/// A second sync over an unchanged tree skips every file
+fn test_sync_second_run_skips_unchanged_files() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir(&src).unwrap();
+    fs::write(src.join("file.txt"), b"original").unwrap();
+    filetime::set_file_mtime(
+        src.join("file.txt"),
+        filetime::FileTime::from_unix_time(1_600_000_000, 42),
+    )
+    .unwrap();
+    let dst = dir.path().join("dst");
+    let backend = Arc::new(LocalBackend::new());
+
+    let run_sync = || {
+        parsync::sync(
+            backend.clone(),
+            src.to_str().unwrap(),
+            backend.clone(),
+            dst.to_str().unwrap(),
+            true,
+            false,
+        )
+        .unwrap()
+    };
+    run_sync();
+
+    // Same size and mtime: a converged sync must not rewrite the file.
+    let dst_file = dst.join("file.txt");
+    let mtime = filetime::FileTime::from_last_modification_time(&fs::metadata(&dst_file).unwrap());
+    fs::write(&dst_file, b"modified").unwrap();
+    filetime::set_file_mtime(&dst_file, mtime).unwrap();
+    run_sync();
+
+    assert_eq!(fs::read(&dst_file).unwrap(), b"modified");
+}
+
+#[test]
+/// A parent directory sync can't create is reported, and the files it could
+/// still write are synced
+fn test_sync_reports_unwritable_parents() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("blocked")).unwrap();
+    fs::write(src.join("blocked/inner.txt"), b"inner").unwrap();
+    fs::write(src.join("ok.txt"), b"ok").unwrap();
+    let dst = dir.path().join("dst");
+    fs::create_dir(&dst).unwrap();
+    fs::write(dst.join("blocked"), b"a file, not a directory").unwrap();
+
+    let backend = Arc::new(LocalBackend::new());
+    let result = parsync::sync(
+        backend.clone(),
+        src.to_str().unwrap(),
+        backend,
+        dst.to_str().unwrap(),
+        true,
+        false,
+    );
+    assert!(result.is_err());
+    assert_eq!(fs::read(dst.join("ok.txt")).unwrap(), b"ok");
+    assert_eq!(
+        fs::read(dst.join("blocked")).unwrap(),
+        b"a file, not a directory"
+    );
+}
+
+#[test]
+fn test_same_mtime_respects_precision() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let precise = UNIX_EPOCH + Duration::new(1_600_000_000, 500_000_000);
+    let whole = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+
+    assert!(!parsync::utils::same_mtime(
+        precise,
+        whole,
+        Duration::from_nanos(1)
+    ));
+    assert!(parsync::utils::same_mtime(
+        precise,
+        whole,
+        Duration::from_secs(1)
+    ));
+}
+
+#[test]
+fn test_localbackend_open_read_range() {
+    use std::io::Read;
+
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("range.txt");
+    fs::write(&file_path, b"0123456789").unwrap();
+    let backend = LocalBackend::new();
+
+    let mut all = String::new();
+    backend
+        .open_read(file_path.to_str().unwrap(), 0, None)
+        .unwrap()
+        .read_to_string(&mut all)
+        .unwrap();
+    assert_eq!(all, "0123456789");
+
+    let mut part = String::new();
+    backend
+        .open_read(file_path.to_str().unwrap(), 3, Some(4))
+        .unwrap()
+        .read_to_string(&mut part)
+        .unwrap();
+    assert_eq!(part, "3456");
+}
+
+#[test]
+fn test_localbackend_put_stream() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("streamed.bin");
+    let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let backend = LocalBackend::new();
+
+    backend
+        .put_stream(
+            file_path.to_str().unwrap(),
+            &mut std::io::Cursor::new(&data),
+            data.len() as u64,
+        )
+        .unwrap();
+
+    assert_eq!(fs::read(&file_path).unwrap(), data);
+}
+
+#[test]
+fn test_localbackend_stat_mkdir_rename() {
+    let dir = tempdir().unwrap();
+    let backend = LocalBackend::new();
+    let nested = dir.path().join("a").join("b");
+    let nested_str = nested.to_str().unwrap();
+
+    assert!(matches!(
+        backend.stat(nested_str),
+        Err(parsync::SyncError::NotFound(_))
+    ));
+    backend.mkdir(nested_str).unwrap();
+    assert!(backend.stat(nested_str).unwrap().is_dir());
+
+    let from = nested.join("from.txt");
+    let to = nested.join("to.txt");
+    fs::write(&from, b"moved").unwrap();
+    backend
+        .rename(from.to_str().unwrap(), to.to_str().unwrap())
+        .unwrap();
+    assert!(!from.exists());
+    let meta = backend.stat(to.to_str().unwrap()).unwrap();
+    assert!(meta.is_file());
+    assert_eq!(meta.size, 5);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_localbackend_set_metadata_and_symlink() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempdir().unwrap();
+    let backend = LocalBackend::new();
+    let file = dir.path().join("file.txt");
+    fs::write(&file, b"x").unwrap();
+    let file_str = file.to_str().unwrap();
+
+    let mut meta = backend.stat(file_str).unwrap();
+    meta.mode = Some(0o600);
+    meta.modified = Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000));
+    backend.set_metadata(file_str, &meta).unwrap();
+    let updated = fs::metadata(&file).unwrap();
+    assert_eq!(updated.permissions().mode() & 0o7777, 0o600);
+    assert_eq!(updated.modified().unwrap(), meta.modified.unwrap());
+
+    let link = dir.path().join("link");
+    backend.symlink(file_str, link.to_str().unwrap()).unwrap();
+    assert_eq!(backend.read_link(link.to_str().unwrap()).unwrap(), file_str);
+}
+
+#[test]
+/// walk visits the root first and every parent before its children
+fn test_localbackend_walk() {
+    let dir = tempdir().unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    File::create(root.join("sub").join("file.txt")).unwrap();
+    let backend = LocalBackend::new();
+
+    let mut seen = Vec::new();
+    backend
+        .walk(root.to_str().unwrap(), &mut |entry| seen.push(entry.path))
+        .unwrap();
+
+    let pos = |p: &Path| seen.iter().position(|s| Path::new(s) == p).unwrap();
+    assert_eq!(seen.len(), 3);
+    assert_eq!(pos(&root), 0);
+    assert!(pos(&root.join("sub")) < pos(&root.join("sub").join("file.txt")));
+
+    assert!(backend
+        .walk(dir.path().join("missing").to_str().unwrap(), &mut |_| {})
+        .is_err());
+}
@@ -0,0 +1,169 @@
+mod common;
+
+use parsync::backends::{backend_and_path, LocalBackend, MemoryBackend, StorageBackend};
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::tempdir;
+
+fn populate(mem: &MemoryBackend, root: &str, files: usize) {
+    mem.mkdir(&format!("{root}/sub/empty")).unwrap();
+    for i in 0..files {
+        mem.put(
+            &format!("{root}/sub/file{i}.txt"),
+            format!("content {i}").as_bytes(),
+        )
+        .unwrap();
+    }
+}
+
+#[test]
+fn test_memorybackend_basic_operations() {
+    let mem = MemoryBackend::new();
+
+    assert!(mem.put("/missing/file.txt", b"x").is_err());
+    mem.mkdir("/a/b").unwrap();
+    mem.put("/a/b/file.txt", b"hello").unwrap();
+
+    assert_eq!(mem.get("/a/b/file.txt").unwrap(), b"hello");
+    assert!(mem.exists("/a/b").unwrap());
+    assert!(mem.stat("/a").unwrap().is_dir());
+    assert_eq!(mem.stat("/a/b/file.txt").unwrap().size, 5);
+    assert_eq!(mem.list("/a/b").unwrap().len(), 1);
+
+    mem.rename("/a/b", "/a/c").unwrap();
+    assert_eq!(mem.get("/a/c/file.txt").unwrap(), b"hello");
+    assert!(!mem.exists("/a/b/file.txt").unwrap());
+
+    mem.delete("/a").unwrap();
+    assert!(!mem.exists("/a/c/file.txt").unwrap());
+    assert!(matches!(
+        mem.stat("/a"),
+        Err(parsync::SyncError::NotFound(_))
+    ));
+}
+
+#[test]
+/// Copy local → memory → local round-trips contents, empty dirs and mtimes
+fn test_copy_round_trip_through_memory() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("empty")).unwrap();
+    fs::write(src.join("file.txt"), b"round trip").unwrap();
+    let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 5);
+    filetime::set_file_mtime(src.join("file.txt"), mtime).unwrap();
+
+    let local = Arc::new(LocalBackend::new());
+    let mem = Arc::new(MemoryBackend::new());
+    parsync::copy(
+        local.clone(),
+        src.to_str().unwrap(),
+        mem.clone(),
+        "/stage",
+        &common::copy_options(),
+    )
+    .unwrap();
+    assert_eq!(mem.get("/stage/file.txt").unwrap(), b"round trip");
+    assert!(mem.stat("/stage/empty").unwrap().is_dir());
+
+    let out = dir.path().join("out");
+    parsync::copy(
+        mem,
+        "/stage",
+        local,
+        out.to_str().unwrap(),
+        &common::copy_options(),
+    )
+    .unwrap();
+    assert_eq!(fs::read(out.join("file.txt")).unwrap(), b"round trip");
+    assert!(out.join("empty").is_dir());
+    let meta = fs::metadata(out.join("file.txt")).unwrap();
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&meta),
+        mtime
+    );
+}
+
+#[test]
+/// A second sync between memory stores transfers nothing
+fn test_sync_memory_converges() {
+    let src = MemoryBackend::new();
+    let dst = MemoryBackend::new();
+    populate(&src, "/src", 10);
+
+    let run = || {
+        parsync::sync(
+            Arc::new(src.clone()),
+            "/src",
+            Arc::new(dst.clone()),
+            "/dst",
+            true,
+            false,
+        )
+        .unwrap()
+    };
+    run();
+    assert_eq!(dst.get("/dst/sub/file3.txt").unwrap(), b"content 3");
+    assert!(dst.stat("/dst/sub/empty").unwrap().is_dir());
+
+    // The next put fails, so a second run that rewrites anything errors.
+    dst.fail_nth_put(1);
+    run();
+}
+
+#[test]
+/// An injected put failure surfaces as a copy error without aborting the rest
+fn test_copy_reports_injected_put_failure() {
+    let src = MemoryBackend::new();
+    let dst = MemoryBackend::new();
+    populate(&src, "/src", 8);
+    dst.fail_nth_put(3);
+
+    let result = parsync::copy(
+        Arc::new(src),
+        "/src",
+        Arc::new(dst.clone()),
+        "/dst",
+        &common::copy_options(),
+    );
+
+    assert!(result.is_err());
+    assert_eq!(dst.list("/dst/sub").unwrap().len(), 8);
+}
+
+#[test]
+fn test_memorybackend_truncated_writes_and_slow_reads() {
+    use std::io::Read;
+
+    let mem = MemoryBackend::new();
+    mem.truncate_writes_to(Some(4));
+    mem.put("/file.bin", b"0123456789").unwrap();
+    assert_eq!(mem.get("/file.bin").unwrap(), b"0123");
+    assert_eq!(mem.stat("/file.bin").unwrap().size, 4);
+
+    mem.truncate_writes_to(None);
+    mem.put("/file.bin", b"0123456789").unwrap();
+    mem.set_read_delay(Duration::from_millis(20));
+    let started = std::time::Instant::now();
+    let mut out = Vec::new();
+    mem.open_read("/file.bin", 2, Some(3))
+        .unwrap()
+        .read_to_end(&mut out)
+        .unwrap();
+    assert_eq!(out, b"234");
+    assert!(started.elapsed() >= Duration::from_millis(20));
+}
+
+#[test]
+fn test_mem_url_shares_named_store() {
+    let (a, path) = backend_and_path("mem://shared-test/dir/file.txt", 1).unwrap();
+    assert_eq!(path, "/dir/file.txt");
+    a.mkdir("/dir").unwrap();
+    a.put(path, b"shared").unwrap();
+
+    let (b, _) = backend_and_path("mem://shared-test/", 1).unwrap();
+    assert_eq!(b.get("/dir/file.txt").unwrap(), b"shared");
+
+    let (other, _) = backend_and_path("mem://other-test/", 1).unwrap();
+    assert!(!other.exists("/dir/file.txt").unwrap());
+}
@@ -0,0 +1,147 @@
+use parsync::backends::pool::{Connector, Pool};
+use parsync::backends::SyncError;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+struct Conn {
+    alive: Arc<AtomicBool>,
+}
+
+/// Refuses connections while `limit` are alive, like a server with
+/// MaxStartups or MaxSessions; keeps every connection's liveness flag.
+#[derive(Clone, Default)]
+struct Server {
+    opened: Arc<AtomicUsize>,
+    limit: Arc<AtomicUsize>,
+    flags: Arc<std::sync::Mutex<Vec<Arc<AtomicBool>>>>,
+}
+
+impl Server {
+    fn new(limit: usize) -> Self {
+        let server = Self::default();
+        server.limit.store(limit, Ordering::SeqCst);
+        server
+    }
+
+    fn kill_all(&self) {
+        for flag in self.flags.lock().unwrap().iter() {
+            flag.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Connector<Conn> for Server {
+    fn connect(&self) -> Result<Conn, SyncError> {
+        let live = self
+            .flags
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|f| f.load(Ordering::SeqCst))
+            .count();
+        if live >= self.limit.load(Ordering::SeqCst) {
+            return Err(SyncError::Other("too many sessions".to_string()));
+        }
+        self.opened.fetch_add(1, Ordering::SeqCst);
+        let alive = Arc::new(AtomicBool::new(true));
+        self.flags.lock().unwrap().push(alive.clone());
+        Ok(Conn { alive })
+    }
+
+    fn is_alive(&self, conn: &mut Conn, _idle: Duration) -> bool {
+        conn.alive.load(Ordering::SeqCst)
+    }
+}
+
+fn growable(max: usize, server: &Server) -> Arc<Pool<Conn>> {
+    let first = server.connect().unwrap();
+    Arc::new(Pool::growable(max, first, server.clone()))
+}
+
+/// Runs `threads` workers that each check a connection out `rounds` times
+/// and hold it briefly; returns the most held at once.
+fn hammer(pool: &Arc<Pool<Conn>>, threads: usize, rounds: usize) -> usize {
+    let held = AtomicUsize::new(0);
+    let peak = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..rounds {
+                    let _conn = pool.checkout().unwrap();
+                    let now = held.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(2));
+                    held.fetch_sub(1, Ordering::SeqCst);
+                }
+            });
+        }
+    });
+    peak.into_inner()
+}
+
+#[test]
+/// Connections are opened as workers need them, never past the maximum,
+/// and a server refusing extra sessions just leaves the pool smaller
+fn test_pool_grows_lazily_within_limits() {
+    let server = Server::new(usize::MAX);
+    let pool = growable(4, &server);
+    assert_eq!(pool.open(), 1);
+    drop(pool.checkout().unwrap());
+    assert_eq!(server.opened.load(Ordering::SeqCst), 1);
+
+    let peak = hammer(&pool, 8, 20);
+    assert!(peak <= 4, "{peak}");
+    assert_eq!(pool.open(), server.opened.load(Ordering::SeqCst));
+    assert!(pool.open() <= 4);
+
+    let server = Server::new(2);
+    let pool = growable(8, &server);
+    let peak = hammer(&pool, 6, 20);
+    assert!(peak <= 2, "{peak}");
+    assert_eq!(pool.open(), 2);
+    assert_eq!(server.opened.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+/// Dead idle connections are replaced on checkout, broken ones aren't
+/// returned, and a pool that can't reconnect fails instead of panicking
+fn test_pool_replaces_dead_connections() {
+    let server = Server::new(usize::MAX);
+    let pool = growable(2, &server);
+    server.kill_all();
+    let mut conn = pool.checkout().unwrap();
+    assert!(conn.alive.load(Ordering::SeqCst));
+    assert_eq!(server.opened.load(Ordering::SeqCst), 2);
+    assert_eq!(pool.open(), 1);
+
+    conn.mark_broken();
+    drop(conn);
+    assert_eq!(pool.open(), 0);
+    drop(pool.checkout().unwrap());
+    assert_eq!(server.opened.load(Ordering::SeqCst), 3);
+
+    // Workers keep going while every connection dies under them.
+    thread::scope(|s| {
+        s.spawn(|| {
+            for _ in 0..5 {
+                thread::sleep(Duration::from_millis(5));
+                server.kill_all();
+            }
+        });
+        hammer(&pool, 4, 10);
+    });
+
+    server.kill_all();
+    server.limit.store(0, Ordering::SeqCst);
+    assert!(pool.checkout().is_err());
+
+    let fixed = Arc::new(Pool::new(vec![1, 2]));
+    let mut one = fixed.checkout().unwrap();
+    let mut two = fixed.checkout().unwrap();
+    one.mark_broken();
+    two.mark_broken();
+    drop((one, two));
+    assert!(fixed.checkout().is_err());
+}
@@ -0,0 +1,157 @@
+mod common;
+
+use parsync::backends::{FileEntry, FileMeta, MemoryBackend, StorageBackend, SyncError};
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Progress of one transfer between two slow in-memory "remotes".
+#[derive(Default)]
+struct Link {
+    read: AtomicU64,
+    written: AtomicU64,
+    /// Most bytes read from the source but not yet written.
+    ahead: AtomicU64,
+    writing: AtomicBool,
+    /// Whether the source was read while the destination was writing.
+    overlapped: AtomicBool,
+}
+
+/// A [`MemoryBackend`] that reads or writes 64 KiB at a time with a delay
+/// per chunk, like a network backend; reads of paths containing `broken`
+/// fail halfway.
+struct Slow {
+    inner: MemoryBackend,
+    link: Arc<Link>,
+    delay: Duration,
+}
+
+struct SlowReader {
+    data: io::Cursor<Vec<u8>>,
+    link: Arc<Link>,
+    delay: Duration,
+    fail_at: Option<u64>,
+}
+
+impl Read for SlowReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.fail_at.is_some_and(|at| self.data.position() >= at) {
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset, "dropped"));
+        }
+        std::thread::sleep(self.delay);
+        let len = buf.len().min(64 << 10);
+        let n = self.data.read(&mut buf[..len])?;
+        if self.link.writing.load(Ordering::SeqCst) {
+            self.link.overlapped.store(true, Ordering::SeqCst);
+        }
+        self.link.read.fetch_add(n as u64, Ordering::SeqCst);
+        Ok(n)
+    }
+}
+
+impl StorageBackend for Slow {
+    fn list(&self, path: &str) -> Result<Vec<FileEntry>, SyncError> {
+        self.inner.list(path)
+    }
+    fn get(&self, path: &str) -> Result<Vec<u8>, SyncError> {
+        self.inner.get(path)
+    }
+    fn put(&self, path: &str, data: &[u8]) -> Result<(), SyncError> {
+        self.inner.put(path, data)
+    }
+    fn delete(&self, path: &str) -> Result<(), SyncError> {
+        self.inner.delete(path)
+    }
+    fn exists(&self, path: &str) -> Result<bool, SyncError> {
+        self.inner.exists(path)
+    }
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn stat(&self, path: &str) -> Result<FileMeta, SyncError> {
+        self.inner.stat(path)
+    }
+    fn mkdir(&self, path: &str) -> Result<(), SyncError> {
+        self.inner.mkdir(path)
+    }
+    fn walk(&self, root: &str, visit: &mut dyn FnMut(FileEntry)) -> Result<(), SyncError> {
+        self.inner.walk(root, visit)
+    }
+    fn set_metadata(&self, path: &str, meta: &FileMeta) -> Result<(), SyncError> {
+        self.inner.set_metadata(path, meta)
+    }
+    fn open_read(
+        &self,
+        path: &str,
+        _offset: u64,
+        _len: Option<u64>,
+    ) -> Result<Box<dyn Read + Send>, SyncError> {
+        let data = self.inner.get(path)?;
+        Ok(Box::new(SlowReader {
+            fail_at: path.contains("broken").then_some(data.len() as u64 / 2),
+            data: io::Cursor::new(data),
+            link: self.link.clone(),
+            delay: self.delay,
+        }))
+    }
+    fn put_stream(&self, path: &str, reader: &mut dyn Read, _size: u64) -> Result<(), SyncError> {
+        let link = &self.link;
+        let mut data = Vec::new();
+        let mut buf = vec![0u8; 64 << 10];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            link.writing.store(true, Ordering::SeqCst);
+            std::thread::sleep(self.delay);
+            data.extend_from_slice(&buf[..n]);
+            let written = link.written.fetch_add(n as u64, Ordering::SeqCst) + n as u64;
+            let ahead = link.read.load(Ordering::SeqCst).saturating_sub(written);
+            link.ahead.fetch_max(ahead, Ordering::SeqCst);
+            link.writing.store(false, Ordering::SeqCst);
+        }
+        self.inner.put(path, &data)
+    }
+}
+
+#[test]
+/// Between two remote backends the source is read ahead of a slower
+/// destination, never by more than a few blocks, and a source dropping
+/// mid-file fails only that file
+fn test_remote_to_remote_relay() {
+    let link = Arc::new(Link::default());
+    let src = Arc::new(Slow {
+        inner: MemoryBackend::new(),
+        link: link.clone(),
+        delay: Duration::from_millis(1),
+    });
+    let dst = Arc::new(Slow {
+        inner: MemoryBackend::new(),
+        link: link.clone(),
+        delay: Duration::from_millis(3),
+    });
+    let big = common::noise(8 << 20, 0);
+    src.mkdir("/a/sub").unwrap();
+    src.put("/a/big.bin", &big).unwrap();
+    src.put("/a/sub/small.txt", b"small").unwrap();
+    src.put("/a/sub/broken.bin", &common::noise(1 << 20, 1))
+        .unwrap();
+
+    let mut options = common::copy_options();
+    options.threads = 1;
+    assert!(parsync::copy(src.clone(), "/a", dst.clone(), "/b", &options).is_err());
+    assert_eq!(dst.get("/b/big.bin").unwrap(), big);
+    assert_eq!(dst.get("/b/sub/small.txt").unwrap(), b"small");
+    assert!(!dst.exists("/b/sub/broken.bin").unwrap());
+    assert!(link.overlapped.load(Ordering::SeqCst));
+    let ahead = link.ahead.load(Ordering::SeqCst);
+    assert!(ahead > 0 && ahead <= 3 << 20, "{ahead}");
+
+    src.delete("/a/sub/broken.bin").unwrap();
+    src.put("/a/sub/small.txt", b"changed").unwrap();
+    parsync::sync(src.clone(), "/a", dst.clone(), "/b", true, false).unwrap();
+    assert_eq!(dst.get("/b/sub/small.txt").unwrap(), b"changed");
+    assert_eq!(dst.get("/b/big.bin").unwrap(), big);
+}
@@ -0,0 +1,182 @@
+mod common;
+
+use parsync::backends::{backend_and_path, LocalBackend, RepoBackend, StorageBackend};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+use tempfile::tempdir;
+
+fn chunk_count(repo: &Path) -> usize {
+    walkdir::WalkDir::new(repo.join("chunks"))
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file())
+        .count()
+}
+
+fn backup(src: &Path, url: &str) {
+    let (repo, root) = backend_and_path(url, 4).unwrap();
+    parsync::sync(
+        Arc::new(LocalBackend::new()),
+        src.to_str().unwrap(),
+        repo.clone(),
+        root,
+        true,
+        false,
+    )
+    .unwrap();
+    repo.finish().unwrap();
+}
+
+fn restore(url: &str, out: &Path) {
+    let (repo, root) = backend_and_path(url, 4).unwrap();
+    parsync::copy(
+        repo,
+        root,
+        Arc::new(LocalBackend::new()),
+        out.to_str().unwrap(),
+        &common::copy_options(),
+    )
+    .unwrap();
+}
+
+#[test]
+/// Two backups share unchanged chunks, even after bytes are inserted at the
+/// front of a large file, and each snapshot restores as it was taken
+fn test_repo_snapshots_dedupe_and_restore() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("docs/empty")).unwrap();
+    let big = common::noise(12 << 20, 7);
+    fs::write(src.join("big.bin"), &big).unwrap();
+    fs::write(src.join("docs/a.txt"), b"alpha").unwrap();
+    fs::write(src.join("docs/copy.bin"), &big[..3 << 20]).unwrap();
+    let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 5);
+    filetime::set_file_mtime(src.join("docs/a.txt"), mtime).unwrap();
+
+    let repo_dir = dir.path().join("repo");
+    let url = format!("repo://{}", repo_dir.display());
+    backup(&src, &url);
+    let first_chunks = chunk_count(&repo_dir);
+    assert!(first_chunks > 5, "{first_chunks}");
+
+    let mut shifted = b"inserted at the front".to_vec();
+    shifted.extend_from_slice(&big);
+    fs::write(src.join("big.bin"), &shifted).unwrap();
+    fs::write(src.join("docs/b.txt"), b"beta").unwrap();
+    backup(&src, &url);
+    // The first chunk of big.bin changes and b.txt is new; the rest is
+    // shared with the first snapshot.
+    let new_chunks = chunk_count(&repo_dir) - first_chunks;
+    assert!((2..=3).contains(&new_chunks), "{new_chunks}");
+
+    let repo = RepoBackend::new(&repo_dir, None);
+    let snapshots = repo.snapshots().unwrap();
+    assert_eq!(snapshots.len(), 2);
+    assert_eq!(snapshots[1].parent.as_deref(), Some(&*snapshots[0].id));
+    assert_eq!(snapshots[1].files, 4);
+
+    let old = dir.path().join("old");
+    restore(&format!("{url}?snapshot={}", snapshots[0].id), &old);
+    assert_eq!(fs::read(old.join("big.bin")).unwrap(), big);
+    assert!(!old.join("docs/b.txt").exists());
+    assert!(old.join("docs/empty").is_dir());
+    let meta = fs::metadata(old.join("docs/a.txt")).unwrap();
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&meta),
+        mtime
+    );
+
+    let new = dir.path().join("new");
+    restore(&url, &new);
+    assert_eq!(fs::read(new.join("big.bin")).unwrap(), shifted);
+    assert_eq!(fs::read(new.join("docs/b.txt")).unwrap(), b"beta");
+    assert_eq!(
+        fs::read(new.join("docs/copy.bin")).unwrap(),
+        &big[..3 << 20]
+    );
+}
+
+#[test]
+/// Forgetting a snapshot and collecting garbage frees only the chunks no
+/// other snapshot uses; corrupt chunks fail reads
+fn test_repo_forget_gc_and_corruption() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("keep.txt"), b"kept in both").unwrap();
+    fs::write(src.join("old.txt"), b"first version").unwrap();
+    let repo_dir = dir.path().join("repo");
+    let url = format!("repo://{}", repo_dir.display());
+    backup(&src, &url);
+    fs::write(src.join("old.txt"), b"second version!").unwrap();
+    backup(&src, &url);
+
+    let repo = RepoBackend::new(&repo_dir, None);
+    let first = repo.snapshots().unwrap()[0].id.clone();
+    assert_eq!(repo.gc().unwrap().chunks_removed, 0);
+    repo.forget(&first).unwrap();
+    assert!(repo.forget(&first).is_err());
+    let stats = repo.gc().unwrap();
+    assert_eq!((stats.chunks_removed, stats.chunks_kept), (1, 2));
+    assert_eq!(repo.get("/old.txt").unwrap(), b"second version!");
+
+    // A read-only repo view; nothing to save on finish.
+    repo.finish().unwrap();
+    assert_eq!(repo.snapshots().unwrap().len(), 1);
+
+    let chunk = walkdir::WalkDir::new(repo_dir.join("chunks"))
+        .into_iter()
+        .filter_map(Result::ok)
+        .find(|e| e.file_type().is_file())
+        .unwrap();
+    let data = zstd::encode_all(&b"tampered"[..], 3).unwrap();
+    fs::write(chunk.path(), data).unwrap();
+    let fresh = RepoBackend::new(&repo_dir, None);
+    let results = [fresh.get("/keep.txt"), fresh.get("/old.txt")];
+    assert_eq!(results.iter().filter(|r| r.is_err()).count(), 1);
+
+    assert!(RepoBackend::new(dir.path(), None).snapshots().is_err());
+    assert!(backend_and_path(&format!("{url}?snapshot=missing"), 1)
+        .unwrap()
+        .0
+        .stat("/")
+        .is_err());
+}
+
+#[test]
+/// `parsync repo` lists snapshots and collects garbage
+fn test_repo_cli() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+    fs::write(src.join("f.txt"), b"file").unwrap();
+    let repo_dir = dir.path().join("repo");
+    let bin = env!("CARGO_BIN_EXE_parsync");
+    let url = format!("repo://{}", repo_dir.display());
+    for _ in 0..2 {
+        let status = Command::new(bin)
+            .args(["--no-progress", "copy", src.to_str().unwrap(), &url])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+    let out = Command::new(bin)
+        .args(["repo", repo_dir.to_str().unwrap(), "snapshots"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    let listing = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(listing.lines().count(), 2, "{listing}");
+    assert!(listing.lines().all(|l| l.contains("1 files  4 bytes")));
+
+    let out = Command::new(bin)
+        .args(["repo", &url, "gc"])
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stdout)
+        .unwrap()
+        .contains("Removed 0 chunks"));
+}
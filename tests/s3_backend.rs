@@ -0,0 +1,359 @@
+mod common;
+
+use common::Reply;
+use parsync::backends::{LocalBackend, S3Backend, S3Config, StorageBackend};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tempfile::tempdir;
+
+/// Keys returned per ListObjectsV2 page, small enough to force pagination.
+const PAGE_SIZE: usize = 3;
+
+/// Pending multipart upload: its mtime metadata and parts by number.
+type Upload = (Option<String>, BTreeMap<u32, Vec<u8>>);
+
+#[derive(Default)]
+struct Bucket {
+    objects: BTreeMap<String, (Vec<u8>, Option<String>)>,
+    uploads: HashMap<String, Upload>,
+    puts: usize,
+    parts: usize,
+    copies: usize,
+    heads: usize,
+}
+
+/// Just enough of the S3 REST API, path-style on bucket `bucket`, to
+/// exercise `S3Backend`. Signatures are not checked.
+struct FakeS3 {
+    endpoint: String,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl FakeS3 {
+    fn start() -> Self {
+        let bucket = Arc::new(Mutex::new(Bucket::default()));
+        let shared = bucket.clone();
+        let endpoint = common::serve(move |req| {
+            assert!(req.headers["authorization"].starts_with("AWS4-HMAC-SHA256 Credential=access/"));
+            let key = req
+                .path
+                .strip_prefix("/bucket")
+                .unwrap()
+                .trim_start_matches('/');
+            handle(
+                &mut shared.lock().unwrap(),
+                &req.method,
+                key,
+                &req.query,
+                &req.headers,
+                req.body,
+            )
+        });
+        Self { endpoint, bucket }
+    }
+
+    fn backend(&self) -> S3Backend {
+        S3Backend::new(self.config(), 4).unwrap()
+    }
+
+    fn config(&self) -> S3Config {
+        S3Config {
+            bucket: "bucket".to_string(),
+            region: "us-east-1".to_string(),
+            endpoint: Some(self.endpoint.clone()),
+            access_key: "access".to_string(),
+            secret_key: "secret".to_string(),
+            session_token: None,
+            stored_mtimes: true,
+        }
+    }
+
+    fn puts(&self) -> usize {
+        self.bucket.lock().unwrap().puts
+    }
+}
+
+fn not_found() -> Reply {
+    let body = "<Error><Code>NoSuchKey</Code><Message>missing</Message></Error>";
+    (404, Vec::new(), body.into())
+}
+
+fn handle(
+    bucket: &mut Bucket,
+    method: &str,
+    key: &str,
+    query: &HashMap<String, String>,
+    headers: &HashMap<String, String>,
+    body: Vec<u8>,
+) -> Reply {
+    let ok = |body: String| (200, Vec::new(), body.into_bytes());
+    let mtime = headers.get("x-amz-meta-mtime").cloned();
+    match method {
+        "GET" if key.is_empty() => {
+            let prefix = query.get("prefix").cloned().unwrap_or_default();
+            let delimiter = query.contains_key("delimiter");
+            let mut items: Vec<String> = Vec::new();
+            for (k, (data, _)) in bucket.objects.range(prefix.clone()..) {
+                let Some(rest) = k.strip_prefix(&prefix) else {
+                    break;
+                };
+                let item = match rest.find('/') {
+                    Some(i) if delimiter => {
+                        format!("<CommonPrefixes><Prefix>{prefix}{}</Prefix></CommonPrefixes>", &rest[..=i])
+                    }
+                    _ => format!(
+                        "<Contents><Key>{k}</Key><LastModified>2020-01-01T00:00:00.000Z</LastModified><Size>{}</Size></Contents>",
+                        data.len()
+                    ),
+                };
+                if items.last() != Some(&item) {
+                    items.push(item);
+                }
+            }
+            let start: usize = query
+                .get("continuation-token")
+                .map_or(0, |t| t.parse().unwrap());
+            let end = (start + PAGE_SIZE).min(items.len());
+            let truncated = end < items.len();
+            ok(format!(
+                "<ListBucketResult>{}<IsTruncated>{truncated}</IsTruncated>{}</ListBucketResult>",
+                items[start..end].concat(),
+                if truncated {
+                    format!("<NextContinuationToken>{end}</NextContinuationToken>")
+                } else {
+                    String::new()
+                }
+            ))
+        }
+        "HEAD" | "GET" => {
+            bucket.heads += (method == "HEAD") as usize;
+            let Some((data, mtime)) = bucket.objects.get(key) else {
+                return not_found();
+            };
+            let mut extra = Vec::new();
+            if let Some(m) = mtime {
+                extra.push(("x-amz-meta-mtime".to_string(), m.clone()));
+            }
+            if method == "HEAD" {
+                return (200, extra, vec![0; data.len()]);
+            }
+            match headers.get("range") {
+                Some(range) => {
+                    let (start, end) = range
+                        .strip_prefix("bytes=")
+                        .unwrap()
+                        .split_once('-')
+                        .unwrap();
+                    let start: usize = start.parse().unwrap();
+                    if start >= data.len() {
+                        return (416, Vec::new(), Vec::new());
+                    }
+                    let end = end
+                        .parse::<usize>()
+                        .map_or(data.len(), |e| (e + 1).min(data.len()));
+                    (206, extra, data[start..end].to_vec())
+                }
+                None => (200, extra, data.clone()),
+            }
+        }
+        "PUT" => {
+            bucket.puts += 1;
+            if let (Some(part), Some(id)) = (query.get("partNumber"), query.get("uploadId")) {
+                bucket.parts += 1;
+                let upload = bucket.uploads.get_mut(id).unwrap();
+                upload.1.insert(part.parse().unwrap(), body);
+                return (
+                    200,
+                    vec![("ETag".into(), format!("\"p{part}\""))],
+                    Vec::new(),
+                );
+            }
+            if let Some(source) = headers.get("x-amz-copy-source") {
+                bucket.copies += 1;
+                let source = common::decode(source);
+                let source = source.strip_prefix("/bucket/").unwrap();
+                let Some((data, _)) = bucket.objects.get(source).cloned() else {
+                    return not_found();
+                };
+                bucket.objects.insert(key.to_string(), (data, mtime));
+                return ok("<CopyObjectResult><ETag>\"c\"</ETag></CopyObjectResult>".into());
+            }
+            bucket.objects.insert(key.to_string(), (body, mtime));
+            ok(String::new())
+        }
+        "POST" if query.contains_key("uploads") => {
+            let id = format!("upload{}", bucket.uploads.len());
+            bucket.uploads.insert(id.clone(), (mtime, BTreeMap::new()));
+            ok(format!(
+                "<InitiateMultipartUploadResult><UploadId>{id}</UploadId></InitiateMultipartUploadResult>"
+            ))
+        }
+        "POST" => {
+            let (mtime, parts) = bucket.uploads.remove(&query["uploadId"]).unwrap();
+            bucket.objects.insert(
+                key.to_string(),
+                (parts.into_values().flatten().collect(), mtime),
+            );
+            ok("<CompleteMultipartUploadResult/>".into())
+        }
+        "DELETE" => {
+            bucket.objects.remove(key);
+            (204, Vec::new(), Vec::new())
+        }
+        _ => {
+            let body = format!("<Error><Code>BadRequest</Code><Message>unexpected {method} {key}</Message></Error>");
+            (400, Vec::new(), body.into())
+        }
+    }
+}
+
+#[test]
+fn test_s3backend_object_operations() {
+    let server = FakeS3::start();
+    let s3 = server.backend();
+
+    for i in 0..5 {
+        s3.put(&format!("/dir/file{i}.txt"), format!("data {i}").as_bytes())
+            .unwrap();
+    }
+    s3.put("/dir/sub/nested.txt", b"0123456789").unwrap();
+
+    assert_eq!(s3.get("/dir/file1.txt").unwrap(), b"data 1");
+    assert!(s3.exists("/dir/sub").unwrap());
+    assert!(!s3.exists("/dir/missing").unwrap());
+    assert!(s3.stat("/dir").unwrap().is_dir());
+    assert_eq!(s3.stat("/dir/sub/nested.txt").unwrap().size, 10);
+
+    // Five files and one prefix span several listing pages.
+    let mut names: Vec<_> = s3
+        .list("/dir")
+        .unwrap()
+        .into_iter()
+        .map(|e| e.path)
+        .collect();
+    names.sort();
+    assert_eq!(names.len(), 6);
+    assert_eq!(names[5], "/dir/sub");
+
+    let mut range = Vec::new();
+    s3.open_read("/dir/sub/nested.txt", 3, Some(4))
+        .unwrap()
+        .read_to_end(&mut range)
+        .unwrap();
+    assert_eq!(range, b"3456");
+
+    let mtime = UNIX_EPOCH + Duration::new(1_600_000_000, 5);
+    let mut meta = s3.stat("/dir/file0.txt").unwrap();
+    meta.modified = Some(mtime);
+    s3.set_metadata("/dir/file0.txt", &meta).unwrap();
+    assert_eq!(s3.stat("/dir/file0.txt").unwrap().modified, Some(mtime));
+
+    s3.rename("/dir/sub", "/moved").unwrap();
+    assert_eq!(s3.get("/moved/nested.txt").unwrap(), b"0123456789");
+    assert!(!s3.exists("/dir/sub").unwrap());
+
+    s3.delete("/dir").unwrap();
+    assert!(matches!(
+        s3.stat("/dir"),
+        Err(parsync::SyncError::NotFound(_))
+    ));
+    assert!(s3.exists("/moved/nested.txt").unwrap());
+}
+
+#[test]
+/// Copy local → S3 → local round-trips nested files, multipart uploads and
+/// nanosecond mtimes
+fn test_copy_round_trip_through_s3() {
+    let server = FakeS3::start();
+    let s3 = Arc::new(server.backend());
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("a/b")).unwrap();
+    fs::write(src.join("top.txt"), b"top").unwrap();
+    fs::write(src.join("a/b/deep.txt"), b"deep").unwrap();
+    let large: Vec<u8> = (0..(17 << 20) + 123).map(|i| (i % 251) as u8).collect();
+    fs::write(src.join("a/large.bin"), &large).unwrap();
+    let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 123_456_789);
+    filetime::set_file_mtime(src.join("a/b/deep.txt"), mtime).unwrap();
+
+    let local = Arc::new(LocalBackend::new());
+    parsync::copy(
+        local.clone(),
+        src.to_str().unwrap(),
+        s3.clone(),
+        "/backup",
+        &common::copy_options(),
+    )
+    .unwrap();
+    assert_eq!(server.bucket.lock().unwrap().parts, 3);
+    assert_eq!(server.bucket.lock().unwrap().copies, 0);
+    assert_eq!(s3.get("/backup/top.txt").unwrap(), b"top");
+
+    let out = dir.path().join("out");
+    parsync::copy(
+        s3,
+        "/backup",
+        local,
+        out.to_str().unwrap(),
+        &common::copy_options(),
+    )
+    .unwrap();
+    assert_eq!(fs::read(out.join("a/large.bin")).unwrap(), large);
+    assert_eq!(fs::read(out.join("a/b/deep.txt")).unwrap(), b"deep");
+    let meta = fs::metadata(out.join("a/b/deep.txt")).unwrap();
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&meta),
+        mtime
+    );
+
+    // Without stored mtimes, a walk costs only the HEAD probing its root and
+    // reports LastModified; stat still reads the stored mtime.
+    let mut config = server.config();
+    config.stored_mtimes = false;
+    let s3 = S3Backend::new(config, 4).unwrap();
+    let heads = server.bucket.lock().unwrap().heads;
+    let mut modified = Vec::new();
+    s3.walk("/backup/a", &mut |entry| {
+        if entry.metadata.is_file() {
+            modified.push(entry.metadata.modified);
+        }
+    })
+    .unwrap();
+    assert_eq!(server.bucket.lock().unwrap().heads, heads + 1);
+    let last_modified = UNIX_EPOCH + Duration::from_secs(1_577_836_800);
+    assert_eq!(modified, vec![Some(last_modified); 2]);
+    assert_eq!(
+        s3.stat("/backup/a/b/deep.txt").unwrap().modified,
+        Some(meta.modified().unwrap())
+    );
+}
+
+#[test]
+/// A second sync to S3 uploads nothing
+fn test_sync_to_s3_converges() {
+    let server = FakeS3::start();
+    let dir = tempdir().unwrap();
+    for i in 0..6 {
+        fs::write(dir.path().join(format!("file{i}.txt")), b"payload").unwrap();
+    }
+
+    let run = || {
+        parsync::sync(
+            Arc::new(LocalBackend::new()),
+            dir.path().to_str().unwrap(),
+            Arc::new(server.backend()),
+            "/mirror",
+            true,
+            false,
+        )
+        .unwrap()
+    };
+    run();
+    let puts = server.puts();
+    assert!(puts > 0);
+    run();
+    assert_eq!(server.puts(), puts);
+}
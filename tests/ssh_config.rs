@@ -0,0 +1,118 @@
+use parsync::backends::backend_and_path;
+use parsync::backends::known_hosts::StrictHostKeyChecking;
+use parsync::backends::ssh_config::HostConfig;
+use parsync::backends::SshConfig;
+use std::fs;
+use std::path::PathBuf;
+use tempfile::tempdir;
+
+#[test]
+/// Aliases resolve through matching `Host` blocks and includes, first value
+/// wins, tokens expand, and URL user and port take precedence
+fn test_ssh_config_resolves_aliases() {
+    let dir = tempdir().unwrap();
+    let keys = dir.path().join("keys");
+    fs::create_dir_all(dir.path().join("conf.d")).unwrap();
+    fs::write(
+        dir.path().join("conf.d/10-team.conf"),
+        "Host *.internal !legacy.internal\n  User team\n  Port 2202\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.path().join("config"),
+        format!(
+            "# defaults for every host\n\
+             StrictHostKeyChecking=yes\n\
+             Include conf.d/*.conf\n\
+             \n\
+             Host prod-db db\n\
+             \tHostName db1.%h.example.com\n\
+             \tUser deploy\n\
+             \tIdentityFile \"{keys}/%r@%h key\"\n\
+             \tIdentityFile {keys}/fallback\n\
+             \tIdentitiesOnly yes\n\
+             \tBatchMode yes\n\
+             \tUserKnownHostsFile {keys}/known_%n\n\
+             \n\
+             Match exec \"true\"\n\
+             \tUser never\n\
+             \n\
+             Host *\n\
+             \tUser everyone\n\
+             \tIdentityFile {keys}/fallback\n\
+             \tPort 2200\n",
+            keys = keys.display()
+        ),
+    )
+    .unwrap();
+    let files = [dir.path().join("config"), dir.path().join("missing")];
+
+    let config = SshConfig::resolve_with("prod-db", None, None, &files).unwrap();
+    assert_eq!(config.host, "db1.prod-db.example.com");
+    assert_eq!(config.user, "deploy");
+    assert_eq!(config.port, 2200);
+    assert_eq!(
+        config.identity_files,
+        [
+            keys.join("deploy@db1.prod-db.example.com key"),
+            keys.join("fallback")
+        ]
+    );
+    assert!(config.identities_only && config.batch_mode);
+    assert_eq!(config.known_hosts, keys.join("known_prod-db"));
+    assert_eq!(config.strict_host_key_checking, StrictHostKeyChecking::Yes);
+
+    let config = SshConfig::resolve_with("prod-db", Some("admin"), Some(22), &files).unwrap();
+    assert_eq!((config.user.as_str(), config.port), ("admin", 22));
+
+    let config = SshConfig::resolve_with("web.internal", None, None, &files).unwrap();
+    assert_eq!(config.host, "web.internal");
+    assert_eq!((config.user.as_str(), config.port), ("team", 2202));
+    assert!(!config.identities_only && !config.batch_mode);
+
+    let legacy = HostConfig::load("legacy.internal", &files).unwrap();
+    assert_eq!(legacy.user.as_deref(), Some("everyone"));
+    assert_eq!(
+        legacy.identity_files,
+        [format!("{}/fallback", keys.display())]
+    );
+    assert_eq!(legacy.host_name, None);
+    assert_eq!(legacy.proxy_jump, None);
+}
+
+#[test]
+/// Broken configs are errors, and a `ProxyJump` host is reached through the
+/// jump host's own entry
+fn test_ssh_config_errors() {
+    let dir = tempdir().unwrap();
+    let write = |name: &str, text: &str| -> PathBuf {
+        let path = dir.path().join(name);
+        fs::write(&path, text).unwrap();
+        path
+    };
+
+    let bad_port = write("bad_port", "Host x\n  Port many\n");
+    assert!(HostConfig::load("x", std::slice::from_ref(&bad_port)).is_err());
+    // The block doesn't apply, so its contents don't matter.
+    assert!(HostConfig::load("y", &[bad_port]).is_ok());
+    let looping = write("looping", "Include looping\n");
+    assert!(HostConfig::load("x", &[looping]).is_err());
+    let token = write("token", "Host x\n  IdentityFile ~/.ssh/%C\n");
+    assert!(SshConfig::resolve_with("x", None, None, &[token]).is_err());
+
+    let jump = write(
+        "jump",
+        "Host prod-db\n  ProxyJump bastion\nHost direct\n  ProxyJump none\n\
+         Host bastion\n  HostName 127.0.0.1\n  Port 1\n",
+    );
+    let config =
+        SshConfig::resolve_with("direct", None, None, std::slice::from_ref(&jump)).unwrap();
+    assert_eq!(config.proxy_jump, None);
+    // The bastion is dialled first, through its own config entry.
+    let url = format!("ssh://prod-db/data?ssh_config={}", jump.display());
+    let err = backend_and_path(&url, 1).err().unwrap();
+    assert!(
+        format!("{err:?}").contains("Jump host 127.0.0.1: TCP 127.0.0.1:1"),
+        "{err:?}"
+    );
+}
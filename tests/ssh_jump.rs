@@ -0,0 +1,88 @@
+use parsync::backends::backend_and_path;
+use parsync::backends::ssh_jump::{parse_jumps, JumpHost};
+use parsync::backends::SshConfig;
+use std::fs;
+use tempfile::tempdir;
+
+fn hop(user: Option<&str>, host: &str, port: Option<u16>) -> JumpHost {
+    JumpHost {
+        user: user.map(String::from),
+        host: host.to_string(),
+        port,
+    }
+}
+
+#[test]
+/// `ProxyJump` lists in OpenSSH's forms
+fn test_parse_jumps() {
+    assert_eq!(
+        parse_jumps("bastion, ops@10.0.0.5:2200,ssh://admin@[fe80::1]:22,[::1]").unwrap(),
+        [
+            hop(None, "bastion", None),
+            hop(Some("ops"), "10.0.0.5", Some(2200)),
+            hop(Some("admin"), "fe80::1", Some(22)),
+            hop(None, "::1", None),
+        ]
+    );
+    for bad in ["", "host:port", "@host", "a,,b", "[::1"] {
+        assert!(parse_jumps(bad).is_err(), "{bad}");
+    }
+}
+
+#[test]
+/// Each hop is resolved from the same config files; the URL option wins
+/// over `ProxyJump` and the first hop is dialled before the server
+fn test_jump_hosts_resolve_and_dial_first_hop() {
+    let dir = tempdir().unwrap();
+    let config_file = dir.path().join("config");
+    fs::write(
+        &config_file,
+        "Host bastion\n  HostName 127.0.0.1\n  User jump\n  Port 1\n  ProxyJump loop\n\
+         Host inner\n  Port 2202\n\
+         Host prod-db\n  ProxyJump bastion,ops@inner\n  BatchMode yes\n",
+    )
+    .unwrap();
+    let files = [config_file.clone()];
+
+    let mut config = SshConfig::resolve_with("prod-db", None, None, &files).unwrap();
+    config.passphrase_file = Some(dir.path().join("passphrase"));
+    config.password_file = Some(dir.path().join("password"));
+    let hops = config.jump_hosts().unwrap();
+    let summary: Vec<_> = hops
+        .iter()
+        .map(|h| (h.user.as_str(), h.host.as_str(), h.port))
+        .collect();
+    assert_eq!(summary, [("jump", "127.0.0.1", 1), ("ops", "inner", 2202)]);
+    for hop in &hops {
+        assert_eq!(hop.proxy_jump, None);
+        assert!(hop.batch_mode);
+        assert_eq!(hop.passphrase_file, config.passphrase_file);
+        assert_eq!(hop.password_file, None);
+    }
+    assert!(SshConfig::new("me", "direct", 22)
+        .jump_hosts()
+        .unwrap()
+        .is_empty());
+
+    let url = |jump: &str| {
+        format!(
+            "ssh://prod-db/data?ssh_config={}&proxy_jump={jump}",
+            config_file.display()
+        )
+    };
+    let err = backend_and_path(&url("root@127.0.0.1:1"), 1).err().unwrap();
+    assert!(
+        format!("{err:?}").contains("Jump host 127.0.0.1: TCP 127.0.0.1:1"),
+        "{err:?}"
+    );
+    assert!(backend_and_path(&url("bastion:port"), 1).is_err());
+    // `none` turns the configured jump off, so the server itself is dialled.
+    let url = format!(
+        "ssh://127.0.0.1:1/data?ssh_config={}&proxy_jump=none",
+        config_file.display()
+    );
+    let err = backend_and_path(&url, 1).err().unwrap();
+    let message = format!("{err:?}");
+    assert!(message.contains("TCP 127.0.0.1:1"), "{message}");
+    assert!(!message.contains("Jump host"), "{message}");
+}
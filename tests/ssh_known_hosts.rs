@@ -0,0 +1,217 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use parsync::backends::backend_and_path;
+use parsync::backends::known_hosts::{
+    fingerprint, HostKeyStatus, KnownHosts, StrictHostKeyChecking,
+};
+use std::fs;
+use tempfile::tempdir;
+
+/// An ed25519 public key blob whose 32 key bytes start at `first`.
+fn ed25519(first: u8) -> Vec<u8> {
+    let mut blob = Vec::new();
+    blob.extend_from_slice(&11u32.to_be_bytes());
+    blob.extend_from_slice(b"ssh-ed25519");
+    blob.extend_from_slice(&32u32.to_be_bytes());
+    blob.extend((0..32).map(|i| first.wrapping_add(i)));
+    blob
+}
+
+fn rsa() -> Vec<u8> {
+    let mut blob = Vec::new();
+    blob.extend_from_slice(&7u32.to_be_bytes());
+    blob.extend_from_slice(b"ssh-rsa");
+    blob.extend_from_slice(&[0, 0, 0, 1, 3, 0, 0, 0, 2, 0, 0xc5]);
+    blob
+}
+
+fn line(hosts: &str, key: &[u8]) -> String {
+    format!("{hosts} ssh-ed25519 {}\n", STANDARD.encode(key))
+}
+
+#[test]
+/// Plain, wildcard, negated, hashed and `[host]:port` entries; revoked keys;
+/// fingerprints as ssh-keygen prints them
+fn test_known_hosts_lookup() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("known_hosts");
+    // The two hashed lines are `ssh-keygen -H` output for hashed.example.com
+    // and [hashed.example.com]:2222.
+    let text = [
+        "# comment\n".to_string(),
+        "@cert-authority *.example.com ssh-ed25519 AAAA\n".to_string(),
+        "garbage\n".to_string(),
+        line("plain.example.com,10.0.0.1", &ed25519(0)),
+        line("*.example.com,!secret.example.com", &ed25519(100)),
+        "|1|4+FA+CmpjAmhcgqVmEXZ2ewi3AI=|CFLZi/VxK/IL35JQf460T6iG/1U= ssh-ed25519 \
+         AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f\n"
+            .to_string(),
+        "|1|f8k/269rXaX2adekIYAsNNQ8OEI=|pzV31/1Kca3PbmVIHouJ8vy1Drc= ssh-ed25519 \
+         AAAAC3NzaC1lZDI1NTE5AAAAIAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8g\n"
+            .to_string(),
+        format!("plain.example.com ssh-rsa {}\n", STANDARD.encode(rsa())),
+        format!("@revoked {}", line("*", &ed25519(200))),
+    ]
+    .join("");
+    fs::write(&path, text).unwrap();
+    let known = KnownHosts::load(&path).unwrap();
+
+    assert_eq!(
+        known.check("plain.example.com", 22, &ed25519(0)),
+        HostKeyStatus::Match
+    );
+    assert_eq!(
+        known.check("PLAIN.example.com", 22, &ed25519(0)),
+        HostKeyStatus::Match
+    );
+    assert_eq!(
+        known.check("10.0.0.1", 22, &ed25519(0)),
+        HostKeyStatus::Match
+    );
+    assert_eq!(
+        known.check("plain.example.com", 22, &ed25519(1)),
+        HostKeyStatus::Mismatch { line: 4 }
+    );
+    // The same host on another port is a different host.
+    assert_eq!(
+        known.check("plain.example.com", 2222, &ed25519(0)),
+        HostKeyStatus::Unknown
+    );
+    assert_eq!(
+        known.check("plain.example.com", 22, &rsa()),
+        HostKeyStatus::Match
+    );
+    assert_eq!(
+        known.key_types("plain.example.com", 22),
+        ["ssh-ed25519", "ssh-rsa"]
+    );
+
+    assert_eq!(
+        known.check("www.example.com", 22, &ed25519(100)),
+        HostKeyStatus::Match
+    );
+    assert_eq!(
+        known.check("secret.example.com", 22, &ed25519(100)),
+        HostKeyStatus::Unknown
+    );
+
+    assert_eq!(
+        known.check("hashed.example.com", 22, &ed25519(0)),
+        HostKeyStatus::Match
+    );
+    assert_eq!(
+        known.check("hashed.example.com", 2222, &ed25519(1)),
+        HostKeyStatus::Match
+    );
+    assert_eq!(
+        known.check("hashed.example.com", 2222, &ed25519(0)),
+        HostKeyStatus::Mismatch { line: 7 }
+    );
+
+    assert_eq!(
+        known.check("anything.org", 22, &ed25519(200)),
+        HostKeyStatus::Revoked { line: 9 }
+    );
+    assert!(known.key_types("anything.org", 22).is_empty());
+
+    assert_eq!(
+        fingerprint(&ed25519(0)),
+        "SHA256:ZkAslGjFiUHdGf/WUL8rQvkib4PTvQatUV0OUQSncCA"
+    );
+    assert_eq!(
+        fingerprint(&ed25519(1)),
+        "SHA256:mKqU+0K8OhKmA8bBQi9Rz0Q5l7/g160hIP+rJYSTNj4"
+    );
+}
+
+#[test]
+/// `yes` refuses new hosts, `accept-new` records them, changed keys fail
+/// with the new fingerprint unless checking is `no`
+fn test_known_hosts_policies() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("ssh/known_hosts");
+    let mut known = KnownHosts::load(&path).unwrap();
+
+    let err = known
+        .verify(
+            "new.example.com",
+            2200,
+            &ed25519(0),
+            StrictHostKeyChecking::Yes,
+        )
+        .unwrap_err();
+    assert!(
+        format!("{err:?}").contains(&fingerprint(&ed25519(0))),
+        "{err:?}"
+    );
+    assert!(!path.exists());
+
+    known
+        .verify(
+            "new.example.com",
+            2200,
+            &ed25519(0),
+            StrictHostKeyChecking::AcceptNew,
+        )
+        .unwrap();
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        line("[new.example.com]:2200", &ed25519(0))
+    );
+    known
+        .verify(
+            "new.example.com",
+            2200,
+            &ed25519(0),
+            StrictHostKeyChecking::Yes,
+        )
+        .unwrap();
+
+    // A file without a trailing newline gets one before the new entry.
+    fs::write(&path, line("other.example.com", &ed25519(9)).trim_end()).unwrap();
+    let mut known = KnownHosts::load(&path).unwrap();
+    known
+        .verify(
+            "new.example.com",
+            22,
+            &ed25519(0),
+            StrictHostKeyChecking::AcceptNew,
+        )
+        .unwrap();
+    let reloaded = KnownHosts::load(&path).unwrap();
+    assert_eq!(
+        reloaded.check("other.example.com", 22, &ed25519(9)),
+        HostKeyStatus::Match
+    );
+    assert_eq!(
+        reloaded.check("new.example.com", 22, &ed25519(0)),
+        HostKeyStatus::Match
+    );
+
+    let changed = ed25519(50);
+    let err = known
+        .verify(
+            "new.example.com",
+            22,
+            &changed,
+            StrictHostKeyChecking::AcceptNew,
+        )
+        .unwrap_err();
+    let message = format!("{err:?}");
+    assert!(message.contains(&fingerprint(&changed)), "{message}");
+    assert!(
+        message.contains(&format!("{}:2", path.display())),
+        "{message}"
+    );
+    known
+        .verify("new.example.com", 22, &changed, StrictHostKeyChecking::No)
+        .unwrap();
+
+    assert_eq!(
+        "accept-new".parse::<StrictHostKeyChecking>().unwrap(),
+        StrictHostKeyChecking::AcceptNew
+    );
+    assert!("maybe".parse::<StrictHostKeyChecking>().is_err());
+    assert!(backend_and_path("ssh://host/x?strict_host_key_checking=maybe", 1).is_err());
+    assert!(backend_and_path("ssh://host/x?hostkey=yes", 1).is_err());
+}
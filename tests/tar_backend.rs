@@ -0,0 +1,183 @@
+mod common;
+
+use parsync::backends::{backend_and_path, LocalBackend, StorageBackend, TarBackend};
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use tempfile::tempdir;
+
+#[test]
+/// Copy local → archive → local round-trips nested files, empty
+/// directories, permissions, FIFOs and nanosecond mtimes for plain, gzip and
+/// zstd archives
+fn test_copy_round_trip_through_tar() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("a/empty")).unwrap();
+    fs::write(src.join("top.txt"), b"top").unwrap();
+    fs::write(src.join("a/secret.txt"), b"secret").unwrap();
+    fs::set_permissions(src.join("a/secret.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+    let large: Vec<u8> = (0..(9 << 20) + 7).map(|i| (i % 251) as u8).collect();
+    fs::write(src.join("a/large.bin"), &large).unwrap();
+    mkfifo(&src.join("a/pipe"));
+    let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 123_456_789);
+    filetime::set_file_mtime(src.join("a/secret.txt"), mtime).unwrap();
+    filetime::set_file_mtime(src.join("a/empty"), mtime).unwrap();
+
+    let options = parsync::CopyOptions {
+        specials: true,
+        ..common::copy_options()
+    };
+    for name in ["out.tar", "out.tar.gz", "out.tar.zst"] {
+        let archive = dir.path().join(name);
+        let url = format!("tar://{}", archive.display());
+        let (tar, path) = backend_and_path(&url, 4).unwrap();
+        assert_eq!(path, "/");
+        parsync::copy(
+            Arc::new(LocalBackend::new()),
+            src.to_str().unwrap(),
+            tar.clone(),
+            path,
+            &options,
+        )
+        .unwrap();
+        tar.finish().unwrap();
+
+        let out = dir.path().join(format!("{name}.out"));
+        let (tar, path) = backend_and_path(&url, 4).unwrap();
+        parsync::copy(
+            tar,
+            path,
+            Arc::new(LocalBackend::new()),
+            out.to_str().unwrap(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(fs::read(out.join("top.txt")).unwrap(), b"top", "{name}");
+        assert_eq!(fs::read(out.join("a/large.bin")).unwrap(), large, "{name}");
+        assert!(out.join("a/empty").is_dir(), "{name}");
+        assert!(fs::symlink_metadata(out.join("a/pipe"))
+            .unwrap()
+            .file_type()
+            .is_fifo());
+        let meta = fs::metadata(out.join("a/secret.txt")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600, "{name}");
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&meta),
+            mtime,
+            "{name}"
+        );
+        let meta = fs::metadata(out.join("a/empty")).unwrap();
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&meta),
+            mtime,
+            "{name}"
+        );
+    }
+    let mut magic = [0u8; 4];
+    fs::File::open(dir.path().join("out.tar.zst"))
+        .unwrap()
+        .read_exact(&mut magic)
+        .unwrap();
+    assert_eq!(magic, [0x28, 0xb5, 0x2f, 0xfd]);
+}
+
+fn mkfifo(path: &std::path::Path) {
+    let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) }, 0);
+}
+
+#[test]
+/// Archives written by other tools: implied parent directories, long names,
+/// hard links and symlinks, with ranged reads of entry data
+fn test_tarbackend_reads_foreign_archive() {
+    let dir = tempdir().unwrap();
+    let archive = dir.path().join("foreign.tar");
+    let long_name = format!("deep/{}/file.txt", "x".repeat(150));
+    {
+        let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(10);
+        header.set_mode(0o640);
+        header.set_mtime(1_500_000_000);
+        builder
+            .append_data(&mut header, "./docs/readme.txt", &b"0123456789"[..])
+            .unwrap();
+        header.set_size(4);
+        builder
+            .append_data(&mut header, &long_name, &b"long"[..])
+            .unwrap();
+        let mut link = tar::Header::new_gnu();
+        link.set_entry_type(tar::EntryType::Link);
+        link.set_size(0);
+        builder
+            .append_link(&mut link, "docs/hard.txt", "docs/readme.txt")
+            .unwrap();
+        link.set_entry_type(tar::EntryType::Symlink);
+        builder
+            .append_link(&mut link, "docs/soft", "readme.txt")
+            .unwrap();
+        builder.finish().unwrap();
+    }
+
+    let tar = TarBackend::new(archive.to_str().unwrap());
+    assert!(tar.stat("/docs").unwrap().is_dir());
+    assert!(tar.stat(&format!("/{long_name}")).unwrap().is_file());
+    let meta = tar.stat("/docs/readme.txt").unwrap();
+    assert_eq!(meta.mode, Some(0o640));
+    assert_eq!(tar.get("/docs/hard.txt").unwrap(), b"0123456789");
+    assert_eq!(tar.read_link("/docs/soft").unwrap(), "readme.txt");
+    assert!(!tar.exists("/missing").unwrap());
+
+    let mut names: Vec<_> = tar
+        .list("/docs")
+        .unwrap()
+        .into_iter()
+        .map(|e| e.path)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["/docs/hard.txt", "/docs/readme.txt", "/docs/soft"]);
+
+    let mut range = Vec::new();
+    tar.open_read("/docs/readme.txt", 3, Some(4))
+        .unwrap()
+        .read_to_end(&mut range)
+        .unwrap();
+    assert_eq!(range, b"3456");
+    assert!(tar.put("/new.txt", b"new").is_err());
+}
+
+#[test]
+/// `parsync copy src tar://-` writes an archive to stdout that
+/// `parsync copy tar://- dst` reads back from stdin
+fn test_tar_through_a_pipe() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("nested")).unwrap();
+    for i in 0..20 {
+        fs::write(src.join(format!("nested/f{i}.txt")), format!("file {i}")).unwrap();
+    }
+
+    let bin = env!("CARGO_BIN_EXE_parsync");
+    let mut writer = Command::new(bin)
+        .args(["--no-progress", "copy", src.to_str().unwrap(), "tar://-"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let out = dir.path().join("out");
+    let status = Command::new(bin)
+        .args(["--no-progress", "copy", "tar://-", out.to_str().unwrap()])
+        .stdin(writer.stdout.take().unwrap())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(writer.wait().unwrap().success());
+    for i in 0..20 {
+        assert_eq!(
+            fs::read_to_string(out.join(format!("nested/f{i}.txt"))).unwrap(),
+            format!("file {i}")
+        );
+    }
+}
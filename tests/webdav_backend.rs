@@ -0,0 +1,325 @@
+mod common;
+
+use common::Reply;
+use parsync::backends::{backend_and_path, LocalBackend, StorageBackend, WebDavBackend};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tempfile::{tempdir, TempDir};
+
+/// `Authorization` header for `user:pw`.
+const BASIC_AUTH: &str = "Basic dXNlcjpwdw==";
+
+/// A class 1 WebDAV server over a temporary directory that, like Apache,
+/// redirects collection requests without a trailing slash. Dead properties
+/// are kept in memory.
+struct FakeDav {
+    authority: String,
+    root: TempDir,
+    puts: Arc<AtomicUsize>,
+}
+
+struct DavState {
+    root: PathBuf,
+    props: Mutex<HashMap<String, String>>,
+    puts: Arc<AtomicUsize>,
+}
+
+impl FakeDav {
+    fn start() -> Self {
+        let root = tempdir().unwrap();
+        let puts = Arc::new(AtomicUsize::new(0));
+        let state = DavState {
+            root: root.path().to_path_buf(),
+            props: Mutex::new(HashMap::new()),
+            puts: puts.clone(),
+        };
+        let endpoint = common::serve(move |req| {
+            assert_eq!(req.headers["authorization"], BASIC_AUTH);
+            state.handle(req)
+        });
+        Self {
+            authority: format!("user:pw@{}", endpoint.strip_prefix("http://").unwrap()),
+            root,
+            puts,
+        }
+    }
+
+    fn backend(&self) -> WebDavBackend {
+        WebDavBackend::connect(&self.authority, false, 4).unwrap()
+    }
+}
+
+fn status(code: u16) -> Reply {
+    (code, Vec::new(), Vec::new())
+}
+
+fn multistatus(responses: String) -> Reply {
+    let body = format!(
+        r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:">{responses}</D:multistatus>"#
+    );
+    (207, Vec::new(), body.into_bytes())
+}
+
+impl DavState {
+    fn fs_path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_matches('/'))
+    }
+
+    fn prop_response(&self, path: &str, fs_path: &Path) -> String {
+        let meta = fs::metadata(fs_path).unwrap();
+        let (href, kind) = if meta.is_dir() {
+            (
+                format!("{}/", path.trim_end_matches('/')),
+                "<D:collection/>",
+            )
+        } else {
+            (path.to_string(), "")
+        };
+        let href = percent_encoding::utf8_percent_encode(&href, percent_encoding::CONTROLS)
+            .to_string()
+            .replace(' ', "%20");
+        let modified = httpdate::fmt_http_date(meta.modified().unwrap());
+        let key = path.trim_end_matches('/').to_string();
+        let stored = match self.props.lock().unwrap().get(&key) {
+            Some(m) => format!(
+                r#"<D:propstat><D:prop><p:mtime xmlns:p="https://github.com/Saphereye/parsync">{m}</p:mtime></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>"#
+            ),
+            None => r#"<D:propstat><D:prop><p:mtime xmlns:p="https://github.com/Saphereye/parsync"/></D:prop><D:status>HTTP/1.1 404 Not Found</D:status></D:propstat>"#.to_string(),
+        };
+        format!(
+            "<D:response><D:href>{href}</D:href><D:propstat><D:prop><D:resourcetype>{kind}</D:resourcetype><D:getcontentlength>{}</D:getcontentlength><D:getlastmodified>{modified}</D:getlastmodified></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>{stored}</D:response>",
+            meta.len()
+        )
+    }
+
+    fn handle(&self, req: common::Request) -> Reply {
+        let path = req.path.as_str();
+        let fs_path = self.fs_path(path);
+        if fs_path.is_dir() && !path.ends_with('/') && req.method != "MKCOL" {
+            return (
+                301,
+                vec![("Location".into(), format!("{path}/"))],
+                Vec::new(),
+            );
+        }
+        match req.method.as_str() {
+            "PROPFIND" => {
+                if !fs_path.exists() {
+                    return status(404);
+                }
+                let mut responses = self.prop_response(path, &fs_path);
+                if req.headers["depth"] == "1" && fs_path.is_dir() {
+                    for entry in fs::read_dir(&fs_path).unwrap() {
+                        let name = entry.unwrap().file_name().to_string_lossy().to_string();
+                        let child = format!("{}/{name}", path.trim_end_matches('/'));
+                        responses.push_str(&self.prop_response(&child, &self.fs_path(&child)));
+                    }
+                }
+                multistatus(responses)
+            }
+            "PROPPATCH" => {
+                let body = String::from_utf8(req.body).unwrap();
+                let value = body
+                    .split("mtime>")
+                    .nth(1)
+                    .unwrap()
+                    .trim_end_matches("</p:");
+                self.props
+                    .lock()
+                    .unwrap()
+                    .insert(path.trim_end_matches('/').to_string(), value.to_string());
+                multistatus(format!(
+                    "<D:response><D:href>{path}</D:href><D:propstat><D:prop/><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>"
+                ))
+            }
+            "GET" => {
+                let Ok(data) = fs::read(&fs_path) else {
+                    return status(404);
+                };
+                match req.headers.get("range") {
+                    Some(range) => {
+                        let (start, end) = range
+                            .strip_prefix("bytes=")
+                            .unwrap()
+                            .split_once('-')
+                            .unwrap();
+                        let start: usize = start.parse().unwrap();
+                        let end = end
+                            .parse::<usize>()
+                            .map_or(data.len(), |e| (e + 1).min(data.len()));
+                        (206, Vec::new(), data[start..end].to_vec())
+                    }
+                    None => (200, Vec::new(), data),
+                }
+            }
+            "PUT" => {
+                self.puts.fetch_add(1, Ordering::SeqCst);
+                if !fs_path.parent().unwrap().is_dir() {
+                    return status(409);
+                }
+                fs::write(&fs_path, req.body).unwrap();
+                status(201)
+            }
+            "MKCOL" => {
+                if fs_path.exists() {
+                    status(405)
+                } else if !fs_path.parent().unwrap().is_dir() {
+                    status(409)
+                } else {
+                    fs::create_dir(&fs_path).unwrap();
+                    status(201)
+                }
+            }
+            "DELETE" => {
+                if fs_path.is_dir() {
+                    fs::remove_dir_all(&fs_path).unwrap();
+                } else if fs::remove_file(&fs_path).is_err() {
+                    return status(404);
+                }
+                status(204)
+            }
+            "MOVE" => {
+                let dest = req.headers["destination"].clone();
+                let dest = common::decode(&dest[dest.find("://").unwrap() + 3..]);
+                let dest = &dest[dest.find('/').unwrap()..];
+                assert_eq!(req.headers["overwrite"], "T");
+                fs::rename(&fs_path, self.fs_path(dest)).unwrap();
+                let mut props = self.props.lock().unwrap();
+                let moved: Vec<_> = props
+                    .keys()
+                    .filter(|k| k.starts_with(path.trim_end_matches('/')))
+                    .cloned()
+                    .collect();
+                for key in moved {
+                    let value = props.remove(&key).unwrap();
+                    let suffix = &key[path.trim_end_matches('/').len()..];
+                    props.insert(format!("{}{suffix}", dest.trim_end_matches('/')), value);
+                }
+                status(201)
+            }
+            _ => status(405),
+        }
+    }
+}
+
+#[test]
+fn test_webdavbackend_operations() {
+    let server = FakeDav::start();
+    let dav = server.backend();
+
+    dav.mkdir("/a/b c").unwrap();
+    assert!(server.root.path().join("a/b c").is_dir());
+    dav.put("/a/b c/file.txt", b"0123456789").unwrap();
+    dav.put("/a/top.txt", b"top").unwrap();
+
+    assert_eq!(dav.get("/a/b c/file.txt").unwrap(), b"0123456789");
+    assert!(dav.stat("/a").unwrap().is_dir());
+    assert_eq!(dav.stat("/a/b c/file.txt").unwrap().size, 10);
+    assert!(!dav.exists("/a/missing").unwrap());
+    let mut names: Vec<_> = dav
+        .list("/a")
+        .unwrap()
+        .into_iter()
+        .map(|e| e.path)
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["/a/b c", "/a/top.txt"]);
+
+    let mut range = Vec::new();
+    dav.open_read("/a/b c/file.txt", 2, Some(5))
+        .unwrap()
+        .read_to_end(&mut range)
+        .unwrap();
+    assert_eq!(range, b"23456");
+
+    let mtime = UNIX_EPOCH + Duration::new(1_600_000_000, 42);
+    let mut meta = dav.stat("/a/top.txt").unwrap();
+    meta.modified = Some(mtime);
+    dav.set_metadata("/a/top.txt", &meta).unwrap();
+    assert_eq!(dav.stat("/a/top.txt").unwrap().modified, Some(mtime));
+
+    dav.rename("/a/b c", "/moved").unwrap();
+    assert_eq!(dav.get("/moved/file.txt").unwrap(), b"0123456789");
+    dav.delete("/a").unwrap();
+    assert!(!server.root.path().join("a").exists());
+    dav.delete("/a").unwrap();
+}
+
+#[test]
+/// Copy local → WebDAV → local round-trips nested files, empty directories
+/// and nanosecond mtimes
+fn test_copy_round_trip_through_webdav() {
+    let server = FakeDav::start();
+    let url = format!("webdav://{}/backup/src", server.authority);
+    let (dav, path) = backend_and_path(&url, 4).unwrap();
+    assert_eq!(path, "/backup/src");
+
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("x/empty")).unwrap();
+    fs::write(src.join("x/deep.txt"), b"deep").unwrap();
+    let large: Vec<u8> = (0..3 << 20).map(|i| (i % 253) as u8).collect();
+    fs::write(src.join("large.bin"), &large).unwrap();
+    let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 987_654_321);
+    filetime::set_file_mtime(src.join("x/deep.txt"), mtime).unwrap();
+
+    let local = Arc::new(LocalBackend::new());
+    parsync::copy(
+        local.clone(),
+        src.to_str().unwrap(),
+        dav.clone(),
+        path,
+        &common::copy_options(),
+    )
+    .unwrap();
+    assert!(server.root.path().join("backup/src/x/empty").is_dir());
+
+    let out = dir.path().join("out");
+    parsync::copy(
+        dav,
+        path,
+        local,
+        out.to_str().unwrap(),
+        &common::copy_options(),
+    )
+    .unwrap();
+    assert_eq!(fs::read(out.join("large.bin")).unwrap(), large);
+    assert!(out.join("x/empty").is_dir());
+    let meta = fs::metadata(out.join("x/deep.txt")).unwrap();
+    assert_eq!(
+        filetime::FileTime::from_last_modification_time(&meta),
+        mtime
+    );
+}
+
+#[test]
+/// A second sync to WebDAV uploads nothing
+fn test_sync_to_webdav_converges() {
+    let server = FakeDav::start();
+    let dir = tempdir().unwrap();
+    fs::create_dir(dir.path().join("sub")).unwrap();
+    for i in 0..6 {
+        fs::write(dir.path().join(format!("sub/file{i}.txt")), b"payload").unwrap();
+    }
+
+    let run = || {
+        parsync::sync(
+            Arc::new(LocalBackend::new()),
+            dir.path().to_str().unwrap(),
+            Arc::new(server.backend()),
+            "/mirror",
+            true,
+            false,
+        )
+        .unwrap()
+    };
+    run();
+    assert_eq!(server.puts.load(Ordering::SeqCst), 6);
+    run();
+    assert_eq!(server.puts.load(Ordering::SeqCst), 6);
+}
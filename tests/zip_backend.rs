@@ -0,0 +1,153 @@
+mod common;
+
+use parsync::backends::{backend_and_path, LocalBackend, StorageBackend, ZipBackend};
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use tempfile::tempdir;
+use zip::write::SimpleFileOptions;
+
+#[test]
+/// Copy local → zip → local round-trips nested files, empty directories,
+/// permissions and whole-second mtimes, deflated and stored
+fn test_copy_round_trip_through_zip() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src");
+    fs::create_dir_all(src.join("a/empty")).unwrap();
+    for i in 0..50 {
+        fs::write(
+            src.join(format!("a/file{i}.txt")),
+            format!("file {i}\n").repeat(i),
+        )
+        .unwrap();
+    }
+    fs::write(src.join("a/script.sh"), b"#!/bin/sh\n").unwrap();
+    fs::set_permissions(src.join("a/script.sh"), fs::Permissions::from_mode(0o750)).unwrap();
+    let large: Vec<u8> = (0..(9 << 20) + 7).map(|i| (i % 251) as u8).collect();
+    fs::write(src.join("large.bin"), &large).unwrap();
+    let mtime = filetime::FileTime::from_unix_time(1_600_000_001, 0);
+    filetime::set_file_mtime(src.join("a/script.sh"), mtime).unwrap();
+    filetime::set_file_mtime(src.join("a/empty"), mtime).unwrap();
+
+    for (name, query) in [("deflated.zip", "?level=9"), ("stored.zip", "?level=0")] {
+        let url = format!("zip://{}{query}", dir.path().join(name).display());
+        let (zip, path) = backend_and_path(&url, 4).unwrap();
+        parsync::copy(
+            Arc::new(LocalBackend::new()),
+            src.to_str().unwrap(),
+            zip.clone(),
+            path,
+            &common::copy_options(),
+        )
+        .unwrap();
+        zip.finish().unwrap();
+
+        let out = dir.path().join(format!("{name}.out"));
+        let (zip, path) = backend_and_path(&url, 4).unwrap();
+        parsync::copy(
+            zip,
+            path,
+            Arc::new(LocalBackend::new()),
+            out.to_str().unwrap(),
+            &common::copy_options(),
+        )
+        .unwrap();
+        for i in 0..50 {
+            assert_eq!(
+                fs::read_to_string(out.join(format!("a/file{i}.txt"))).unwrap(),
+                format!("file {i}\n").repeat(i)
+            );
+        }
+        assert_eq!(fs::read(out.join("large.bin")).unwrap(), large, "{name}");
+        assert!(out.join("a/empty").is_dir(), "{name}");
+        let meta = fs::metadata(out.join("a/script.sh")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o750, "{name}");
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&meta),
+            mtime,
+            "{name}"
+        );
+        let meta = fs::metadata(out.join("a/empty")).unwrap();
+        assert_eq!(
+            filetime::FileTime::from_last_modification_time(&meta),
+            mtime,
+            "{name}"
+        );
+    }
+    let deflated = fs::metadata(dir.path().join("deflated.zip")).unwrap().len();
+    let stored = fs::metadata(dir.path().join("stored.zip")).unwrap().len();
+    assert!(deflated < stored / 4, "{deflated} vs {stored}");
+
+    // Other zip readers see the same entries.
+    let mut archive =
+        zip::ZipArchive::new(fs::File::open(dir.path().join("deflated.zip")).unwrap()).unwrap();
+    let mut data = String::new();
+    archive
+        .by_name("a/file3.txt")
+        .unwrap()
+        .read_to_string(&mut data)
+        .unwrap();
+    assert_eq!(data, "file 3\n".repeat(3));
+    assert!(archive.by_name("a/empty/").unwrap().is_dir());
+}
+
+#[test]
+/// Archives written by other tools: directories implied by entry names,
+/// stored and deflated entries, symlinks, ranged reads and CRC checks
+fn test_zipbackend_reads_foreign_archive() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("foreign.zip");
+    {
+        let mut writer = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+        let stored = SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored)
+            .unix_permissions(0o600);
+        writer.start_file("docs/plain.txt", stored).unwrap();
+        writer.write_all(b"0123456789").unwrap();
+        writer
+            .start_file("docs/deep/packed.txt", SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(&b"packed ".repeat(1000)).unwrap();
+        writer
+            .add_symlink("docs/link", "plain.txt", SimpleFileOptions::default())
+            .unwrap();
+        writer.finish().unwrap();
+    }
+
+    let zip = ZipBackend::new(path.to_str().unwrap(), None);
+    assert!(zip.stat("/docs/deep").unwrap().is_dir());
+    assert_eq!(zip.stat("/docs/plain.txt").unwrap().mode, Some(0o600));
+    assert_eq!(
+        zip.get("/docs/deep/packed.txt").unwrap(),
+        b"packed ".repeat(1000)
+    );
+    assert_eq!(zip.read_link("/docs/link").unwrap(), "plain.txt");
+    assert!(!zip.exists("/docs/missing").unwrap());
+
+    let mut names: Vec<_> = zip
+        .list("/docs")
+        .unwrap()
+        .into_iter()
+        .map(|e| e.path)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["/docs/deep", "/docs/link", "/docs/plain.txt"]);
+
+    let mut range = Vec::new();
+    zip.open_read("/docs/deep/packed.txt", 7, Some(6))
+        .unwrap()
+        .read_to_end(&mut range)
+        .unwrap();
+    assert_eq!(range, b"packed");
+    assert!(zip.put("/new.txt", b"new").is_err());
+    drop(zip);
+
+    // Flip a byte of the stored entry's data.
+    let mut bytes = fs::read(&path).unwrap();
+    let at = bytes.windows(10).position(|w| w == b"0123456789").unwrap();
+    bytes[at] = b'X';
+    fs::write(&path, bytes).unwrap();
+    let zip = ZipBackend::new(path.to_str().unwrap(), None);
+    assert!(zip.get("/docs/plain.txt").is_err());
+}